@@ -0,0 +1,199 @@
+// Learning the circuit build timeout from observed build times (path-spec 2.4).
+// Build times are assumed to follow a Pareto distribution, whose parameters are
+// estimated from a histogram that is kept in the state file.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Error;
+use std::time::Duration;
+
+use crate::crypto;
+use crate::dir::netdoc::invalid;
+use crate::state::StateFile;
+
+const STATE_KEY_BIN: &str = "CircuitBuildTimeBin";
+const STATE_KEY_ABANDONED: &str = "CircuitBuildAbandonedCount";
+const STATE_KEY_TOTAL: &str = "TotalBuildTimes";
+
+// Build times remembered, older ones are forgotten (CBT_NCIRCUITS_TO_OBSERVE)
+const MAX_BUILD_TIMES: usize = 1000;
+// Completed builds needed before the timeout is learned (CBT_DEFAULT_MIN_CIRCUITS_TO_OBSERVE)
+const MIN_BUILD_TIMES: usize = 100;
+// Histogram bin width in milliseconds (CBT_BIN_WIDTH)
+const BIN_WIDTH: u32 = 10;
+// Number of most frequent bins averaged for the Pareto mode Xm (CBT_DEFAULT_NUM_XM_MODES)
+const NUM_XM_MODES: usize = 10;
+// Quantile of the distribution used as timeout (CBT_DEFAULT_QUANTILE_CUTOFF)
+const QUANTILE_CUTOFF: f64 = 0.8;
+// Timeout used until enough builds were observed (CBT_DEFAULT_TIMEOUT_INITIAL_VALUE)
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(60_000);
+// Lower bound for the learned timeout (CBT_DEFAULT_TIMEOUT_MIN_VALUE)
+const MIN_TIMEOUT: Duration = Duration::from_millis(10);
+// If this many of the most recent builds timed out, the network has probably changed
+// (CBT_DEFAULT_RECENT_CIRCUITS and CBT_DEFAULT_MAX_RECENT_TIMEOUT_COUNT)
+const RECENT_BUILDS: usize = 20;
+const MAX_RECENT_TIMEOUTS: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BuildTime {
+    Completed(u32),
+    // Given up on after the timeout
+    Abandoned,
+}
+
+/// Observed circuit build times and the timeout learned from them.
+#[derive(Debug, Clone)]
+pub(crate) struct BuildTimes {
+    // Oldest first
+    times: VecDeque<BuildTime>,
+    // Whether each of the most recent builds timed out, oldest first
+    recent: VecDeque<bool>,
+    timeout: Duration,
+}
+
+impl BuildTimes {
+    pub(crate) fn new() -> BuildTimes {
+        return BuildTimes {
+            times: VecDeque::new(),
+            recent: VecDeque::new(),
+            timeout: INITIAL_TIMEOUT,
+        };
+    }
+
+    /// Restores the histogram from the state file. Like tor, the build times are
+    /// shuffled, as their order was not kept.
+    pub(crate) fn load(state: &StateFile) -> Result<BuildTimes, Error> {
+        let mut times: Vec<BuildTime> = vec![];
+        for line in state.get_all(STATE_KEY_BIN) {
+            let mut parts = line.split_whitespace();
+            let bin = parts.next().and_then(|b| b.parse::<u32>().ok());
+            let count = parts.next().and_then(|c| c.parse::<usize>().ok());
+            match (bin, count) {
+                (Some(b), Some(c)) => {
+                    times.extend(std::iter::repeat(BuildTime::Completed(b)).take(c))
+                }
+                _ => return Err(invalid(&format!("Invalid {}: {}", STATE_KEY_BIN, line))),
+            }
+        }
+        if let Some(n) = state.get(STATE_KEY_ABANDONED) {
+            let n = n
+                .parse::<usize>()
+                .map_err(|_| invalid(&format!("Invalid {}: {}", STATE_KEY_ABANDONED, n)))?;
+            times.extend(std::iter::repeat(BuildTime::Abandoned).take(n));
+        }
+        for i in (1..times.len()).rev() {
+            let j = crypto::random_below(i as u64 + 1) as usize;
+            times.swap(i, j);
+        }
+        let excess = times.len().saturating_sub(MAX_BUILD_TIMES);
+
+        let mut bt = BuildTimes::new();
+        bt.times = times.into_iter().skip(excess).collect();
+        bt.update_timeout();
+        return Ok(bt);
+    }
+
+    /// Writes the histogram to the state file.
+    pub(crate) fn store(&self, state: &mut StateFile) {
+        let mut bins: Vec<(u32, usize)> = self.histogram().into_iter().collect();
+        bins.sort_unstable();
+        state.set_all(
+            STATE_KEY_BIN,
+            bins.iter().map(|(b, c)| format!("{} {}", b, c)).collect(),
+        );
+        let abandoned = self
+            .times
+            .iter()
+            .filter(|t| **t == BuildTime::Abandoned)
+            .count();
+        state.set(STATE_KEY_ABANDONED, abandoned.to_string());
+        state.set(STATE_KEY_TOTAL, self.times.len().to_string());
+    }
+
+    /// How long to wait for a circuit to be built before giving up on it.
+    pub(crate) fn timeout(&self) -> Duration {
+        return self.timeout;
+    }
+
+    pub(crate) fn note_completed(&mut self, build_time: Duration) {
+        let ms = build_time.as_millis().min(u32::MAX as u128) as u32;
+        self.add(BuildTime::Completed(ms));
+    }
+
+    pub(crate) fn note_timeout(&mut self) {
+        self.add(BuildTime::Abandoned);
+        // With almost every build timing out, the history no longer describes the network
+        if self.recent.iter().filter(|t| **t).count() >= MAX_RECENT_TIMEOUTS {
+            *self = BuildTimes::new();
+        }
+    }
+
+    fn add(&mut self, t: BuildTime) {
+        if self.times.len() == MAX_BUILD_TIMES {
+            self.times.pop_front();
+        }
+        self.times.push_back(t);
+        if self.recent.len() == RECENT_BUILDS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(t == BuildTime::Abandoned);
+        self.update_timeout();
+    }
+
+    /// Completed builds per bin, keyed by the bin's midpoint in milliseconds.
+    fn histogram(&self) -> HashMap<u32, usize> {
+        let mut bins: HashMap<u32, usize> = HashMap::new();
+        for t in &self.times {
+            if let BuildTime::Completed(ms) = t {
+                *bins
+                    .entry(ms / BIN_WIDTH * BIN_WIDTH + BIN_WIDTH / 2)
+                    .or_insert(0) += 1;
+            }
+        }
+        return bins;
+    }
+
+    /// Estimates the Pareto parameters and sets the timeout to the cutoff quantile (path-spec 2.4.3).
+    fn update_timeout(&mut self) {
+        let completed: Vec<u32> = self
+            .times
+            .iter()
+            .filter_map(|t| match t {
+                BuildTime::Completed(ms) => Some(*ms),
+                BuildTime::Abandoned => None,
+            })
+            .collect();
+        if completed.len() < MIN_BUILD_TIMES {
+            self.timeout = INITIAL_TIMEOUT;
+            return;
+        }
+
+        // Xm is the average of the most frequent bins, weighted by their counts
+        let mut bins: Vec<(u32, usize)> = self.histogram().into_iter().collect();
+        bins.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let modes = &bins[..bins.len().min(NUM_XM_MODES)];
+        let mode_count: usize = modes.iter().map(|(_, c)| c).sum();
+        let xm = modes
+            .iter()
+            .map(|(b, c)| *b as f64 * *c as f64)
+            .sum::<f64>()
+            / mode_count as f64;
+
+        // Maximum likelihood estimate of alpha, treating abandoned builds as right-censored
+        // at the longest completed build time
+        let n_abandoned = self.times.len() - completed.len();
+        let max_time = *completed.iter().max().unwrap() as f64;
+        let mut sum_ln: f64 = completed.iter().map(|ms| (*ms as f64).max(xm).ln()).sum();
+        sum_ln += n_abandoned as f64 * max_time.max(xm).ln();
+        sum_ln -= self.times.len() as f64 * xm.ln();
+        if sum_ln <= 0.0 {
+            // All builds took about the same time, so there's nothing to fit
+            self.timeout = Duration::from_millis(max_time.max(xm) as u64).max(MIN_TIMEOUT);
+            return;
+        }
+        let alpha = completed.len() as f64 / sum_ln;
+
+        let timeout_ms = xm / (1.0 - QUANTILE_CUTOFF).powf(1.0 / alpha);
+        self.timeout =
+            Duration::from_millis(timeout_ms.min(u32::MAX as f64) as u64).max(MIN_TIMEOUT);
+    }
+}
@@ -1,8 +1,10 @@
 use super::variable_cell::{VariableCell, VariableCommand};
+use crate::crypto;
 use byteorder::{NetworkEndian, ReadBytesExt};
 use mbedtls::x509::certificate::Certificate;
-use std::io::{Cursor, Error, Read};
 use std::convert::TryInto;
+use std::io::{Cursor, Error, ErrorKind, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug)]
 pub(crate) struct CertsCell {
@@ -36,6 +38,9 @@ impl CertType {
     }
 }
 
+// The certified key is an Ed25519 key
+const CERT_KEY_TYPE_ED25519: u8 = 0x1;
+
 #[derive(Debug)]
 enum CertExtType {
     SignedWithEd25519Key = 4,
@@ -43,12 +48,12 @@ enum CertExtType {
 }
 
 impl CertExtType {
-    fn from_u8(b: u8) -> CertExtType {
-        match b {
-            4 => CertExtType::SignedWithEd25519Key,
-            7 => CertExtType::RSAEd25519Cross,
-            _ => panic!("Unknown certificate data extension type: {}", b),
-        }
+    fn from_u8(b: u8) -> Option<CertExtType> {
+        return match b {
+            4 => Some(CertExtType::SignedWithEd25519Key),
+            7 => Some(CertExtType::RSAEd25519Cross),
+            _ => None,
+        };
     }
 }
 
@@ -72,25 +77,101 @@ struct RSAEd25519Cross {
 }
 
 #[derive(Debug)]
-struct TorCustomFormatCert {
+pub(crate) struct TorCustomFormatCert {
     cert_type: u8,
-    expiration_date: u32,
-    cert_key_type: CertType,
+    expiration_date: u32, // In hours since the epoch
+    cert_key_type: u8,
     certified_key: [u8; 32],
     extensions: Option<Vec<CertExtension>>,
     signature: Vec<u8>, // Actually always 64 bytes long, but Debug can't be autoderived on long arrays
+    signed_bytes: Vec<u8>, // Everything preceding the signature, which is what it covers
 }
 
 impl TorCustomFormatCert {
+    /// Parses a certificate that makes up the entire buffer.
+    pub(crate) fn from_bytes(buf: &[u8]) -> Result<TorCustomFormatCert, Error> {
+        let mut c = Cursor::new(buf);
+        let mut cert = TorCustomFormatCert::from_reader(&mut c)?;
+        if c.position() as usize != buf.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Trailing data after tor custom format certificate",
+            ));
+        }
+        cert.signed_bytes = buf[..buf.len() - 64].to_vec();
+        return Ok(cert);
+    }
+
+    pub(crate) fn cert_type(&self) -> u8 {
+        return self.cert_type;
+    }
+
+    pub(crate) fn certified_key(&self) -> &[u8; 32] {
+        return &self.certified_key;
+    }
+
+    /// The key that signed this certificate, if included as an extension.
+    pub(crate) fn signing_key(&self) -> Option<&[u8; 32]> {
+        return self
+            .extensions
+            .iter()
+            .flatten()
+            .filter_map(|e| e.ed25519_key_extension.as_ref())
+            .map(|e| &e.key)
+            .next();
+    }
+
+    pub(crate) fn is_expired(&self, now: SystemTime) -> bool {
+        let hours = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 3600;
+        return hours >= self.expiration_date as u64;
+    }
+
+    /// Checks the certificate's signature. Only valid for certificates parsed with from_bytes().
+    pub(crate) fn verify(&self, signing_key: &[u8; 32]) -> bool {
+        return !self.signed_bytes.is_empty()
+            && crypto::ed25519_verify(signing_key, &self.signed_bytes, &self.signature);
+    }
+
+    /// Encodes a certificate for the Ed25519 key, signed by the given key pair and naming
+    /// the signing key in an extension, as onion service certificates do (cert-spec 2.1).
+    pub(crate) fn sign(
+        cert_type: u8,
+        expiration: SystemTime,
+        certified_key: &[u8; 32],
+        signing_public: &[u8; 32],
+        signing_expanded: &[u8; 64],
+    ) -> Vec<u8> {
+        let hours = expiration
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 3600;
+        let mut out: Vec<u8> = vec![0x1, cert_type];
+        out.extend_from_slice(&(hours as u32).to_be_bytes());
+        out.push(CERT_KEY_TYPE_ED25519);
+        out.extend_from_slice(certified_key);
+        // One extension: signed-with-ed25519-key, without flags
+        out.push(1);
+        out.extend_from_slice(&(signing_public.len() as u16).to_be_bytes());
+        out.push(CertExtType::SignedWithEd25519Key as u8);
+        out.push(0);
+        out.extend_from_slice(signing_public);
+        let sig = crypto::ed25519_sign(signing_expanded, signing_public, &out);
+        out.extend_from_slice(&sig);
+        return out;
+    }
+
     fn from_reader(rdr: &mut dyn Read) -> Result<TorCustomFormatCert, Error> {
         let mut ver_buf: [u8; 1] = [0x0];
         rdr.read_exact(&mut ver_buf)?;
-        // FIXME: Don't panic
         if ver_buf[0] != 0x1 {
-            panic!(
-                "Invalid tor custom format certificate version: {}, expected 1",
-                ver_buf[0]
-            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Invalid tor custom format certificate version: {}, expected 1",
+                    ver_buf[0]
+                ),
+            ));
         }
 
         let mut cert_type_buf: [u8; 1] = [0x0];
@@ -103,7 +184,7 @@ impl TorCustomFormatCert {
 
         let mut cert_key_type_buf: [u8; 1] = [0x0];
         rdr.read_exact(&mut cert_key_type_buf)?;
-        let cert_key_type = CertType::from_u8(cert_key_type_buf[0]);
+        let cert_key_type = cert_key_type_buf[0];
 
         let mut certified_key: [u8; 32] = [0x0; 32];
         rdr.read_exact(&mut certified_key)?;
@@ -127,24 +208,47 @@ impl TorCustomFormatCert {
             match ext_flags_buf[0] {
                 0 => (),
                 1 => affects_validation = true,
-                _ => panic!("Unknown certificate extension flag: {}", ext_flags_buf[0]),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown certificate extension flag: {}", ext_flags_buf[0]),
+                    ))
+                }
             }
 
             let mut ext_data: Vec<u8> = Vec::new();
             ext_data.resize(ext_length as usize, 0x0);
             rdr.read_exact(&mut ext_data)?;
 
-            let ed25519_cross: Option<RSAEd25519Cross>;
-            let signed_with_ed25519_key_extension: Option<SignedWithEd25519KeyExtension>;
+            let mut rsa_ed25519_cross: Option<RSAEd25519Cross> = None;
+            let mut ed25519_key_extension: Option<SignedWithEd25519KeyExtension> = None;
+            let ext_type = match ext_type {
+                Some(t) => t,
+                // Unknown extensions may only be ignored if they don't affect validation
+                None if affects_validation => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown certificate extension type: {}", ext_type_buf[0]),
+                    ))
+                }
+                None => continue,
+            };
             match ext_type {
                 CertExtType::RSAEd25519Cross => {
-                    parse_ed25519_cross(&mut &*ext_data);
+                    rsa_ed25519_cross = Some(parse_ed25519_cross(&mut &*ext_data)?);
                 }
                 CertExtType::SignedWithEd25519Key => {
-                    parse_signed_with_ed25519_key(&mut &*ext_data);
+                    ed25519_key_extension = Some(parse_signed_with_ed25519_key(
+                        &mut &*ext_data,
+                        affects_validation,
+                    )?);
                 }
             }
-            extensions.push(CertExtension { rsa_ed25519_cross: None, ext_type: ext_type, ed25519_key_extension: None });
+            extensions.push(CertExtension {
+                rsa_ed25519_cross,
+                ext_type,
+                ed25519_key_extension,
+            });
         }
 
         let mut signature_buf: Vec<u8> = vec![];
@@ -158,6 +262,7 @@ impl TorCustomFormatCert {
                 certified_key: certified_key,
                 extensions: Some(extensions),
                 signature: signature_buf,
+                signed_bytes: vec![],
             });
         } else {
             return Ok(TorCustomFormatCert {
@@ -167,6 +272,7 @@ impl TorCustomFormatCert {
                 certified_key: certified_key,
                 extensions: None,
                 signature: signature_buf,
+                signed_bytes: vec![],
             });
         }
     }
@@ -211,17 +317,21 @@ impl CertsCell {
                 | CertType::RSA1024AUTHENTICATECellLinkCertificate => {
                     final_cert = Cert {
                         cert_type,
-                        x509_cert: Some(Certificate::from_der(&cert).unwrap()),
+                        x509_cert: Some(Certificate::from_der(&cert).map_err(|e| {
+                            Error::new(
+                                ErrorKind::InvalidData,
+                                format!("Invalid X.509 certificate: {}", e),
+                            )
+                        })?),
                         tor_format_cert: None,
                     }
                 }
 
                 _ => {
-                    let mut c2 = Cursor::new(cert);
                     final_cert = Cert {
                         cert_type,
                         x509_cert: None,
-                        tor_format_cert: Some(TorCustomFormatCert::from_reader(&mut c2).unwrap()),
+                        tor_format_cert: Some(TorCustomFormatCert::from_bytes(&cert)?),
                     }
                 } // Tor's custom formats
             };
@@ -232,31 +342,34 @@ impl CertsCell {
     }
 }
 
-// FIXME: Implement
-struct SignedWithEd25519Key {
-
-}
-fn parse_signed_with_ed25519_key(rdr: &mut dyn Read) -> SignedWithEd25519Key {
-	return SignedWithEd25519Key{};
+fn parse_signed_with_ed25519_key(
+    rdr: &mut dyn Read,
+    affects_validation: bool,
+) -> Result<SignedWithEd25519KeyExtension, Error> {
+    let mut key: [u8; 32] = [0x0; 32];
+    rdr.read_exact(&mut key)?;
+    return Ok(SignedWithEd25519KeyExtension {
+        affects_validation,
+        key,
+    });
 }
 
-fn parse_ed25519_cross(rdr: &mut dyn Read) -> RSAEd25519Cross {
+fn parse_ed25519_cross(rdr: &mut dyn Read) -> Result<RSAEd25519Cross, Error> {
     let mut ed25519_key: [u8; 32] = [0x0; 32];
-    rdr.read_exact(&mut ed25519_key);
+    rdr.read_exact(&mut ed25519_key)?;
 
-    let mut expiration_date: [u8; 4] = [0x0; 4];
-    rdr.read_exact(&mut expiration_date);
+    let expiration_date = rdr.read_u32::<NetworkEndian>()?;
 
-    let mut sig_len: [u8; 1] = [0x0;1];
-    rdr.read_exact(&mut sig_len);
+    let mut sig_len: [u8; 1] = [0x0; 1];
+    rdr.read_exact(&mut sig_len)?;
 
     let mut sig_buf: Vec<u8> = vec![];
     sig_buf.resize(sig_len[0].try_into().unwrap(), 0x0);
-    rdr.read_exact(&mut sig_buf);
+    rdr.read_exact(&mut sig_buf)?;
 
-    return RSAEd25519Cross {
+    return Ok(RSAEd25519Cross {
         ed25519_key: ed25519_key,
-        expiration_date: expiration_date.as_ref().read_u32::<NetworkEndian>().unwrap(),
+        expiration_date: expiration_date,
         signature: sig_buf,
-    };
+    });
 }
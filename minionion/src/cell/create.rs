@@ -0,0 +1,170 @@
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use super::fixed_cell::{FixedCell, FixedCommand};
+
+// Handshake type of the ntor handshake in CREATE2/EXTEND2
+pub(crate) const HTYPE_NTOR: u16 = 0x0002;
+// Length of the CREATE_FAST key material
+pub(crate) const FAST_KEY_LEN: usize = 20;
+
+fn invalid(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg.to_string());
+}
+
+/// Tells the extending relay how to reach the next hop (tor-spec 5.1.2).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum LinkSpecifier {
+    Ipv4(SocketAddrV4),
+    Ipv6(SocketAddrV6),
+    RsaId([u8; 20]),
+    Ed25519Id([u8; 32]),
+}
+
+impl LinkSpecifier {
+    pub(crate) fn from_addr(addr: SocketAddr) -> LinkSpecifier {
+        return match addr {
+            SocketAddr::V4(a) => LinkSpecifier::Ipv4(a),
+            SocketAddr::V6(a) => LinkSpecifier::Ipv6(a),
+        };
+    }
+
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        let (ls_type, data): (u8, Vec<u8>) = match self {
+            LinkSpecifier::Ipv4(a) => {
+                let mut d = a.ip().octets().to_vec();
+                d.extend_from_slice(&a.port().to_be_bytes());
+                (0, d)
+            }
+            LinkSpecifier::Ipv6(a) => {
+                let mut d = a.ip().octets().to_vec();
+                d.extend_from_slice(&a.port().to_be_bytes());
+                (1, d)
+            }
+            LinkSpecifier::RsaId(id) => (2, id.to_vec()),
+            LinkSpecifier::Ed25519Id(id) => (3, id.to_vec()),
+        };
+        out.push(ls_type);
+        out.push(data.len() as u8);
+        out.extend(data);
+    }
+
+    /// Parses a list of link specifiers prefixed by their number, as in EXTEND2 and
+    /// onion service descriptors. Unknown types are skipped. Returns the list and the length read.
+    pub(crate) fn decode_list(buf: &[u8]) -> Result<(Vec<LinkSpecifier>, usize), Error> {
+        let n = match buf.first() {
+            Some(n) => *n,
+            None => return Err(invalid("Missing link specifier count")),
+        };
+        let mut pos: usize = 1;
+        let mut specs: Vec<LinkSpecifier> = vec![];
+        for _ in 0..n {
+            if buf.len() < pos + 2 || buf.len() < pos + 2 + buf[pos + 1] as usize {
+                return Err(invalid("Truncated link specifier"));
+            }
+            let ls_type = buf[pos];
+            let data = &buf[pos + 2..pos + 2 + buf[pos + 1] as usize];
+            pos += 2 + data.len();
+            let spec = match (ls_type, data.len()) {
+                (0, 6) => LinkSpecifier::Ipv4(SocketAddrV4::new(
+                    Ipv4Addr::new(data[0], data[1], data[2], data[3]),
+                    u16::from_be_bytes([data[4], data[5]]),
+                )),
+                (1, 18) => {
+                    let ip: [u8; 16] = data[..16].try_into().unwrap();
+                    let port = u16::from_be_bytes([data[16], data[17]]);
+                    LinkSpecifier::Ipv6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+                }
+                (2, 20) => LinkSpecifier::RsaId(data.try_into().unwrap()),
+                (3, 32) => LinkSpecifier::Ed25519Id(data.try_into().unwrap()),
+                (0..=3, _) => return Err(invalid("Link specifier has wrong length")),
+                _ => continue,
+            };
+            specs.push(spec);
+        }
+        return Ok((specs, pos));
+    }
+}
+
+/// Encodes a list of link specifiers prefixed by their number.
+pub(crate) fn encode_link_specifiers(specs: &[LinkSpecifier]) -> Vec<u8> {
+    let mut out: Vec<u8> = vec![specs.len() as u8];
+    for spec in specs {
+        spec.encode(&mut out);
+    }
+    return out;
+}
+
+pub(crate) fn create_fast_cell(circ_id: u32, x: &[u8; FAST_KEY_LEN]) -> FixedCell {
+    return FixedCell::new(
+        x.to_vec(),
+        true,
+        None,
+        FixedCommand::CreateFast,
+        circ_id.to_be_bytes(),
+    );
+}
+
+/// Returns Y and the derivative key data KH from a CREATED_FAST cell.
+pub(crate) fn parse_created_fast(
+    cell: &FixedCell,
+) -> Result<([u8; FAST_KEY_LEN], [u8; FAST_KEY_LEN]), Error> {
+    if cell.command != FixedCommand::CreatedFast {
+        return Err(invalid(&format!(
+            "Expected CREATED_FAST, got {:?}",
+            cell.command
+        )));
+    }
+    return Ok((
+        cell.payload[..FAST_KEY_LEN].try_into().unwrap(),
+        cell.payload[FAST_KEY_LEN..2 * FAST_KEY_LEN]
+            .try_into()
+            .unwrap(),
+    ));
+}
+
+pub(crate) fn create2_cell(circ_id: u32, htype: u16, hdata: &[u8]) -> FixedCell {
+    let mut payload: Vec<u8> = vec![];
+    payload.extend_from_slice(&htype.to_be_bytes());
+    payload.extend_from_slice(&(hdata.len() as u16).to_be_bytes());
+    payload.extend_from_slice(hdata);
+    return FixedCell::new(
+        payload,
+        true,
+        None,
+        FixedCommand::Create2,
+        circ_id.to_be_bytes(),
+    );
+}
+
+/// Returns HDATA of a CREATED2 cell or the body of an EXTENDED2 relay cell, which share a format.
+pub(crate) fn parse_created2_body(body: &[u8]) -> Result<Vec<u8>, Error> {
+    if body.len() < 2 {
+        return Err(invalid("Truncated CREATED2"));
+    }
+    let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    if body.len() < 2 + len {
+        return Err(invalid("CREATED2 HLEN exceeds payload"));
+    }
+    return Ok(body[2..2 + len].to_vec());
+}
+
+/// Builds the body of an EXTEND2 relay cell.
+pub(crate) fn extend2_body(specs: &[LinkSpecifier], htype: u16, hdata: &[u8]) -> Vec<u8> {
+    let mut body = encode_link_specifiers(specs);
+    body.extend_from_slice(&htype.to_be_bytes());
+    body.extend_from_slice(&(hdata.len() as u16).to_be_bytes());
+    body.extend_from_slice(hdata);
+    return body;
+}
+
+pub(crate) fn destroy_cell(circ_id: u32, reason: u8) -> FixedCell {
+    return FixedCell::new(
+        vec![reason],
+        true,
+        None,
+        FixedCommand::Destroy,
+        circ_id.to_be_bytes(),
+    );
+}
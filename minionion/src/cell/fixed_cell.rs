@@ -2,12 +2,12 @@ use std::convert::TryInto;
 use std::io::{Error, Read};
 
 // Size of fixed-size cell payload
-const PAYLOAD_LEN: usize = 509;
+pub(crate) const PAYLOAD_LEN: usize = 509;
 // Total length of fixed-size cell
-const CELL_LEN: usize = 514; // For version 4
+pub(crate) const CELL_LEN: usize = 514; // For version 4
 const CIRCID_LEN: usize = 4; // We support link protocol version 4 only
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum FixedCommand {
     Padding = 0,
     Create = 1,
@@ -24,6 +24,14 @@ pub(crate) enum FixedCommand {
 }
 
 impl FixedCommand {
+    /// Returns None for variable-length and unknown commands.
+    pub(crate) fn try_from_u8(b: u8) -> Option<FixedCommand> {
+        return match b {
+            0..=6 | 8..=12 => Some(FixedCommand::from_u8(b)),
+            _ => None,
+        };
+    }
+
     fn from_u8(b: u8) -> FixedCommand {
         match b {
             0 => FixedCommand::Padding,
@@ -51,8 +59,8 @@ pub(crate) struct FixedCell {
 }
 
 impl FixedCell {
-    fn new(
-        payload: Vec<u8>,
+    pub(crate) fn new(
+        mut payload: Vec<u8>,
         should_pad: bool,
         padding_byte: Option<u8>,
         cmd: FixedCommand,
@@ -72,7 +80,6 @@ impl FixedCell {
                 None => byte = 0x00,
             }
 
-            let mut payload = payload.clone();
             for _i in 0..padding_len {
                 payload.push(byte);
             }
@@ -88,7 +95,7 @@ impl FixedCell {
     pub(crate) fn from_bytes(buf: [u8; CELL_LEN]) -> FixedCell {
         let circ_id: [u8; CIRCID_LEN] = buf[0..CIRCID_LEN].try_into().unwrap();
         let cmd = FixedCommand::from_u8(buf[CIRCID_LEN]);
-        let payload: Vec<u8> = buf[(CIRCID_LEN + 1)..PAYLOAD_LEN].to_vec();
+        let payload: Vec<u8> = buf[(CIRCID_LEN + 1)..CELL_LEN].to_vec();
         return FixedCell::new(payload, true, Some(0x0), cmd, circ_id);
     }
 
@@ -98,6 +105,10 @@ impl FixedCell {
         return Ok(FixedCell::from_bytes(buf));
     }
 
+    pub(crate) fn circ_id(&self) -> u32 {
+        return u32::from_be_bytes(self.circuit_id);
+    }
+
     pub(crate) fn to_bytes(&self) -> [u8; CELL_LEN] {
         let mut vector: Vec<u8> = vec![];
         let mut buf: [u8; CELL_LEN] = [0x0; CELL_LEN];
@@ -5,11 +5,23 @@ const CIRCID_LEN_LEGACY: usize = 2;
 
 mod auth_challenge;
 mod certs;
+mod create;
 mod fixed_cell;
 mod net_info;
+mod relay_cell;
+mod resolve;
 mod variable_cell;
 pub(crate) mod versions;
 
 pub(crate) use auth_challenge::AuthChallengeCell;
-pub(crate) use certs::CertsCell;
+pub(crate) use certs::{CertsCell, TorCustomFormatCert};
+pub(crate) use create::{
+    create2_cell, create_fast_cell, destroy_cell, encode_link_specifiers, extend2_body,
+    parse_created2_body, parse_created_fast, LinkSpecifier, FAST_KEY_LEN, HTYPE_NTOR,
+};
+pub(crate) use fixed_cell::{FixedCell, FixedCommand, CELL_LEN, PAYLOAD_LEN};
 pub(crate) use net_info::NetInfoCell;
+pub use relay_cell::EndReason;
+pub(crate) use relay_cell::{RelayCell, RelayCommand, DIGEST_OFFSET, RELAY_DATA_LEN};
+pub(crate) use resolve::{parse_resolved, resolve_body, reverse_name};
+pub use resolve::{Resolved, ResolvedValue};
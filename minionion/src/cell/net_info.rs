@@ -1,5 +1,6 @@
 use super::fixed_cell::{FixedCell, FixedCommand};
 use std::io::{Error, Read};
+use std::net::IpAddr;
 
 #[derive(Debug, Clone)]
 pub(crate) struct NetInfoCell {
@@ -14,4 +15,27 @@ impl NetInfoCell {
         }
         return Ok(NetInfoCell { cell });
     }
+
+    /// Creates the NETINFO cell a client sends to complete the handshake.
+    /// Clients send a zero timestamp and no addresses of their own (tor-spec 4.5).
+    pub(crate) fn new_client(peer: IpAddr) -> NetInfoCell {
+        let mut payload: Vec<u8> = vec![0x0; 4];
+        match peer {
+            IpAddr::V4(ip) => {
+                payload.extend_from_slice(&[0x04, 4]);
+                payload.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                payload.extend_from_slice(&[0x06, 16]);
+                payload.extend_from_slice(&ip.octets());
+            }
+        }
+        payload.push(0); // NMYADDR
+        let cell = FixedCell::new(payload, true, None, FixedCommand::Netinfo, [0x0; 4]);
+        return NetInfoCell { cell };
+    }
+
+    pub(crate) fn to_bytes(&self) -> [u8; super::fixed_cell::CELL_LEN] {
+        return self.cell.to_bytes();
+    }
 }
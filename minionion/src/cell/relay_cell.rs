@@ -0,0 +1,213 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use super::fixed_cell::PAYLOAD_LEN;
+
+// Length of the relay header: command, recognized, stream ID, digest, length
+const RELAY_HEADER_LEN: usize = 11;
+/// Maximum amount of data in one relay cell
+pub(crate) const RELAY_DATA_LEN: usize = PAYLOAD_LEN - RELAY_HEADER_LEN;
+// Offset of the digest field in the relay header
+pub(crate) const DIGEST_OFFSET: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RelayCommand {
+    Begin = 1,
+    Data = 2,
+    End = 3,
+    Connected = 4,
+    Sendme = 5,
+    Extend = 6,
+    Extended = 7,
+    Truncate = 8,
+    Truncated = 9,
+    Drop = 10,
+    Resolve = 11,
+    Resolved = 12,
+    BeginDir = 13,
+    Extend2 = 14,
+    Extended2 = 15,
+    EstablishIntro = 32,
+    EstablishRendezvous = 33,
+    Introduce1 = 34,
+    Introduce2 = 35,
+    Rendezvous1 = 36,
+    Rendezvous2 = 37,
+    IntroEstablished = 38,
+    RendezvousEstablished = 39,
+    IntroduceAck = 40,
+}
+
+impl RelayCommand {
+    // Unlike link commands, unknown relay commands must be dropped rather than treated as fatal
+    fn from_u8(b: u8) -> Option<RelayCommand> {
+        use RelayCommand::*;
+        return Some(match b {
+            1 => Begin,
+            2 => Data,
+            3 => End,
+            4 => Connected,
+            5 => Sendme,
+            6 => Extend,
+            7 => Extended,
+            8 => Truncate,
+            9 => Truncated,
+            10 => Drop,
+            11 => Resolve,
+            12 => Resolved,
+            13 => BeginDir,
+            14 => Extend2,
+            15 => Extended2,
+            32 => EstablishIntro,
+            33 => EstablishRendezvous,
+            34 => Introduce1,
+            35 => Introduce2,
+            36 => Rendezvous1,
+            37 => Rendezvous2,
+            38 => IntroEstablished,
+            39 => RendezvousEstablished,
+            40 => IntroduceAck,
+            _ => return None,
+        });
+    }
+}
+
+/// Reasons for closing a stream, as carried in RELAY_END cells (tor-spec 6.3).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EndReason {
+    Misc = 1,
+    ResolveFailed = 2,
+    ConnectRefused = 3,
+    ExitPolicy = 4,
+    Destroy = 5,
+    Done = 6,
+    Timeout = 7,
+    NoRoute = 8,
+    Hibernating = 9,
+    Internal = 10,
+    ResourceLimit = 11,
+    ConnReset = 12,
+    TorProtocol = 13,
+    NotDirectory = 14,
+}
+
+impl EndReason {
+    pub(crate) fn from_u8(b: u8) -> EndReason {
+        use EndReason::*;
+        return match b {
+            2 => ResolveFailed,
+            3 => ConnectRefused,
+            4 => ExitPolicy,
+            5 => Destroy,
+            6 => Done,
+            7 => Timeout,
+            8 => NoRoute,
+            9 => Hibernating,
+            10 => Internal,
+            11 => ResourceLimit,
+            12 => ConnReset,
+            13 => TorProtocol,
+            14 => NotDirectory,
+            // Unknown reasons are treated as MISC
+            _ => Misc,
+        };
+    }
+
+    /// Extracts the reason from an error created from an EndReason.
+    pub fn from_error(err: &Error) -> Option<EndReason> {
+        return err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<EndReason>())
+            .copied();
+    }
+
+    fn error_kind(self) -> ErrorKind {
+        use EndReason::*;
+        return match self {
+            ResolveFailed | NoRoute => ErrorKind::NotFound,
+            ConnectRefused => ErrorKind::ConnectionRefused,
+            ExitPolicy => ErrorKind::PermissionDenied,
+            Timeout => ErrorKind::TimedOut,
+            ConnReset => ErrorKind::ConnectionReset,
+            Destroy | Done => ErrorKind::ConnectionAborted,
+            _ => ErrorKind::Other,
+        };
+    }
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "stream closed by relay: {:?}", self);
+    }
+}
+
+impl std::error::Error for EndReason {}
+
+impl From<EndReason> for Error {
+    /// The reason can be recovered with `err.get_ref()` and `downcast_ref::<EndReason>()`.
+    fn from(reason: EndReason) -> Error {
+        return Error::new(reason.error_kind(), reason);
+    }
+}
+
+/// The payload of a RELAY or RELAY_EARLY cell.
+#[derive(Debug, Clone)]
+pub(crate) struct RelayCell {
+    pub(crate) command: RelayCommand,
+    pub(crate) stream_id: u16,
+    pub(crate) data: Vec<u8>,
+}
+
+impl RelayCell {
+    pub(crate) fn new(command: RelayCommand, stream_id: u16, data: Vec<u8>) -> RelayCell {
+        if data.len() > RELAY_DATA_LEN {
+            panic!("Relay cell data too large: {} bytes", data.len());
+        }
+        return RelayCell {
+            command,
+            stream_id,
+            data,
+        };
+    }
+
+    /// Encodes the cell with "recognized" and the digest set to zero.
+    /// The unused space is padded with random bytes, preceded by 4 zero bytes (tor-spec 6.1).
+    pub(crate) fn to_payload(&self) -> [u8; PAYLOAD_LEN] {
+        let mut buf: [u8; PAYLOAD_LEN] = [0x0; PAYLOAD_LEN];
+        buf[0] = self.command as u8;
+        buf[3..5].copy_from_slice(&self.stream_id.to_be_bytes());
+        buf[9..11].copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+        buf[RELAY_HEADER_LEN..RELAY_HEADER_LEN + self.data.len()].copy_from_slice(&self.data);
+        let padding_start = RELAY_HEADER_LEN + self.data.len() + 4;
+        if padding_start < PAYLOAD_LEN {
+            crate::crypto::random_bytes(&mut buf[padding_start..]);
+        }
+        return buf;
+    }
+
+    /// Whether a decrypted payload could be meant for us; the digest must be checked as well.
+    pub(crate) fn is_recognized(payload: &[u8; PAYLOAD_LEN]) -> bool {
+        return payload[1] == 0 && payload[2] == 0;
+    }
+
+    /// Decodes a recognized payload. Returns None for unknown commands.
+    pub(crate) fn from_payload(payload: &[u8; PAYLOAD_LEN]) -> Result<Option<RelayCell>, Error> {
+        let len = u16::from_be_bytes(payload[9..11].try_into().unwrap()) as usize;
+        if len > RELAY_DATA_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Relay cell length field too large",
+            ));
+        }
+        let command = match RelayCommand::from_u8(payload[0]) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        return Ok(Some(RelayCell {
+            command,
+            stream_id: u16::from_be_bytes(payload[3..5].try_into().unwrap()),
+            data: payload[RELAY_HEADER_LEN..RELAY_HEADER_LEN + len].to_vec(),
+        }));
+    }
+}
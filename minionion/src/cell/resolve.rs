@@ -0,0 +1,92 @@
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// Answer types in RELAY_RESOLVED cells (tor-spec 6.4)
+const RESOLVED_TYPE_HOSTNAME: u8 = 0x00;
+const RESOLVED_TYPE_IPV4: u8 = 0x04;
+const RESOLVED_TYPE_IPV6: u8 = 0x06;
+const RESOLVED_TYPE_ERROR_TRANSIENT: u8 = 0xf0;
+const RESOLVED_TYPE_ERROR: u8 = 0xf1;
+
+/// One answer of a RELAY_RESOLVED cell.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolvedValue {
+    Ip(IpAddr),
+    Hostname(String),
+    /// The lookup failed, but may succeed later (like SERVFAIL)
+    TransientError,
+    /// The name does not exist (like NXDOMAIN)
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved {
+    pub value: ResolvedValue,
+    /// Time to live in seconds
+    pub ttl: u32,
+}
+
+/// Builds the body of a RELAY_RESOLVE cell.
+pub(crate) fn resolve_body(name: &str) -> Vec<u8> {
+    let mut body = name.as_bytes().to_vec();
+    body.push(0);
+    return body;
+}
+
+/// Returns the in-addr.arpa or ip6.arpa name used to look up the address' hostname.
+pub(crate) fn reverse_name(addr: IpAddr) -> String {
+    return match addr {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for b in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", b & 0xf, b >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    };
+}
+
+pub(crate) fn parse_resolved(data: &[u8]) -> Result<Vec<Resolved>, Error> {
+    let truncated = || Error::new(ErrorKind::InvalidData, "Truncated RELAY_RESOLVED cell");
+    let mut answers: Vec<Resolved> = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        if data.len() < pos + 2 {
+            return Err(truncated());
+        }
+        let (answer_type, len) = (data[pos], data[pos + 1] as usize);
+        let value = match data.get(pos + 2..pos + 2 + len) {
+            Some(v) => v,
+            None => return Err(truncated()),
+        };
+        let ttl = match data.get(pos + 2 + len..pos + 6 + len) {
+            Some(t) => u32::from_be_bytes(t.try_into().unwrap()),
+            None => return Err(truncated()),
+        };
+        pos += 6 + len;
+        let value = match (answer_type, len) {
+            (RESOLVED_TYPE_IPV4, 4) => ResolvedValue::Ip(IpAddr::V4(Ipv4Addr::new(
+                value[0], value[1], value[2], value[3],
+            ))),
+            (RESOLVED_TYPE_IPV6, 16) => {
+                let octets: [u8; 16] = value.try_into().unwrap();
+                ResolvedValue::Ip(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            (RESOLVED_TYPE_HOSTNAME, _) => {
+                ResolvedValue::Hostname(String::from_utf8_lossy(value).to_string())
+            }
+            (RESOLVED_TYPE_ERROR_TRANSIENT, _) => ResolvedValue::TransientError,
+            (RESOLVED_TYPE_ERROR, _) => ResolvedValue::Error,
+            // Unknown or malformed answers are skipped
+            _ => continue,
+        };
+        answers.push(Resolved { value, ttl });
+    }
+    return Ok(answers);
+}
@@ -0,0 +1,145 @@
+// The open connections to relays. Circuits through the same relay share one connection,
+// and connections to different relays are made concurrently.
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::connection::TorConnection;
+
+// Connections without circuits are closed after this long, like tor's idle timeout for client channels
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+/// What a connection is looked up by: the relay's RSA identity, or only its address
+/// for bridges whose identity was not configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ChannelKey {
+    Identity([u8; 20]),
+    Addr(SocketAddr),
+}
+
+enum Slot {
+    Open(TorConnection),
+    // Another thread is connecting and notifies `done` when finished
+    Connecting,
+}
+
+/// Keeps at most one open connection per relay.
+pub(crate) struct ChannelManager {
+    channels: Mutex<HashMap<ChannelKey, Slot>>,
+    done: Condvar,
+}
+
+impl ChannelManager {
+    pub(crate) fn new() -> ChannelManager {
+        return ChannelManager {
+            channels: Mutex::new(HashMap::new()),
+            done: Condvar::new(),
+        };
+    }
+
+    /// Returns the open connection to the relay, connecting to it first if there is none.
+    /// If another thread is already connecting to the relay, its connection is used.
+    pub(crate) fn get_or_connect(
+        &self,
+        key: ChannelKey,
+        addr: SocketAddr,
+    ) -> Result<TorConnection, Error> {
+        let mut channels = self.channels.lock().unwrap();
+        loop {
+            let connecting = match channels.get(&key) {
+                Some(Slot::Open(conn)) if !conn.is_closed() => return Ok(conn.clone()),
+                Some(Slot::Connecting) => true,
+                _ => false,
+            };
+            if !connecting {
+                break;
+            }
+            // If that attempt fails, this thread tries on its own
+            channels = self.done.wait(channels).unwrap();
+        }
+        channels.insert(key, Slot::Connecting);
+        // Handshakes with other relays go ahead meanwhile
+        drop(channels);
+
+        let mut attempt = Attempt {
+            mgr: self,
+            key,
+            conn: None,
+        };
+        let result = TorConnection::handshake(addr);
+        if let Ok(conn) = &result {
+            attempt.conn = Some(conn.clone());
+        }
+        return result;
+    }
+
+    /// Forgets connections that were closed and closes those without circuits for a while.
+    pub(crate) fn expire(&self, now: Instant) {
+        self.channels.lock().unwrap().retain(|_, slot| {
+            let conn = match slot {
+                Slot::Open(conn) => conn,
+                Slot::Connecting => return true,
+            };
+            if conn.is_closed() {
+                return false;
+            }
+            match conn.idle_since() {
+                Some(t) if now.saturating_duration_since(t) >= IDLE_TIMEOUT => {
+                    conn.close();
+                    return false;
+                }
+                _ => return true,
+            }
+        });
+    }
+}
+
+/// Replaces a `Slot::Connecting` with the outcome of the attempt when dropped,
+/// so waiting threads are woken up even if the handshake panics.
+struct Attempt<'a> {
+    mgr: &'a ChannelManager,
+    key: ChannelKey,
+    conn: Option<TorConnection>,
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        // Poisoning is ignored, as this may run while unwinding
+        let mut channels = self.mgr.channels.lock().unwrap_or_else(|e| e.into_inner());
+        match self.conn.take() {
+            Some(conn) => channels.insert(self.key, Slot::Open(conn)),
+            None => channels.remove(&self.key),
+        };
+        self.mgr.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn attempt_clears_slot_on_panic() {
+        let mgr = Arc::new(ChannelManager::new());
+        let key = ChannelKey::Identity([7; 20]);
+        mgr.channels.lock().unwrap().insert(key, Slot::Connecting);
+
+        let panicking = mgr.clone();
+        let res = thread::spawn(move || {
+            let _attempt = Attempt {
+                mgr: &panicking,
+                key,
+                conn: None,
+            };
+            panic!("handshake failed");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(mgr.channels.lock().unwrap().get(&key).is_none());
+    }
+}
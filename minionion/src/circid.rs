@@ -0,0 +1,122 @@
+// Circuit IDs on a link (tor-spec 5.1.1). In link protocol 4 and later, the side that opened
+// the link sets the high bit of the IDs it picks and the other side clears it, so both can
+// open circuits without colliding.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::crypto;
+
+const HIGH_BIT: u32 = 0x8000_0000;
+// Random IDs tried before the link is considered full, as in tor. With fewer than half the
+// IDs in use, failing all of them is far less likely than 2^-64.
+const MAX_ATTEMPTS: usize = 64;
+// IDs of destroyed circuits aren't reused for this long, so late cells for the
+// old circuit aren't taken for the new one
+const DESTROYED_ID_HOLD: Duration = Duration::from_secs(60);
+
+/// Hands out the circuit IDs for one side of a link.
+pub(crate) struct CircIdAllocator {
+    // Whether this side opened the link and so sets the high bit
+    initiator: bool,
+    // Oldest first
+    destroyed: VecDeque<(u32, Instant)>,
+}
+
+impl CircIdAllocator {
+    pub(crate) fn new(initiator: bool) -> CircIdAllocator {
+        return CircIdAllocator {
+            initiator,
+            destroyed: VecDeque::new(),
+        };
+    }
+
+    /// Returns a random ID for a new circuit, skipping IDs for which `in_use` is true and
+    /// those of recently destroyed circuits. Fails if no free ID turns up after a few tries.
+    pub(crate) fn allocate<F: Fn(u32) -> bool>(
+        &mut self,
+        in_use: F,
+        now: Instant,
+    ) -> Result<u32, Error> {
+        while let Some((_, t)) = self.destroyed.front() {
+            if now.saturating_duration_since(*t) < DESTROYED_ID_HOLD {
+                break;
+            }
+            self.destroyed.pop_front();
+        }
+        for _ in 0..MAX_ATTEMPTS {
+            let candidate = crypto::random_u64() as u32 & !HIGH_BIT;
+            let id = if self.initiator {
+                candidate | HIGH_BIT
+            } else {
+                candidate
+            };
+            // Zero is never a valid circuit ID
+            if id == 0 || in_use(id) || self.destroyed.iter().any(|(d, _)| *d == id) {
+                continue;
+            }
+            return Ok(id);
+        }
+        return Err(Error::new(
+            ErrorKind::Other,
+            "No circuit IDs left on connection",
+        ));
+    }
+
+    /// Notes that the circuit with the ID was destroyed.
+    pub(crate) fn release(&mut self, id: u32, now: Instant) {
+        if self.is_ours(id) {
+            self.destroyed.push_back((id, now));
+        }
+    }
+
+    /// Whether the other side may open a circuit with the ID.
+    pub(crate) fn accepts_inbound(&self, id: u32) -> bool {
+        return id != 0 && !self.is_ours(id);
+    }
+
+    fn is_ours(&self, id: u32) -> bool {
+        return (id & HIGH_BIT != 0) == self.initiator;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_sets_high_bit_for_initiator() {
+        let now = Instant::now();
+        let mut ours = CircIdAllocator::new(true);
+        let mut theirs = CircIdAllocator::new(false);
+        for _ in 0..100 {
+            let id = ours.allocate(|_| false, now).unwrap();
+            assert!(id & HIGH_BIT != 0);
+            assert!(theirs.accepts_inbound(id));
+            assert!(!ours.accepts_inbound(id));
+            let id = theirs.allocate(|_| false, now).unwrap();
+            assert!(id != 0 && id & HIGH_BIT == 0);
+            assert!(ours.accepts_inbound(id));
+            assert!(!theirs.accepts_inbound(id));
+        }
+    }
+
+    #[test]
+    fn allocate_fails_when_full() {
+        let mut alloc = CircIdAllocator::new(true);
+        assert!(alloc.allocate(|_| true, Instant::now()).is_err());
+    }
+
+    #[test]
+    fn destroyed_ids_are_held() {
+        let now = Instant::now();
+        let mut alloc = CircIdAllocator::new(true);
+        let id = alloc.allocate(|_| false, now).unwrap();
+        alloc.release(id, now);
+        // Every other ID is taken, and this one is held back
+        assert!(alloc.allocate(|other| other != id, now).is_err());
+        alloc.allocate(|_| false, now + DESTROYED_ID_HOLD).unwrap();
+        assert!(alloc.destroyed.is_empty());
+    }
+}
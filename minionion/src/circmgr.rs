@@ -0,0 +1,174 @@
+// The pool of exit circuits: which are clean or dirty, which can take a stream, and which
+// should be built ahead of time for the ports in use (path-spec 2.1).
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::circuit::Circuit;
+use crate::dir::PortPolicy;
+use crate::isolation::{CircuitIsolation, StreamIsolation};
+
+// Circuits which have carried a stream get no new streams after this long, as tor's MaxCircuitDirtiness
+const MAX_CIRCUIT_DIRTINESS: Duration = Duration::from_secs(10 * 60);
+// How long a port counts as in use after a stream to it, as tor's PredictedPortsRelevanceTime
+const PREDICTED_PORT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+// Clean circuits wanted for each predicted port
+const CLEAN_CIRCUITS_PER_PORT: usize = 2;
+// Never keep more clean circuits than this, as tor's MAX_UNUSED_OPEN_CIRCUITS
+const MAX_CLEAN_CIRCUITS: usize = 14;
+// Like tor, expect web browsing before anything was requested
+const INITIAL_PREDICTED_PORT: u16 = 80;
+
+/// A circuit opened for exit streams, together with what decides whether it can be reused.
+struct ExitCircuit {
+    circ: Circuit,
+    exit_policy: PortPolicy,
+    isolation: CircuitIsolation,
+    // When the first stream was attached; None while the circuit is clean
+    dirty_since: Option<Instant>,
+}
+
+impl ExitCircuit {
+    fn allows(&self, port: Option<u16>) -> bool {
+        return match port {
+            Some(p) => self.exit_policy.allows_port(p),
+            None => self.exit_policy.allows_some_port(),
+        };
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        return match self.dirty_since {
+            Some(t) => now.duration_since(t) >= MAX_CIRCUIT_DIRTINESS,
+            None => false,
+        };
+    }
+}
+
+/// Keeps the exit circuits of a client. Circuits are built elsewhere and handed in.
+pub(crate) struct CircuitManager {
+    circuits: Vec<ExitCircuit>,
+    // Circuits which take no new streams, but still carry some
+    retired: Vec<Circuit>,
+    // Ports streams were recently opened to, with the time of the last one.
+    // None stands for RELAY_RESOLVE, for which any exit will do.
+    predicted: HashMap<Option<u16>, Instant>,
+}
+
+impl CircuitManager {
+    pub(crate) fn new(now: Instant) -> CircuitManager {
+        let mut predicted = HashMap::new();
+        predicted.insert(Some(INITIAL_PREDICTED_PORT), now);
+        return CircuitManager {
+            circuits: vec![],
+            retired: vec![],
+            predicted,
+        };
+    }
+
+    /// Notes that a stream is about to be opened, for predicting which circuits to build.
+    pub(crate) fn note_request(&mut self, port: Option<u16>, now: Instant) {
+        self.predicted.insert(port, now);
+    }
+
+    /// Returns a circuit for the stream and attaches it, marking the circuit dirty.
+    /// Dirty circuits are preferred, so that clean ones stay available for other isolation needs.
+    pub(crate) fn get(
+        &mut self,
+        dest_addr: &str,
+        port: Option<u16>,
+        isolation: &StreamIsolation,
+        now: Instant,
+    ) -> Option<Circuit> {
+        self.expire(now);
+        let usable =
+            |c: &ExitCircuit| c.allows(port) && c.isolation.allows(isolation, dest_addr, port);
+        let idx = match self
+            .circuits
+            .iter()
+            .position(|c| c.dirty_since.is_some() && usable(c))
+        {
+            Some(i) => i,
+            None => self.circuits.iter().position(usable)?,
+        };
+        let c = &mut self.circuits[idx];
+        c.isolation.add(isolation, dest_addr, port);
+        if c.dirty_since.is_none() {
+            c.dirty_since = Some(now);
+        }
+        return Some(c.circ.clone());
+    }
+
+    /// Adds a newly built circuit. If a stream is given, it's attached right away.
+    pub(crate) fn add(
+        &mut self,
+        circ: Circuit,
+        exit_policy: PortPolicy,
+        stream: Option<(&str, Option<u16>, &StreamIsolation)>,
+        now: Instant,
+    ) {
+        let mut c = ExitCircuit {
+            circ,
+            exit_policy,
+            isolation: CircuitIsolation::new(),
+            dirty_since: None,
+        };
+        if let Some((dest_addr, port, isolation)) = stream {
+            c.isolation.add(isolation, dest_addr, port);
+            c.dirty_since = Some(now);
+        }
+        self.circuits.push(c);
+    }
+
+    /// Stops using the circuit for new streams, e.g. because streams on it failed.
+    pub(crate) fn retire(&mut self, circ: &Circuit) {
+        if let Some(idx) = self.circuits.iter().position(|c| c.circ.same_as(circ)) {
+            let c = self.circuits.remove(idx);
+            self.retired.push(c.circ);
+        }
+        self.close_idle_retired();
+    }
+
+    /// Drops closed circuits and retires circuits which have been dirty for too long.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.circuits.retain(|c| !c.circ.is_closed());
+        let (expired, live): (Vec<ExitCircuit>, Vec<ExitCircuit>) =
+            self.circuits.drain(..).partition(|c| c.is_expired(now));
+        self.circuits = live;
+        self.retired.extend(expired.into_iter().map(|c| c.circ));
+        self.predicted
+            .retain(|_, t| now.duration_since(*t) < PREDICTED_PORT_LIFETIME);
+        self.close_idle_retired();
+    }
+
+    /// A port a clean circuit should be built for, if any predicted port lacks them.
+    pub(crate) fn next_needed(&self) -> Option<Option<u16>> {
+        let clean: Vec<&ExitCircuit> = self
+            .circuits
+            .iter()
+            .filter(|c| c.dirty_since.is_none())
+            .collect();
+        if clean.len() >= MAX_CLEAN_CIRCUITS {
+            return None;
+        }
+        let mut ports: Vec<&Option<u16>> = self.predicted.keys().collect();
+        // Build for the most recently used ports first
+        ports.sort_by_key(|p| std::cmp::Reverse(self.predicted[p]));
+        return ports
+            .into_iter()
+            .find(|p| clean.iter().filter(|c| c.allows(**p)).count() < CLEAN_CIRCUITS_PER_PORT)
+            .copied();
+    }
+
+    fn close_idle_retired(&mut self) {
+        self.retired.retain(|circ| {
+            if circ.is_closed() {
+                return false;
+            }
+            if circ.n_streams() == 0 {
+                circ.close();
+                return false;
+            }
+            return true;
+        });
+    }
+}
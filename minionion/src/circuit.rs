@@ -0,0 +1,792 @@
+// Circuits: relay cell crypto, stream multiplexing and flow control (tor-spec 5, 6 and 7).
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
+use sha3::Sha3_256;
+
+use crate::cell::{
+    self, EndReason, FixedCell, FixedCommand, LinkSpecifier, RelayCell, RelayCommand,
+    DIGEST_OFFSET, HTYPE_NTOR, PAYLOAD_LEN,
+};
+use crate::connection::TorConnection;
+use crate::crypto::{self, Aes128Ctr, Aes256Ctr, SyncStreamCipher};
+use crate::dir::Relay;
+use crate::ntor::{FastClient, NtorClient, HOP_KEY_LEN, NONCE_LEN};
+
+// Flow control windows (tor-spec 7.3 and 7.4)
+const CIRCWINDOW_START: u32 = 1000;
+const CIRCWINDOW_INCREMENT: u32 = 100;
+const STREAMWINDOW_START: u32 = 500;
+const STREAMWINDOW_INCREMENT: u32 = 50;
+// Relays refuse circuits on which more than 8 RELAY_EARLY cells arrive
+const MAX_RELAY_EARLY: u8 = 8;
+// How long to wait for the answer to a CREATE or EXTEND
+const BUILD_STEP_TIMEOUT: Duration = Duration::from_secs(30);
+// DESTROY reason sent when closing a circuit ourselves
+const DESTROY_REASON_FINISHED: u8 = 9;
+// DESTROY reason sent when the other side violated the protocol
+const DESTROY_REASON_PROTOCOL: u8 = 1;
+
+fn closed_error() -> Error {
+    return Error::from(EndReason::Destroy);
+}
+
+/// What we need to know about a relay to add it to a circuit.
+#[derive(Debug, Clone)]
+pub(crate) struct CircTarget {
+    pub(crate) addr: SocketAddr,
+    pub(crate) rsa_identity: [u8; 20],
+    pub(crate) ed25519_identity: Option<[u8; 32]>,
+    pub(crate) ntor_onion_key: [u8; 32],
+}
+
+impl CircTarget {
+    pub(crate) fn from_relay(relay: &Relay) -> CircTarget {
+        return CircTarget {
+            addr: relay.ipv4_addr(),
+            rsa_identity: *relay.identity(),
+            ed25519_identity: relay.md.ed25519_identity,
+            ntor_onion_key: relay.md.ntor_onion_key,
+        };
+    }
+
+    /// The target described by link specifiers, as given for introduction points
+    /// and rendezvous points. An IPv4 address and an RSA identity are required.
+    pub(crate) fn from_link_specifiers(
+        specs: &[LinkSpecifier],
+        ntor_onion_key: [u8; 32],
+    ) -> Result<CircTarget, Error> {
+        let mut addr: Option<SocketAddr> = None;
+        let mut rsa_identity: Option<[u8; 20]> = None;
+        let mut ed25519_identity: Option<[u8; 32]> = None;
+        for spec in specs {
+            match spec {
+                LinkSpecifier::Ipv4(a) => addr = Some(SocketAddr::V4(*a)),
+                LinkSpecifier::RsaId(id) => rsa_identity = Some(*id),
+                LinkSpecifier::Ed25519Id(id) => ed25519_identity = Some(*id),
+                LinkSpecifier::Ipv6(_) => (),
+            }
+        }
+        return match (addr, rsa_identity) {
+            (Some(addr), Some(rsa_identity)) => Ok(CircTarget {
+                addr,
+                rsa_identity,
+                ed25519_identity,
+                ntor_onion_key,
+            }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Link specifiers lack an IPv4 address or RSA identity",
+            )),
+        };
+    }
+
+    pub(crate) fn link_specifiers(&self) -> Vec<LinkSpecifier> {
+        let mut specs = vec![
+            LinkSpecifier::from_addr(self.addr),
+            LinkSpecifier::RsaId(self.rsa_identity),
+        ];
+        if let Some(id) = self.ed25519_identity {
+            specs.push(LinkSpecifier::Ed25519Id(id));
+        }
+        return specs;
+    }
+}
+
+/// Length of the key material for the virtual hop to an onion service: Df, Db, Kf and Kb
+pub(crate) const ONION_HOP_KEY_LEN: usize = 32 + 32 + 32 + 32;
+
+/// The running digest of relay cells: SHA-1 for relays, SHA3-256 for onion services (rend-spec-v3 4.2.1).
+#[derive(Clone)]
+enum RelayDigest {
+    Sha1(Sha1),
+    Sha3(Sha3_256),
+}
+
+impl RelayDigest {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            RelayDigest::Sha1(d) => d.update(data),
+            RelayDigest::Sha3(d) => d.update(data),
+        }
+    }
+
+    /// The digest so far, truncated to 20 bytes as used in SENDMEs.
+    fn value(&self) -> [u8; 20] {
+        let full = match self {
+            RelayDigest::Sha1(d) => d.clone().finalize().to_vec(),
+            RelayDigest::Sha3(d) => d.clone().finalize().to_vec(),
+        };
+        return full[..20].try_into().unwrap();
+    }
+}
+
+/// AES-128-CTR for relays, AES-256-CTR for onion services.
+enum RelayCipher {
+    Aes128(Aes128Ctr),
+    Aes256(Aes256Ctr),
+}
+
+impl RelayCipher {
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        match self {
+            RelayCipher::Aes128(c) => c.apply_keystream(data),
+            RelayCipher::Aes256(c) => c.apply_keystream(data),
+        }
+    }
+}
+
+/// Relay cell encryption and integrity state shared with one hop.
+struct HopCrypto {
+    forward_cipher: RelayCipher,
+    backward_cipher: RelayCipher,
+    forward_digest: RelayDigest,
+    backward_digest: RelayDigest,
+    // Digest after the last cell received from the hop, as echoed in SENDMEs
+    last_backward_digest: [u8; 20],
+    // KH from the ntor handshake, if the key material included it
+    nonce: Option<[u8; NONCE_LEN]>,
+}
+
+impl HopCrypto {
+    /// Sets up the hop from key material laid out as Df | Db | Kf | Kb, optionally followed by KH.
+    fn new(keys: &[u8]) -> HopCrypto {
+        let mut forward_digest = Sha1::new();
+        forward_digest.update(&keys[0..20]);
+        let mut backward_digest = Sha1::new();
+        backward_digest.update(&keys[20..40]);
+        return HopCrypto {
+            forward_cipher: RelayCipher::Aes128(crypto::aes128_ctr(&keys[40..56])),
+            backward_cipher: RelayCipher::Aes128(crypto::aes128_ctr(&keys[56..72])),
+            forward_digest: RelayDigest::Sha1(forward_digest),
+            backward_digest: RelayDigest::Sha1(backward_digest),
+            last_backward_digest: [0x0; 20],
+            nonce: keys
+                .get(HOP_KEY_LEN..HOP_KEY_LEN + NONCE_LEN)
+                .map(|n| n.try_into().unwrap()),
+        };
+    }
+
+    /// Sets up the end-to-end hop with an onion service from key material laid out as
+    /// Df | Db | Kf | Kb. The service passes `reverse`, as its forward direction is our backward one.
+    fn new_onion(keys: &[u8], reverse: bool) -> HopCrypto {
+        let (df, db, kf, kb) = (&keys[0..32], &keys[32..64], &keys[64..96], &keys[96..128]);
+        let (df, db, kf, kb) = if reverse {
+            (db, df, kb, kf)
+        } else {
+            (df, db, kf, kb)
+        };
+        let mut forward_digest = Sha3_256::new();
+        forward_digest.update(df);
+        let mut backward_digest = Sha3_256::new();
+        backward_digest.update(db);
+        return HopCrypto {
+            forward_cipher: RelayCipher::Aes256(crypto::aes256_ctr(kf, &[0x0; 16])),
+            backward_cipher: RelayCipher::Aes256(crypto::aes256_ctr(kb, &[0x0; 16])),
+            forward_digest: RelayDigest::Sha3(forward_digest),
+            backward_digest: RelayDigest::Sha3(backward_digest),
+            last_backward_digest: [0x0; 20],
+            nonce: None,
+        };
+    }
+}
+
+/// A stream attached to the circuit.
+struct StreamEntry {
+    hop: usize,
+    data: VecDeque<u8>,
+    // CONNECTED or RESOLVED, whichever answers the opening cell
+    reply: Option<RelayCell>,
+    end: Option<EndReason>,
+    package_window: u32,
+    deliver_window: u32,
+}
+
+impl StreamEntry {
+    fn new(hop: usize) -> StreamEntry {
+        return StreamEntry {
+            hop,
+            data: VecDeque::new(),
+            reply: None,
+            end: None,
+            package_window: STREAMWINDOW_START,
+            deliver_window: STREAMWINDOW_START,
+        };
+    }
+}
+
+struct CircState {
+    hops: Vec<HopCrypto>,
+    streams: HashMap<u16, StreamEntry>,
+    next_stream_id: u16,
+    package_window: u32,
+    deliver_window: u32,
+    // Relay cells on stream 0 not handled by the circuit itself, with the hop they came from
+    control: VecDeque<(usize, RelayCell)>,
+    relay_early_left: u8,
+    // BEGIN cells from the other end not yet accepted. Only onion services accept streams.
+    incoming: Option<VecDeque<RelayCell>>,
+    closed: bool,
+}
+
+struct Shared {
+    conn: TorConnection,
+    circ_id: u32,
+    state: Mutex<CircState>,
+    // Signalled whenever anything in the state changes
+    changed: Condvar,
+}
+
+/// A circuit built by us. Cloning it yields another handle to the same circuit.
+#[derive(Clone)]
+pub(crate) struct Circuit {
+    shared: Arc<Shared>,
+}
+
+impl Circuit {
+    /// Creates a one-hop circuit with CREATE_FAST, which is only suitable for directory requests.
+    pub(crate) fn create_fast(conn: &TorConnection) -> Result<Circuit, Error> {
+        let (circ_id, rx) = conn.open_circuit()?;
+        let client = FastClient::new();
+        conn.send_cell(&cell::create_fast_cell(circ_id, client.x()))?;
+        let reply = Circuit::wait_created(conn, circ_id, &rx, BUILD_STEP_TIMEOUT)?;
+        let (y, kh) = cell::parse_created_fast(&reply)?;
+        let keys = client.complete(&y, &kh, HOP_KEY_LEN)?;
+        return Ok(Circuit::start(conn, circ_id, rx, keys));
+    }
+
+    /// Creates a circuit to the relay with an ntor handshake, waiting at most `timeout` for CREATED2.
+    pub(crate) fn create(
+        conn: &TorConnection,
+        target: &CircTarget,
+        timeout: Duration,
+    ) -> Result<Circuit, Error> {
+        let (circ_id, rx) = conn.open_circuit()?;
+        let (client, onion_skin) = NtorClient::new(&target.rsa_identity, &target.ntor_onion_key);
+        conn.send_cell(&cell::create2_cell(circ_id, HTYPE_NTOR, &onion_skin))?;
+        let reply = Circuit::wait_created(conn, circ_id, &rx, timeout)?;
+        if reply.command != FixedCommand::Created2 {
+            conn.close_circuit(circ_id);
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Expected CREATED2, got {:?}", reply.command),
+            ));
+        }
+        let keys = client.complete(
+            &cell::parse_created2_body(&reply.payload)?,
+            HOP_KEY_LEN + NONCE_LEN,
+        )?;
+        return Ok(Circuit::start(conn, circ_id, rx, keys));
+    }
+
+    fn wait_created(
+        conn: &TorConnection,
+        circ_id: u32,
+        rx: &Receiver<FixedCell>,
+        timeout: Duration,
+    ) -> Result<FixedCell, Error> {
+        let result = match rx.recv_timeout(timeout) {
+            Ok(c) if c.command == FixedCommand::Destroy => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Relay refused to create circuit, reason {}", c.payload[0]),
+            )),
+            Ok(c) => return Ok(c),
+            Err(RecvTimeoutError::Timeout) => Err(Error::new(
+                ErrorKind::TimedOut,
+                "Timed out waiting for relay to create circuit",
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(closed_error()),
+        };
+        conn.close_circuit(circ_id);
+        return result;
+    }
+
+    fn start(
+        conn: &TorConnection,
+        circ_id: u32,
+        rx: Receiver<FixedCell>,
+        keys: Vec<u8>,
+    ) -> Circuit {
+        let circ = Circuit {
+            shared: Arc::new(Shared {
+                conn: conn.clone(),
+                circ_id,
+                state: Mutex::new(CircState {
+                    hops: vec![HopCrypto::new(&keys)],
+                    streams: HashMap::new(),
+                    next_stream_id: crypto::random_u64() as u16,
+                    package_window: CIRCWINDOW_START,
+                    deliver_window: CIRCWINDOW_START,
+                    control: VecDeque::new(),
+                    relay_early_left: MAX_RELAY_EARLY,
+                    incoming: None,
+                    closed: false,
+                }),
+                changed: Condvar::new(),
+            }),
+        };
+        let reactor = circ.clone();
+        thread::spawn(move || reactor.run_reactor(rx));
+        return circ;
+    }
+
+    /// Extends the circuit by one hop with EXTEND2, waiting at most `timeout` for EXTENDED2.
+    pub(crate) fn extend(&self, target: &CircTarget, timeout: Duration) -> Result<(), Error> {
+        let (client, onion_skin) = NtorClient::new(&target.rsa_identity, &target.ntor_onion_key);
+        let body = cell::extend2_body(&target.link_specifiers(), HTYPE_NTOR, &onion_skin);
+        let last_hop = self.n_hops() - 1;
+        self.send_control(last_hop, RelayCommand::Extend2, body)?;
+        let (hop, reply) = self.wait_control(timeout)?;
+        if hop != last_hop || reply.command != RelayCommand::Extended2 {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Failed to extend circuit, got {:?}", reply.command),
+            ));
+        }
+        let keys = client.complete(
+            &cell::parse_created2_body(&reply.data)?,
+            HOP_KEY_LEN + NONCE_LEN,
+        )?;
+        self.lock().hops.push(HopCrypto::new(&keys));
+        return Ok(());
+    }
+
+    /// Adds the end-to-end hop with an onion service after the rendezvous handshake
+    /// (rend-spec-v3 4.2.1). Streams opened afterwards end at the service.
+    /// On the service's side, the client's streams are accepted with `wait_begin`.
+    pub(crate) fn add_onion_hop(&self, keys: &[u8], is_service: bool) {
+        let mut state = self.lock();
+        state.hops.push(HopCrypto::new_onion(keys, is_service));
+        if is_service {
+            state.incoming = Some(VecDeque::new());
+        }
+    }
+
+    /// KH of the last hop, which authenticates ESTABLISH_INTRO to an introduction point.
+    pub(crate) fn last_hop_nonce(&self) -> Option<[u8; NONCE_LEN]> {
+        return self.lock().hops.last().and_then(|h| h.nonce);
+    }
+
+    pub(crate) fn n_hops(&self) -> usize {
+        return self.lock().hops.len();
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        return self.lock().closed;
+    }
+
+    /// Whether both handles refer to the same circuit.
+    pub(crate) fn same_as(&self, other: &Circuit) -> bool {
+        return Arc::ptr_eq(&self.shared, &other.shared);
+    }
+
+    pub(crate) fn n_streams(&self) -> usize {
+        return self.lock().streams.len();
+    }
+
+    /// Tears down the circuit with a DESTROY cell.
+    pub(crate) fn close(&self) {
+        let mut state = self.lock();
+        if !state.closed {
+            let _ = self.shared.conn.send_cell(&cell::destroy_cell(
+                self.shared.circ_id,
+                DESTROY_REASON_FINISHED,
+            ));
+            self.mark_closed(&mut state);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CircState> {
+        return self.shared.state.lock().unwrap();
+    }
+
+    fn mark_closed(&self, state: &mut CircState) {
+        state.closed = true;
+        self.shared.conn.close_circuit(self.shared.circ_id);
+        self.shared.changed.notify_all();
+    }
+
+    /// Encrypts a relay cell for the hop and sends it, as RELAY_EARLY if requested and still allowed.
+    fn send_relay(
+        &self,
+        state: &mut CircState,
+        hop: usize,
+        rc: &RelayCell,
+        early: bool,
+    ) -> Result<(), Error> {
+        if state.closed {
+            return Err(closed_error());
+        }
+        let mut payload = rc.to_payload();
+        let crypto = &mut state.hops[hop];
+        crypto.forward_digest.update(&payload[..]);
+        let digest = crypto.forward_digest.value();
+        payload[DIGEST_OFFSET..DIGEST_OFFSET + 4].copy_from_slice(&digest[..4]);
+        for h in state.hops[..=hop].iter_mut().rev() {
+            h.forward_cipher.apply_keystream(&mut payload);
+        }
+        let command = if early && state.relay_early_left > 0 {
+            state.relay_early_left -= 1;
+            FixedCommand::RelayEarly
+        } else {
+            FixedCommand::Relay
+        };
+        let fixed = FixedCell::new(
+            payload.to_vec(),
+            false,
+            None,
+            command,
+            self.shared.circ_id.to_be_bytes(),
+        );
+        return self.shared.conn.send_cell(&fixed);
+    }
+
+    /// Sends a relay cell on stream 0. EXTEND2 cells are sent as RELAY_EARLY.
+    pub(crate) fn send_control(
+        &self,
+        hop: usize,
+        command: RelayCommand,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        let early = command == RelayCommand::Extend2;
+        let mut state = self.lock();
+        return self.send_relay(&mut state, hop, &RelayCell::new(command, 0, data), early);
+    }
+
+    /// Waits for a relay cell on stream 0 that the circuit doesn't handle itself.
+    pub(crate) fn wait_control(&self, timeout: Duration) -> Result<(usize, RelayCell), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(c) = state.control.pop_front() {
+                return Ok(c);
+            }
+            if state.closed {
+                return Err(closed_error());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Timed out waiting for relay cell",
+                ));
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Opens a stream to the last hop with BEGIN, BEGIN_DIR or RESOLVE and returns its ID.
+    /// Use `wait_reply` to find out whether it succeeded.
+    pub(crate) fn begin_stream(&self, command: RelayCommand, body: Vec<u8>) -> Result<u16, Error> {
+        let mut state = self.lock();
+        let mut id = state.next_stream_id;
+        while id == 0 || state.streams.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        state.next_stream_id = id.wrapping_add(1);
+        let hop = state.hops.len() - 1;
+        self.send_relay(&mut state, hop, &RelayCell::new(command, id, body), false)?;
+        state.streams.insert(id, StreamEntry::new(hop));
+        return Ok(id);
+    }
+
+    /// Waits for a BEGIN from the client on an onion service's rendezvous circuit.
+    /// Answer it with `accept_connected` or `end_stream`.
+    pub(crate) fn wait_begin(&self, timeout: Duration) -> Result<RelayCell, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(rc) = state.incoming.as_mut().and_then(|i| i.pop_front()) {
+                return Ok(rc);
+            }
+            if state.closed {
+                return Err(closed_error());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Timed out waiting for BEGIN",
+                ));
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Accepts a stream received with `wait_begin` by sending CONNECTED.
+    pub(crate) fn accept_connected(&self, stream_id: u16) -> Result<(), Error> {
+        let mut state = self.lock();
+        let hop = match state.streams.get(&stream_id) {
+            Some(e) => e.hop,
+            None => return Err(Error::from(EndReason::Done)),
+        };
+        // Onion services don't reveal an address in CONNECTED
+        let rc = RelayCell::new(RelayCommand::Connected, stream_id, vec![]);
+        return self.send_relay(&mut state, hop, &rc, false);
+    }
+
+    /// Waits for the CONNECTED or RESOLVED cell answering the opening cell of the stream.
+    /// A RELAY_END is turned into an error carrying its reason.
+    pub(crate) fn wait_reply(&self, stream_id: u16, timeout: Duration) -> Result<RelayCell, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            let entry = match state.streams.get_mut(&stream_id) {
+                Some(e) => e,
+                None => return Err(Error::from(EndReason::Done)),
+            };
+            if let Some(reply) = entry.reply.take() {
+                return Ok(reply);
+            }
+            if let Some(reason) = entry.end {
+                state.streams.remove(&stream_id);
+                return Err(Error::from(reason));
+            }
+            if state.closed {
+                return Err(closed_error());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                drop(state);
+                self.end_stream(stream_id, EndReason::Timeout);
+                return Err(Error::from(EndReason::Timeout));
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Reads data received on the stream, blocking until some is available.
+    /// Returns 0 once the stream was closed normally.
+    pub(crate) fn read_stream(&self, stream_id: u16, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.lock();
+        loop {
+            let entry = match state.streams.get_mut(&stream_id) {
+                Some(e) => e,
+                None => return Ok(0),
+            };
+            if !entry.data.is_empty() {
+                let n = std::cmp::min(buf.len(), entry.data.len());
+                for (dst, src) in buf.iter_mut().zip(entry.data.drain(..n)) {
+                    *dst = src;
+                }
+                // Only ask for more once the application consumed what it got
+                if entry.data.is_empty()
+                    && entry.deliver_window <= STREAMWINDOW_START - STREAMWINDOW_INCREMENT
+                {
+                    entry.deliver_window += STREAMWINDOW_INCREMENT;
+                    let hop = entry.hop;
+                    let sendme = RelayCell::new(RelayCommand::Sendme, stream_id, vec![]);
+                    let _ = self.send_relay(&mut state, hop, &sendme, false);
+                }
+                return Ok(n);
+            }
+            match entry.end {
+                Some(EndReason::Done) => return Ok(0),
+                Some(reason) => return Err(Error::from(reason)),
+                None => (),
+            }
+            if state.closed {
+                return Err(closed_error());
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Sends up to one cell worth of data on the stream, blocking while the windows are exhausted.
+    pub(crate) fn write_stream(&self, stream_id: u16, buf: &[u8]) -> Result<usize, Error> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(closed_error());
+            }
+            let circ_window = state.package_window;
+            let entry = match state.streams.get_mut(&stream_id) {
+                Some(e) => e,
+                None => return Err(Error::new(ErrorKind::BrokenPipe, "Stream is closed")),
+            };
+            if let Some(reason) = entry.end {
+                return Err(Error::from(reason));
+            }
+            if entry.package_window > 0 && circ_window > 0 {
+                entry.package_window -= 1;
+                let hop = entry.hop;
+                state.package_window -= 1;
+                let n = std::cmp::min(buf.len(), cell::RELAY_DATA_LEN);
+                let rc = RelayCell::new(RelayCommand::Data, stream_id, buf[..n].to_vec());
+                self.send_relay(&mut state, hop, &rc, false)?;
+                return Ok(n);
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Drops the stream without sending anything, e.g. after RELAY_RESOLVED, which implicitly closes it.
+    pub(crate) fn forget_stream(&self, stream_id: u16) {
+        self.lock().streams.remove(&stream_id);
+        self.shared.changed.notify_all();
+    }
+
+    /// Closes the stream, sending a RELAY_END unless the other side already did.
+    pub(crate) fn end_stream(&self, stream_id: u16, reason: EndReason) {
+        let mut state = self.lock();
+        if let Some(entry) = state.streams.remove(&stream_id) {
+            if entry.end.is_none() {
+                let rc = RelayCell::new(RelayCommand::End, stream_id, vec![reason as u8]);
+                let _ = self.send_relay(&mut state, entry.hop, &rc, false);
+            }
+        }
+        self.shared.changed.notify_all();
+    }
+
+    fn run_reactor(&self, rx: Receiver<FixedCell>) {
+        for fixed in rx.iter() {
+            let mut state = self.lock();
+            match fixed.command {
+                FixedCommand::Relay | FixedCommand::RelayEarly => {
+                    if self.handle_relay(&mut state, &fixed).is_err() {
+                        let _ = self.shared.conn.send_cell(&cell::destroy_cell(
+                            self.shared.circ_id,
+                            DESTROY_REASON_PROTOCOL,
+                        ));
+                        self.mark_closed(&mut state);
+                    }
+                }
+                FixedCommand::Destroy => self.mark_closed(&mut state),
+                _ => (),
+            }
+            self.shared.changed.notify_all();
+            if state.closed {
+                return;
+            }
+        }
+        // The connection went away
+        let mut state = self.lock();
+        self.mark_closed(&mut state);
+    }
+
+    fn handle_relay(&self, state: &mut CircState, fixed: &FixedCell) -> Result<(), Error> {
+        let mut payload: [u8; PAYLOAD_LEN] = fixed.payload[..PAYLOAD_LEN].try_into().unwrap();
+        let hop = match Circuit::decrypt(state, &mut payload) {
+            Some(h) => h,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unrecognized relay cell",
+                ))
+            }
+        };
+        let rc = match RelayCell::from_payload(&payload)? {
+            Some(rc) => rc,
+            // Unknown relay commands are dropped
+            None => return Ok(()),
+        };
+
+        if rc.command == RelayCommand::Data {
+            if state.deliver_window == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Circuit deliver window exceeded",
+                ));
+            }
+            state.deliver_window -= 1;
+            if state.deliver_window <= CIRCWINDOW_START - CIRCWINDOW_INCREMENT {
+                state.deliver_window += CIRCWINDOW_INCREMENT;
+                // Authenticated SENDME (proposal 289): version 1 with the digest of the last cell
+                let mut body: Vec<u8> = vec![0x01, 0x00, 20];
+                body.extend_from_slice(&state.hops[hop].last_backward_digest);
+                let sendme = RelayCell::new(RelayCommand::Sendme, 0, body);
+                self.send_relay(state, hop, &sendme, false)?;
+            }
+        }
+
+        if rc.stream_id == 0 {
+            match rc.command {
+                RelayCommand::Sendme => state.package_window += CIRCWINDOW_INCREMENT,
+                RelayCommand::Drop => (),
+                _ => state.control.push_back((hop, rc)),
+            }
+            return Ok(());
+        }
+
+        let last_hop = state.hops.len() - 1;
+        if rc.command == RelayCommand::Begin
+            && hop == last_hop
+            && !state.streams.contains_key(&rc.stream_id)
+        {
+            if let Some(incoming) = &mut state.incoming {
+                state.streams.insert(rc.stream_id, StreamEntry::new(hop));
+                incoming.push_back(rc);
+                return Ok(());
+            }
+        }
+
+        // Cells for streams we already closed are dropped
+        let entry = match state.streams.get_mut(&rc.stream_id) {
+            Some(e) if e.hop == hop => e,
+            _ => return Ok(()),
+        };
+        match rc.command {
+            RelayCommand::Data => {
+                if entry.deliver_window == 0 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Stream deliver window exceeded",
+                    ));
+                }
+                entry.deliver_window -= 1;
+                entry.data.extend(rc.data.iter());
+            }
+            RelayCommand::Sendme => entry.package_window += STREAMWINDOW_INCREMENT,
+            RelayCommand::Connected | RelayCommand::Resolved => entry.reply = Some(rc),
+            RelayCommand::End => {
+                entry.end = Some(EndReason::from_u8(*rc.data.first().unwrap_or(&1)));
+            }
+            _ => (),
+        }
+        return Ok(());
+    }
+
+    /// Removes the layers of encryption until a hop recognizes the cell. Returns that hop.
+    fn decrypt(state: &mut CircState, payload: &mut [u8; PAYLOAD_LEN]) -> Option<usize> {
+        for (i, hop) in state.hops.iter_mut().enumerate() {
+            hop.backward_cipher.apply_keystream(payload);
+            if !RelayCell::is_recognized(payload) {
+                continue;
+            }
+            let mut zeroed = *payload;
+            zeroed[DIGEST_OFFSET..DIGEST_OFFSET + 4].copy_from_slice(&[0x0; 4]);
+            let mut digest = hop.backward_digest.clone();
+            digest.update(&zeroed[..]);
+            let value = digest.value();
+            if value[..4] == payload[DIGEST_OFFSET..DIGEST_OFFSET + 4] {
+                hop.backward_digest = digest;
+                hop.last_backward_digest = value;
+                return Some(i);
+            }
+        }
+        return None;
+    }
+}
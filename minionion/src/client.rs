@@ -0,0 +1,788 @@
+// The client: bootstrapping, building exit circuits and opening streams on them.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cbt::BuildTimes;
+use crate::cell::{self, EndReason, RelayCommand, Resolved};
+use crate::chanmgr::{ChannelKey, ChannelManager};
+use crate::circmgr::CircuitManager;
+use crate::circuit::{CircTarget, Circuit};
+use crate::crypto;
+use crate::dir::{NetDir, PortPolicy, Relay};
+use crate::dirclient;
+use crate::guard::{GuardMgr, GuardStatus};
+use crate::hs::cache::DescriptorCache;
+use crate::hs::{
+    self, BuiltinEquiX, EquiX, OnionAddress, OnionService, OnionServiceConfig, TimePeriod,
+};
+use crate::isolation::StreamIsolation;
+use crate::path::{NodeRestrictions, PathSelector};
+use crate::state::StateFile;
+use crate::stream::TorStream;
+
+const STATE_FILE_NAME: &str = "state";
+// How often to try another circuit when a stream can't be opened
+const MAX_STREAM_ATTEMPTS: usize = 3;
+// How long to wait for the exit to answer a BEGIN
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+// BEGIN flag: we accept IPv6 addresses (tor-spec 6.2)
+const BEGIN_FLAG_IPV6_OK: u32 = 1 << 0;
+// How often the background thread expires circuits and builds predicted ones
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+// How long the background thread waits after failing to build a circuit
+const FAILED_BUILD_DELAY: Duration = Duration::from_secs(5);
+// Changes to the guards and build times are written to the state file at most this often
+const STATE_SAVE_DELAY: Duration = Duration::from_secs(60);
+// How long a circuit through a guard that's only usable if no better guard is reachable
+// is held back for the primary guards to be tried (guard-spec 4.9)
+const BETTER_GUARD_WAIT: Duration = Duration::from_secs(15);
+// How often a held back circuit is replaced before giving up
+const MAX_GUARD_ATTEMPTS: usize = 3;
+// How long to wait before retrying when fetching a new consensus failed
+const DIR_RETRY_DELAY: Duration = Duration::from_secs(60);
+// Proof-of-work effort clients spend at most by default, as in tor
+const DEFAULT_POW_MAX_EFFORT: u32 = 10000;
+
+/// A bridge relay, which is used as first hop instead of a guard from the consensus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bridge {
+    pub addr: SocketAddr,
+    /// Known identity, so the bridge is not picked again later in the path
+    pub identity: Option<[u8; 20]>,
+}
+
+impl Bridge {
+    fn channel_key(&self) -> ChannelKey {
+        return match self.identity {
+            Some(id) => ChannelKey::Identity(id),
+            None => ChannelKey::Addr(self.addr),
+        };
+    }
+}
+
+/// How the client reaches the network and which relays it may use.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Directory cache to bootstrap from when not using bridges
+    pub dir_cache: SocketAddr,
+    /// Where the state file is kept
+    pub data_dir: PathBuf,
+    /// If not empty, all circuits start at one of these and the directory is fetched from them
+    pub bridges: Vec<Bridge>,
+    pub restrictions: NodeRestrictions,
+    /// Directory of `*.auth_private` files with our keys for onion services that require
+    /// client authorization, as with tor's ClientOnionAuthDir
+    pub onion_auth_dir: Option<PathBuf>,
+    /// Equi-X, needed to solve the proof-of-work puzzles of onion services under attack
+    /// and for `OnionServiceConfig::pow_defenses`. Defaults to `BuiltinEquiX`.
+    pub equix: Option<Arc<dyn EquiX>>,
+    /// The highest proof-of-work effort spent on introductions to a service
+    pub onion_pow_max_effort: u32,
+    /// Onion services are hosted non-anonymously, with one-hop circuits to their intro and
+    /// rendezvous points, as with tor's HiddenServiceSingleHopMode. The client then refuses
+    /// anonymous streams, which would be easy to tell apart from the service's traffic.
+    pub single_onion_mode: bool,
+}
+
+impl ClientConfig {
+    pub fn new(dir_cache: SocketAddr, data_dir: &Path) -> ClientConfig {
+        return ClientConfig {
+            dir_cache,
+            data_dir: data_dir.to_path_buf(),
+            bridges: vec![],
+            restrictions: NodeRestrictions::default(),
+            onion_auth_dir: None,
+            equix: Some(Arc::new(BuiltinEquiX)),
+            onion_pow_max_effort: DEFAULT_POW_MAX_EFFORT,
+            single_onion_mode: false,
+        };
+    }
+}
+
+/// Where a new circuit ends.
+enum LastHop {
+    /// An exit allowing the port, or some port if None
+    Exit(Option<u16>),
+    Relay(CircTarget),
+}
+
+struct ClientState {
+    netdir: NetDir,
+    guards: GuardMgr,
+    state: StateFile,
+    circuits: CircuitManager,
+    build_times: BuildTimes,
+    // When the guards or build times first changed since the state file was written
+    unsaved_since: Option<Instant>,
+    // When to fetch a new consensus, once the current one is no longer fresh
+    next_dir_fetch: SystemTime,
+}
+
+impl ClientState {
+    /// Notes that the state file needs to be written, which the background thread does
+    /// after a while, so that building circuits doesn't rewrite it every time.
+    fn mark_unsaved(&mut self) {
+        if self.unsaved_since.is_none() {
+            self.unsaved_since = Some(Instant::now());
+        }
+    }
+}
+
+/// A Tor client, able to open streams to hosts through exit relays.
+/// It can be shared between threads.
+pub struct TorClient {
+    inner: Arc<ClientInner>,
+}
+
+// Shared with the thread building circuits in the background
+pub(crate) struct ClientInner {
+    config: ClientConfig,
+    state_path: PathBuf,
+    state: Mutex<ClientState>,
+    // Notified when a guard's reachability was learned, for circuits held back
+    guards_changed: Condvar,
+    // Kept outside the state, so connecting to a relay doesn't hold up anything else
+    channels: ChannelManager,
+    // x25519 client authorization keys by onion service identity
+    onion_auth_keys: HashMap<[u8; 32], [u8; 32]>,
+    onion_cache: Mutex<DescriptorCache>,
+}
+
+impl TorClient {
+    /// Downloads the network directory from the configured directory cache or the first bridge,
+    /// which is contacted over a one-hop CREATE_FAST circuit, and loads the guard state.
+    pub fn bootstrap(config: ClientConfig) -> Result<TorClient, Error> {
+        fs::create_dir_all(&config.data_dir)?;
+        // The connection is kept, as a bridge is the first hop of later circuits as well
+        let channels = ChannelManager::new();
+        let conn = match config.bridges.first() {
+            Some(b) => channels.get_or_connect(b.channel_key(), b.addr)?,
+            None => {
+                channels.get_or_connect(ChannelKey::Addr(config.dir_cache), config.dir_cache)?
+            }
+        };
+        let circ = Circuit::create_fast(&conn)?;
+        let netdir = dirclient::fetch_netdir(&circ, None);
+        circ.close();
+        let netdir = netdir?;
+
+        let onion_auth_keys = match &config.onion_auth_dir {
+            Some(dir) => hs::clientauth::load_client_keys(dir)?,
+            None => HashMap::new(),
+        };
+        let state_path = config.data_dir.join(STATE_FILE_NAME);
+        let state = StateFile::load(&state_path)?;
+        let mut guards = GuardMgr::load(&state)?;
+        let build_times = BuildTimes::load(&state)?;
+        guards.set_excluded(config.restrictions.excluded_guards(&netdir));
+        guards.update(&netdir, SystemTime::now());
+
+        let next_dir_fetch = netdir.consensus.fresh_until;
+        let inner = Arc::new(ClientInner {
+            config,
+            state_path,
+            state: Mutex::new(ClientState {
+                next_dir_fetch,
+                netdir,
+                guards,
+                state,
+                circuits: CircuitManager::new(Instant::now()),
+                build_times,
+                unsaved_since: None,
+            }),
+            guards_changed: Condvar::new(),
+            channels,
+            onion_auth_keys,
+            onion_cache: Mutex::new(DescriptorCache::new()),
+        });
+        inner.save_state(&mut inner.state.lock().unwrap())?;
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || maintain_circuits(weak));
+        return Ok(TorClient { inner });
+    }
+
+    /// Opens a stream to host:port through an exit. The host may be a hostname or an
+    /// IP address literal. It only shares a circuit with streams `isolation` allows.
+    /// Errors caused by a RELAY_END carry the reason, see `EndReason::from_error`.
+    /// A v3 .onion host is reached through a rendezvous circuit instead; failures to
+    /// reach the service carry an `OnionError`.
+    pub fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        isolation: &StreamIsolation,
+    ) -> Result<TorStream, Error> {
+        self.inner.check_anonymous()?;
+        // Exits refuse .onion names, and sending them there would leak them
+        if OnionAddress::is_onion(host) {
+            let addr = OnionAddress::parse(host)?;
+            return hs::client::connect(&self.inner, &addr, port);
+        }
+        let mut body = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port).into_bytes(),
+            _ => format!("{}:{}", host, port).into_bytes(),
+        };
+        body.push(0);
+        let flags: u32 = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => BEGIN_FLAG_IPV6_OK,
+            _ => 0,
+        };
+        body.extend_from_slice(&flags.to_be_bytes());
+
+        return self
+            .inner
+            .with_exit_circuit(host, Some(port), isolation, |circ| {
+                let id = circ.begin_stream(RelayCommand::Begin, body.clone())?;
+                circ.wait_reply(id, STREAM_TIMEOUT)?;
+                return Ok(TorStream::new(circ.clone(), id));
+            });
+    }
+
+    /// Publishes an onion service, whose identity key is kept in the configured directory.
+    /// It stays reachable until the returned handle is dropped.
+    pub fn launch_onion_service(&self, config: OnionServiceConfig) -> Result<OnionService, Error> {
+        return hs::service::launch(&self.inner, config);
+    }
+
+    /// The state of the guards, e.g. to tell that none is reachable. Guards are not used
+    /// with bridges.
+    pub fn guard_status(&self) -> GuardStatus {
+        return self.inner.state.lock().unwrap().guards.status();
+    }
+
+    /// Resolves a hostname to addresses through an exit with RELAY_RESOLVE.
+    /// A failed lookup is reported as a `ResolvedValue::Error` or `TransientError` answer.
+    pub fn resolve(&self, host: &str, isolation: &StreamIsolation) -> Result<Vec<Resolved>, Error> {
+        return self.resolve_name(host, isolation);
+    }
+
+    /// Looks up the hostname of an address through an exit.
+    pub fn resolve_ptr(
+        &self,
+        addr: IpAddr,
+        isolation: &StreamIsolation,
+    ) -> Result<Vec<Resolved>, Error> {
+        return self.resolve_name(&cell::reverse_name(addr), isolation);
+    }
+
+    fn resolve_name(
+        &self,
+        name: &str,
+        isolation: &StreamIsolation,
+    ) -> Result<Vec<Resolved>, Error> {
+        self.inner.check_anonymous()?;
+        if OnionAddress::is_onion(name) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Onion addresses can't be resolved",
+            ));
+        }
+        return self.inner.with_exit_circuit(name, None, isolation, |circ| {
+            let id = circ.begin_stream(RelayCommand::Resolve, cell::resolve_body(name))?;
+            let reply = circ.wait_reply(id, STREAM_TIMEOUT)?;
+            // RELAY_RESOLVED closes the stream without a RELAY_END
+            circ.forget_stream(id);
+            if reply.command != RelayCommand::Resolved {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Expected RELAY_RESOLVED, got {:?}", reply.command),
+                ));
+            }
+            return cell::parse_resolved(&reply.data);
+        });
+    }
+}
+
+impl ClientInner {
+    /// Fails in single onion service mode, which doesn't allow anonymous use.
+    fn check_anonymous(&self) -> Result<(), Error> {
+        if self.config.single_onion_mode {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Anonymous streams are refused in single onion service mode",
+            ));
+        }
+        return Ok(());
+    }
+
+    /// Runs `f` on a suitable exit circuit, trying other circuits if the exit rather
+    /// than the destination seems to be the problem.
+    fn with_exit_circuit<T, F>(
+        &self,
+        dest_addr: &str,
+        port: Option<u16>,
+        isolation: &StreamIsolation,
+        f: F,
+    ) -> Result<T, Error>
+    where
+        F: Fn(&Circuit) -> Result<T, Error>,
+    {
+        let mut last_err = Error::new(ErrorKind::Other, "No attempt to use a circuit was made");
+        for _attempt in 0..MAX_STREAM_ATTEMPTS {
+            let circ = self.exit_circuit(dest_addr, port, isolation)?;
+            match f(&circ) {
+                Ok(r) => return Ok(r),
+                Err(e) => {
+                    let retry = matches!(
+                        EndReason::from_error(&e),
+                        Some(EndReason::ExitPolicy)
+                            | Some(EndReason::ResourceLimit)
+                            | Some(EndReason::Hibernating)
+                            | Some(EndReason::Destroy)
+                            | Some(EndReason::Timeout)
+                    );
+                    if !retry {
+                        return Err(e);
+                    }
+                    self.retire_circuit(&circ);
+                    last_err = e;
+                }
+            }
+        }
+        return Err(last_err);
+    }
+
+    /// Returns an open circuit suitable for the destination and isolation, building one if needed.
+    /// The stream counts as attached to the circuit for isolating later streams.
+    fn exit_circuit(
+        &self,
+        dest_addr: &str,
+        port: Option<u16>,
+        isolation: &StreamIsolation,
+    ) -> Result<Circuit, Error> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.circuits.note_request(port, now);
+        if let Some(circ) = state.circuits.get(dest_addr, port, isolation, now) {
+            return Ok(circ);
+        }
+        drop(state);
+
+        let (circ, exit_policy) = self.build_exit_circuit(port)?;
+        self.state.lock().unwrap().circuits.add(
+            circ.clone(),
+            exit_policy,
+            Some((dest_addr, port, isolation)),
+            Instant::now(),
+        );
+        return Ok(circ);
+    }
+
+    /// Builds a three-hop circuit to an exit allowing the port.
+    fn build_exit_circuit(&self, port: Option<u16>) -> Result<(Circuit, PortPolicy), Error> {
+        let (circ, exit_policy) = self.build_circuit(&LastHop::Exit(port))?;
+        return Ok((
+            circ,
+            exit_policy.expect("Circuits to exits have an exit policy"),
+        ));
+    }
+
+    /// Builds a three-hop circuit ending at the relay, as used for onion services.
+    pub(crate) fn build_circuit_to(&self, target: &CircTarget) -> Result<Circuit, Error> {
+        return Ok(self.build_circuit(&LastHop::Relay(target.clone()))?.0);
+    }
+
+    /// Builds a circuit for an onion service we host to its intro or rendezvous point:
+    /// a one-hop circuit in single onion service mode, a three-hop one otherwise.
+    pub(crate) fn build_service_circuit_to(&self, target: &CircTarget) -> Result<Circuit, Error> {
+        if !self.config.single_onion_mode {
+            return self.build_circuit_to(target);
+        }
+        let timeout = self.state.lock().unwrap().build_times.timeout();
+        let conn = self
+            .channels
+            .get_or_connect(ChannelKey::Identity(target.rsa_identity), target.addr)?;
+        return Circuit::create(&conn, target, timeout);
+    }
+
+    pub(crate) fn single_onion_mode(&self) -> bool {
+        return self.config.single_onion_mode;
+    }
+
+    /// Picks a random relay usable as middle hop, e.g. as rendezvous point.
+    pub(crate) fn pick_middle(&self) -> Result<CircTarget, Error> {
+        let state = self.state.lock().unwrap();
+        let selector =
+            PathSelector::new(&state.netdir).with_restrictions(&self.config.restrictions);
+        return Ok(CircTarget::from_relay(&selector.pick_middle(&[])?));
+    }
+
+    /// Equi-X and the highest effort to solve proof-of-work puzzles with, if configured.
+    pub(crate) fn pow_solver(&self) -> Option<(&dyn EquiX, u32)> {
+        return self
+            .config
+            .equix
+            .as_deref()
+            .map(|e| (e, self.config.onion_pow_max_effort));
+    }
+
+    /// Our client authorization key for the onion service, if we have one.
+    pub(crate) fn onion_auth_key(&self, identity: &[u8; 32]) -> Option<&[u8; 32]> {
+        return self.onion_auth_keys.get(identity);
+    }
+
+    /// Runs `f` on the cache of onion service descriptors.
+    pub(crate) fn with_onion_cache<T, F: FnOnce(&mut DescriptorCache) -> T>(&self, f: F) -> T {
+        return f(&mut self.onion_cache.lock().unwrap());
+    }
+
+    /// Picks a random introduction point for an onion service, skipping the given relays.
+    pub(crate) fn pick_intro_point(&self, skip: &[[u8; 20]]) -> Result<CircTarget, Error> {
+        let state = self.state.lock().unwrap();
+        let selector =
+            PathSelector::new(&state.netdir).with_restrictions(&self.config.restrictions);
+        return Ok(CircTarget::from_relay(&selector.pick_intro_point(skip)?));
+    }
+
+    /// The time period of the current consensus, which onion service keys are blinded for.
+    /// It moves on as the consensus is refreshed.
+    pub(crate) fn time_period(&self) -> TimePeriod {
+        return self.with_netdir(|netdir| TimePeriod::current(&netdir.consensus));
+    }
+
+    /// Runs `f` on the current network directory.
+    pub(crate) fn with_netdir<T, F: FnOnce(&NetDir) -> T>(&self, f: F) -> T {
+        return f(&self.state.lock().unwrap().netdir);
+    }
+
+    /// Builds a three-hop circuit through a guard or bridge and a middle relay.
+    /// The exit policy is returned for circuits to exits. A circuit through a guard that's
+    /// only usable if no better guard is reachable is held back until that's decided, and
+    /// replaced if a primary guard turns out to be reachable (guard-spec 4.9).
+    fn build_circuit(&self, last_hop: &LastHop) -> Result<(Circuit, Option<PortPolicy>), Error> {
+        for _attempt in 0..MAX_GUARD_ATTEMPTS {
+            let (circ, exit_policy, held_guard) = self.build_circuit_once(last_hop)?;
+            let usable = match held_guard {
+                Some(guard_id) => self.wait_for_better_guard(&guard_id),
+                None => true,
+            };
+            if usable {
+                return Ok((circ, exit_policy));
+            }
+            circ.close();
+        }
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Circuits through better guards kept being preferred",
+        ));
+    }
+
+    /// Waits until the guard manager decides whether the held back circuit through the guard
+    /// may be used. If that takes too long, it's given up, so a new circuit tries the
+    /// primary guards.
+    fn wait_for_better_guard(&self, guard_id: &[u8; 20]) -> bool {
+        let deadline = Instant::now() + BETTER_GUARD_WAIT;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(usable) = state.guards.may_use(guard_id) {
+                return usable;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return false;
+            }
+            state = self
+                .guards_changed
+                .wait_timeout(state, remaining)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Builds a circuit as `build_circuit` does, returning the guard if the circuit must be
+    /// held back.
+    fn build_circuit_once(
+        &self,
+        last_hop: &LastHop,
+    ) -> Result<(Circuit, Option<PortPolicy>, Option<[u8; 20]>), Error> {
+        let mut state = self.state.lock().unwrap();
+        let now = SystemTime::now();
+        let (first_key, first_addr, guard, path, exit_policy) = {
+            let ClientState { netdir, guards, .. } = &mut *state;
+            let selector = PathSelector::new(netdir).with_restrictions(&self.config.restrictions);
+            // The guard is None when starting at a bridge, which is not in the guard manager
+            let (first_key, first_addr, guard, first_relay) = if self.config.bridges.is_empty() {
+                let guard = guards.select_guard(netdir, now)?.relay;
+                (
+                    ChannelKey::Identity(*guard.identity()),
+                    guard.ipv4_addr(),
+                    Some((*guard.identity(), CircTarget::from_relay(&guard))),
+                    Some(guard),
+                )
+            } else {
+                let n = crypto::random_below(self.config.bridges.len() as u64) as usize;
+                let bridge = &self.config.bridges[n];
+                (
+                    bridge.channel_key(),
+                    bridge.addr,
+                    None,
+                    bridge.identity.and_then(|id| netdir.relay_by_identity(&id)),
+                )
+            };
+            let mut exclude: Vec<Relay> = first_relay.into_iter().collect();
+            let (last, exit_policy) = match last_hop {
+                LastHop::Exit(port) => {
+                    let exit = selector.pick_exit(*port, &exclude)?;
+                    exclude.push(exit);
+                    (
+                        CircTarget::from_relay(&exit),
+                        Some(exit.md.ipv4_policy.clone()),
+                    )
+                }
+                LastHop::Relay(target) => {
+                    if exclude.iter().any(|r| r.identity() == &target.rsa_identity) {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "Circuit would start and end at the same relay",
+                        ));
+                    }
+                    exclude.extend(netdir.relay_by_identity(&target.rsa_identity));
+                    (target.clone(), None)
+                }
+            };
+            let middle = selector.pick_middle(&exclude)?;
+            (
+                first_key,
+                first_addr,
+                guard,
+                vec![CircTarget::from_relay(&middle), last],
+                exit_policy,
+            )
+        };
+        let timeout = state.build_times.timeout();
+        // Building takes a while, so don't block other streams meanwhile
+        drop(state);
+
+        // The timeout covers the circuit handshakes, not connecting to the first hop
+        let mut started = Instant::now();
+        let first_hop = self
+            .channels
+            .get_or_connect(first_key, first_addr)
+            .and_then(|conn| {
+                started = Instant::now();
+                // We don't know the ntor onion keys of bridges, but the TLS link is enough
+                let circ = match &guard {
+                    Some((_, target)) => Circuit::create(&conn, target, timeout),
+                    None => Circuit::create_fast(&conn),
+                };
+                return circ;
+            });
+
+        let mut state = self.state.lock().unwrap();
+        let now = SystemTime::now();
+        // Only failing to reach the guard counts against it; later hops are not its fault
+        let mut held_guard: Option<[u8; 20]> = None;
+        let circ = match first_hop {
+            Ok(circ) => {
+                if let Some((guard_id, _)) = &guard {
+                    if !state.guards.note_success(guard_id, now) {
+                        held_guard = Some(*guard_id);
+                    }
+                    self.guards_changed.notify_all();
+                }
+                state.mark_unsaved();
+                circ
+            }
+            Err(e) => {
+                if let Some((guard_id, _)) = &guard {
+                    state.guards.note_failure(guard_id, now);
+                    self.guards_changed.notify_all();
+                }
+                if e.kind() == ErrorKind::TimedOut {
+                    state.build_times.note_timeout();
+                }
+                state.mark_unsaved();
+                return Err(e);
+            }
+        };
+        drop(state);
+
+        let deadline = started + timeout;
+        let mut result = Ok(());
+        for target in &path {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            result = circ.extend(target, remaining);
+            if result.is_err() {
+                break;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match &result {
+            Ok(()) => state.build_times.note_completed(started.elapsed()),
+            Err(e) if e.kind() == ErrorKind::TimedOut => state.build_times.note_timeout(),
+            Err(_) => {}
+        }
+        state.mark_unsaved();
+        drop(state);
+
+        if let Err(e) = result {
+            circ.close();
+            return Err(e);
+        }
+        return Ok((circ, exit_policy, held_guard));
+    }
+
+    /// Stops using the circuit for new streams.
+    fn retire_circuit(&self, circ: &Circuit) {
+        self.state.lock().unwrap().circuits.retire(circ);
+    }
+
+    /// Replaces the network directory once the consensus is no longer fresh, fetching it
+    /// over a one-hop circuit to a guard or bridge as tor does. Only the microdescriptors
+    /// we don't have yet are downloaded.
+    fn refresh_netdir(&self) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let (key, addr, guard_id) = {
+            let mut state = self.state.lock().unwrap();
+            if now < state.next_dir_fetch {
+                return Ok(());
+            }
+            // Retried later if this attempt fails
+            state.next_dir_fetch = now + DIR_RETRY_DELAY;
+            if self.config.bridges.is_empty() {
+                let ClientState { netdir, guards, .. } = &mut *state;
+                let guard = guards.select_guard(netdir, now)?.relay;
+                (
+                    ChannelKey::Identity(*guard.identity()),
+                    guard.ipv4_addr(),
+                    Some(*guard.identity()),
+                )
+            } else {
+                let n = crypto::random_below(self.config.bridges.len() as u64) as usize;
+                let bridge = &self.config.bridges[n];
+                (bridge.channel_key(), bridge.addr, None)
+            }
+        };
+
+        let circ = self
+            .channels
+            .get_or_connect(key, addr)
+            .and_then(|conn| Circuit::create_fast(&conn));
+        if let Some(guard_id) = &guard_id {
+            let mut state = self.state.lock().unwrap();
+            match &circ {
+                Ok(_) => {
+                    state.guards.note_success(guard_id, SystemTime::now());
+                }
+                Err(_) => state.guards.note_failure(guard_id, SystemTime::now()),
+            }
+            state.mark_unsaved();
+            self.guards_changed.notify_all();
+        }
+        let circ = circ?;
+        // The microdescriptors we have are reused, so copying the directory saves
+        // refetching them without holding the lock during the download
+        let previous = self.state.lock().unwrap().netdir.clone();
+        let netdir = dirclient::fetch_netdir(&circ, Some(&previous));
+        circ.close();
+        let netdir = netdir?;
+        if netdir.consensus.valid_after <= previous.consensus.valid_after {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Directory cache served an outdated consensus",
+            ));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = SystemTime::now();
+        let excluded = self.config.restrictions.excluded_guards(&netdir);
+        state.guards.set_excluded(excluded);
+        state.guards.update(&netdir, now);
+        state.next_dir_fetch = netdir.consensus.fresh_until;
+        state.netdir = netdir;
+        state.mark_unsaved();
+        self.guards_changed.notify_all();
+        return Ok(());
+    }
+
+    /// Refreshes the network directory when due, expires old circuits, writes the state file
+    /// if it changed a while ago and builds a clean circuit if a predicted port lacks them.
+    /// Returns false if building failed.
+    fn maintain_circuits(&self) -> bool {
+        // A failure is retried after a while
+        let _ = self.refresh_netdir();
+        let needed = {
+            let mut state = self.state.lock().unwrap();
+            if state
+                .unsaved_since
+                .map_or(false, |t| t.elapsed() >= STATE_SAVE_DELAY)
+            {
+                // Failing to write is retried on the next round
+                let _ = self.save_state(&mut state);
+            }
+            state.circuits.expire(Instant::now());
+            self.channels.expire(Instant::now());
+            // Nothing is predicted to need exit circuits in single onion service mode
+            if self.config.single_onion_mode {
+                None
+            } else {
+                state.circuits.next_needed()
+            }
+        };
+        let port = match needed {
+            Some(p) => p,
+            None => return true,
+        };
+        // A failure is retried later, with another path
+        return match self.build_exit_circuit(port) {
+            Ok((circ, exit_policy)) => {
+                self.state
+                    .lock()
+                    .unwrap()
+                    .circuits
+                    .add(circ, exit_policy, None, Instant::now());
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    fn save_state(&self, state: &mut ClientState) -> Result<(), Error> {
+        let ClientState {
+            guards,
+            build_times,
+            state,
+            unsaved_since,
+            ..
+        } = state;
+        guards.store(state);
+        build_times.store(state);
+        state.save(&self.state_path)?;
+        *unsaved_since = None;
+        return Ok(());
+    }
+}
+
+impl Drop for ClientInner {
+    fn drop(&mut self) {
+        // A poisoned lock means a thread panicked with the state half updated
+        if let Ok(mut state) = self.state.lock() {
+            if state.unsaved_since.is_some() {
+                let _ = self.save_state(&mut state);
+            }
+        }
+    }
+}
+
+/// Keeps circuits ready until the client is dropped.
+fn maintain_circuits(inner: Weak<ClientInner>) {
+    loop {
+        let built = match inner.upgrade() {
+            Some(i) => i.maintain_circuits(),
+            None => return,
+        };
+        thread::sleep(if built {
+            MAINTENANCE_INTERVAL
+        } else {
+            FAILED_BUILD_DELAY
+        });
+    }
+}
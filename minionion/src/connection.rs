@@ -2,75 +2,374 @@ use mbedtls::rng::{CtrDrbg, OsEntropy};
 use mbedtls::ssl::config::{AuthMode, Config, Endpoint, Preset, Transport, Version};
 use mbedtls::ssl::context::{Context, Session};
 
-use std::io::Error;
-use std::io::Write;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
 use std::net::SocketAddr;
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::cell;
+use crate::cell::{self, FixedCell, FixedCommand, CELL_LEN};
+use crate::circid::CircIdAllocator;
 
-// FIXME: Evaluate whether it should be public
-pub struct TorConnection {}
+// Largest plaintext of a TLS record; reading this much drains mbedtls' buffer
+const TLS_RECORD_LEN: usize = 16384;
+// Header of a variable-length cell: circuit ID, command and length
+const VAR_HEADER_LEN: usize = 4 + 1 + 2;
+// A PADDING cell is sent after this long without writing, so that a dead peer makes
+// writes fail instead of going unnoticed (tor's KeepalivePeriod)
+const KEEPALIVE_PERIOD: Duration = Duration::from_secs(5 * 60);
+// How long connecting to a relay and each read or write of the handshake may take
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the connection thread is woken up for.
+enum Event {
+    /// An encoded cell to write
+    Outbound([u8; CELL_LEN]),
+    /// The socket has data to read, or was closed by the peer
+    Readable,
+    /// `TorConnection::close` was called
+    Close,
+}
+
+struct Shared {
+    peer: SocketAddr,
+    // Cells to be written and other work for the connection thread
+    events: Mutex<Sender<Event>>,
+    // Where to deliver cells arriving for each circuit
+    circuits: Mutex<HashMap<u32, Sender<FixedCell>>>,
+    // Locked after `circuits` when both are needed
+    circ_ids: Mutex<CircIdAllocator>,
+    // When a circuit was last opened or closed
+    last_active: Mutex<Instant>,
+    closed: AtomicBool,
+}
+
+/// A TLS connection to a relay, over which circuits are multiplexed.
+/// Cloning it yields another handle to the same connection.
+#[derive(Clone)]
+pub struct TorConnection {
+    shared: Arc<Shared>,
+}
 
 impl TorConnection {
     /// Completes a v3 client -> relay handshake.
     /// Older versions are not supported.
     pub fn handshake(relay: SocketAddr) -> Result<TorConnection, Error> {
-        let mut entropy = OsEntropy::new();
-        let mut rng = CtrDrbg::new(&mut entropy, None).unwrap();
-        let mut config = Config::new(Endpoint::Client, Transport::Stream, Preset::Default);
-        config.set_rng(Some(&mut rng));
-        // Security setup
-        config.set_authmode(AuthMode::None);
-        config.set_min_version(Version::Tls1_2).unwrap();
-        //config.set_ciphersuites(&[
-        //    CipherSuite::DheRsaWithAes256CbcSha as i32,
-        //    CipherSuite::DheRsaWithAes256GcmSha384 as i32,
-        //    CipherSuite::EcdhEcdsaWithAes256CbcSha as i32,
-        //    0, // Don't ask me why this is needed, but it is checked by the wrapper whether the last element is 0
-        //]); // Only support new relays
-
-        let mut ctx = Context::new(&config).unwrap();
-
-        // FIXME: Error handling
-        // Connect to relay
-        let mut stream = TcpStream::connect(relay)?;
-        // Certificate validation is not needed (actual keys are fetched from dir authorities and negotiated later)
-        let mut tls_stream = ctx.establish(&mut stream, None).unwrap();
-
-        negotiate_version(&mut tls_stream);
-        authenticate(&mut tls_stream);
-
-        return Ok(TorConnection {});
+        let (events_tx, events_rx) = mpsc::channel::<Event>();
+        let shared = Arc::new(Shared {
+            peer: relay,
+            events: Mutex::new(events_tx),
+            circuits: Mutex::new(HashMap::new()),
+            // We always open the link, as relay mode is not supported
+            circ_ids: Mutex::new(CircIdAllocator::new(true)),
+            last_active: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        });
+
+        // The TLS session borrows its configuration and socket, so it lives
+        // entirely on the connection thread.
+        let (ready_tx, ready_rx) = mpsc::channel::<Result<(), Error>>();
+        let thread_shared = shared.clone();
+        thread::spawn(move || {
+            run_connection(&thread_shared, events_rx, ready_tx);
+            thread_shared.closed.store(true, Ordering::SeqCst);
+            // Dropping the senders tells every circuit that the connection is gone
+            thread_shared.circuits.lock().unwrap().clear();
+        });
+        return match ready_rx.recv() {
+            Ok(Ok(())) => Ok(TorConnection { shared }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::new(
+                ErrorKind::Other,
+                "Connection thread exited during handshake",
+            )),
+        };
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        return self.shared.peer;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        return self.shared.closed.load(Ordering::SeqCst);
+    }
+
+    /// Closes the connection. Circuits on it see it as closed by the relay.
+    pub(crate) fn close(&self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        let _ = self.shared.events.lock().unwrap().send(Event::Close);
+    }
+
+    /// When the last circuit on the connection was closed, or None if it has circuits.
+    pub(crate) fn idle_since(&self) -> Option<Instant> {
+        if !self.shared.circuits.lock().unwrap().is_empty() {
+            return None;
+        }
+        return Some(*self.shared.last_active.lock().unwrap());
+    }
+
+    /// Allocates a circuit ID and returns it, along with the channel its cells will arrive on.
+    pub(crate) fn open_circuit(&self) -> Result<(u32, Receiver<FixedCell>), Error> {
+        let (tx, rx) = mpsc::channel::<FixedCell>();
+        let mut circuits = self.shared.circuits.lock().unwrap();
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+        let now = Instant::now();
+        let id = self
+            .shared
+            .circ_ids
+            .lock()
+            .unwrap()
+            .allocate(|id| circuits.contains_key(&id), now)?;
+        circuits.insert(id, tx);
+        *self.shared.last_active.lock().unwrap() = now;
+        return Ok((id, rx));
+    }
+
+    /// Stops delivering cells for the circuit.
+    pub(crate) fn close_circuit(&self, circ_id: u32) {
+        let mut circuits = self.shared.circuits.lock().unwrap();
+        if circuits.remove(&circ_id).is_some() {
+            let now = Instant::now();
+            self.shared.circ_ids.lock().unwrap().release(circ_id, now);
+            *self.shared.last_active.lock().unwrap() = now;
+        }
+    }
+
+    pub(crate) fn send_cell(&self, cell: &FixedCell) -> Result<(), Error> {
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+        return self
+            .shared
+            .events
+            .lock()
+            .unwrap()
+            .send(Event::Outbound(cell.to_bytes()))
+            .map_err(|_| closed_error());
     }
 }
 
-fn negotiate_version(sess: &mut Session) {
-    println!("Sending VERSIONS cell");
+fn closed_error() -> Error {
+    return Error::new(ErrorKind::NotConnected, "Connection to relay is closed");
+}
+
+fn run_connection(shared: &Shared, events: Receiver<Event>, ready: Sender<Result<(), Error>>) {
+    let mut entropy = OsEntropy::new();
+    let mut rng = CtrDrbg::new(&mut entropy, None).unwrap();
+    let mut config = Config::new(Endpoint::Client, Transport::Stream, Preset::Default);
+    config.set_rng(Some(&mut rng));
+    // Security setup
+    config.set_authmode(AuthMode::None);
+    config.set_min_version(Version::Tls1_2).unwrap();
+    //config.set_ciphersuites(&[
+    //    CipherSuite::DheRsaWithAes256CbcSha as i32,
+    //    CipherSuite::DheRsaWithAes256GcmSha384 as i32,
+    //    CipherSuite::EcdhEcdsaWithAes256CbcSha as i32,
+    //    0, // Don't ask me why this is needed, but it is checked by the wrapper whether the last element is 0
+    //]); // Only support new relays
+
+    let mut ctx = Context::new(&config).unwrap();
+
+    // Connect to relay
+    let mut stream = match TcpStream::connect_timeout(&shared.peer, HANDSHAKE_TIMEOUT) {
+        Ok(s) => s,
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+    // A relay that stops answering mid-handshake makes it fail instead of hanging
+    let timeouts = stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT)));
+    if let Err(e) = timeouts {
+        let _ = ready.send(Err(e));
+        return;
+    }
+    // More handles to the socket, to wait for data without involving mbedtls and to
+    // wake that thread up when we're done
+    let (wait_stream, shutdown_stream) = match (stream.try_clone(), stream.try_clone()) {
+        (Ok(w), Ok(s)) => (w, s),
+        (Err(e), _) | (_, Err(e)) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+    // Certificate validation is not needed (actual keys are fetched from dir authorities and negotiated later)
+    let mut tls_stream = match ctx.establish(&mut stream, None) {
+        Ok(s) => s,
+        Err(e) => {
+            let _ = ready.send(Err(Error::new(ErrorKind::Other, e)));
+            return;
+        }
+    };
+
+    let handshake = negotiate_version(&mut tls_stream)
+        .and_then(|_| authenticate(&mut tls_stream))
+        .and_then(|_| {
+            let netinfo = cell::NetInfoCell::new_client(shared.peer.ip());
+            return tls_stream.write_all(&netinfo.to_bytes());
+        })
+        // The socket is shared with the waiting thread, which blocks until data arrives
+        .and_then(|_| shutdown_stream.set_read_timeout(None))
+        .and_then(|_| shutdown_stream.set_write_timeout(None));
+    let failed = handshake.is_err();
+    let _ = ready.send(handshake);
+    if failed {
+        return;
+    }
+
+    let (ack_tx, ack_rx) = mpsc::channel::<()>();
+    let waker = shared.events.lock().unwrap().clone();
+    thread::spawn(move || wait_readable(&wait_stream, &waker, &ack_rx));
+    serve(shared, &mut tls_stream, &events, &ack_tx);
+    // Makes the waiting thread's peek return, so it exits
+    let _ = shutdown_stream.shutdown(Shutdown::Both);
+}
+
+/// Writes outbound cells and dispatches inbound ones until the connection fails or is closed.
+fn serve(shared: &Shared, tls_stream: &mut Session, events: &Receiver<Event>, ack: &Sender<()>) {
+    let mut inbuf: Vec<u8> = vec![];
+    let mut readbuf: Vec<u8> = vec![0x0; TLS_RECORD_LEN];
+    let mut last_write = Instant::now();
+    loop {
+        if shared.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        let keepalive_in = KEEPALIVE_PERIOD
+            .checked_sub(last_write.elapsed())
+            .unwrap_or_default();
+        match events.recv_timeout(keepalive_in) {
+            Ok(Event::Outbound(c)) => {
+                if tls_stream.write_all(&c).is_err() {
+                    return;
+                }
+                last_write = Instant::now();
+            }
+            Ok(Event::Readable) => {
+                let n = match tls_stream.read(&mut readbuf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => n,
+                };
+                inbuf.extend_from_slice(&readbuf[..n]);
+                while let Some(len) = next_cell_len(&inbuf) {
+                    if inbuf.len() < len {
+                        break;
+                    }
+                    let raw: Vec<u8> = inbuf.drain(..len).collect();
+                    if !dispatch(shared, &raw) {
+                        return;
+                    }
+                }
+                if ack.send(()).is_err() {
+                    return;
+                }
+            }
+            Ok(Event::Close) | Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => {
+                let padding = FixedCell::new(vec![], true, None, FixedCommand::Padding, [0x0; 4]);
+                if tls_stream.write_all(&padding.to_bytes()).is_err() {
+                    return;
+                }
+                last_write = Instant::now();
+            }
+        }
+    }
+}
+
+/// Blocks until the socket has data, then wakes up the connection thread and waits for it
+/// to have read before looking again.
+fn wait_readable(stream: &TcpStream, waker: &Sender<Event>, ack: &Receiver<()>) {
+    let mut probe: [u8; 1] = [0x0; 1];
+    loop {
+        let peeked = stream.peek(&mut probe);
+        // The connection thread finds out about errors and EOF itself when reading
+        if waker.send(Event::Readable).is_err() || ack.recv().is_err() {
+            return;
+        }
+        if !matches!(peeked, Ok(n) if n > 0) {
+            return;
+        }
+    }
+}
+
+/// Returns the length of the cell at the start of the buffer, if enough of it is there to tell.
+fn next_cell_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 5 {
+        return None;
+    }
+    let cmd = buf[4];
+    if cmd == 7 || cmd >= 128 {
+        if buf.len() < VAR_HEADER_LEN {
+            return None;
+        }
+        return Some(VAR_HEADER_LEN + u16::from_be_bytes([buf[5], buf[6]]) as usize);
+    }
+    return Some(CELL_LEN);
+}
+
+/// Delivers the cell to its circuit. Returns false if the relay violated the protocol
+/// and the connection should be closed.
+fn dispatch(shared: &Shared, raw: &[u8]) -> bool {
+    // Variable-length cells are only used during the handshake; VPADDING is ignored
+    if raw.len() != CELL_LEN {
+        return true;
+    }
+    match FixedCommand::try_from_u8(raw[4]) {
+        None | Some(FixedCommand::Padding) | Some(FixedCommand::Netinfo) => return true,
+        Some(FixedCommand::Create)
+        | Some(FixedCommand::Create2)
+        | Some(FixedCommand::CreateFast) => {
+            // Like tor, a circuit opened with one of our IDs ends the connection.
+            // Valid ones are ignored, as we don't act as a relay.
+            let circ_id = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]);
+            return shared.circ_ids.lock().unwrap().accepts_inbound(circ_id);
+        }
+        Some(_) => (),
+    }
+    let mut buf: [u8; CELL_LEN] = [0x0; CELL_LEN];
+    buf.copy_from_slice(raw);
+    let cell = FixedCell::from_bytes(buf);
+    let circ_id = cell.circ_id();
+    let mut circuits = shared.circuits.lock().unwrap();
+    let delivered = match circuits.get(&circ_id) {
+        Some(tx) => tx.send(cell).is_ok(),
+        // Cells for unknown circuits are dropped
+        None => return true,
+    };
+    if !delivered {
+        circuits.remove(&circ_id);
+        let now = Instant::now();
+        shared.circ_ids.lock().unwrap().release(circ_id, now);
+        *shared.last_active.lock().unwrap() = now;
+    }
+    return true;
+}
+
+fn negotiate_version(sess: &mut Session) -> Result<(), Error> {
     let our_version_cell = cell::versions::create_handshake_versions_cell();
-    println!("{:?}", our_version_cell);
-    sess.write_all(&our_version_cell.to_bytes()).unwrap();
-    println!("Sent VERSIONS cell");
-    println!("Reading VERSIONS cell");
-    let their_version_cell = cell::versions::read_handshake_versions_cell(sess).unwrap();
+    sess.write_all(&our_version_cell.to_bytes())?;
+    let their_version_cell = cell::versions::read_handshake_versions_cell(sess)?;
     if !cell::versions::is_supported(&their_version_cell) {
-        panic!("Relay does not support link protocol version 5");
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Relay does not support link protocol version 5",
+        ));
     }
-    println!("Link proto version 5 supported, continuing handshake");
-    println!("{:?}", their_version_cell);
+    return Ok(());
 }
 
-fn authenticate(sess: &mut Session) {
-    println!("Reading CERTS cell");
-    let certs_cell = cell::CertsCell::from_reader(sess).unwrap();
-    println!("{:?}", certs_cell);
+fn authenticate(sess: &mut Session) -> Result<(), Error> {
+    let _certs_cell = cell::CertsCell::from_reader(sess)?;
     // FIXME: VALIDATE CERTS!
 
-    println!("Reading AUTH_CHALLENGE cell");
-    let auth_challenge_cell = cell::AuthChallengeCell::from_reader(sess);
-    println!("{:?}", auth_challenge_cell);
-
-    println!("Reading NETINFO cell");
-    let netinfo_cell = cell::NetInfoCell::from_reader(sess);
-    println!("{:?}", netinfo_cell);
+    let _auth_challenge_cell = cell::AuthChallengeCell::from_reader(sess)?;
+    let _netinfo_cell = cell::NetInfoCell::from_reader(sess)?;
+    return Ok(());
 }
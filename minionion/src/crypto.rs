@@ -0,0 +1,330 @@
+use aes_ctr::cipher::NewStreamCipher;
+pub(crate) use aes_ctr::cipher::SyncStreamCipher;
+pub(crate) use aes_ctr::{Aes128Ctr, Aes256Ctr};
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::Verifier;
+use mbedtls::hash::Type as MdType;
+use mbedtls::pk::Pk;
+use mbedtls::rng::{CtrDrbg, OsEntropy, Random};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use sha3::digest::{ExtendableOutput, XofReader};
+use sha3::{Sha3_256, Shake256};
+use std::convert::{TryFrom, TryInto};
+
+/// Fills the buffer with output from mbedtls' CTR_DRBG seeded from the OS.
+pub(crate) fn random_bytes(buf: &mut [u8]) {
+    let mut entropy = OsEntropy::new();
+    let mut rng = CtrDrbg::new(&mut entropy, None).unwrap();
+    rng.random(buf).unwrap();
+}
+
+pub(crate) fn random_u64() -> u64 {
+    let mut buf: [u8; 8] = [0x0; 8];
+    random_bytes(&mut buf);
+    return u64::from_be_bytes(buf);
+}
+
+/// Returns a uniformly distributed random number in 0..bound.
+pub(crate) fn random_below(bound: u64) -> u64 {
+    if bound == 0 {
+        panic!("random_below() called with bound 0");
+    }
+    // Rejection sampling to avoid modulo bias
+    let zone = u64::MAX - (u64::MAX % bound);
+    loop {
+        let n = random_u64();
+        if n < zone {
+            return n % bound;
+        }
+    }
+}
+
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut out: [u8; 32] = [0x0; 32];
+    out.copy_from_slice(&Sha256::digest(data));
+    return out;
+}
+
+pub(crate) fn sha512(data: &[u8]) -> [u8; 64] {
+    let mut out: [u8; 64] = [0x0; 64];
+    out.copy_from_slice(&Sha512::digest(data));
+    return out;
+}
+
+pub(crate) fn sha3_256(data: &[u8]) -> [u8; 32] {
+    let mut out: [u8; 32] = [0x0; 32];
+    out.copy_from_slice(&Sha3_256::digest(data));
+    return out;
+}
+
+/// SHAKE-256 with `len` bytes of output, the KDF of onion services.
+pub(crate) fn shake256(data: &[u8], len: usize) -> Vec<u8> {
+    let mut shake = Shake256::default();
+    sha3::digest::Update::update(&mut shake, data);
+    let mut out: Vec<u8> = vec![0x0; len];
+    shake.finalize_xof().read(&mut out);
+    return out;
+}
+
+// BLAKE2b (RFC 7693), which the proof-of-work defense hashes solutions with
+const BLAKE2B_IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+const BLAKE2B_SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+/// Unkeyed BLAKE2b with `len` (1 to 64) bytes of output.
+pub(crate) fn blake2b(data: &[u8], len: usize) -> Vec<u8> {
+    let mut h = BLAKE2B_IV;
+    h[0] ^= 0x01010000 ^ len as u64;
+    let n_blocks = std::cmp::max(1, (data.len() + 127) / 128);
+    for n in 0..n_blocks {
+        let mut block: [u8; 128] = [0x0; 128];
+        let chunk =
+            &data[std::cmp::min(n * 128, data.len())..std::cmp::min((n + 1) * 128, data.len())];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let last = n + 1 == n_blocks;
+        let counter = if last { data.len() } else { (n + 1) * 128 } as u128;
+        blake2b_compress(&mut h, &block, counter, last);
+    }
+    let mut out: Vec<u8> = h.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+    out.truncate(len);
+    return out;
+}
+
+fn blake2b_compress(h: &mut [u64; 8], block: &[u8; 128], counter: u128, last: bool) {
+    let mut m: [u64; 16] = [0; 16];
+    for (i, word) in m.iter_mut().enumerate() {
+        *word = u64::from_le_bytes(block[i * 8..i * 8 + 8].try_into().unwrap());
+    }
+    let mut v: [u64; 16] = [0; 16];
+    v[..8].copy_from_slice(h);
+    v[8..].copy_from_slice(&BLAKE2B_IV);
+    v[12] ^= counter as u64;
+    v[13] ^= (counter >> 64) as u64;
+    if last {
+        v[14] = !v[14];
+    }
+    let g = |v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64| {
+        v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+        v[d] = (v[d] ^ v[a]).rotate_right(32);
+        v[c] = v[c].wrapping_add(v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(24);
+        v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+        v[d] = (v[d] ^ v[a]).rotate_right(16);
+        v[c] = v[c].wrapping_add(v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(63);
+    };
+    for round in 0..12 {
+        let s = &BLAKE2B_SIGMA[round % 10];
+        g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+        g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+        g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+        g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+        g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+        g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+        g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+        g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+    }
+    for i in 0..8 {
+        h[i] ^= v[i] ^ v[i + 8];
+    }
+}
+
+pub(crate) fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut out: [u8; 20] = [0x0; 20];
+    out.copy_from_slice(&Sha1::digest(data));
+    return out;
+}
+
+/// Whether the bytes encode an Ed25519 point without a small-order component, as tor requires
+/// of onion service keys.
+pub(crate) fn ed25519_key_is_valid(key: &[u8; 32]) -> bool {
+    return match CompressedEdwardsY(*key).decompress() {
+        Some(p) => p.is_torsion_free(),
+        None => false,
+    };
+}
+
+pub(crate) fn ed25519_verify(key: &[u8; 32], msg: &[u8], sig: &[u8]) -> bool {
+    let key = match ed25519_dalek::PublicKey::from_bytes(key) {
+        Ok(k) => k,
+        Err(_) => return false,
+    };
+    let sig = match ed25519_dalek::Signature::try_from(sig) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    return key.verify(msg, &sig).is_ok();
+}
+
+/// Expands an Ed25519 secret key seed into the scalar and hash prefix used for signing,
+/// returning them with the public key.
+pub(crate) fn ed25519_expand(seed: &[u8; 32]) -> ([u8; 64], [u8; 32]) {
+    let secret = ed25519_dalek::SecretKey::from_bytes(seed).unwrap();
+    let expanded = ed25519_dalek::ExpandedSecretKey::from(&secret);
+    let public = ed25519_dalek::PublicKey::from(&expanded);
+    return (expanded.to_bytes(), public.to_bytes());
+}
+
+/// Generates an Ed25519 key pair, returning the expanded secret key and the public key.
+pub(crate) fn ed25519_generate() -> ([u8; 64], [u8; 32]) {
+    let mut seed: [u8; 32] = [0x0; 32];
+    random_bytes(&mut seed);
+    return ed25519_expand(&seed);
+}
+
+/// The public key of an expanded secret key. The scalar is used as is rather than clamped
+/// like ed25519_dalek does, as blinded scalars are reduced mod l and clamping would change them.
+pub(crate) fn ed25519_public(expanded: &[u8; 64]) -> [u8; 32] {
+    let mut a: [u8; 32] = [0x0; 32];
+    a.copy_from_slice(&expanded[..32]);
+    let point = &Scalar::from_bits(a) * &ED25519_BASEPOINT_TABLE;
+    return point.compress().to_bytes();
+}
+
+/// Signs with an expanded secret key, which is how blinded keys and C tor's key files
+/// store secret keys.
+pub(crate) fn ed25519_sign(expanded: &[u8; 64], public: &[u8; 32], msg: &[u8]) -> [u8; 64] {
+    let secret = ed25519_dalek::ExpandedSecretKey::from_bytes(expanded).unwrap();
+    let public = ed25519_dalek::PublicKey::from_bytes(public).unwrap();
+    return secret.sign(msg, &public).to_bytes();
+}
+
+/// The Ed25519 form of an x25519 public key with sign bit 0, as certified in enc-key-cert.
+pub(crate) fn x25519_to_ed25519(key: &[u8; 32]) -> Option<[u8; 32]> {
+    return MontgomeryPoint(*key)
+        .to_edwards(0)
+        .map(|p| p.compress().to_bytes());
+}
+
+/// Verifies a PKCS#1 v1.5 signature over a digest, as tor makes them: without the
+/// DigestInfo structure usually wrapping the digest. The key is a DER-encoded PKCS#1 RSAPublicKey.
+pub(crate) fn rsa_verify_digest(pkcs1_der: &[u8], digest: &[u8], sig: &[u8]) -> bool {
+    let mut key = match Pk::from_public_key(&pkcs1_to_spki(pkcs1_der)) {
+        Ok(k) => k,
+        Err(_) => return false,
+    };
+    return key.verify(MdType::None, digest, sig).is_ok();
+}
+
+/// Wraps a PKCS#1 RSAPublicKey into a SubjectPublicKeyInfo, which is what mbedtls can parse.
+fn pkcs1_to_spki(pkcs1_der: &[u8]) -> Vec<u8> {
+    // AlgorithmIdentifier for rsaEncryption with NULL parameters
+    const RSA_ALGORITHM_ID: [u8; 15] = [
+        0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+    ];
+    let mut bit_string: Vec<u8> = vec![0x0]; // No unused bits
+    bit_string.extend_from_slice(pkcs1_der);
+    let mut body: Vec<u8> = RSA_ALGORITHM_ID.to_vec();
+    body.extend(der_tlv(0x03, &bit_string));
+    return der_tlv(0x30, &body);
+}
+
+fn der_tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend(len_bytes);
+    }
+    out.extend_from_slice(value);
+    return out;
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    const BLOCK_LEN: usize = 64;
+    let mut block_key: [u8; BLOCK_LEN] = [0x0; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block_key.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(msg);
+    let mut outer = Sha256::new();
+    outer.update(block_key.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner.finalize());
+    let mut out: [u8; 32] = [0x0; 32];
+    out.copy_from_slice(&outer.finalize());
+    return out;
+}
+
+/// The expand step of HKDF-SHA256 (RFC 5869), with an already extracted key.
+pub(crate) fn hkdf_sha256_expand(prk: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(len);
+    let mut prev: Vec<u8> = vec![];
+    let mut counter: u8 = 1;
+    while out.len() < len {
+        let mut msg = prev.clone();
+        msg.extend_from_slice(info);
+        msg.push(counter);
+        prev = hmac_sha256(prk, &msg).to_vec();
+        out.extend_from_slice(&prev);
+        counter += 1;
+    }
+    out.truncate(len);
+    return out;
+}
+
+/// KDF-TOR as used by CREATE_FAST (tor-spec 5.2.1).
+pub(crate) fn kdf_tor(k0: &[u8], len: usize) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(len);
+    let mut i: u8 = 0;
+    while out.len() < len {
+        let mut input = k0.to_vec();
+        input.push(i);
+        out.extend_from_slice(&sha1(&input));
+        i += 1;
+    }
+    out.truncate(len);
+    return out;
+}
+
+/// AES-128 in counter mode with an all-zero IV, as used for relay cell encryption.
+pub(crate) fn aes128_ctr(key: &[u8]) -> Aes128Ctr {
+    return Aes128Ctr::new_var(key, &[0x0; 16]).unwrap();
+}
+
+/// AES-256 in counter mode, as used by onion services.
+pub(crate) fn aes256_ctr(key: &[u8], iv: &[u8]) -> Aes256Ctr {
+    return Aes256Ctr::new_var(key, iv).unwrap();
+}
+
+/// Compares two byte strings in constant time.
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::SystemTime;
+
+use super::netdoc::{self, invalid, Item};
+use crate::encoding;
+
+/// Flags assigned to a relay by the directory authorities.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RelayFlags(u16);
+
+impl RelayFlags {
+    pub const AUTHORITY: RelayFlags = RelayFlags(1 << 0);
+    pub const BAD_EXIT: RelayFlags = RelayFlags(1 << 1);
+    pub const EXIT: RelayFlags = RelayFlags(1 << 2);
+    pub const FAST: RelayFlags = RelayFlags(1 << 3);
+    pub const GUARD: RelayFlags = RelayFlags(1 << 4);
+    pub const HSDIR: RelayFlags = RelayFlags(1 << 5);
+    pub const MIDDLE_ONLY: RelayFlags = RelayFlags(1 << 6);
+    pub const NO_ED_CONSENSUS: RelayFlags = RelayFlags(1 << 7);
+    pub const RUNNING: RelayFlags = RelayFlags(1 << 8);
+    pub const STABLE: RelayFlags = RelayFlags(1 << 9);
+    pub const STALE_DESC: RelayFlags = RelayFlags(1 << 10);
+    pub const SYBIL: RelayFlags = RelayFlags(1 << 11);
+    pub const V2DIR: RelayFlags = RelayFlags(1 << 12);
+    pub const VALID: RelayFlags = RelayFlags(1 << 13);
+
+    pub fn contains(self, other: RelayFlags) -> bool {
+        return self.0 & other.0 == other.0;
+    }
+
+    fn from_token(token: &str) -> RelayFlags {
+        return match token {
+            "Authority" => RelayFlags::AUTHORITY,
+            "BadExit" => RelayFlags::BAD_EXIT,
+            "Exit" => RelayFlags::EXIT,
+            "Fast" => RelayFlags::FAST,
+            "Guard" => RelayFlags::GUARD,
+            "HSDir" => RelayFlags::HSDIR,
+            "MiddleOnly" => RelayFlags::MIDDLE_ONLY,
+            "NoEdConsensus" => RelayFlags::NO_ED_CONSENSUS,
+            "Running" => RelayFlags::RUNNING,
+            "Stable" => RelayFlags::STABLE,
+            "StaleDesc" => RelayFlags::STALE_DESC,
+            "Sybil" => RelayFlags::SYBIL,
+            "V2Dir" => RelayFlags::V2DIR,
+            "Valid" => RelayFlags::VALID,
+            // Unknown flags must be ignored
+            _ => RelayFlags(0),
+        };
+    }
+}
+
+impl std::ops::BitOr for RelayFlags {
+    type Output = RelayFlags;
+    fn bitor(self, rhs: RelayFlags) -> RelayFlags {
+        return RelayFlags(self.0 | rhs.0);
+    }
+}
+
+/// A single router status entry of a microdescriptor consensus.
+#[derive(Debug, Clone)]
+pub struct RouterStatus {
+    pub nickname: String,
+    /// SHA1 digest of the relay's RSA identity key
+    pub identity: [u8; 20],
+    pub published: SystemTime,
+    pub or_addrs: Vec<SocketAddr>,
+    pub dir_port: u16,
+    pub flags: RelayFlags,
+    /// Consensus weight, in kilobytes per second
+    pub bandwidth: u64,
+    pub unmeasured: bool,
+    /// SHA256 digest of the relay's microdescriptor
+    pub md_digest: [u8; 32],
+    pub protocols: String,
+}
+
+impl RouterStatus {
+    /// Whether the relay supports the version of the subprotocol, going by its "pr" line.
+    pub fn supports_protocol(&self, name: &str, version: u32) -> bool {
+        let versions = match self
+            .protocols
+            .split(' ')
+            .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+        {
+            Some(v) => v,
+            None => return false,
+        };
+        return versions.split(',').any(|range| {
+            let mut bounds = range.splitn(2, '-').map(|b| b.parse::<u32>());
+            return match (bounds.next(), bounds.next()) {
+                (Some(Ok(lo)), None) => lo == version,
+                (Some(Ok(lo)), Some(Ok(hi))) => lo <= version && version <= hi,
+                _ => false,
+            };
+        });
+    }
+}
+
+/// A parsed microdescriptor-flavored consensus.
+///
+/// FIXME: Signatures are not validated yet.
+#[derive(Debug, Clone)]
+pub struct Consensus {
+    pub valid_after: SystemTime,
+    pub fresh_until: SystemTime,
+    pub valid_until: SystemTime,
+    pub params: HashMap<String, i64>,
+    pub bandwidth_weights: HashMap<String, i64>,
+    /// Shared random values as (number of reveals, value)
+    pub shared_rand_current: Option<(u32, [u8; 32])>,
+    pub shared_rand_previous: Option<(u32, [u8; 32])>,
+    pub relays: Vec<RouterStatus>,
+}
+
+impl Consensus {
+    pub fn parse(doc: &str) -> Result<Consensus, Error> {
+        let items = netdoc::parse_items(doc)?;
+        let mut iter = items.iter().peekable();
+
+        match iter.next() {
+            Some(i) if i.keyword == "network-status-version" => {
+                if i.arg(0)? != "3" || i.args.get(1) != Some(&"microdesc") {
+                    return Err(invalid(
+                        "Only version 3 microdesc consensuses are supported",
+                    ));
+                }
+            }
+            _ => return Err(invalid("Consensus must start with network-status-version")),
+        }
+
+        let mut valid_after: Option<SystemTime> = None;
+        let mut fresh_until: Option<SystemTime> = None;
+        let mut valid_until: Option<SystemTime> = None;
+        let mut params: HashMap<String, i64> = HashMap::new();
+        let mut shared_rand_current: Option<(u32, [u8; 32])> = None;
+        let mut shared_rand_previous: Option<(u32, [u8; 32])> = None;
+
+        // Preamble and authority sections
+        while let Some(item) = iter.peek() {
+            if item.keyword == "r" || item.keyword == "directory-footer" {
+                break;
+            }
+            let item = iter.next().unwrap();
+            match item.keyword {
+                "valid-after" => {
+                    valid_after = Some(netdoc::parse_time(item.arg(0)?, item.arg(1)?)?)
+                }
+                "fresh-until" => {
+                    fresh_until = Some(netdoc::parse_time(item.arg(0)?, item.arg(1)?)?)
+                }
+                "valid-until" => {
+                    valid_until = Some(netdoc::parse_time(item.arg(0)?, item.arg(1)?)?)
+                }
+                "params" => params.extend(netdoc::parse_int_params(&item.args)?),
+                "shared-rand-current-value" => shared_rand_current = Some(parse_srv(item)?),
+                "shared-rand-previous-value" => shared_rand_previous = Some(parse_srv(item)?),
+                _ => (),
+            }
+        }
+
+        let mut relays: Vec<RouterStatus> = vec![];
+        while let Some(item) = iter.peek() {
+            if item.keyword != "r" {
+                break;
+            }
+            let r = iter.next().unwrap();
+            let mut entry: Vec<&Item> = vec![];
+            while let Some(item) = iter.peek() {
+                if item.keyword == "r" || item.keyword == "directory-footer" {
+                    break;
+                }
+                entry.push(iter.next().unwrap());
+            }
+            relays.push(parse_router_status(r, &entry)?);
+        }
+
+        let mut bandwidth_weights: HashMap<String, i64> = HashMap::new();
+        for item in iter {
+            if item.keyword == "bandwidth-weights" {
+                bandwidth_weights.extend(netdoc::parse_int_params(&item.args)?);
+            }
+        }
+
+        return Ok(Consensus {
+            valid_after: valid_after.ok_or_else(|| invalid("Consensus lacks valid-after"))?,
+            fresh_until: fresh_until.ok_or_else(|| invalid("Consensus lacks fresh-until"))?,
+            valid_until: valid_until.ok_or_else(|| invalid("Consensus lacks valid-until"))?,
+            params,
+            bandwidth_weights,
+            shared_rand_current,
+            shared_rand_previous,
+            relays,
+        });
+    }
+
+    /// Returns a consensus parameter, clamped to the given bounds.
+    pub fn param(&self, name: &str, default: i64, min: i64, max: i64) -> i64 {
+        return match self.params.get(name) {
+            Some(v) => (*v).max(min).min(max),
+            None => default,
+        };
+    }
+
+    /// Returns the bandwidth weight with the given name (e.g. "Wgg") out of 10000.
+    pub fn weight(&self, name: &str) -> i64 {
+        return *self.bandwidth_weights.get(name).unwrap_or(&10000);
+    }
+}
+
+fn parse_srv(item: &Item) -> Result<(u32, [u8; 32]), Error> {
+    let reveals = item
+        .arg(0)?
+        .parse::<u32>()
+        .map_err(|_| invalid("Invalid shared random reveal count"))?;
+    let value = decode_fixed(item.arg(1)?, 32, "shared random value")?;
+    return Ok((reveals, value.as_slice().try_into().unwrap()));
+}
+
+fn decode_fixed(b64: &str, len: usize, what: &str) -> Result<Vec<u8>, Error> {
+    return match encoding::base64_decode(b64) {
+        Some(v) if v.len() == len => Ok(v),
+        _ => Err(invalid(&format!("Invalid {}: {}", what, b64))),
+    };
+}
+
+fn parse_router_status(r: &Item, entry: &[&Item]) -> Result<RouterStatus, Error> {
+    // The microdesc flavor omits the descriptor digest that the ns flavor has
+    let (date_idx, addr_idx) = match r.args.len() {
+        7 => (2, 4),
+        8 => (3, 5),
+        _ => return Err(invalid("Malformed \"r\" line")),
+    };
+    let identity = decode_fixed(r.arg(1)?, 20, "identity")?;
+    let published = netdoc::parse_time(r.arg(date_idx)?, r.arg(date_idx + 1)?)?;
+    let addr: Ipv4Addr = r
+        .arg(addr_idx)?
+        .parse()
+        .map_err(|_| invalid("Invalid relay address"))?;
+    let or_port: u16 = r
+        .arg(addr_idx + 1)?
+        .parse()
+        .map_err(|_| invalid("Invalid ORPort"))?;
+    let dir_port: u16 = r
+        .arg(addr_idx + 2)?
+        .parse()
+        .map_err(|_| invalid("Invalid DirPort"))?;
+
+    let mut rs = RouterStatus {
+        nickname: r.arg(0)?.to_string(),
+        identity: identity.as_slice().try_into().unwrap(),
+        published,
+        or_addrs: vec![SocketAddr::new(addr.into(), or_port)],
+        dir_port,
+        flags: RelayFlags::default(),
+        bandwidth: 0,
+        unmeasured: false,
+        md_digest: [0x0; 32],
+        protocols: String::new(),
+    };
+
+    let mut have_digest = false;
+    for item in entry {
+        match item.keyword {
+            "a" => match item.arg(0)?.parse::<SocketAddr>() {
+                Ok(a) => rs.or_addrs.push(a),
+                Err(_) => return Err(invalid("Invalid \"a\" line")),
+            },
+            "s" => {
+                for flag in &item.args {
+                    rs.flags = rs.flags | RelayFlags::from_token(flag);
+                }
+            }
+            "w" => {
+                for (k, v) in netdoc::parse_int_params(&item.args)? {
+                    match k.as_str() {
+                        "Bandwidth" => rs.bandwidth = v.max(0) as u64,
+                        "Unmeasured" => rs.unmeasured = v == 1,
+                        _ => (),
+                    }
+                }
+            }
+            "pr" => rs.protocols = item.args.join(" "),
+            "m" => {
+                let digest = decode_fixed(item.arg(0)?, 32, "microdescriptor digest")?;
+                rs.md_digest.copy_from_slice(&digest);
+                have_digest = true;
+            }
+            _ => (),
+        }
+    }
+    if !have_digest {
+        return Err(invalid(&format!(
+            "Router status for {} lacks a microdescriptor digest",
+            rs.nickname
+        )));
+    }
+    return Ok(rs);
+}
@@ -0,0 +1,550 @@
+// Exit policies (dir-spec 2.1.3) and their summaries (dir-spec 3.4.1).
+
+use std::fmt;
+use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use super::netdoc::invalid;
+
+// Networks matched by the "private" address pattern, as in tor
+const PRIVATE_NETWORKS: [&str; 11] = [
+    "0.0.0.0/8",
+    "169.254.0.0/16",
+    "127.0.0.0/8",
+    "192.168.0.0/16",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "[::]/8",
+    "[fc00::]/7",
+    "[fe80::]/10",
+    "[fec0::]/10",
+    "[::]/127",
+];
+
+/// The answer to whether a policy allows exiting to a destination.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PolicyResult {
+    Accepted,
+    Rejected,
+    /// The answer depends on the address, which isn't known (yet).
+    Maybe,
+}
+
+/// Exit policy summary as found in the "p" and "p6" lines of a microdescriptor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortPolicy {
+    accept: bool,
+    ranges: Vec<(u16, u16)>,
+}
+
+impl PortPolicy {
+    /// A policy rejecting all ports; the default when no "p" line is present.
+    pub fn reject_all() -> PortPolicy {
+        return PortPolicy {
+            accept: false,
+            ranges: vec![(1, 65535)],
+        };
+    }
+
+    /// Parses the arguments of a "p" line, e.g. "accept 80,443,1000-2000".
+    pub fn parse(args: &[&str]) -> Result<PortPolicy, Error> {
+        if args.len() != 2 {
+            return Err(invalid("Malformed port policy summary"));
+        }
+        let accept = match args[0] {
+            "accept" => true,
+            "reject" => false,
+            _ => return Err(invalid("Port policy summary must accept or reject")),
+        };
+        let mut ranges: Vec<(u16, u16)> = vec![];
+        for entry in args[1].split(',') {
+            let range = parse_port_range(entry)?;
+            if range.0 == 0 {
+                return Err(invalid(&format!("Invalid port range: {}", entry)));
+            }
+            ranges.push(range);
+        }
+        return Ok(PortPolicy { accept, ranges });
+    }
+
+    pub fn allows_port(&self, port: u16) -> bool {
+        let listed = self
+            .ranges
+            .iter()
+            .any(|(lo, hi)| *lo <= port && port <= *hi);
+        return listed == self.accept;
+    }
+
+    /// Whether the policy allows exiting to at least one port.
+    pub fn allows_some_port(&self) -> bool {
+        if self.accept {
+            return !self.ranges.is_empty();
+        }
+        return (1..=65535).any(|p| self.allows_port(p));
+    }
+}
+
+/// Address part of a policy rule.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddrPattern {
+    /// "*", matching any address
+    Any,
+    /// "*4"
+    AnyV4,
+    /// "*6"
+    AnyV6,
+    V4(Ipv4Addr, u8),
+    V6(Ipv6Addr, u8),
+}
+
+impl AddrPattern {
+    pub fn matches(&self, addr: &IpAddr) -> bool {
+        return match (self, addr) {
+            (AddrPattern::Any, _) => true,
+            (AddrPattern::AnyV4, IpAddr::V4(_)) => true,
+            (AddrPattern::AnyV6, IpAddr::V6(_)) => true,
+            (AddrPattern::V4(net, bits), IpAddr::V4(a)) => {
+                prefix_matches(&net.octets(), &a.octets(), *bits)
+            }
+            (AddrPattern::V6(net, bits), IpAddr::V6(a)) => {
+                prefix_matches(&net.octets(), &a.octets(), *bits)
+            }
+            _ => false,
+        };
+    }
+
+    /// Whether the pattern matches any address of the family.
+    fn in_family(&self, ipv6: bool) -> bool {
+        return match self {
+            AddrPattern::Any => true,
+            AddrPattern::AnyV4 | AddrPattern::V4(..) => !ipv6,
+            AddrPattern::AnyV6 | AddrPattern::V6(..) => ipv6,
+        };
+    }
+
+    /// Whether the pattern matches every address of the family.
+    fn covers_family(&self, ipv6: bool) -> bool {
+        return match self {
+            AddrPattern::V4(_, bits) | AddrPattern::V6(_, bits) => {
+                *bits == 0 && self.in_family(ipv6)
+            }
+            _ => self.in_family(ipv6),
+        };
+    }
+
+    pub fn parse(s: &str) -> Result<AddrPattern, Error> {
+        let err = || invalid(&format!("Invalid address pattern: {}", s));
+        match s {
+            "*" => return Ok(AddrPattern::Any),
+            "*4" => return Ok(AddrPattern::AnyV4),
+            "*6" => return Ok(AddrPattern::AnyV6),
+            _ => (),
+        }
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap();
+        let mask = parts.next();
+
+        if addr.starts_with('[') {
+            let ip = Ipv6Addr::from_str(addr.trim_start_matches('[').trim_end_matches(']'))
+                .map_err(|_| err())?;
+            let bits = match mask {
+                Some(m) => m.parse::<u8>().map_err(|_| err())?,
+                None => 128,
+            };
+            if bits > 128 {
+                return Err(err());
+            }
+            return Ok(AddrPattern::V6(ip, bits));
+        }
+
+        let ip = Ipv4Addr::from_str(addr).map_err(|_| err())?;
+        let bits = match mask {
+            None => 32,
+            // Masks may be given as a dotted quad, e.g. 255.255.0.0
+            Some(m) if m.contains('.') => {
+                let m = u32::from(Ipv4Addr::from_str(m).map_err(|_| err())?);
+                if m.leading_ones() + m.trailing_zeros() != 32 {
+                    return Err(err());
+                }
+                m.leading_ones() as u8
+            }
+            Some(m) => m.parse::<u8>().map_err(|_| err())?,
+        };
+        if bits > 32 {
+            return Err(err());
+        }
+        return Ok(AddrPattern::V4(ip, bits));
+    }
+}
+
+impl fmt::Display for AddrPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            AddrPattern::Any => write!(f, "*"),
+            AddrPattern::AnyV4 => write!(f, "*4"),
+            AddrPattern::AnyV6 => write!(f, "*6"),
+            AddrPattern::V4(ip, 32) => write!(f, "{}", ip),
+            AddrPattern::V4(ip, bits) => write!(f, "{}/{}", ip, bits),
+            AddrPattern::V6(ip, 128) => write!(f, "[{}]", ip),
+            AddrPattern::V6(ip, bits) => write!(f, "[{}]/{}", ip, bits),
+        };
+    }
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], bits: u8) -> bool {
+    let full_bytes = (bits / 8) as usize;
+    if net[..full_bytes] != addr[..full_bytes] {
+        return false;
+    }
+    let rem = bits % 8;
+    if rem == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rem);
+    return net[full_bytes] & mask == addr[full_bytes] & mask;
+}
+
+fn parse_port_range(s: &str) -> Result<(u16, u16), Error> {
+    if s == "*" {
+        return Ok((1, 65535));
+    }
+    let mut bounds = s.splitn(2, '-');
+    let lo = bounds.next().unwrap().parse::<u16>();
+    let hi = match bounds.next() {
+        Some(h) => h.parse::<u16>(),
+        None => lo.clone(),
+    };
+    return match (lo, hi) {
+        (Ok(lo), Ok(hi)) if lo <= hi => Ok((lo, hi)),
+        _ => Err(invalid(&format!("Invalid port range: {}", s))),
+    };
+}
+
+/// A single "accept" or "reject" rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyRule {
+    pub accept: bool,
+    pub addr: AddrPattern,
+    pub ports: (u16, u16),
+}
+
+impl PolicyRule {
+    /// Parses a rule such as "accept 10.0.0.0/8:80-443" or "reject6 [::1]:*".
+    pub fn parse(line: &str) -> Result<PolicyRule, Error> {
+        let err = || invalid(&format!("Invalid policy rule: {}", line));
+        let mut parts = line.split_whitespace();
+        let (accept, v6_only) = match parts.next() {
+            Some("accept") => (true, false),
+            Some("reject") => (false, false),
+            Some("accept6") => (true, true),
+            Some("reject6") => (false, true),
+            _ => return Err(err()),
+        };
+        let pattern = parts.next().ok_or_else(err)?;
+        if parts.next().is_some() {
+            return Err(err());
+        }
+        // The port is separated by the last colon, IPv6 addresses are bracketed
+        let colon = pattern.rfind(':').ok_or_else(err)?;
+        let mut addr = AddrPattern::parse(&pattern[..colon])?;
+        let ports = parse_port_range(&pattern[colon + 1..])?;
+        if v6_only {
+            addr = match addr {
+                AddrPattern::Any | AddrPattern::AnyV6 => AddrPattern::AnyV6,
+                AddrPattern::V6(ip, bits) => AddrPattern::V6(ip, bits),
+                // accept6/reject6 with IPv4 addresses are ignored by tor with a warning
+                _ => return Err(err()),
+            };
+        }
+        return Ok(PolicyRule {
+            accept,
+            addr,
+            ports,
+        });
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        return self.ports.0 <= port && port <= self.ports.1;
+    }
+}
+
+impl fmt::Display for PolicyRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}:",
+            if self.accept { "accept" } else { "reject" },
+            self.addr
+        )?;
+        return match self.ports {
+            (1, 65535) => write!(f, "*"),
+            (lo, hi) if lo == hi => write!(f, "{}", lo),
+            (lo, hi) => write!(f, "{}-{}", lo, hi),
+        };
+    }
+}
+
+/// An ordered list of rules; the first one matching a destination decides.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ExitPolicy {
+    pub rules: Vec<PolicyRule>,
+}
+
+impl ExitPolicy {
+    pub fn reject_all() -> ExitPolicy {
+        return ExitPolicy {
+            rules: vec![PolicyRule {
+                accept: false,
+                addr: AddrPattern::Any,
+                ports: (1, 65535),
+            }],
+        };
+    }
+
+    /// Parses the "accept"/"reject" lines of a router descriptor, one rule per line.
+    pub fn parse_lines(lines: &[&str]) -> Result<ExitPolicy, Error> {
+        let mut rules: Vec<PolicyRule> = vec![];
+        for line in lines {
+            rules.push(PolicyRule::parse(line)?);
+        }
+        return Ok(ExitPolicy { rules });
+    }
+
+    /// Parses a torrc-style comma separated policy such as "reject private:*, accept *:80".
+    /// The "private" pattern is expanded to the local and private networks.
+    pub fn parse_torrc(value: &str) -> Result<ExitPolicy, Error> {
+        let mut rules: Vec<PolicyRule> = vec![];
+        for entry in value.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let mut parts = entry.split_whitespace();
+            let action = parts.next().unwrap();
+            let pattern = parts.next().unwrap_or("");
+            if pattern.starts_with("private:") {
+                let ports = &pattern["private".len()..];
+                for net in PRIVATE_NETWORKS.iter() {
+                    rules.push(PolicyRule::parse(&format!("{} {}{}", action, net, ports))?);
+                }
+            } else {
+                rules.push(PolicyRule::parse(entry)?);
+            }
+        }
+        return Ok(ExitPolicy { rules });
+    }
+
+    /// Builds the policy equivalent to a port summary, applying to one address family.
+    pub fn from_summary(summary: &PortPolicy, ipv6: bool) -> ExitPolicy {
+        let addr = if ipv6 {
+            AddrPattern::AnyV6
+        } else {
+            AddrPattern::AnyV4
+        };
+        let mut rules: Vec<PolicyRule> = summary
+            .ranges
+            .iter()
+            .map(|r| PolicyRule {
+                accept: summary.accept,
+                addr: addr.clone(),
+                ports: *r,
+            })
+            .collect();
+        rules.push(PolicyRule {
+            accept: !summary.accept,
+            addr,
+            ports: (1, 65535),
+        });
+        return ExitPolicy { rules };
+    }
+
+    /// Decides whether exiting to the given port is allowed. If the address isn't
+    /// known yet (e.g. the hostname is resolved by the exit), the answer may be `Maybe`.
+    pub fn allows(&self, addr: Option<IpAddr>, port: u16) -> PolicyResult {
+        let addr = match addr {
+            Some(a) => a,
+            None => return self.allows_unknown_addr(port),
+        };
+        for rule in &self.rules {
+            if rule.matches_port(port) && rule.addr.matches(&addr) {
+                return if rule.accept {
+                    PolicyResult::Accepted
+                } else {
+                    PolicyResult::Rejected
+                };
+            }
+        }
+        // If no rule matches, the address is accepted (dir-spec 2.1.3)
+        return PolicyResult::Accepted;
+    }
+
+    // The address may turn out to be of either family, so a rule covering only one
+    // of them decides only if the rules for the other family agree
+    fn allows_unknown_addr(&self, port: u16) -> PolicyResult {
+        let v4 = self.allows_unknown_in_family(port, false);
+        let v6 = self.allows_unknown_in_family(port, true);
+        return if v4 == v6 { v4 } else { PolicyResult::Maybe };
+    }
+
+    fn allows_unknown_in_family(&self, port: u16, ipv6: bool) -> PolicyResult {
+        let mut maybe_accept = false;
+        let mut maybe_reject = false;
+        let rules = self
+            .rules
+            .iter()
+            .filter(|r| r.matches_port(port) && r.addr.in_family(ipv6));
+        for rule in rules {
+            if rule.addr.covers_family(ipv6) {
+                if rule.accept {
+                    return if maybe_reject {
+                        PolicyResult::Maybe
+                    } else {
+                        PolicyResult::Accepted
+                    };
+                }
+                return if maybe_accept {
+                    PolicyResult::Maybe
+                } else {
+                    PolicyResult::Rejected
+                };
+            }
+            if rule.accept {
+                maybe_accept = true;
+            } else {
+                maybe_reject = true;
+            }
+        }
+        return if maybe_reject {
+            PolicyResult::Maybe
+        } else {
+            PolicyResult::Accepted
+        };
+    }
+
+    /// Whether any destination on the port could be allowed.
+    pub fn allows_port(&self, port: u16) -> bool {
+        return self.allows(None, port) != PolicyResult::Rejected;
+    }
+}
+
+impl fmt::Display for ExitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for rule in &self.rules {
+            writeln!(f, "{}", rule)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(lines: &[&str]) -> ExitPolicy {
+        return ExitPolicy::parse_lines(lines).unwrap();
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        return Some(IpAddr::from_str(s).unwrap());
+    }
+
+    #[test]
+    fn parse_lines() {
+        let p = policy(&[
+            "reject 0.0.0.0/255.0.0.0:*",
+            "accept 10.0.0.0/8:80-443",
+            "reject6 [fc00::]/7:*",
+            "accept *:22",
+            "reject *:*",
+        ]);
+        assert_eq!(p.rules.len(), 5);
+        assert_eq!(
+            p.rules[0].addr,
+            AddrPattern::V4(Ipv4Addr::new(0, 0, 0, 0), 8)
+        );
+        assert_eq!(p.rules[1].ports, (80, 443));
+        assert!(p.rules[1].accept);
+        assert_eq!(
+            p.rules[2].addr,
+            AddrPattern::V6("fc00::".parse().unwrap(), 7)
+        );
+        assert_eq!(p.rules[4].addr, AddrPattern::Any);
+        assert_eq!(p.rules[4].ports, (1, 65535));
+        assert_eq!(
+            p.to_string(),
+            "reject 0.0.0.0/8:*\naccept 10.0.0.0/8:80-443\nreject [fc00::]/7:*\naccept *:22\nreject *:*\n"
+        );
+
+        assert!(ExitPolicy::parse_lines(&["allow *:*"]).is_err());
+        assert!(ExitPolicy::parse_lines(&["accept *"]).is_err());
+        assert!(ExitPolicy::parse_lines(&["accept 10.0.0.0/33:*"]).is_err());
+        assert!(ExitPolicy::parse_lines(&["accept 255.0.255.0/255.0.255.0:*"]).is_err());
+        assert!(ExitPolicy::parse_lines(&["accept *:443-80"]).is_err());
+        assert!(ExitPolicy::parse_lines(&["accept6 1.2.3.4:*"]).is_err());
+    }
+
+    #[test]
+    fn known_addresses() {
+        let p = policy(&["reject 10.0.0.0/8:*", "accept *:80", "reject *:*"]);
+        assert_eq!(p.allows(ip("10.1.2.3"), 80), PolicyResult::Rejected);
+        assert_eq!(p.allows(ip("11.1.2.3"), 80), PolicyResult::Accepted);
+        assert_eq!(p.allows(ip("11.1.2.3"), 443), PolicyResult::Rejected);
+        assert_eq!(p.allows(ip("2001:db8::1"), 80), PolicyResult::Accepted);
+        // Without a matching rule the destination is accepted
+        let p = policy(&["reject *:25"]);
+        assert_eq!(p.allows(ip("11.1.2.3"), 80), PolicyResult::Accepted);
+    }
+
+    #[test]
+    fn unknown_addresses() {
+        let p = policy(&["accept *:80", "reject *:*"]);
+        assert_eq!(p.allows(None, 80), PolicyResult::Accepted);
+        assert_eq!(p.allows(None, 443), PolicyResult::Rejected);
+        assert!(!p.allows_port(443));
+
+        let p = policy(&["reject 10.0.0.0/8:*", "accept *:80", "reject *:*"]);
+        assert_eq!(p.allows(None, 80), PolicyResult::Maybe);
+        assert_eq!(p.allows(None, 443), PolicyResult::Rejected);
+        let p = policy(&["accept 10.0.0.0/8:*", "reject *:*"]);
+        assert_eq!(p.allows(None, 80), PolicyResult::Maybe);
+        assert!(p.allows_port(80));
+
+        // A rule for one family does not decide for the other
+        let p = policy(&["reject *4:*", "accept *:*"]);
+        assert_eq!(p.allows(None, 80), PolicyResult::Maybe);
+        let p = policy(&["accept *4:80", "reject *:*"]);
+        assert_eq!(p.allows(None, 80), PolicyResult::Maybe);
+        let p = policy(&["accept 0.0.0.0/0:80", "reject *:*"]);
+        assert_eq!(p.allows(None, 80), PolicyResult::Maybe);
+        // Unless the rules for both families agree
+        let p = policy(&["reject *4:*", "reject6 *:*", "accept *:*"]);
+        assert_eq!(p.allows(None, 80), PolicyResult::Rejected);
+        let p = policy(&["accept *4:80", "accept [::]/0:80", "reject *:*"]);
+        assert_eq!(p.allows(None, 80), PolicyResult::Accepted);
+    }
+
+    #[test]
+    fn from_summary() {
+        let summary = PortPolicy::parse(&["accept", "80,443,1000-2000"]).unwrap();
+        assert!(summary.allows_port(1500));
+        assert!(!summary.allows_port(22));
+        let p = ExitPolicy::from_summary(&summary, false);
+        assert_eq!(
+            p.to_string(),
+            "accept *4:80\naccept *4:443\naccept *4:1000-2000\nreject *4:*\n"
+        );
+        assert_eq!(p.allows(ip("1.2.3.4"), 443), PolicyResult::Accepted);
+        assert_eq!(p.allows(ip("1.2.3.4"), 22), PolicyResult::Rejected);
+        // The summary says nothing about IPv6
+        assert_eq!(p.allows(ip("2001:db8::1"), 22), PolicyResult::Accepted);
+        assert_eq!(p.allows(None, 22), PolicyResult::Maybe);
+
+        let summary = PortPolicy::parse(&["reject", "25"]).unwrap();
+        let p = ExitPolicy::from_summary(&summary, true);
+        assert_eq!(p.to_string(), "reject *6:25\naccept *6:*\n");
+        assert_eq!(p.allows(ip("2001:db8::1"), 25), PolicyResult::Rejected);
+        assert_eq!(p.allows(ip("2001:db8::1"), 80), PolicyResult::Accepted);
+        assert_eq!(p.allows(None, 80), PolicyResult::Accepted);
+
+        assert!(PortPolicy::parse(&["accept", "0-80"]).is_err());
+        assert!(PortPolicy::parse(&["allow", "80"]).is_err());
+        assert!(!PortPolicy::reject_all().allows_some_port());
+    }
+}
@@ -0,0 +1,92 @@
+use std::convert::TryInto;
+use std::io::Error;
+
+use super::exit_policy::PortPolicy;
+use super::netdoc::{self, invalid};
+use crate::crypto;
+use crate::encoding;
+
+/// A relay's microdescriptor, holding the keys and policies needed for building circuits.
+#[derive(Debug, Clone)]
+pub struct Microdesc {
+    /// SHA256 digest of the document, as referenced by the consensus
+    pub digest: [u8; 32],
+    pub ntor_onion_key: [u8; 32],
+    pub ed25519_identity: Option<[u8; 32]>,
+    /// Family members, as "$HEXID" or nicknames
+    pub family: Vec<String>,
+    pub ipv4_policy: PortPolicy,
+    pub ipv6_policy: PortPolicy,
+}
+
+impl Microdesc {
+    /// Parses a concatenation of microdescriptors, as served by directory caches.
+    pub fn parse_all(doc: &str) -> Result<Vec<Microdesc>, Error> {
+        let items = netdoc::parse_items(doc)?;
+        let starts: Vec<usize> = items
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| i.keyword == "onion-key")
+            .map(|(n, _)| n)
+            .collect();
+        if starts.first() != Some(&0) {
+            return Err(invalid("Microdescriptor must start with onion-key"));
+        }
+
+        let mut mds: Vec<Microdesc> = vec![];
+        for (n, first) in starts.iter().enumerate() {
+            let last = *starts.get(n + 1).unwrap_or(&items.len());
+            let text_end = items.get(last).map(|i| i.start).unwrap_or(doc.len());
+            let text = &doc[items[*first].start..text_end];
+
+            let mut ntor_onion_key: Option<[u8; 32]> = None;
+            let mut md = Microdesc {
+                digest: crypto::sha256(text.as_bytes()),
+                ntor_onion_key: [0x0; 32],
+                ed25519_identity: None,
+                family: vec![],
+                ipv4_policy: PortPolicy::reject_all(),
+                ipv6_policy: PortPolicy::reject_all(),
+            };
+            for item in &items[*first..last] {
+                match item.keyword {
+                    "ntor-onion-key" => {
+                        ntor_onion_key = match encoding::base64_decode(item.arg(0)?) {
+                            Some(k) if k.len() == 32 => Some(k.as_slice().try_into().unwrap()),
+                            _ => return Err(invalid("Invalid ntor-onion-key")),
+                        }
+                    }
+                    "id" if item.arg(0)? == "ed25519" => {
+                        md.ed25519_identity = match encoding::base64_decode(item.arg(1)?) {
+                            Some(k) if k.len() == 32 => Some(k.as_slice().try_into().unwrap()),
+                            _ => return Err(invalid("Invalid ed25519 identity")),
+                        }
+                    }
+                    "family" => md.family = item.args.iter().map(|s| s.to_string()).collect(),
+                    "p" => md.ipv4_policy = PortPolicy::parse(&item.args)?,
+                    "p6" => md.ipv6_policy = PortPolicy::parse(&item.args)?,
+                    _ => (),
+                }
+            }
+            md.ntor_onion_key = match ntor_onion_key {
+                Some(k) => k,
+                None => return Err(invalid("Microdescriptor lacks ntor-onion-key")),
+            };
+            mds.push(md);
+        }
+        return Ok(mds);
+    }
+
+    /// Whether this microdescriptor lists the relay with the given RSA identity as a family member.
+    pub fn lists_in_family(&self, identity: &[u8; 20]) -> bool {
+        let hex_id = encoding::hex_encode(identity);
+        return self.family.iter().any(|member| {
+            member.starts_with('$')
+                && member[1..]
+                    .split(|c| c == '=' || c == '~')
+                    .next()
+                    .unwrap()
+                    .eq_ignore_ascii_case(&hex_id)
+        });
+    }
+}
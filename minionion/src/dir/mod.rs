@@ -0,0 +1,138 @@
+// Directory documents: the consensus and the microdescriptors it references.
+
+mod consensus;
+mod exit_policy;
+mod microdesc;
+pub(crate) mod netdoc;
+mod server_descriptor;
+
+pub use consensus::{Consensus, RelayFlags, RouterStatus};
+pub use exit_policy::{AddrPattern, ExitPolicy, PolicyResult, PolicyRule, PortPolicy};
+pub use microdesc::Microdesc;
+pub use server_descriptor::ServerDescriptor;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// A consensus together with the microdescriptors of its relays.
+#[derive(Debug, Clone)]
+pub struct NetDir {
+    pub consensus: Consensus,
+    microdescs: HashMap<[u8; 32], Microdesc>,
+}
+
+/// A relay listed in the consensus for which we have a microdescriptor.
+#[derive(Debug, Clone, Copy)]
+pub struct Relay<'a> {
+    pub rs: &'a RouterStatus,
+    pub md: &'a Microdesc,
+}
+
+impl NetDir {
+    pub fn new(consensus: Consensus) -> NetDir {
+        return NetDir {
+            consensus,
+            microdescs: HashMap::new(),
+        };
+    }
+
+    /// Adds microdescriptors. Ones not referenced by the consensus are dropped.
+    pub fn add_microdescs(&mut self, mds: Vec<Microdesc>) {
+        for md in mds {
+            if self
+                .consensus
+                .relays
+                .iter()
+                .any(|rs| rs.md_digest == md.digest)
+            {
+                self.microdescs.insert(md.digest, md);
+            }
+        }
+    }
+
+    /// Takes over the microdescriptors of an older directory that the consensus still references.
+    pub fn reuse_microdescs(&mut self, old: &NetDir) {
+        for rs in &self.consensus.relays {
+            if let Some(md) = old.microdescs.get(&rs.md_digest) {
+                self.microdescs.insert(md.digest, md.clone());
+            }
+        }
+    }
+
+    /// Digests of microdescriptors referenced by the consensus that we don't have yet.
+    pub fn missing_microdescs(&self) -> Vec<[u8; 32]> {
+        return self
+            .consensus
+            .relays
+            .iter()
+            .map(|rs| rs.md_digest)
+            .filter(|d| !self.microdescs.contains_key(d))
+            .collect();
+    }
+
+    /// All relays usable for building circuits.
+    pub fn relays(&self) -> Vec<Relay<'_>> {
+        return self
+            .consensus
+            .relays
+            .iter()
+            .filter_map(|rs| {
+                self.microdescs
+                    .get(&rs.md_digest)
+                    .map(|md| Relay { rs, md })
+            })
+            .collect();
+    }
+
+    pub fn relay_by_identity(&self, identity: &[u8; 20]) -> Option<Relay<'_>> {
+        return self
+            .relays()
+            .into_iter()
+            .find(|r| &r.rs.identity == identity);
+    }
+}
+
+impl<'a> Relay<'a> {
+    pub fn identity(&self) -> &'a [u8; 20] {
+        return &self.rs.identity;
+    }
+
+    pub fn has_flags(&self, flags: RelayFlags) -> bool {
+        return self.rs.flags.contains(flags);
+    }
+
+    /// The relay's IPv4 ORPort address, which every relay has.
+    pub fn ipv4_addr(&self) -> SocketAddr {
+        return self.rs.or_addrs[0];
+    }
+
+    /// Whether two relays are in the same family. Both must list each other.
+    pub fn in_same_family(&self, other: &Relay) -> bool {
+        return self.md.lists_in_family(other.identity())
+            && other.md.lists_in_family(self.identity());
+    }
+
+    /// Whether two relays share a /16 IPv4 or /32 IPv6 network.
+    pub fn in_same_subnet(&self, other: &Relay) -> bool {
+        for a in &self.rs.or_addrs {
+            for b in &other.rs.or_addrs {
+                let same = match (a.ip(), b.ip()) {
+                    (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..2] == b.octets()[..2],
+                    (IpAddr::V6(a), IpAddr::V6(b)) => a.octets()[..4] == b.octets()[..4],
+                    _ => false,
+                };
+                if same {
+                    return true;
+                }
+            }
+        }
+        return false;
+    }
+
+    /// Whether tor's path restrictions forbid using both relays in the same circuit.
+    pub fn conflicts_with(&self, other: &Relay) -> bool {
+        return self.identity() == other.identity()
+            || self.in_same_family(other)
+            || self.in_same_subnet(other);
+    }
+}
@@ -0,0 +1,215 @@
+// Tokenizer for the generic directory document meta-format (dir-spec section 1.2).
+// The individual document parsers are built on top of this.
+
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::encoding;
+
+/// A keyword line, its arguments and the optional object following it.
+#[derive(Debug, Clone)]
+pub(crate) struct Item<'a> {
+    pub(crate) keyword: &'a str,
+    pub(crate) args: Vec<&'a str>,
+    pub(crate) object: Option<Object>,
+    /// Byte offset of the start of the keyword line in the document
+    pub(crate) start: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Object {
+    pub(crate) tag: String,
+    pub(crate) data: Vec<u8>,
+}
+
+impl<'a> Item<'a> {
+    /// Returns the n-th argument or an error naming the keyword.
+    pub(crate) fn arg(&self, n: usize) -> Result<&'a str, Error> {
+        return match self.args.get(n) {
+            Some(a) => Ok(a),
+            None => Err(invalid(&format!(
+                "Missing argument {} for \"{}\"",
+                n + 1,
+                self.keyword
+            ))),
+        };
+    }
+
+    /// Returns the object data, checking that it carries the expected tag.
+    pub(crate) fn object(&self, tag: &str) -> Result<&[u8], Error> {
+        return match &self.object {
+            Some(o) if o.tag == tag => Ok(&o.data),
+            Some(o) => Err(invalid(&format!(
+                "Expected {} object after \"{}\", got {}",
+                tag, self.keyword, o.tag
+            ))),
+            None => Err(invalid(&format!(
+                "Expected {} object after \"{}\"",
+                tag, self.keyword
+            ))),
+        };
+    }
+}
+
+pub(crate) fn invalid(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg.to_string());
+}
+
+/// Splits a document into items.
+pub(crate) fn parse_items(doc: &str) -> Result<Vec<Item<'_>>, Error> {
+    let mut items: Vec<Item> = vec![];
+    let mut pos: usize = 0;
+    // Tag and base64 body of the object currently being read
+    let mut object: Option<(String, String)> = None;
+
+    while pos < doc.len() {
+        let line_end = match doc[pos..].find('\n') {
+            Some(i) => pos + i + 1,
+            None => doc.len(),
+        };
+        let line = doc[pos..line_end].trim_end_matches(|c| c == '\n' || c == '\r');
+
+        if let Some((tag, mut body)) = object.take() {
+            if line.starts_with("-----END ") {
+                let end_tag = line
+                    .trim_start_matches("-----END ")
+                    .trim_end_matches("-----");
+                if end_tag != tag {
+                    return Err(invalid(&format!(
+                        "Object END tag {} does not match BEGIN tag {}",
+                        end_tag, tag
+                    )));
+                }
+                let data = match encoding::base64_decode(&body) {
+                    Some(d) => d,
+                    None => return Err(invalid(&format!("Invalid base64 in {} object", tag))),
+                };
+                let item = items.last_mut().unwrap();
+                item.object = Some(Object { tag, data });
+            } else {
+                body.push_str(line);
+                object = Some((tag, body));
+            }
+        } else if line.starts_with("-----BEGIN ") {
+            if items.is_empty() || items.last().unwrap().object.is_some() {
+                return Err(invalid("Object without a preceding keyword line"));
+            }
+            let tag = line
+                .trim_start_matches("-----BEGIN ")
+                .trim_end_matches("-----")
+                .to_string();
+            object = Some((tag, String::new()));
+        } else if !line.trim().is_empty() {
+            let mut parts = line
+                .split(|c| c == ' ' || c == '\t')
+                .filter(|s| !s.is_empty());
+            let keyword = parts.next().unwrap();
+            items.push(Item {
+                keyword,
+                args: parts.collect(),
+                object: None,
+                start: pos,
+            });
+        }
+        pos = line_end;
+    }
+
+    if object.is_some() {
+        return Err(invalid("Unterminated object at end of document"));
+    }
+    return Ok(items);
+}
+
+/// Formats an object with its BEGIN and END lines, the base64 wrapped at 64 columns.
+pub(crate) fn format_object(tag: &str, data: &[u8]) -> String {
+    let b64 = encoding::base64_encode(data);
+    let mut out = format!("-----BEGIN {}-----\n", tag);
+    for line in b64.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", tag));
+    return out;
+}
+
+/// Parses a "YYYY-MM-DD HH:MM:SS" timestamp split across two arguments.
+pub(crate) fn parse_time(date: &str, time: &str) -> Result<SystemTime, Error> {
+    let err = || invalid(&format!("Invalid timestamp: {} {}", date, time));
+    let d: Vec<i64> = date
+        .split('-')
+        .map(|s| s.parse::<i64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| err())?;
+    let t: Vec<u64> = time
+        .split(':')
+        .map(|s| s.parse::<u64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| err())?;
+    if d.len() != 3 || t.len() != 3 || t[0] > 23 || t[1] > 59 || t[2] > 60 {
+        return Err(err());
+    }
+    if d[1] < 1 || d[1] > 12 || d[2] < 1 || d[2] > 31 {
+        return Err(err());
+    }
+    let days = days_from_civil(d[0], d[1], d[2]);
+    if days < 0 {
+        return Err(err());
+    }
+    let secs = days as u64 * 86400 + t[0] * 3600 + t[1] * 60 + t[2];
+    return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+}
+
+/// Formats a timestamp as "YYYY-MM-DD HH:MM:SS".
+pub(crate) fn format_time(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (y, m, d) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        y,
+        m,
+        d,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    );
+}
+
+// Howard Hinnant's algorithms for converting between days since the epoch and dates
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    return era * 146097 + doe - 719468;
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400;
+    return (if m <= 2 { y + 1 } else { y }, m, d);
+}
+
+/// Parses space-separated "key=value" integer arguments, as found in "params" and "bandwidth-weights".
+pub(crate) fn parse_int_params(args: &[&str]) -> Result<Vec<(String, i64)>, Error> {
+    let mut out: Vec<(String, i64)> = vec![];
+    for arg in args {
+        let mut kv = arg.splitn(2, '=');
+        let key = kv.next().unwrap();
+        let value = match kv.next().map(|v| v.parse::<i64>()) {
+            Some(Ok(v)) => v,
+            _ => return Err(invalid(&format!("Invalid parameter: {}", arg))),
+        };
+        out.push((key.to_string(), value));
+    }
+    return Ok(out);
+}
@@ -0,0 +1,291 @@
+use std::convert::TryInto;
+use std::io::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::SystemTime;
+
+use super::exit_policy::{ExitPolicy, PolicyRule, PortPolicy};
+use super::netdoc::{self, invalid, Item};
+use crate::cell::TorCustomFormatCert;
+use crate::crypto;
+use crate::encoding;
+
+// Certificate type of the identity-ed25519 certificate: signing key certified by the identity key
+const CERT_TYPE_SIGNING_KEY: u8 = 4;
+// Prefix of the data the router-sig-ed25519 signature covers
+const ED25519_SIG_PREFIX: &[u8] = b"Tor router descriptor signature v1";
+
+/// A router descriptor (dir-spec 2.1), as published by relays and bridges.
+#[derive(Debug, Clone)]
+pub struct ServerDescriptor {
+    pub nickname: String,
+    pub address: Ipv4Addr,
+    pub or_port: u16,
+    pub dir_port: u16,
+    /// Additional ORPort addresses from "or-address" lines
+    pub or_addresses: Vec<SocketAddr>,
+    pub ed25519_identity: [u8; 32],
+    pub ed25519_signing_key: [u8; 32],
+    pub platform: Option<String>,
+    pub protocols: String,
+    pub bandwidth_avg: u64,
+    pub bandwidth_burst: u64,
+    pub bandwidth_observed: u64,
+    pub published: SystemTime,
+    /// SHA1 digest of the RSA identity key
+    pub fingerprint: [u8; 20],
+    /// DER-encoded RSA identity key
+    pub signing_key: Vec<u8>,
+    pub ntor_onion_key: [u8; 32],
+    pub family: Vec<String>,
+    pub exit_policy: ExitPolicy,
+    pub ipv6_policy: PortPolicy,
+}
+
+impl ServerDescriptor {
+    /// Parses a single descriptor and verifies both its RSA and Ed25519 signatures,
+    /// as well as the identity-ed25519 certificate.
+    pub fn parse(doc: &str) -> Result<ServerDescriptor, Error> {
+        return ServerDescriptor::parse_at(doc, SystemTime::now());
+    }
+
+    /// Like `parse`, checking the certificate's expiry at `now`.
+    pub(crate) fn parse_at(doc: &str, now: SystemTime) -> Result<ServerDescriptor, Error> {
+        let items = netdoc::parse_items(doc)?;
+        let router = match items.first() {
+            Some(i) if i.keyword == "router" => i,
+            _ => return Err(invalid("Server descriptor must start with \"router\"")),
+        };
+        let rsa_sig_item = match items.last() {
+            Some(i) if i.keyword == "router-signature" => i,
+            _ => {
+                return Err(invalid(
+                    "Server descriptor must end with \"router-signature\"",
+                ))
+            }
+        };
+
+        let cert_item = ServerDescriptor::unique(&items, "identity-ed25519")?;
+        if items.iter().position(|i| i.keyword == "identity-ed25519") != Some(1) {
+            return Err(invalid("identity-ed25519 must directly follow \"router\""));
+        }
+        let cert = TorCustomFormatCert::from_bytes(cert_item.object("ED25519 CERT")?)?;
+        if cert.cert_type() != CERT_TYPE_SIGNING_KEY {
+            return Err(invalid("identity-ed25519 has the wrong certificate type"));
+        }
+        let ed25519_identity = match cert.signing_key() {
+            Some(k) => *k,
+            None => return Err(invalid("identity-ed25519 lacks the signing key extension")),
+        };
+        if !cert.verify(&ed25519_identity) {
+            return Err(invalid(
+                "identity-ed25519 certificate has an invalid signature",
+            ));
+        }
+        if cert.is_expired(now) {
+            return Err(invalid("identity-ed25519 certificate is expired"));
+        }
+        let ed25519_signing_key = *cert.certified_key();
+
+        let master_key = ServerDescriptor::unique(&items, "master-key-ed25519")?;
+        if decode_key(master_key.arg(0)?)? != ed25519_identity {
+            return Err(invalid(
+                "master-key-ed25519 does not match identity-ed25519",
+            ));
+        }
+
+        // Ed25519 signature over everything up to and including "router-sig-ed25519 "
+        let ed_sig_item = ServerDescriptor::unique(&items, "router-sig-ed25519")?;
+        let ed_signed_end = ed_sig_item.start + "router-sig-ed25519 ".len();
+        let mut ed_signed: Vec<u8> = ED25519_SIG_PREFIX.to_vec();
+        ed_signed.extend_from_slice(doc[router.start..ed_signed_end].as_bytes());
+        let ed_sig = match encoding::base64_decode(ed_sig_item.arg(0)?) {
+            Some(s) if s.len() == 64 => s,
+            _ => return Err(invalid("Invalid router-sig-ed25519")),
+        };
+        if !crypto::ed25519_verify(&ed25519_signing_key, &crypto::sha256(&ed_signed), &ed_sig) {
+            return Err(invalid("Invalid router-sig-ed25519 signature"));
+        }
+
+        // RSA signature over everything up to and including "router-signature\n"
+        let signing_key = ServerDescriptor::unique(&items, "signing-key")?
+            .object("RSA PUBLIC KEY")?
+            .to_vec();
+        let rsa_signed_end = rsa_sig_item.start + "router-signature\n".len();
+        let digest = crypto::sha1(doc[router.start..rsa_signed_end].as_bytes());
+        if !crypto::rsa_verify_digest(&signing_key, &digest, rsa_sig_item.object("SIGNATURE")?) {
+            return Err(invalid("Invalid router-signature"));
+        }
+        let fingerprint = crypto::sha1(&signing_key);
+
+        let bandwidth = ServerDescriptor::unique(&items, "bandwidth")?;
+        let bw = |n: usize| -> Result<u64, Error> {
+            return bandwidth
+                .arg(n)?
+                .parse::<u64>()
+                .map_err(|_| invalid("Invalid bandwidth"));
+        };
+        let published = ServerDescriptor::unique(&items, "published")?;
+
+        let mut desc = ServerDescriptor {
+            nickname: router.arg(0)?.to_string(),
+            address: router
+                .arg(1)?
+                .parse()
+                .map_err(|_| invalid("Invalid router address"))?,
+            or_port: parse_port(router.arg(2)?)?,
+            dir_port: parse_port(router.arg(4)?)?,
+            or_addresses: vec![],
+            ed25519_identity,
+            ed25519_signing_key,
+            platform: None,
+            protocols: String::new(),
+            bandwidth_avg: bw(0)?,
+            bandwidth_burst: bw(1)?,
+            bandwidth_observed: bw(2)?,
+            published: netdoc::parse_time(published.arg(0)?, published.arg(1)?)?,
+            fingerprint,
+            signing_key,
+            ntor_onion_key: decode_key(
+                ServerDescriptor::unique(&items, "ntor-onion-key")?.arg(0)?,
+            )?,
+            family: vec![],
+            exit_policy: ExitPolicy::default(),
+            ipv6_policy: PortPolicy::reject_all(),
+        };
+
+        for item in &items {
+            let line = doc[item.start..].lines().next().unwrap();
+            match item.keyword {
+                "or-address" => match item.arg(0)?.parse::<SocketAddr>() {
+                    Ok(a) => desc.or_addresses.push(a),
+                    Err(_) => return Err(invalid("Invalid or-address")),
+                },
+                "platform" => desc.platform = Some(item.args.join(" ")),
+                "proto" => desc.protocols = item.args.join(" "),
+                "fingerprint" => {
+                    let hex: String = item.args.concat();
+                    if encoding::hex_decode(&hex).as_deref() != Some(&fingerprint[..]) {
+                        return Err(invalid("Fingerprint does not match signing-key"));
+                    }
+                }
+                "family" => desc.family = item.args.iter().map(|s| s.to_string()).collect(),
+                "accept" | "reject" => desc.exit_policy.rules.push(PolicyRule::parse(line)?),
+                "ipv6-policy" => desc.ipv6_policy = PortPolicy::parse(&item.args)?,
+                _ => (),
+            }
+        }
+        return Ok(desc);
+    }
+
+    /// Returns the item with the given keyword, which must occur exactly once.
+    fn unique<'a, 'b>(items: &'b [Item<'a>], keyword: &str) -> Result<&'b Item<'a>, Error> {
+        let mut found = items.iter().filter(|i| i.keyword == keyword);
+        return match (found.next(), found.next()) {
+            (Some(i), None) => Ok(i),
+            (None, _) => Err(invalid(&format!("Server descriptor lacks \"{}\"", keyword))),
+            (Some(_), Some(_)) => Err(invalid(&format!(
+                "Server descriptor contains \"{}\" more than once",
+                keyword
+            ))),
+        };
+    }
+}
+
+fn decode_key(b64: &str) -> Result<[u8; 32], Error> {
+    return match encoding::base64_decode(b64) {
+        Some(k) if k.len() == 32 => Ok(k.as_slice().try_into().unwrap()),
+        _ => Err(invalid(&format!("Invalid key: {}", b64))),
+    };
+}
+
+fn parse_port(s: &str) -> Result<u16, Error> {
+    return s.parse::<u16>().map_err(|_| invalid("Invalid port"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dir::netdoc::parse_time;
+
+    const DESC: &str = include_str!("../../testdata/routerdesc1.txt");
+
+    fn published() -> SystemTime {
+        return parse_time("2022-11-14", "19:58:52").unwrap();
+    }
+
+    #[test]
+    fn parse() {
+        let desc = ServerDescriptor::parse_at(DESC, published()).unwrap();
+        assert_eq!(desc.nickname, "Akka");
+        assert_eq!(desc.address, Ipv4Addr::new(95, 216, 33, 58));
+        assert_eq!(desc.or_port, 443);
+        assert_eq!(desc.dir_port, 0);
+        assert_eq!(
+            desc.or_addresses,
+            vec!["[2a01:4f9:2a:2145::2]:443".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            encoding::base64_encode(&desc.ed25519_identity),
+            "CVTjf1oeaL616hH+1+UvYZ8OgkwF3z7UMITvJzm5r7A="
+        );
+        assert_eq!(
+            desc.platform.as_deref(),
+            Some("Tor 0.4.8.0-alpha-dev on Linux")
+        );
+        assert!(desc.protocols.starts_with("Cons=1-2 Desc=1-2 "));
+        assert_eq!(
+            (
+                desc.bandwidth_avg,
+                desc.bandwidth_burst,
+                desc.bandwidth_observed
+            ),
+            (1073741824, 1073741824, 61224922)
+        );
+        assert_eq!(desc.published, published());
+        assert_eq!(
+            desc.fingerprint.to_vec(),
+            encoding::hex_decode("56927E61B51E6F363FB55498150A6DDFCF7077F2").unwrap()
+        );
+        assert_eq!(
+            encoding::base64_encode(&desc.ntor_onion_key),
+            "Mps7UpkWEzkuNdGoId1nU+EhBFjswzN/e305v89donM="
+        );
+        assert_eq!(
+            desc.family,
+            vec![
+                "$303509AB910EF207B7438C27435C4A2FD579F1B1",
+                "$56927E61B51E6F363FB55498150A6DDFCF7077F2"
+            ]
+        );
+        assert_eq!(desc.exit_policy, ExitPolicy::reject_all());
+        assert_eq!(desc.ipv6_policy, PortPolicy::reject_all());
+
+        // The identity certificate has since expired
+        assert!(ServerDescriptor::parse(DESC).is_err());
+    }
+
+    /// Flips a bit of the byte at `offset` past the first occurrence of `marker`.
+    fn altered(marker: &str, offset: usize) -> String {
+        let mut bytes = DESC.as_bytes().to_vec();
+        bytes[DESC.find(marker).unwrap() + offset] ^= 0x01;
+        return String::from_utf8(bytes).unwrap();
+    }
+
+    #[test]
+    fn altered_descriptors() {
+        // Signed body, router-sig-ed25519 and router-signature
+        let body = altered("bandwidth 1073741824", "bandwidth 10".len());
+        let ed_sig = altered("router-sig-ed25519 Ga9G", "router-sig-ed25519 ".len());
+        let rsa_sig = altered("mIqhMooA", 2);
+        let cases = [
+            (body, "Invalid router-sig-ed25519 signature"),
+            (ed_sig, "Invalid router-sig-ed25519 signature"),
+            (rsa_sig, "Invalid router-signature"),
+        ];
+        for (doc, msg) in &cases {
+            assert_ne!(doc.as_str(), DESC);
+            let err = ServerDescriptor::parse_at(doc, published()).unwrap_err();
+            assert_eq!(err.to_string(), *msg);
+        }
+    }
+}
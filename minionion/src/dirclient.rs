@@ -0,0 +1,85 @@
+// Fetching directory documents over BEGIN_DIR streams (dir-spec 6).
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::time::Duration;
+
+use crate::cell::RelayCommand;
+use crate::circuit::Circuit;
+use crate::dir::{Consensus, Microdesc, NetDir};
+use crate::encoding;
+use crate::stream::TorStream;
+
+const CONSENSUS_PATH: &str = "/tor/status-vote/current/consensus-microdesc";
+const MICRODESC_PATH: &str = "/tor/micro/d/";
+// Number of microdescriptors requested at once, as in tor
+const MICRODESC_BATCH: usize = 92;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Performs an HTTP GET request for the path on the directory cache at the end of the circuit.
+pub(crate) fn get(circ: &Circuit, path: &str) -> Result<Vec<u8>, Error> {
+    return request(circ, "GET", path, &[]);
+}
+
+/// Performs an HTTP POST request, as used to publish onion service descriptors to HSDirs.
+pub(crate) fn post(circ: &Circuit, path: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+    return request(circ, "POST", path, body);
+}
+
+fn request(circ: &Circuit, method: &str, path: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let stream_id = circ.begin_stream(RelayCommand::BeginDir, vec![])?;
+    circ.wait_reply(stream_id, CONNECT_TIMEOUT)?;
+    let mut stream = TorStream::new(circ.clone(), stream_id);
+    let mut req = format!("{} {} HTTP/1.0\r\n", method, path).into_bytes();
+    if !body.is_empty() {
+        req.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    req.extend_from_slice(b"\r\n");
+    req.extend_from_slice(body);
+    stream.write_all(&req)?;
+    let mut response: Vec<u8> = vec![];
+    stream.read_to_end(&mut response)?;
+
+    let header_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(p) => p,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Truncated HTTP response",
+            ))
+        }
+    };
+    let headers = String::from_utf8_lossy(&response[..header_end]).to_string();
+    let status_line = headers.lines().next().unwrap_or("");
+    if status_line.split(' ').nth(1) != Some("200") {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("Directory request for {} failed: {}", path, status_line),
+        ));
+    }
+    return Ok(response[header_end + 4..].to_vec());
+}
+
+/// Downloads the microdesc consensus and the microdescriptors it references that the
+/// previous directory, if any, lacks.
+pub(crate) fn fetch_netdir(circ: &Circuit, previous: Option<&NetDir>) -> Result<NetDir, Error> {
+    let consensus = Consensus::parse(&to_string(get(circ, CONSENSUS_PATH)?)?)?;
+    let mut netdir = NetDir::new(consensus);
+    if let Some(old) = previous {
+        netdir.reuse_microdescs(old);
+    }
+    let missing = netdir.missing_microdescs();
+    for batch in missing.chunks(MICRODESC_BATCH) {
+        let digests: Vec<String> = batch
+            .iter()
+            .map(|d| encoding::base64_encode(d).trim_end_matches('=').to_string())
+            .collect();
+        let path = format!("{}{}", MICRODESC_PATH, digests.join("-"));
+        netdir.add_microdescs(Microdesc::parse_all(&to_string(get(circ, &path)?)?)?);
+    }
+    return Ok(netdir);
+}
+
+fn to_string(body: Vec<u8>) -> Result<String, Error> {
+    return String::from_utf8(body)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Directory document is not UTF-8"));
+}
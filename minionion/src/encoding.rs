@@ -0,0 +1,131 @@
+// Minimal encoders/decoders for the text encodings used in directory documents.
+// Implemented here to avoid pulling in additional crates.
+
+fn base64_value(c: u8) -> Option<u8> {
+    return match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+}
+
+/// Decodes base64, ignoring whitespace. Padding is optional, as tor often omits it.
+pub(crate) fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let mut seen_padding = false;
+    for c in input.bytes() {
+        if c.is_ascii_whitespace() {
+            continue;
+        }
+        if c == b'=' {
+            seen_padding = true;
+            continue;
+        }
+        if seen_padding {
+            return None;
+        }
+        acc = (acc << 6) | base64_value(c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    // Leftover bits must be zero, otherwise the encoding is not canonical
+    if acc != 0 {
+        return None;
+    }
+    return Some(out);
+}
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes base64 with padding.
+pub(crate) fn base64_encode(input: &[u8]) -> String {
+    let mut out = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let mut group: u32 = 0;
+        for (i, b) in chunk.iter().enumerate() {
+            group |= (*b as u32) << (16 - 8 * i);
+        }
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[((group >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    return out;
+}
+
+const BASE32_CHARS: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Decodes unpadded base32 as used in onion addresses, in either case.
+pub(crate) fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::with_capacity(input.len() * 5 / 8);
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    for c in input.bytes() {
+        let value = BASE32_CHARS
+            .iter()
+            .position(|b| *b == c.to_ascii_lowercase())?;
+        acc = (acc << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    // Leftover bits must be zero, otherwise the encoding is not canonical
+    if acc != 0 {
+        return None;
+    }
+    return Some(out);
+}
+
+/// Encodes lowercase base32 without padding.
+pub(crate) fn base32_encode(input: &[u8]) -> String {
+    let mut out = String::with_capacity((input.len() * 8 + 4) / 5);
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    for b in input {
+        acc = (acc << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_CHARS[((acc >> bits) & 0x1f) as usize] as char);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(BASE32_CHARS[((acc << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    return out;
+}
+
+pub(crate) fn hex_encode(input: &[u8]) -> String {
+    let mut out = String::with_capacity(input.len() * 2);
+    for b in input {
+        out.push_str(&format!("{:02X}", b));
+    }
+    return out;
+}
+
+pub(crate) fn hex_decode(input: &str) -> Option<Vec<u8>> {
+    if input.len() % 2 != 0 || !input.is_ascii() {
+        return None;
+    }
+    let mut out: Vec<u8> = Vec::with_capacity(input.len() / 2);
+    for i in (0..input.len()).step_by(2) {
+        out.push(u8::from_str_radix(&input[i..i + 2], 16).ok()?);
+    }
+    return Some(out);
+}
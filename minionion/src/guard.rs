@@ -0,0 +1,596 @@
+// Guard selection as described in guard-spec (proposal 271).
+//
+// Guards are sampled once and kept across restarts via the state file, so that a
+// client keeps using the same few entry relays instead of exposing itself to many.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime};
+
+use crate::crypto;
+use crate::dir::{NetDir, Relay, RelayFlags};
+use crate::encoding;
+use crate::path::PathSelector;
+use crate::state::{self, StateFile};
+
+const DAY: u64 = 24 * 60 * 60;
+const HOUR: u64 = 60 * 60;
+
+// Parameters from guard-spec section 3.1, using tor's defaults
+const MAX_SAMPLE_THRESHOLD_PERCENT: usize = 20;
+const MAX_SAMPLE_SIZE: usize = 60;
+const MIN_FILTERED_SAMPLE: usize = 20;
+const N_PRIMARY_GUARDS: usize = 3;
+const GUARD_LIFETIME: Duration = Duration::from_secs(120 * DAY);
+const GUARD_CONFIRMED_MIN_LIFETIME: Duration = Duration::from_secs(60 * DAY);
+const REMOVE_UNLISTED_GUARDS_AFTER: Duration = Duration::from_secs(20 * DAY);
+const NONPRIMARY_GUARD_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const INTERNET_LIKELY_DOWN_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Key under which guards are stored in the state file
+const STATE_KEY: &str = "Guard";
+
+/// Whether we believe we can connect to a guard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reachability {
+    Reachable,
+    Unreachable,
+    Maybe,
+}
+
+#[derive(Debug, Clone)]
+struct Guard {
+    identity: [u8; 20],
+    nickname: String,
+    sampled_on: SystemTime,
+    listed: bool,
+    unlisted_since: Option<SystemTime>,
+    confirmed_on: Option<SystemTime>,
+    confirmed_idx: Option<u32>,
+    // The fields below are not persisted
+    filtered: bool,
+    reachable: Reachability,
+    failing_since: Option<SystemTime>,
+    last_tried: Option<SystemTime>,
+    pending_since: Option<SystemTime>,
+}
+
+impl Guard {
+    fn to_state_line(&self) -> String {
+        let mut line = format!(
+            "in=default rsa_id={} nickname={} sampled_on={} sampled_by=minitor listed={}",
+            encoding::hex_encode(&self.identity),
+            self.nickname,
+            state::format_state_time(self.sampled_on),
+            if self.listed { 1 } else { 0 }
+        );
+        if let Some(t) = self.unlisted_since {
+            line.push_str(&format!(" unlisted_since={}", state::format_state_time(t)));
+        }
+        if let (Some(t), Some(idx)) = (self.confirmed_on, self.confirmed_idx) {
+            line.push_str(&format!(
+                " confirmed_on={} confirmed_idx={}",
+                state::format_state_time(t),
+                idx
+            ));
+        }
+        return line;
+    }
+
+    fn from_state_line(line: &str) -> Result<Guard, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid guard entry: {}", line),
+            )
+        };
+        let mut identity: Option<[u8; 20]> = None;
+        let mut guard = Guard {
+            identity: [0x0; 20],
+            nickname: String::new(),
+            sampled_on: SystemTime::now(),
+            listed: true,
+            unlisted_since: None,
+            confirmed_on: None,
+            confirmed_idx: None,
+            filtered: false,
+            reachable: Reachability::Maybe,
+            failing_since: None,
+            last_tried: None,
+            pending_since: None,
+        };
+        for field in line.split_whitespace() {
+            let mut kv = field.splitn(2, '=');
+            let key = kv.next().unwrap();
+            let value = kv.next().ok_or_else(invalid)?;
+            match key {
+                "in" if value != "default" => return Err(invalid()),
+                "rsa_id" => {
+                    identity = match encoding::hex_decode(value) {
+                        Some(id) if id.len() == 20 => Some(id.as_slice().try_into().unwrap()),
+                        _ => return Err(invalid()),
+                    }
+                }
+                "nickname" => guard.nickname = value.to_string(),
+                "sampled_on" => {
+                    guard.sampled_on = state::parse_state_time(value).ok_or_else(invalid)?
+                }
+                "listed" => guard.listed = value == "1",
+                "unlisted_since" => guard.unlisted_since = state::parse_state_time(value),
+                "confirmed_on" => guard.confirmed_on = state::parse_state_time(value),
+                "confirmed_idx" => guard.confirmed_idx = value.parse::<u32>().ok(),
+                // Unknown fields must be ignored
+                _ => (),
+            }
+        }
+        guard.identity = identity.ok_or_else(invalid)?;
+        if guard.confirmed_on.is_none() || guard.confirmed_idx.is_none() {
+            guard.confirmed_on = None;
+            guard.confirmed_idx = None;
+        }
+        return Ok(guard);
+    }
+
+    fn is_usable(&self) -> bool {
+        return self.filtered && self.listed && self.reachable != Reachability::Unreachable;
+    }
+
+    fn is_pending(&self, now: SystemTime) -> bool {
+        return match self.pending_since {
+            Some(t) => elapsed(t, now) < NONPRIMARY_GUARD_CONNECT_TIMEOUT,
+            None => false,
+        };
+    }
+
+    /// Time at which an unreachable guard should be retried (guard-spec 4.7).
+    fn next_retry(&self, is_primary: bool) -> Option<SystemTime> {
+        if self.reachable != Reachability::Unreachable {
+            return None;
+        }
+        let (failing_since, last_tried) = match (self.failing_since, self.last_tried) {
+            (Some(f), Some(l)) => (f, l),
+            _ => return None,
+        };
+        let failing_for = elapsed(failing_since, last_tried).as_secs();
+        let delay = match (is_primary, failing_for) {
+            (true, t) if t < 6 * HOUR => 10 * 60,
+            (true, t) if t < 96 * HOUR => 90 * 60,
+            (true, t) if t < 7 * DAY => 4 * HOUR,
+            (true, _) => 9 * HOUR,
+            (false, t) if t < 6 * HOUR => HOUR,
+            (false, t) if t < 96 * HOUR => 4 * HOUR,
+            (false, t) if t < 7 * DAY => 18 * HOUR,
+            (false, _) => 36 * HOUR,
+        };
+        return Some(last_tried + Duration::from_secs(delay));
+    }
+}
+
+fn elapsed(since: SystemTime, now: SystemTime) -> Duration {
+    return now.duration_since(since).unwrap_or_default();
+}
+
+/// Timestamps are randomized into the past so they don't reveal when exactly we sampled a guard.
+fn randomized_past(now: SystemTime, max: Duration) -> SystemTime {
+    return now - Duration::from_secs(crypto::random_below(max.as_secs()));
+}
+
+/// Whether a guard chosen by `GuardMgr::select_guard()` may be used right away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuardUsability {
+    /// The guard is primary, circuits through it can be used as soon as they are built.
+    Usable,
+    /// Circuits through this guard should only be used once it turns out no primary guard is reachable.
+    /// `GuardMgr::note_success()` tells whether this is the case.
+    UsableIfNoBetterGuard,
+}
+
+/// A guard picked for building a circuit.
+#[derive(Debug, Clone, Copy)]
+pub struct GuardChoice<'a> {
+    pub relay: Relay<'a>,
+    pub usability: GuardUsability,
+}
+
+/// Snapshot of the guard subsystem, for monitoring.
+#[derive(Debug, Clone)]
+pub struct GuardStatus {
+    pub sampled: usize,
+    pub filtered: usize,
+    pub usable_filtered: usize,
+    pub confirmed: usize,
+    pub primary: Vec<GuardInfo>,
+    /// No guard at all is currently usable, so no circuits can be built
+    pub stuck: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct GuardInfo {
+    pub identity: [u8; 20],
+    pub nickname: String,
+    pub reachable: Reachability,
+    pub confirmed: bool,
+    pub failing_since: Option<SystemTime>,
+    pub next_retry: Option<SystemTime>,
+}
+
+impl fmt::Display for GuardStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "guards: {} sampled, {} filtered, {} usable, {} confirmed{}",
+            self.sampled,
+            self.filtered,
+            self.usable_filtered,
+            self.confirmed,
+            if self.stuck { " (STUCK)" } else { "" }
+        )?;
+        for g in &self.primary {
+            write!(
+                f,
+                "  primary ${} ({}): {:?}",
+                encoding::hex_encode(&g.identity),
+                g.nickname,
+                g.reachable
+            )?;
+            if let Some(t) = g.next_retry {
+                write!(f, ", retry at {}", state::format_state_time(t))?;
+            }
+            writeln!(f)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Keeps track of sampled, confirmed and primary guards.
+#[derive(Debug, Clone, Default)]
+pub struct GuardMgr {
+    // In the order they were sampled
+    sampled: Vec<Guard>,
+    primary: Vec<[u8; 20]>,
+    excluded: HashSet<[u8; 20]>,
+    last_success: Option<SystemTime>,
+}
+
+impl GuardMgr {
+    pub fn new() -> GuardMgr {
+        return GuardMgr::default();
+    }
+
+    /// Restores the sampled guards from the state file.
+    pub fn load(state: &StateFile) -> Result<GuardMgr, Error> {
+        let mut mgr = GuardMgr::new();
+        for line in state.get_all(STATE_KEY) {
+            let guard = Guard::from_state_line(line)?;
+            if !mgr.sampled.iter().any(|g| g.identity == guard.identity) {
+                mgr.sampled.push(guard);
+            }
+        }
+        return Ok(mgr);
+    }
+
+    /// Writes the sampled guards to the state file.
+    pub fn store(&self, state: &mut StateFile) {
+        state.set_all(
+            STATE_KEY,
+            self.sampled.iter().map(|g| g.to_state_line()).collect(),
+        );
+    }
+
+    /// Sets relays which must not be used as guards, e.g. due to configuration.
+    pub fn set_excluded(&mut self, excluded: HashSet<[u8; 20]>) {
+        self.excluded = excluded;
+    }
+
+    /// Updates the guard lists after a new consensus has arrived (guard-spec 4.6 and 4.11).
+    pub fn update(&mut self, netdir: &NetDir, now: SystemTime) {
+        for g in self.sampled.iter_mut() {
+            let relay = netdir.relay_by_identity(&g.identity);
+            g.listed = netdir
+                .consensus
+                .relays
+                .iter()
+                .any(|rs| rs.identity == g.identity);
+            if g.listed {
+                g.unlisted_since = None;
+            } else if g.unlisted_since.is_none() {
+                g.unlisted_since = Some(now);
+            }
+            g.filtered = match relay {
+                Some(r) => r.has_flags(RelayFlags::GUARD) && !self.excluded.contains(&g.identity),
+                None => false,
+            };
+        }
+
+        self.sampled.retain(|g| {
+            if let Some(t) = g.unlisted_since {
+                if elapsed(t, now) >= REMOVE_UNLISTED_GUARDS_AFTER {
+                    return false;
+                }
+            }
+            let expired = elapsed(g.sampled_on, now) >= GUARD_LIFETIME;
+            return match g.confirmed_on {
+                Some(t) => !(expired && elapsed(t, now) >= GUARD_CONFIRMED_MIN_LIFETIME),
+                None => !expired,
+            };
+        });
+
+        self.expand_sample(netdir, now);
+        self.update_primary();
+    }
+
+    fn expand_sample(&mut self, netdir: &NetDir, now: SystemTime) {
+        let n_guards = netdir
+            .relays()
+            .iter()
+            .filter(|r| r.has_flags(RelayFlags::GUARD))
+            .count();
+        let max_sample = (n_guards * MAX_SAMPLE_THRESHOLD_PERCENT / 100)
+            .max(MIN_FILTERED_SAMPLE)
+            .min(MAX_SAMPLE_SIZE);
+        let selector = PathSelector::new(netdir);
+
+        while self.sampled.iter().filter(|g| g.is_usable()).count() < MIN_FILTERED_SAMPLE
+            && self.sampled.len() < max_sample
+        {
+            let mut skip: Vec<[u8; 20]> = self.sampled.iter().map(|g| g.identity).collect();
+            skip.extend(self.excluded.iter());
+            let relay = match selector.sample_guard(&skip) {
+                Ok(r) => r,
+                // Nothing left to sample
+                Err(_) => break,
+            };
+            self.sampled.push(Guard {
+                identity: *relay.identity(),
+                nickname: relay.rs.nickname.clone(),
+                sampled_on: randomized_past(now, GUARD_LIFETIME / 10),
+                listed: true,
+                unlisted_since: None,
+                confirmed_on: None,
+                confirmed_idx: None,
+                filtered: true,
+                reachable: Reachability::Maybe,
+                failing_since: None,
+                last_tried: None,
+                pending_since: None,
+            });
+        }
+    }
+
+    /// Confirmed guards in order of confirmation.
+    fn confirmed(&self) -> Vec<&Guard> {
+        let mut confirmed: Vec<&Guard> = self
+            .sampled
+            .iter()
+            .filter(|g| g.confirmed_idx.is_some())
+            .collect();
+        confirmed.sort_by_key(|g| g.confirmed_idx);
+        return confirmed;
+    }
+
+    /// Recomputes the primary guards: confirmed guards first, then others in sample order (guard-spec 4.6).
+    fn update_primary(&mut self) {
+        let mut primary: Vec<[u8; 20]> = vec![];
+        let candidates = self
+            .confirmed()
+            .into_iter()
+            .chain(self.sampled.iter().filter(|g| g.confirmed_idx.is_none()));
+        for g in candidates {
+            if primary.len() >= N_PRIMARY_GUARDS {
+                break;
+            }
+            if g.filtered && g.listed {
+                primary.push(g.identity);
+            }
+        }
+        // Guards that just became primary get a fresh chance
+        for g in self.sampled.iter_mut() {
+            if primary.contains(&g.identity) && !self.primary.contains(&g.identity) {
+                g.reachable = Reachability::Maybe;
+            }
+        }
+        self.primary = primary;
+    }
+
+    fn guard_mut(&mut self, identity: &[u8; 20]) -> Option<&mut Guard> {
+        return self.sampled.iter_mut().find(|g| &g.identity == identity);
+    }
+
+    /// Marks unreachable guards whose retry time has come as maybe reachable again.
+    fn apply_retry_schedule(&mut self, now: SystemTime) {
+        let primary = self.primary.clone();
+        for g in self.sampled.iter_mut() {
+            if let Some(t) = g.next_retry(primary.contains(&g.identity)) {
+                if t <= now {
+                    g.reachable = Reachability::Maybe;
+                }
+            }
+        }
+    }
+
+    /// Picks a guard for a new circuit (guard-spec 4.9).
+    pub fn select_guard<'a>(
+        &mut self,
+        netdir: &'a NetDir,
+        now: SystemTime,
+    ) -> Result<GuardChoice<'a>, Error> {
+        self.apply_retry_schedule(now);
+
+        // Primary guards are always preferred
+        let primary = self.primary.clone();
+        for id in &primary {
+            let g = self.guard_mut(id).unwrap();
+            if g.reachable != Reachability::Unreachable {
+                g.last_tried = Some(now);
+                return GuardMgr::choice(netdir, id, GuardUsability::Usable);
+            }
+        }
+
+        // Then confirmed guards, in confirmation order
+        let confirmed: Vec<[u8; 20]> = self
+            .confirmed()
+            .iter()
+            .filter(|g| g.is_usable() && !g.is_pending(now) && !primary.contains(&g.identity))
+            .map(|g| g.identity)
+            .collect();
+        if let Some(id) = confirmed.first() {
+            let g = self.guard_mut(id).unwrap();
+            g.pending_since = Some(now);
+            g.last_tried = Some(now);
+            return GuardMgr::choice(netdir, id, GuardUsability::UsableIfNoBetterGuard);
+        }
+
+        // Finally a random one of the remaining usable filtered guards
+        let others: Vec<[u8; 20]> = self
+            .sampled
+            .iter()
+            .filter(|g| g.is_usable() && !g.is_pending(now) && !primary.contains(&g.identity))
+            .map(|g| g.identity)
+            .collect();
+        if others.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "No usable guards, all of them are unreachable or unlisted",
+            ));
+        }
+        let id = others[crypto::random_below(others.len() as u64) as usize];
+        let g = self.guard_mut(&id).unwrap();
+        g.pending_since = Some(now);
+        g.last_tried = Some(now);
+        return GuardMgr::choice(netdir, &id, GuardUsability::UsableIfNoBetterGuard);
+    }
+
+    fn choice<'a>(
+        netdir: &'a NetDir,
+        id: &[u8; 20],
+        usability: GuardUsability,
+    ) -> Result<GuardChoice<'a>, Error> {
+        return match netdir.relay_by_identity(id) {
+            Some(relay) => Ok(GuardChoice { relay, usability }),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                "Selected guard is missing from the directory",
+            )),
+        };
+    }
+
+    /// Records that a circuit through the guard was built successfully.
+    /// Returns whether the circuit may be used now.
+    pub fn note_success(&mut self, identity: &[u8; 20], now: SystemTime) -> bool {
+        let next_idx = self
+            .sampled
+            .iter()
+            .filter_map(|g| g.confirmed_idx)
+            .max()
+            .map(|i| i + 1)
+            .unwrap_or(0);
+
+        // If we haven't had any success in a while, our network was probably down
+        // rather than the primary guards; give them another chance (guard-spec 4.10).
+        let network_was_down = match self.last_success {
+            Some(t) => elapsed(t, now) >= INTERNET_LIKELY_DOWN_INTERVAL,
+            None => false,
+        };
+        self.last_success = Some(now);
+
+        let g = match self.guard_mut(identity) {
+            Some(g) => g,
+            None => return false,
+        };
+        g.reachable = Reachability::Reachable;
+        g.failing_since = None;
+        g.pending_since = None;
+        if g.confirmed_idx.is_none() {
+            g.confirmed_on = Some(randomized_past(now, GUARD_LIFETIME / 10));
+            g.confirmed_idx = Some(next_idx);
+        }
+        self.update_primary();
+
+        if self.primary.contains(identity) {
+            return true;
+        }
+        if network_was_down {
+            for id in self.primary.clone() {
+                self.guard_mut(&id).unwrap().reachable = Reachability::Maybe;
+            }
+            return false;
+        }
+        let primary = self.primary.clone();
+        return primary.iter().all(|id| {
+            self.sampled
+                .iter()
+                .find(|g| &g.identity == id)
+                .map(|g| g.reachable == Reachability::Unreachable)
+                .unwrap_or(true)
+        });
+    }
+
+    /// Whether a circuit through the guard, which `note_success()` held back, may be used now:
+    /// Some(true) once no primary guard is reachable, Some(false) if one turned out to be,
+    /// and None while some primary guard hasn't been tried yet.
+    pub fn may_use(&self, identity: &[u8; 20]) -> Option<bool> {
+        if self.primary.contains(identity) {
+            return Some(true);
+        }
+        let mut undecided = false;
+        for id in &self.primary {
+            match self
+                .sampled
+                .iter()
+                .find(|g| &g.identity == id)
+                .map(|g| g.reachable)
+            {
+                Some(Reachability::Reachable) => return Some(false),
+                Some(Reachability::Maybe) => undecided = true,
+                _ => {}
+            }
+        }
+        if undecided {
+            return None;
+        }
+        return Some(true);
+    }
+
+    /// Records that connecting to the guard or building a circuit through it failed.
+    pub fn note_failure(&mut self, identity: &[u8; 20], now: SystemTime) {
+        if let Some(g) = self.guard_mut(identity) {
+            g.reachable = Reachability::Unreachable;
+            g.pending_since = None;
+            g.last_tried = Some(now);
+            if g.failing_since.is_none() {
+                g.failing_since = Some(now);
+            }
+        }
+    }
+
+    pub fn status(&self) -> GuardStatus {
+        let primary = self
+            .primary
+            .iter()
+            .filter_map(|id| self.sampled.iter().find(|g| &g.identity == id))
+            .map(|g| GuardInfo {
+                identity: g.identity,
+                nickname: g.nickname.clone(),
+                reachable: g.reachable,
+                confirmed: g.confirmed_idx.is_some(),
+                failing_since: g.failing_since,
+                next_retry: g.next_retry(true),
+            })
+            .collect();
+        let usable_filtered = self.sampled.iter().filter(|g| g.is_usable()).count();
+        return GuardStatus {
+            sampled: self.sampled.len(),
+            filtered: self.sampled.iter().filter(|g| g.filtered).count(),
+            usable_filtered,
+            confirmed: self
+                .sampled
+                .iter()
+                .filter(|g| g.confirmed_idx.is_some())
+                .count(),
+            primary,
+            stuck: usable_filtered == 0,
+        };
+    }
+}
@@ -0,0 +1,177 @@
+// v3 onion addresses (rend-spec-v3 6):
+// base32(PUBKEY | CHECKSUM | VERSION) + ".onion",
+// where CHECKSUM = SHA3-256(".onion checksum" | PUBKEY | VERSION)[:2].
+
+use std::fmt;
+use std::io::Error;
+
+use super::OnionError;
+use crate::crypto;
+use crate::encoding;
+
+const SUFFIX: &str = ".onion";
+const VERSION: u8 = 3;
+// Length of the base32 label: 35 bytes encode to 56 characters
+const LABEL_LEN: usize = 56;
+
+/// The address of a v3 onion service, which is its Ed25519 identity key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnionAddress {
+    identity: [u8; 32],
+}
+
+impl OnionAddress {
+    /// Whether the hostname is meant for an onion service, valid or not.
+    pub fn is_onion(host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        let host = host.as_bytes();
+        return host.len() >= SUFFIX.len()
+            && host[host.len() - SUFFIX.len()..].eq_ignore_ascii_case(SUFFIX.as_bytes());
+    }
+
+    /// Parses `<56 characters>.onion`, in any case. Subdomains as in `www.<56 characters>.onion`
+    /// are allowed and ignored, since they name the same service.
+    pub fn parse(host: &str) -> Result<OnionAddress, Error> {
+        if !OnionAddress::is_onion(host) {
+            return Err(OnionError::BadAddress.into());
+        }
+        let host = host.trim_end_matches('.');
+        let name = &host[..host.len() - SUFFIX.len()];
+        let label = match name.rfind('.') {
+            Some(i) => &name[i + 1..],
+            None => name,
+        };
+        if label.len() != LABEL_LEN {
+            return Err(OnionError::BadAddress.into());
+        }
+        let decoded = match encoding::base32_decode(label) {
+            Some(d) if d.len() == 35 => d,
+            _ => return Err(OnionError::BadAddress.into()),
+        };
+        let mut identity: [u8; 32] = [0x0; 32];
+        identity.copy_from_slice(&decoded[..32]);
+        if decoded[34] != VERSION || decoded[32..34] != checksum(&identity) {
+            return Err(OnionError::BadAddress.into());
+        }
+        if !crypto::ed25519_key_is_valid(&identity) {
+            return Err(OnionError::BadAddress.into());
+        }
+        return Ok(OnionAddress { identity });
+    }
+
+    /// The address of the service with the Ed25519 identity key.
+    pub fn from_identity(identity: [u8; 32]) -> OnionAddress {
+        return OnionAddress { identity };
+    }
+
+    /// The service's Ed25519 identity key.
+    pub fn identity(&self) -> &[u8; 32] {
+        return &self.identity;
+    }
+}
+
+fn checksum(identity: &[u8; 32]) -> [u8; 2] {
+    let mut data: Vec<u8> = b".onion checksum".to_vec();
+    data.extend_from_slice(identity);
+    data.push(VERSION);
+    let digest = crypto::sha3_256(&data);
+    return [digest[0], digest[1]];
+}
+
+impl fmt::Display for OnionAddress {
+    /// Formats the address without subdomain, in lowercase.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut raw: Vec<u8> = self.identity.to_vec();
+        raw.extend_from_slice(&checksum(&self.identity));
+        raw.push(VERSION);
+        return write!(f, "{}{}", encoding::base32_encode(&raw), SUFFIX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants::EIGHT_TORSION;
+    use curve25519_dalek::edwards::CompressedEdwardsY;
+
+    // From C tor's test_build_address in src/test/test_hs_common.c
+    const IDENTITY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const ADDRESS: &str = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid.onion";
+
+    fn identity() -> [u8; 32] {
+        let mut identity = [0u8; 32];
+        identity.copy_from_slice(&encoding::hex_decode(IDENTITY).unwrap());
+        return identity;
+    }
+
+    /// Encodes an address from raw parts, which need not be valid.
+    fn encode(identity: &[u8; 32], checksum: [u8; 2], version: u8) -> String {
+        let mut raw = identity.to_vec();
+        raw.extend_from_slice(&checksum);
+        raw.push(version);
+        return format!("{}{}", encoding::base32_encode(&raw), SUFFIX);
+    }
+
+    fn is_bad(host: &str) -> bool {
+        return OnionError::from_error(&OnionAddress::parse(host).unwrap_err())
+            == Some(OnionError::BadAddress);
+    }
+
+    #[test]
+    fn round_trip() {
+        let addr = OnionAddress::parse(ADDRESS).unwrap();
+        assert_eq!(addr.identity(), &identity());
+        assert_eq!(addr.to_string(), ADDRESS);
+        assert_eq!(OnionAddress::from_identity(identity()), addr);
+        assert_eq!(encode(&identity(), checksum(&identity()), VERSION), ADDRESS);
+    }
+
+    #[test]
+    fn spellings() {
+        let addr = OnionAddress::parse(ADDRESS).unwrap();
+        let upper = ADDRESS.to_ascii_uppercase();
+        assert!(OnionAddress::is_onion(&upper));
+        assert_eq!(OnionAddress::parse(&upper).unwrap(), addr);
+        assert_eq!(
+            OnionAddress::parse(&format!("www.{}", ADDRESS)).unwrap(),
+            addr
+        );
+        assert_eq!(OnionAddress::parse(&format!("{}.", ADDRESS)).unwrap(), addr);
+        assert!(!OnionAddress::is_onion("example.com"));
+        assert!(!OnionAddress::is_onion("onion"));
+    }
+
+    #[test]
+    fn bad_addresses() {
+        let id = identity();
+        let mut flipped = checksum(&id);
+        flipped[1] ^= 0x01;
+        assert!(is_bad(&encode(&id, flipped, VERSION)));
+
+        // A correct checksum for another version
+        let mut data: Vec<u8> = b".onion checksum".to_vec();
+        data.extend_from_slice(&id);
+        data.push(4);
+        let digest = crypto::sha3_256(&data);
+        assert!(is_bad(&encode(&id, [digest[0], digest[1]], 4)));
+
+        let label = &ADDRESS[..LABEL_LEN];
+        assert!(is_bad(&format!("{}.onion", &label[..55])));
+        assert!(is_bad(&format!("{}a.onion", label)));
+        assert!(is_bad(&format!("1{}.onion", &label[1..])));
+        assert!(is_bad(&format!("-{}.onion", &label[1..])));
+        assert!(is_bad(".onion"));
+    }
+
+    #[test]
+    fn small_order_key() {
+        let point = CompressedEdwardsY(identity()).decompress().unwrap();
+        let key = (point + EIGHT_TORSION[1]).compress().to_bytes();
+        assert!(is_bad(&encode(&key, checksum(&key), VERSION)));
+        // The torsion points themselves, but the neutral element
+        for t in EIGHT_TORSION.iter().skip(1) {
+            let key = t.compress().to_bytes();
+            assert!(is_bad(&encode(&key, checksum(&key), VERSION)));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+// Descriptors of onion services we connected to, so reconnecting doesn't fetch them again,
+// and which of their introduction points failed us.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::desc::HsDescriptor;
+
+/// Decrypted descriptors by blinded key. As the blinded key changes with the time period,
+/// entries expire at its end at the latest.
+pub(crate) struct DescriptorCache {
+    entries: HashMap<[u8; 32], CachedDescriptor>,
+}
+
+struct CachedDescriptor {
+    desc: Arc<HsDescriptor>,
+    expires: SystemTime,
+    // Auth keys of intro points that timed out, refused the introduction or were unreachable
+    failed: HashSet<[u8; 32]>,
+}
+
+impl DescriptorCache {
+    pub(crate) fn new() -> DescriptorCache {
+        return DescriptorCache {
+            entries: HashMap::new(),
+        };
+    }
+
+    /// The descriptor for the blinded key, unless it expired or all its intro points failed.
+    pub(crate) fn get(&mut self, blinded: &[u8; 32], now: SystemTime) -> Option<Arc<HsDescriptor>> {
+        self.entries.retain(|_, e| e.expires > now);
+        let entry = self.entries.get(blinded)?;
+        if entry
+            .desc
+            .intro_points
+            .iter()
+            .all(|ip| entry.failed.contains(&ip.auth_key))
+        {
+            return None;
+        }
+        return Some(entry.desc.clone());
+    }
+
+    /// Caches a freshly fetched descriptor, forgetting the failures of the one it replaces.
+    pub(crate) fn insert(
+        &mut self,
+        blinded: [u8; 32],
+        desc: Arc<HsDescriptor>,
+        expires: SystemTime,
+    ) {
+        self.entries.insert(
+            blinded,
+            CachedDescriptor {
+                desc,
+                expires,
+                failed: HashSet::new(),
+            },
+        );
+    }
+
+    pub(crate) fn intro_failed(&self, blinded: &[u8; 32], auth_key: &[u8; 32]) -> bool {
+        return self
+            .entries
+            .get(blinded)
+            .map_or(false, |e| e.failed.contains(auth_key));
+    }
+
+    /// Notes that the intro point failed, so it's skipped until the descriptor is fetched again.
+    pub(crate) fn note_intro_failure(&mut self, blinded: &[u8; 32], auth_key: &[u8; 32]) {
+        if let Some(entry) = self.entries.get_mut(blinded) {
+            entry.failed.insert(*auth_key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hs::desc::IntroPoint;
+    use std::time::Duration;
+
+    fn descriptor(auth_keys: &[[u8; 32]]) -> Arc<HsDescriptor> {
+        return Arc::new(HsDescriptor {
+            lifetime: Duration::from_secs(3 * 60 * 60),
+            revision_counter: 1,
+            signing_key: [0x0; 32],
+            auth_ephemeral_key: [0x0; 32],
+            auth_clients: vec![],
+            single_onion: false,
+            pow_params: None,
+            intro_points: auth_keys
+                .iter()
+                .map(|k| IntroPoint {
+                    link_specifiers: vec![],
+                    onion_key: [0x0; 32],
+                    auth_key: *k,
+                    enc_key: [0x0; 32],
+                })
+                .collect(),
+        });
+    }
+
+    #[test]
+    fn expires() {
+        let now = SystemTime::now();
+        let mut cache = DescriptorCache::new();
+        cache.insert(
+            [0x1; 32],
+            descriptor(&[[0xa; 32]]),
+            now + Duration::from_secs(60),
+        );
+        assert!(cache.get(&[0x1; 32], now).is_some());
+        assert!(cache.get(&[0x2; 32], now).is_none());
+        assert!(cache
+            .get(&[0x1; 32], now + Duration::from_secs(60))
+            .is_none());
+        // Expired entries are gone even if the clock goes back
+        assert!(cache.get(&[0x1; 32], now).is_none());
+    }
+
+    #[test]
+    fn failed_intro_points() {
+        let now = SystemTime::now();
+        let expires = now + Duration::from_secs(60);
+        let mut cache = DescriptorCache::new();
+        cache.insert([0x1; 32], descriptor(&[[0xa; 32], [0xb; 32]]), expires);
+
+        cache.note_intro_failure(&[0x1; 32], &[0xa; 32]);
+        assert!(cache.intro_failed(&[0x1; 32], &[0xa; 32]));
+        assert!(!cache.intro_failed(&[0x1; 32], &[0xb; 32]));
+        assert!(cache.get(&[0x1; 32], now).is_some());
+
+        // Once all failed, the descriptor must be fetched again
+        cache.note_intro_failure(&[0x1; 32], &[0xb; 32]);
+        assert!(cache.get(&[0x1; 32], now).is_none());
+
+        // A fresh descriptor starts without failures
+        cache.insert([0x1; 32], descriptor(&[[0xa; 32], [0xb; 32]]), expires);
+        assert!(!cache.intro_failed(&[0x1; 32], &[0xa; 32]));
+        assert!(cache.get(&[0x1; 32], now).is_some());
+    }
+}
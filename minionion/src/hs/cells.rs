@@ -0,0 +1,418 @@
+// Bodies of the relay cells used to reach onion services (rend-spec-v3 3).
+
+use std::io::{Error, ErrorKind};
+
+use std::convert::TryInto;
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::ntor::{self, hs_mac, HsNtorClient, INTRO_MAC_LEN};
+use super::pow::{PowSolution, EXT_TYPE_POW};
+use crate::cell::{encode_link_specifiers, LinkSpecifier};
+use crate::crypto::{self, SyncStreamCipher};
+
+/// Length of the cookie identifying a rendezvous circuit
+pub(crate) const REND_COOKIE_LEN: usize = 20;
+// AUTH_KEY_TYPE for Ed25519 keys
+const AUTH_KEY_TYPE_ED25519: u8 = 2;
+// ONION_KEY_TYPE for ntor keys
+const ONION_KEY_TYPE_NTOR: u8 = 1;
+// The plaintext of INTRODUCE1 is padded to this length, as in tor, so cells don't reveal
+// the number of link specifiers
+const INTRO_PLAINTEXT_LEN: usize = 246;
+// INTRODUCE_ACK status for a successfully relayed INTRODUCE1
+const INTRODUCE_ACK_SUCCESS: u16 = 0;
+const ESTABLISH_INTRO_SIG_PREFIX: &[u8] = b"Tor establish-intro cell v1";
+// Length of the fields preceding AUTH_KEY in INTRODUCE1 and INTRODUCE2
+const INTRO_HEADER_LEN: usize = 20 + 1 + 2;
+
+/// The parts of INTRODUCE2 the service needs to reach the client's rendezvous point.
+pub(crate) struct Introduce2 {
+    pub(crate) client_pk: [u8; 32],
+    pub(crate) cookie: [u8; REND_COOKIE_LEN],
+    pub(crate) rp_ntor_key: [u8; 32],
+    pub(crate) rp_specs: Vec<LinkSpecifier>,
+    /// The proof-of-work solution, if the client sent one
+    pub(crate) pow: Option<PowSolution>,
+}
+
+fn invalid(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg.to_string());
+}
+
+/// The body of ESTABLISH_INTRO, which makes the relay an introduction point for the
+/// auth key (rend-spec-v3 3.1.1). `nonce` is KH of the circuit's last hop.
+pub(crate) fn establish_intro_body(
+    auth_key: &[u8; 32],
+    auth_secret: &[u8; 64],
+    nonce: &[u8],
+) -> Vec<u8> {
+    let mut body: Vec<u8> = vec![AUTH_KEY_TYPE_ED25519];
+    body.extend_from_slice(&(auth_key.len() as u16).to_be_bytes());
+    body.extend_from_slice(auth_key);
+    // N_EXTENSIONS
+    body.push(0);
+    let handshake_auth = hs_mac(nonce, &body);
+    body.extend_from_slice(&handshake_auth);
+
+    let mut signed: Vec<u8> = ESTABLISH_INTRO_SIG_PREFIX.to_vec();
+    signed.extend_from_slice(&body);
+    let sig = crypto::ed25519_sign(auth_secret, auth_key, &signed);
+    body.extend_from_slice(&(sig.len() as u16).to_be_bytes());
+    body.extend_from_slice(&sig);
+    return body;
+}
+
+/// The body of INTRODUCE1 for an introduction point with the auth key, asking the service
+/// to meet us at the rendezvous point (rend-spec-v3 3.2.1). A proof-of-work solution goes
+/// into an extension of the encrypted part.
+pub(crate) fn introduce1_body(
+    auth_key: &[u8; 32],
+    client: &HsNtorClient,
+    subcredential: &[u8; 32],
+    cookie: &[u8; REND_COOKIE_LEN],
+    rp_specs: &[LinkSpecifier],
+    rp_ntor_key: &[u8; 32],
+    pow: Option<&PowSolution>,
+) -> Vec<u8> {
+    // LEGACY_KEY_ID is only used for v2 services
+    let mut body: Vec<u8> = vec![0x0; 20];
+    body.push(AUTH_KEY_TYPE_ED25519);
+    body.extend_from_slice(&(auth_key.len() as u16).to_be_bytes());
+    body.extend_from_slice(auth_key);
+    // N_EXTENSIONS
+    body.push(0);
+
+    let mut plaintext: Vec<u8> = cookie.to_vec();
+    match pow {
+        Some(solution) => {
+            let ext = solution.encode();
+            plaintext.push(1);
+            plaintext.push(EXT_TYPE_POW);
+            plaintext.push(ext.len() as u8);
+            plaintext.extend_from_slice(&ext);
+        }
+        // N_EXTENSIONS
+        None => plaintext.push(0),
+    }
+    plaintext.push(ONION_KEY_TYPE_NTOR);
+    plaintext.extend_from_slice(&(rp_ntor_key.len() as u16).to_be_bytes());
+    plaintext.extend_from_slice(rp_ntor_key);
+    plaintext.extend_from_slice(&encode_link_specifiers(rp_specs));
+    if plaintext.len() < INTRO_PLAINTEXT_LEN {
+        plaintext.resize(INTRO_PLAINTEXT_LEN, 0);
+    }
+
+    let (enc_key, mac_key) = client.intro_keys(subcredential);
+    crypto::aes256_ctr(&enc_key, &[0x0; 16]).apply_keystream(&mut plaintext);
+    body.extend_from_slice(client.public_key());
+    body.extend_from_slice(&plaintext);
+    let mac = hs_mac(&mac_key, &body);
+    body.extend_from_slice(&mac);
+    return body;
+}
+
+/// Checks the status of an INTRODUCE_ACK.
+pub(crate) fn parse_introduce_ack(body: &[u8]) -> Result<(), Error> {
+    if body.len() < 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "INTRODUCE_ACK too short",
+        ));
+    }
+    let status = u16::from_be_bytes([body[0], body[1]]);
+    if status != INTRODUCE_ACK_SUCCESS {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "Introduction point refused INTRODUCE1 with status {}",
+                status
+            ),
+        ));
+    }
+    return Ok(());
+}
+
+/// Checks and decrypts an INTRODUCE2 received through the introduction point with the
+/// auth key, whose encryption key pair is `enc_secret` (rend-spec-v3 3.3).
+pub(crate) fn parse_introduce2(
+    body: &[u8],
+    auth_key: &[u8; 32],
+    enc_secret: &StaticSecret,
+    subcredential: &[u8; 32],
+) -> Result<Introduce2, Error> {
+    if body.len() < INTRO_HEADER_LEN {
+        return Err(invalid("INTRODUCE2 too short"));
+    }
+    let key_len = u16::from_be_bytes([body[21], body[22]]) as usize;
+    let mut pos = INTRO_HEADER_LEN + key_len;
+    if body[20] != AUTH_KEY_TYPE_ED25519 || body.get(INTRO_HEADER_LEN..pos) != Some(auth_key) {
+        return Err(invalid("INTRODUCE2 for another auth key"));
+    }
+    let n_ext = *body
+        .get(pos)
+        .ok_or_else(|| invalid("INTRODUCE2 too short"))?;
+    pos += 1;
+    for _ in 0..n_ext {
+        match body.get(pos + 1) {
+            Some(len) => pos += 2 + *len as usize,
+            None => return Err(invalid("Truncated INTRODUCE2 extension")),
+        }
+    }
+    if body.len() < pos + 32 + INTRO_MAC_LEN {
+        return Err(invalid("INTRODUCE2 too short"));
+    }
+    let client_pk: [u8; 32] = body[pos..pos + 32].try_into().unwrap();
+    let mac_start = body.len() - INTRO_MAC_LEN;
+
+    let enc_key = PublicKey::from(enc_secret);
+    let dh = enc_secret.diffie_hellman(&PublicKey::from(client_pk));
+    let (key, mac_key) = ntor::intro_keys(
+        dh.as_bytes(),
+        auth_key,
+        &client_pk,
+        enc_key.as_bytes(),
+        subcredential,
+    );
+    if !crypto::ct_eq(&hs_mac(&mac_key, &body[..mac_start]), &body[mac_start..]) {
+        return Err(invalid("INTRODUCE2 MAC mismatch"));
+    }
+    let mut plaintext = body[pos + 32..mac_start].to_vec();
+    crypto::aes256_ctr(&key, &[0x0; 16]).apply_keystream(&mut plaintext);
+
+    if plaintext.len() < REND_COOKIE_LEN + 1 {
+        return Err(invalid("INTRODUCE2 plaintext too short"));
+    }
+    let cookie: [u8; REND_COOKIE_LEN] = plaintext[..REND_COOKIE_LEN].try_into().unwrap();
+    let mut pos = REND_COOKIE_LEN + 1;
+    let mut pow: Option<PowSolution> = None;
+    for _ in 0..plaintext[REND_COOKIE_LEN] {
+        let (ext_type, len) = match plaintext.get(pos..pos + 2) {
+            Some(h) => (h[0], h[1] as usize),
+            None => return Err(invalid("Truncated INTRODUCE2 extension")),
+        };
+        let ext = plaintext
+            .get(pos + 2..pos + 2 + len)
+            .ok_or_else(|| invalid("Truncated INTRODUCE2 extension"))?;
+        if ext_type == EXT_TYPE_POW {
+            pow = PowSolution::parse(ext);
+        }
+        pos += 2 + len;
+    }
+    let onion_key_type = plaintext.get(pos);
+    let onion_key_len = plaintext
+        .get(pos + 1..pos + 3)
+        .map(|l| u16::from_be_bytes([l[0], l[1]]));
+    if onion_key_type != Some(&ONION_KEY_TYPE_NTOR) || onion_key_len != Some(32) {
+        return Err(invalid("INTRODUCE2 lacks an ntor onion key"));
+    }
+    let rp_ntor_key: [u8; 32] = match plaintext.get(pos + 3..pos + 35) {
+        Some(k) => k.try_into().unwrap(),
+        None => return Err(invalid("INTRODUCE2 plaintext too short")),
+    };
+    let (rp_specs, _) = LinkSpecifier::decode_list(&plaintext[pos + 35..])?;
+    return Ok(Introduce2 {
+        client_pk,
+        cookie,
+        rp_ntor_key,
+        rp_specs,
+        pow,
+    });
+}
+
+/// The body of RENDEZVOUS1, completing the handshake through the rendezvous point.
+pub(crate) fn rendezvous1_body(
+    cookie: &[u8; REND_COOKIE_LEN],
+    y: &[u8; 32],
+    auth: &[u8; 32],
+) -> Vec<u8> {
+    let mut body: Vec<u8> = cookie.to_vec();
+    body.extend_from_slice(y);
+    body.extend_from_slice(auth);
+    return body;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::hex_decode;
+    use crate::hs::SOLUTION_LEN;
+    use std::net::SocketAddrV4;
+
+    fn key(hex: &str) -> [u8; 32] {
+        return hex_decode(hex).unwrap().as_slice().try_into().unwrap();
+    }
+
+    struct Intro {
+        auth_key: [u8; 32],
+        enc_secret: StaticSecret,
+        client: HsNtorClient,
+        subcredential: [u8; 32],
+    }
+
+    fn intro() -> Intro {
+        let enc_secret = StaticSecret::from([3; 32]);
+        let enc_key = *PublicKey::from(&enc_secret).as_bytes();
+        return Intro {
+            auth_key: [1; 32],
+            client: HsNtorClient::new(&[1; 32], &enc_key),
+            enc_secret,
+            subcredential: [2; 32],
+        };
+    }
+
+    fn rp_specs() -> Vec<LinkSpecifier> {
+        return vec![
+            LinkSpecifier::Ipv4(SocketAddrV4::new([192, 0, 2, 7].into(), 9001)),
+            LinkSpecifier::RsaId([4; 20]),
+            LinkSpecifier::Ed25519Id([5; 32]),
+        ];
+    }
+
+    #[test]
+    fn introduce_round_trip() {
+        let i = intro();
+        let solution = PowSolution {
+            nonce: [6; 16],
+            effort: 1234,
+            seed_head: [7, 8, 9, 10],
+            solution: [11; SOLUTION_LEN],
+        };
+        for pow in &[None, Some(solution)] {
+            let body = introduce1_body(
+                &i.auth_key,
+                &i.client,
+                &i.subcredential,
+                &[8; REND_COOKIE_LEN],
+                &rp_specs(),
+                &[9; 32],
+                pow.as_ref(),
+            );
+            let intro2 =
+                parse_introduce2(&body, &i.auth_key, &i.enc_secret, &i.subcredential).unwrap();
+            assert_eq!(&intro2.client_pk, i.client.public_key());
+            assert_eq!(intro2.cookie, [8; REND_COOKIE_LEN]);
+            assert_eq!(intro2.rp_ntor_key, [9; 32]);
+            assert_eq!(intro2.rp_specs, rp_specs());
+            assert_eq!(&intro2.pow, pow);
+            // The plaintext is padded, so the cell doesn't depend on the link specifiers
+            assert_eq!(
+                body.len(),
+                INTRO_HEADER_LEN + 32 + 1 + 32 + INTRO_PLAINTEXT_LEN + INTRO_MAC_LEN
+            );
+        }
+    }
+
+    #[test]
+    fn introduce_tampered() {
+        let i = intro();
+        let body = introduce1_body(
+            &i.auth_key,
+            &i.client,
+            &i.subcredential,
+            &[8; REND_COOKIE_LEN],
+            &rp_specs(),
+            &[9; 32],
+            None,
+        );
+        let parse = |body: &[u8]| {
+            return parse_introduce2(body, &i.auth_key, &i.enc_secret, &i.subcredential);
+        };
+        assert!(parse(&body).is_ok());
+        let mut flipped = body.clone();
+        *flipped.last_mut().unwrap() ^= 0x01;
+        assert_eq!(
+            parse(&flipped).err().unwrap().to_string(),
+            "INTRODUCE2 MAC mismatch"
+        );
+        // The MAC covers the encrypted part as well
+        let mut flipped = body.clone();
+        flipped[INTRO_HEADER_LEN + 32 + 1 + 32 + 5] ^= 0x01;
+        assert!(parse(&flipped).is_err());
+        assert!(parse_introduce2(&body, &i.auth_key, &i.enc_secret, &[3; 32]).is_err());
+        assert!(parse_introduce2(&body, &[2; 32], &i.enc_secret, &i.subcredential).is_err());
+        assert!(parse(&body[..INTRO_HEADER_LEN + 10]).is_err());
+    }
+
+    /// An INTRODUCE1 generated with C tor and chutney, from arti's hs_ntor tests.
+    #[test]
+    fn c_tor_introduce() {
+        let auth_key = key("34E171E4358E501BFF21ED907E96AC6BFEF697C779D040BBAF49ACC30FC5D21F");
+        let subcredential = key("0085D26A9DEBA252263BF0231AEAC59B17CA11BAD8A218238AD6487CBAD68B57");
+        let enc_key = key("8E5127A40E83AABF6493E41F142B6EE3604B85A3961CD7E38D247239AFF71979");
+        let enc_secret = StaticSecret::from(key(
+            "A0ED5DBF94EEB2EDB3B514E4CF6ABFF6022051CC5F103391F1970A3FCD15296A",
+        ));
+        let x = StaticSecret::from(key(
+            "60B4D6BF5234DCF87A4E9D7487BDF3F4A69B6729835E825CA29089CFDDA1E341",
+        ));
+        let header = hex_decode(
+            "000000000000000000000000000000000000000002002034E171E4358E501BFF\
+             21ED907E96AC6BFEF697C779D040BBAF49ACC30FC5D21F00",
+        )
+        .unwrap();
+        let plaintext = hex_decode(
+            "6BD364C12638DD5C3BE23D76ACA05B04E6CE932C0101000100200DE6130E4FCA\
+             C4EDDA24E21220CC3EADAE403EF6B7D11C8273AC71908DE565450300067F0000\
+             0113890214F823C4F8CC085C792E0AEE0283FE00AD7520B37D0320728D5DF39B\
+             7B7077A0118A900FF4456C382F0041300ACF9C58E51C392795EF870000000000\
+             0000000000000000000000000000000000000000000000000000000000000000\
+             000000000000000000000000000000000000000000000000000000000000",
+        )
+        .unwrap();
+        let cell = hex_decode(
+            "000000000000000000000000000000000000000002002034E171E4358E501BFF\
+             21ED907E96AC6BFEF697C779D040BBAF49ACC30FC5D21F00BF04348B46D09AED\
+             726F1D66C618FDEA1DE58E8CB8B89738D7356A0C59111D5DADBECCCB38E37830\
+             4DCC179D3D9E437B452AF5702CED2CCFEC085BC02C4C175FA446525C1B9D5530\
+             563C362FDFFB802DAB8CD9EBC7A5EE17DA62E37DEEB0EB187FBB48C63298B0E8\
+             3F391B7566F42ADC97C46BA7588278273A44CE96BC68FFDAE31EF5F0913B9A9C\
+             7E0F173DBC0BDDCD4ACB4C4600980A7DDD9EAEC6E7F3FA3FC37CD95E5B8BFB3E\
+             35717012B78B4930569F895CB349A07538E42309C993223AEA77EF8AEA64F25D\
+             DEE97DA623F1AEC0A47F150002150455845C385E5606E41A9A199E7111D54EF2\
+             D1A51B7554D8B3692D85AC587FB9E69DF990EFB776D8",
+        )
+        .unwrap();
+
+        // Encrypting the plaintext as introduce1_body does yields C tor's cell
+        let client = HsNtorClient::with_secret(&auth_key, &enc_key, x);
+        let (enc, mac_key) = client.intro_keys(&subcredential);
+        let mut encrypted = plaintext;
+        crypto::aes256_ctr(&enc, &[0x0; 16]).apply_keystream(&mut encrypted);
+        let mut body = header;
+        body.extend_from_slice(client.public_key());
+        body.extend_from_slice(&encrypted);
+        let mac = hs_mac(&mac_key, &body);
+        body.extend_from_slice(&mac);
+        assert_eq!(body, cell);
+
+        // The service decrypts it, skipping the unknown extension
+        let intro2 = parse_introduce2(&cell, &auth_key, &enc_secret, &subcredential).unwrap();
+        assert_eq!(&intro2.client_pk, client.public_key());
+        assert_eq!(
+            intro2.cookie.to_vec(),
+            hex_decode("6BD364C12638DD5C3BE23D76ACA05B04E6CE932C").unwrap()
+        );
+        assert_eq!(
+            intro2.rp_ntor_key,
+            key("0DE6130E4FCAC4EDDA24E21220CC3EADAE403EF6B7D11C8273AC71908DE56545")
+        );
+        assert_eq!(
+            intro2.rp_specs,
+            vec![
+                LinkSpecifier::Ipv4(SocketAddrV4::new([127, 0, 0, 1].into(), 5001)),
+                LinkSpecifier::RsaId(
+                    hex_decode("F823C4F8CC085C792E0AEE0283FE00AD7520B37D")
+                        .unwrap()
+                        .as_slice()
+                        .try_into()
+                        .unwrap()
+                ),
+                LinkSpecifier::Ed25519Id(key(
+                    "728D5DF39B7B7077A0118A900FF4456C382F0041300ACF9C58E51C392795EF87"
+                )),
+            ]
+        );
+        assert_eq!(intro2.pow, None);
+    }
+}
@@ -0,0 +1,268 @@
+// Connecting to onion services (rend-spec-v3 3 and 4): fetching the descriptor from an
+// HSDir, meeting the service at a rendezvous point and opening a stream through it.
+
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::cells::{self, REND_COOKIE_LEN};
+use super::desc::HsDescriptor;
+use super::hashring;
+use super::keys::{self, TimePeriod};
+use super::ntor::HsNtorClient;
+use super::pow;
+use super::{OnionAddress, OnionError};
+use crate::cell::{RelayCell, RelayCommand};
+use crate::circuit::{CircTarget, Circuit};
+use crate::client::ClientInner;
+use crate::crypto;
+use crate::dirclient;
+use crate::encoding;
+use crate::stream::TorStream;
+
+const HSDIR_PATH: &str = "/tor/hs/3/";
+// How long to wait for the rendezvous point and the introduction point to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+// How long to wait for the service to reach the rendezvous point after introducing us
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(60);
+// How long to wait for the service to answer a BEGIN
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Opens a stream to the port of the onion service. Failures are reported as an `OnionError`.
+/// A cached descriptor is used if we have one whose intro points didn't all fail.
+pub(crate) fn connect(
+    inner: &ClientInner,
+    addr: &OnionAddress,
+    port: u16,
+) -> Result<TorStream, Error> {
+    let period = inner.time_period();
+    let blinded = keys::blind_public_key(addr.identity(), period)?;
+    let subcredential = keys::subcredential(addr.identity(), &blinded);
+    let cached = inner.with_onion_cache(|cache| cache.get(&blinded, SystemTime::now()));
+    let desc = match &cached {
+        Some(d) => d.clone(),
+        None => fetch_descriptor(inner, addr, &blinded, &subcredential, period)?,
+    };
+    if desc.intro_points.is_empty() {
+        return Err(OnionError::IntroFailed.into());
+    }
+
+    let (rend_circ, rp, cookie) = establish_rendezvous(inner)?;
+    let mut introduced = introduce(inner, &desc, &blinded, &subcredential, &rp, &cookie);
+    // All remaining intro points of the cached descriptor failed, the service may have new ones
+    if introduced.is_err() && cached.is_some() {
+        introduced = fetch_descriptor(inner, addr, &blinded, &subcredential, period)
+            .and_then(|desc| introduce(inner, &desc, &blinded, &subcredential, &rp, &cookie));
+    }
+    let client = match introduced {
+        Ok(c) => c,
+        Err(e) => {
+            rend_circ.close();
+            return Err(e);
+        }
+    };
+    let keys = match wait_for(&rend_circ, RelayCommand::Rendezvous2, RENDEZVOUS_TIMEOUT)
+        .and_then(|cell| client.complete(&cell.data))
+    {
+        Ok(k) => k,
+        Err(_) => {
+            rend_circ.close();
+            return Err(OnionError::RendezvousFailed.into());
+        }
+    };
+    rend_circ.add_onion_hop(&keys, false);
+
+    // The service maps the port itself, so the address is left empty
+    let mut body = format!(":{}", port).into_bytes();
+    body.push(0);
+    body.extend_from_slice(&0u32.to_be_bytes());
+    let stream_id = rend_circ
+        .begin_stream(RelayCommand::Begin, body)
+        .and_then(|id| rend_circ.wait_reply(id, STREAM_TIMEOUT).map(|_| id));
+    return match stream_id {
+        Ok(id) => Ok(TorStream::with_own_circuit(rend_circ, id)),
+        Err(e) => {
+            rend_circ.close();
+            Err(e)
+        }
+    };
+}
+
+/// Fetches and decrypts the service's descriptor for the time period from one of the
+/// HSDirs responsible for it, with our client authorization key if we have one.
+/// The descriptor is cached until its lifetime or the time period ends.
+fn fetch_descriptor(
+    inner: &ClientInner,
+    addr: &OnionAddress,
+    blinded: &[u8; 32],
+    subcredential: &[u8; 32],
+    period: TimePeriod,
+) -> Result<Arc<HsDescriptor>, Error> {
+    let mut hsdirs: Vec<CircTarget> = inner.with_netdir(|netdir| {
+        let spread = hashring::spread_fetch(&netdir.consensus);
+        return hashring::responsible_hsdirs(netdir, blinded, period, spread)
+            .iter()
+            .map(CircTarget::from_relay)
+            .collect();
+    });
+    for i in (1..hsdirs.len()).rev() {
+        let j = crypto::random_below(i as u64 + 1) as usize;
+        hsdirs.swap(i, j);
+    }
+
+    let path = format!(
+        "{}{}",
+        HSDIR_PATH,
+        encoding::base64_encode(blinded).trim_end_matches('=')
+    );
+    // A broken descriptor is only reported if no HSDir has a good one
+    let mut found_invalid: Option<OnionError> = None;
+    let client_key = inner.onion_auth_key(addr.identity());
+    for hsdir in &hsdirs {
+        let doc = match inner.build_circuit_to(hsdir) {
+            Ok(circ) => {
+                let doc = dirclient::get(&circ, &path);
+                circ.close();
+                doc
+            }
+            Err(e) => Err(e),
+        };
+        let doc = match doc.map(|d| String::from_utf8(d)) {
+            Ok(Ok(d)) => d,
+            Ok(Err(_)) => {
+                found_invalid = found_invalid.or(Some(OnionError::DescriptorInvalid));
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let now = SystemTime::now();
+        match HsDescriptor::decode(&doc, blinded, subcredential, client_key, now) {
+            Ok(desc) => {
+                let expires = std::cmp::min(now + desc.lifetime, period.next().start());
+                let desc = Arc::new(desc);
+                inner.with_onion_cache(|cache| cache.insert(*blinded, desc.clone(), expires));
+                return Ok(desc);
+            }
+            // Client authorization failures say more than other broken descriptors
+            Err(e) => {
+                found_invalid = match OnionError::from_error(&e) {
+                    Some(reason) => Some(reason),
+                    None => found_invalid.or(Some(OnionError::DescriptorInvalid)),
+                }
+            }
+        }
+    }
+    if let Some(reason) = found_invalid {
+        return Err(reason.into());
+    }
+    return Err(OnionError::DescriptorNotFound.into());
+}
+
+/// Builds a circuit to a random rendezvous point and registers a cookie there.
+fn establish_rendezvous(
+    inner: &ClientInner,
+) -> Result<(Circuit, CircTarget, [u8; REND_COOKIE_LEN]), Error> {
+    let rp = inner.pick_middle()?;
+    let circ = inner.build_circuit_to(&rp)?;
+    let mut cookie: [u8; REND_COOKIE_LEN] = [0x0; REND_COOKIE_LEN];
+    crypto::random_bytes(&mut cookie);
+    let established = circ
+        .send_control(
+            circ.n_hops() - 1,
+            RelayCommand::EstablishRendezvous,
+            cookie.to_vec(),
+        )
+        .and_then(|_| wait_for(&circ, RelayCommand::RendezvousEstablished, REPLY_TIMEOUT));
+    if established.is_err() {
+        circ.close();
+        return Err(OnionError::RendezvousFailed.into());
+    }
+    return Ok((circ, rp, cookie));
+}
+
+/// Sends INTRODUCE1 to the introduction points in random order until one accepts it,
+/// skipping those that failed before. Failing ones are noted in the cache. If the service
+/// asks for proof of work, the puzzle is solved with the suggested effort, up to our limit.
+/// Returns the handshake state for completing the rendezvous.
+fn introduce(
+    inner: &ClientInner,
+    desc: &HsDescriptor,
+    blinded: &[u8; 32],
+    subcredential: &[u8; 32],
+    rp: &CircTarget,
+    cookie: &[u8; REND_COOKIE_LEN],
+) -> Result<HsNtorClient, Error> {
+    let mut order: Vec<usize> = (0..desc.intro_points.len()).collect();
+    for i in (1..order.len()).rev() {
+        let j = crypto::random_below(i as u64 + 1) as usize;
+        order.swap(i, j);
+    }
+
+    let pow = match (&desc.pow_params, inner.pow_solver()) {
+        (Some(params), Some((equix, max_effort)))
+            if params.suggested_effort > 0 && params.expiration > SystemTime::now() =>
+        {
+            let effort = std::cmp::min(params.suggested_effort, max_effort);
+            Some(pow::solve(equix, blinded, &params.seed, effort))
+        }
+        _ => None,
+    };
+
+    let mut timed_out = false;
+    for n in order {
+        let ip = &desc.intro_points[n];
+        if inner.with_onion_cache(|cache| cache.intro_failed(blinded, &ip.auth_key)) {
+            continue;
+        }
+        let note_failure = || {
+            inner.with_onion_cache(|cache| cache.note_intro_failure(blinded, &ip.auth_key));
+        };
+        let circ = match CircTarget::from_link_specifiers(&ip.link_specifiers, ip.onion_key)
+            .and_then(|target| inner.build_circuit_to(&target))
+        {
+            Ok(c) => c,
+            Err(_) => {
+                note_failure();
+                continue;
+            }
+        };
+        let client = HsNtorClient::new(&ip.auth_key, &ip.enc_key);
+        let body = cells::introduce1_body(
+            &ip.auth_key,
+            &client,
+            subcredential,
+            cookie,
+            &rp.link_specifiers(),
+            &rp.ntor_onion_key,
+            pow.as_ref(),
+        );
+        let result = circ
+            .send_control(circ.n_hops() - 1, RelayCommand::Introduce1, body)
+            .and_then(|_| wait_for(&circ, RelayCommand::IntroduceAck, REPLY_TIMEOUT))
+            .and_then(|ack| cells::parse_introduce_ack(&ack.data));
+        circ.close();
+        match result {
+            Ok(()) => return Ok(client),
+            Err(e) => {
+                timed_out |= e.kind() == ErrorKind::TimedOut;
+                note_failure();
+            }
+        }
+    }
+    if timed_out {
+        return Err(OnionError::IntroTimedOut.into());
+    }
+    return Err(OnionError::IntroFailed.into());
+}
+
+/// Waits for a relay cell with the command on stream 0.
+fn wait_for(circ: &Circuit, command: RelayCommand, timeout: Duration) -> Result<RelayCell, Error> {
+    let (_, cell) = circ.wait_control(timeout)?;
+    if cell.command != command {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Expected {:?}, got {:?}", command, cell.command),
+        ));
+    }
+    return Ok(cell);
+}
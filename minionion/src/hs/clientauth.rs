@@ -0,0 +1,165 @@
+// Client authorization (rend-spec-v3 2.5.1.2 and appendix G): the inner descriptor layer is
+// additionally keyed with a descriptor cookie, which the middle layer gives each authorized
+// client encrypted to its x25519 key. Keys are kept in files in C tor's format.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::desc::{self, AuthClient};
+use super::OnionAddress;
+use crate::crypto::{self, SyncStreamCipher};
+use crate::encoding;
+
+pub(crate) const DESCRIPTOR_COOKIE_LEN: usize = 16;
+const CLIENT_ID_LEN: usize = 8;
+const COOKIE_KEY_LEN: usize = 32;
+// The number of auth-client lines is padded to a multiple of this with fake ones, as in tor
+const CLIENT_PAD_MULTIPLE: usize = 16;
+const KEY_PREFIX: &str = "descriptor:x25519:";
+
+/// Reads the public keys of authorized clients from the `*.auth` files in the directory,
+/// each holding `descriptor:x25519:<base32 key>`. A missing directory means no client
+/// authorization.
+pub(crate) fn load_authorized_clients(dir: &Path) -> Result<Vec<[u8; 32]>, Error> {
+    let mut keys: Vec<[u8; 32]> = vec![];
+    for contents in read_files(dir, "auth")? {
+        let key = contents
+            .trim()
+            .strip_prefix(KEY_PREFIX)
+            .and_then(decode_key)
+            .ok_or_else(|| invalid(dir))?;
+        keys.push(key);
+    }
+    return Ok(keys);
+}
+
+/// Reads client keys from the `*.auth_private` files in the directory, each holding
+/// `<onion address>:descriptor:x25519:<base32 key>`. Returns them by service identity.
+pub(crate) fn load_client_keys(dir: &Path) -> Result<HashMap<[u8; 32], [u8; 32]>, Error> {
+    let mut keys: HashMap<[u8; 32], [u8; 32]> = HashMap::new();
+    for contents in read_files(dir, "auth_private")? {
+        let contents = contents.trim();
+        let (addr, key) = match contents.find(':') {
+            Some(i) => (&contents[..i], &contents[i + 1..]),
+            None => return Err(invalid(dir)),
+        };
+        // The address is written without .onion
+        let addr = if OnionAddress::is_onion(addr) {
+            OnionAddress::parse(addr)
+        } else {
+            OnionAddress::parse(&format!("{}.onion", addr))
+        };
+        let key = key.strip_prefix(KEY_PREFIX).and_then(decode_key);
+        match (addr, key) {
+            (Ok(addr), Some(key)) => keys.insert(*addr.identity(), key),
+            _ => return Err(invalid(dir)),
+        };
+    }
+    return Ok(keys);
+}
+
+fn read_files(dir: &Path, extension: &str) -> Result<Vec<String>, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut files: Vec<String> = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(extension) {
+            files.push(fs::read_to_string(&path)?);
+        }
+    }
+    return Ok(files);
+}
+
+fn decode_key(s: &str) -> Option<[u8; 32]> {
+    let decoded = encoding::base32_decode(s)?;
+    if decoded.len() != 32 {
+        return None;
+    }
+    let mut key: [u8; 32] = [0x0; 32];
+    key.copy_from_slice(&decoded);
+    return Some(key);
+}
+
+fn invalid(dir: &Path) -> Error {
+    return Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid client authorization key in {}", dir.display()),
+    );
+}
+
+/// CLIENT-ID and COOKIE-KEY for the shared secret of the service's ephemeral key and a
+/// client's key.
+fn client_keys(
+    shared: &[u8; 32],
+    subcredential: &[u8; 32],
+) -> ([u8; CLIENT_ID_LEN], [u8; COOKIE_KEY_LEN]) {
+    let mut input: Vec<u8> = subcredential.to_vec();
+    input.extend_from_slice(shared);
+    let keys = crypto::shake256(&input, CLIENT_ID_LEN + COOKIE_KEY_LEN);
+    let mut client_id: [u8; CLIENT_ID_LEN] = [0x0; CLIENT_ID_LEN];
+    let mut cookie_key: [u8; COOKIE_KEY_LEN] = [0x0; COOKIE_KEY_LEN];
+    client_id.copy_from_slice(&keys[..CLIENT_ID_LEN]);
+    cookie_key.copy_from_slice(&keys[CLIENT_ID_LEN..]);
+    return (client_id, cookie_key);
+}
+
+/// The auth-client lines giving each client the descriptor cookie, padded with fake ones.
+/// Returns them with the ephemeral public key they were made with.
+pub(crate) fn auth_clients(
+    cookie: &[u8; DESCRIPTOR_COOKIE_LEN],
+    clients: &[[u8; 32]],
+    subcredential: &[u8; 32],
+) -> ([u8; 32], Vec<AuthClient>) {
+    let mut seed: [u8; 32] = [0x0; 32];
+    crypto::random_bytes(&mut seed);
+    let ephemeral = StaticSecret::from(seed);
+
+    let mut entries: Vec<AuthClient> = clients
+        .iter()
+        .map(|client| {
+            let shared = ephemeral.diffie_hellman(&PublicKey::from(*client));
+            let (client_id, cookie_key) = client_keys(shared.as_bytes(), subcredential);
+            let mut entry = AuthClient {
+                client_id,
+                iv: [0x0; 16],
+                encrypted_cookie: *cookie,
+            };
+            crypto::random_bytes(&mut entry.iv);
+            crypto::aes256_ctr(&cookie_key, &entry.iv).apply_keystream(&mut entry.encrypted_cookie);
+            entry
+        })
+        .collect();
+    let padded_len =
+        (entries.len() + CLIENT_PAD_MULTIPLE - 1) / CLIENT_PAD_MULTIPLE * CLIENT_PAD_MULTIPLE;
+    let (_, fakes) = desc::fake_auth_clients();
+    entries.extend(fakes.into_iter().take(padded_len - entries.len()));
+    // Sorted, so the position of the real lines tells nothing
+    entries.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+    return (*PublicKey::from(&ephemeral).as_bytes(), entries);
+}
+
+/// Finds the client's auth-client line and decrypts the descriptor cookie from it.
+pub(crate) fn decrypt_cookie(
+    entries: &[AuthClient],
+    ephemeral_key: &[u8; 32],
+    client_secret: &[u8; 32],
+    subcredential: &[u8; 32],
+) -> Option<[u8; DESCRIPTOR_COOKIE_LEN]> {
+    let secret = StaticSecret::from(*client_secret);
+    let shared = secret.diffie_hellman(&PublicKey::from(*ephemeral_key));
+    let (client_id, cookie_key) = client_keys(shared.as_bytes(), subcredential);
+    let entry = entries
+        .iter()
+        .find(|e| crypto::ct_eq(&e.client_id, &client_id))?;
+    let mut cookie = entry.encrypted_cookie;
+    crypto::aes256_ctr(&cookie_key, &entry.iv).apply_keystream(&mut cookie);
+    return Some(cookie);
+}
@@ -0,0 +1,832 @@
+// v3 onion service descriptors (rend-spec-v3 2.4 and 2.5): a signed outer document
+// carrying two encrypted layers, the inner one listing the introduction points.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime};
+
+use super::clientauth::{self, DESCRIPTOR_COOKIE_LEN};
+use super::pow::PowParams;
+use super::OnionError;
+use crate::cell::{encode_link_specifiers, LinkSpecifier, TorCustomFormatCert};
+use crate::crypto::{self, SyncStreamCipher};
+use crate::dir::netdoc::{self, invalid, Item};
+use crate::encoding;
+
+const SIG_PREFIX: &[u8] = b"Tor onion service descriptor sig v3";
+const SUPERENCRYPTED_CONSTANT: &[u8] = b"hsdir-superencrypted-data";
+const ENCRYPTED_CONSTANT: &[u8] = b"hsdir-encrypted-data";
+const SALT_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+const S_KEY_LEN: usize = 32;
+const S_IV_LEN: usize = 16;
+// The middle layer's plaintext is padded to a multiple of this, as in tor
+const PAD_MULTIPLE: usize = 10000;
+// Number of auth-client lines in descriptors of services without client authorization
+const FAKE_AUTH_CLIENTS: usize = 16;
+// HSDirs reject descriptors living longer, as in tor
+const MAX_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+// Certificate types (cert-spec A.1)
+pub(crate) const CERT_TYPE_DESC_SIGNING: u8 = 0x08;
+pub(crate) const CERT_TYPE_INTRO_AUTH: u8 = 0x09;
+pub(crate) const CERT_TYPE_NTOR_ENC: u8 = 0x0b;
+
+/// An introduction point as listed in a descriptor.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IntroPoint {
+    pub(crate) link_specifiers: Vec<LinkSpecifier>,
+    /// The relay's ntor onion key
+    pub(crate) onion_key: [u8; 32],
+    /// The service's Ed25519 key for this introduction point
+    pub(crate) auth_key: [u8; 32],
+    /// The service's x25519 key for the hs-ntor handshake
+    pub(crate) enc_key: [u8; 32],
+}
+
+/// An auth-client line of the middle layer, giving an authorized client the descriptor
+/// cookie encrypted to its key (rend-spec-v3 2.5.1.2).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AuthClient {
+    pub(crate) client_id: [u8; 8],
+    pub(crate) iv: [u8; 16],
+    pub(crate) encrypted_cookie: [u8; DESCRIPTOR_COOKIE_LEN],
+}
+
+/// A descriptor with both layers decrypted.
+#[derive(Debug, Clone)]
+pub(crate) struct HsDescriptor {
+    pub(crate) lifetime: Duration,
+    pub(crate) revision_counter: u64,
+    /// The descriptor signing key, certified by the blinded key
+    pub(crate) signing_key: [u8; 32],
+    /// The service's x25519 key the auth-client lines were made with
+    pub(crate) auth_ephemeral_key: [u8; 32],
+    pub(crate) auth_clients: Vec<AuthClient>,
+    pub(crate) single_onion: bool,
+    /// Parameters of the proof-of-work defense, if the service enabled it
+    pub(crate) pow_params: Option<PowParams>,
+    pub(crate) intro_points: Vec<IntroPoint>,
+}
+
+impl HsDescriptor {
+    /// Checks the signatures of a descriptor fetched for the blinded key and decrypts it.
+    /// `client_secret` is our x25519 key for services with client authorization; failing
+    /// to decrypt the inner layer is reported as a missing or wrong key.
+    pub(crate) fn decode(
+        doc: &str,
+        blinded: &[u8; 32],
+        subcredential: &[u8; 32],
+        client_secret: Option<&[u8; 32]>,
+        now: SystemTime,
+    ) -> Result<HsDescriptor, Error> {
+        let items = netdoc::parse_items(doc)?;
+        match items.first() {
+            Some(i) if i.keyword == "hs-descriptor" && i.arg(0)? == "3" => (),
+            _ => return Err(invalid("Descriptor must start with hs-descriptor 3")),
+        }
+        let sig_item = match items.last() {
+            Some(i) if i.keyword == "signature" => i,
+            _ => return Err(invalid("Descriptor must end with a signature")),
+        };
+
+        let mut lifetime: Option<Duration> = None;
+        let mut signing_key: Option<[u8; 32]> = None;
+        let mut revision_counter: Option<u64> = None;
+        let mut superencrypted: Option<&[u8]> = None;
+        for item in &items[1..items.len() - 1] {
+            match item.keyword {
+                "descriptor-lifetime" => {
+                    let minutes = item
+                        .arg(0)?
+                        .parse::<u64>()
+                        .map_err(|_| invalid("Invalid descriptor-lifetime"))?;
+                    let secs = match minutes.checked_mul(60) {
+                        Some(s) if s > 0 && s <= MAX_LIFETIME.as_secs() => s,
+                        _ => return Err(invalid("Invalid descriptor-lifetime")),
+                    };
+                    lifetime = Some(Duration::from_secs(secs));
+                }
+                "descriptor-signing-key-cert" => {
+                    let cert = parse_cert(item, CERT_TYPE_DESC_SIGNING, blinded, now)?;
+                    signing_key = Some(*cert.certified_key());
+                }
+                "revision-counter" => {
+                    revision_counter = Some(
+                        item.arg(0)?
+                            .parse::<u64>()
+                            .map_err(|_| invalid("Invalid revision-counter"))?,
+                    );
+                }
+                "superencrypted" => superencrypted = Some(item.object("MESSAGE")?),
+                _ => (),
+            }
+        }
+        let signing_key = signing_key.ok_or_else(|| invalid("Descriptor lacks signing key"))?;
+        let revision_counter =
+            revision_counter.ok_or_else(|| invalid("Descriptor lacks revision-counter"))?;
+
+        let mut signed: Vec<u8> = SIG_PREFIX.to_vec();
+        signed.extend_from_slice(doc[..sig_item.start].as_bytes());
+        let sig = decode_base64(sig_item.arg(0)?, 64)?;
+        if !crypto::ed25519_verify(&signing_key, &signed, &sig) {
+            return Err(invalid("Invalid descriptor signature"));
+        }
+
+        let middle = decrypt_layer(
+            superencrypted.ok_or_else(|| invalid("Descriptor lacks superencrypted"))?,
+            blinded,
+            subcredential,
+            revision_counter,
+            SUPERENCRYPTED_CONSTANT,
+        )?;
+        let middle_items = netdoc::parse_items(&middle)?;
+        let (auth_ephemeral_key, auth_clients, encrypted) = parse_middle(&middle_items)?;
+        let cookie = client_secret.and_then(|k| {
+            clientauth::decrypt_cookie(&auth_clients, &auth_ephemeral_key, k, subcredential)
+        });
+        let inner = decrypt_layer(
+            encrypted,
+            &inner_secret_data(blinded, cookie.as_ref()),
+            subcredential,
+            revision_counter,
+            ENCRYPTED_CONSTANT,
+        );
+        let inner = match (inner, client_secret) {
+            (Ok(i), _) => i,
+            (Err(_), Some(_)) => return Err(OnionError::ClientAuthIncorrect.into()),
+            (Err(_), None) => return Err(OnionError::ClientAuthMissing.into()),
+        };
+
+        let mut desc = HsDescriptor {
+            lifetime: lifetime.ok_or_else(|| invalid("Descriptor lacks descriptor-lifetime"))?,
+            revision_counter,
+            signing_key,
+            auth_ephemeral_key,
+            auth_clients,
+            single_onion: false,
+            pow_params: None,
+            intro_points: vec![],
+        };
+        desc.parse_inner(&inner, now)?;
+        return Ok(desc);
+    }
+
+    /// Encodes the descriptor for publication, encrypting both layers and signing it
+    /// (service side). `signing_secret` is the expanded secret key of `signing_key`, and
+    /// `blinded_secret` that of the blinded key certifying it. Certificates expire at `expiration`.
+    /// With client authorization, the inner layer is keyed with the descriptor cookie as well.
+    pub(crate) fn encode(
+        &self,
+        blinded: &[u8; 32],
+        blinded_secret: &[u8; 64],
+        signing_secret: &[u8; 64],
+        subcredential: &[u8; 32],
+        cookie: Option<&[u8; DESCRIPTOR_COOKIE_LEN]>,
+        expiration: SystemTime,
+    ) -> Result<String, Error> {
+        let inner = self.encode_inner(signing_secret, expiration)?;
+        let encrypted = encrypt_layer(
+            inner.as_bytes(),
+            &inner_secret_data(blinded, cookie),
+            subcredential,
+            self.revision_counter,
+            ENCRYPTED_CONSTANT,
+        );
+
+        let mut middle = String::from("desc-auth-type x25519\n");
+        middle.push_str(&format!(
+            "desc-auth-ephemeral-key {}\n",
+            encoding::base64_encode(&self.auth_ephemeral_key)
+        ));
+        for client in &self.auth_clients {
+            middle.push_str(&format!(
+                "auth-client {} {} {}\n",
+                encoding::base64_encode(&client.client_id).trim_end_matches('='),
+                encoding::base64_encode(&client.iv).trim_end_matches('='),
+                encoding::base64_encode(&client.encrypted_cookie).trim_end_matches('=')
+            ));
+        }
+        middle.push_str("encrypted\n");
+        middle.push_str(&netdoc::format_object("MESSAGE", &encrypted));
+        let mut middle = middle.into_bytes();
+        let padded_len = (middle.len() + PAD_MULTIPLE - 1) / PAD_MULTIPLE * PAD_MULTIPLE;
+        middle.resize(padded_len, 0);
+        let superencrypted = encrypt_layer(
+            &middle,
+            blinded,
+            subcredential,
+            self.revision_counter,
+            SUPERENCRYPTED_CONSTANT,
+        );
+
+        let cert = TorCustomFormatCert::sign(
+            CERT_TYPE_DESC_SIGNING,
+            expiration,
+            &self.signing_key,
+            blinded,
+            blinded_secret,
+        );
+        let mut doc = String::from("hs-descriptor 3\n");
+        doc.push_str(&format!(
+            "descriptor-lifetime {}\n",
+            self.lifetime.as_secs() / 60
+        ));
+        doc.push_str("descriptor-signing-key-cert\n");
+        doc.push_str(&netdoc::format_object("ED25519 CERT", &cert));
+        doc.push_str(&format!("revision-counter {}\n", self.revision_counter));
+        doc.push_str("superencrypted\n");
+        doc.push_str(&netdoc::format_object("MESSAGE", &superencrypted));
+
+        let mut signed: Vec<u8> = SIG_PREFIX.to_vec();
+        signed.extend_from_slice(doc.as_bytes());
+        let sig = crypto::ed25519_sign(signing_secret, &self.signing_key, &signed);
+        doc.push_str(&format!(
+            "signature {}\n",
+            encoding::base64_encode(&sig).trim_end_matches('=')
+        ));
+        return Ok(doc);
+    }
+
+    fn encode_inner(
+        &self,
+        signing_secret: &[u8; 64],
+        expiration: SystemTime,
+    ) -> Result<String, Error> {
+        let mut doc = String::from("create2-formats 2\n");
+        if self.single_onion {
+            doc.push_str("single-onion-service\n");
+        }
+        if let Some(params) = &self.pow_params {
+            doc.push_str(&params.format());
+        }
+        for ip in &self.intro_points {
+            doc.push_str(&format!(
+                "introduction-point {}\n",
+                encoding::base64_encode(&encode_link_specifiers(&ip.link_specifiers))
+            ));
+            doc.push_str(&format!(
+                "onion-key ntor {}\n",
+                encoding::base64_encode(&ip.onion_key)
+            ));
+            let auth_cert = TorCustomFormatCert::sign(
+                CERT_TYPE_INTRO_AUTH,
+                expiration,
+                &ip.auth_key,
+                &self.signing_key,
+                signing_secret,
+            );
+            doc.push_str("auth-key\n");
+            doc.push_str(&netdoc::format_object("ED25519 CERT", &auth_cert));
+            doc.push_str(&format!(
+                "enc-key ntor {}\n",
+                encoding::base64_encode(&ip.enc_key)
+            ));
+            // The x25519 key is certified in its Ed25519 form with sign bit 0
+            let enc_key_ed = match crypto::x25519_to_ed25519(&ip.enc_key) {
+                Some(k) => k,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Introduction point encryption key has no Ed25519 form",
+                    ))
+                }
+            };
+            let enc_cert = TorCustomFormatCert::sign(
+                CERT_TYPE_NTOR_ENC,
+                expiration,
+                &enc_key_ed,
+                &self.signing_key,
+                signing_secret,
+            );
+            doc.push_str("enc-key-cert\n");
+            doc.push_str(&netdoc::format_object("ED25519 CERT", &enc_cert));
+        }
+        return Ok(doc);
+    }
+
+    fn parse_inner(&mut self, doc: &str, now: SystemTime) -> Result<(), Error> {
+        let items = netdoc::parse_items(doc)?;
+        match items.first() {
+            Some(i) if i.keyword == "create2-formats" => {
+                if !i.args.contains(&"2") {
+                    return Err(invalid("Service doesn't support the ntor handshake"));
+                }
+            }
+            _ => return Err(invalid("Encrypted layer must start with create2-formats")),
+        }
+        let starts: Vec<usize> = items
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| i.keyword == "introduction-point")
+            .map(|(n, _)| n)
+            .collect();
+        let first_intro = *starts.first().unwrap_or(&items.len());
+        for item in &items[1..first_intro] {
+            match item.keyword {
+                "single-onion-service" => self.single_onion = true,
+                "pow-params" if self.pow_params.is_none() => {
+                    self.pow_params = PowParams::parse(item)?;
+                }
+                _ => (),
+            }
+        }
+
+        for (n, first) in starts.iter().enumerate() {
+            let last = *starts.get(n + 1).unwrap_or(&items.len());
+            let specs = encoding::base64_decode(items[*first].arg(0)?)
+                .ok_or_else(|| invalid("Invalid introduction-point"))?;
+            let (link_specifiers, _) = LinkSpecifier::decode_list(&specs)?;
+            let mut onion_key: Option<[u8; 32]> = None;
+            let mut auth_key: Option<[u8; 32]> = None;
+            let mut enc_key: Option<[u8; 32]> = None;
+            let mut enc_key_certified = false;
+            for item in &items[*first + 1..last] {
+                match item.keyword {
+                    "onion-key" if item.arg(0)? == "ntor" => {
+                        onion_key = Some(decode_key(item.arg(1)?)?);
+                    }
+                    "auth-key" => {
+                        let cert = parse_cert(item, CERT_TYPE_INTRO_AUTH, &self.signing_key, now)?;
+                        auth_key = Some(*cert.certified_key());
+                    }
+                    "enc-key" if item.arg(0)? == "ntor" => {
+                        enc_key = Some(decode_key(item.arg(1)?)?);
+                    }
+                    "enc-key-cert" => {
+                        parse_cert(item, CERT_TYPE_NTOR_ENC, &self.signing_key, now)?;
+                        enc_key_certified = true;
+                    }
+                    _ => (),
+                }
+            }
+            match (onion_key, auth_key, enc_key, enc_key_certified) {
+                (Some(onion_key), Some(auth_key), Some(enc_key), true) => {
+                    self.intro_points.push(IntroPoint {
+                        link_specifiers,
+                        onion_key,
+                        auth_key,
+                        enc_key,
+                    })
+                }
+                _ => return Err(invalid("Incomplete introduction point")),
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Random auth-client lines for services without client authorization, so descriptors
+/// don't tell whether a service restricts its clients. Returns them with a random
+/// ephemeral key.
+pub(crate) fn fake_auth_clients() -> ([u8; 32], Vec<AuthClient>) {
+    let mut ephemeral_key: [u8; 32] = [0x0; 32];
+    crypto::random_bytes(&mut ephemeral_key);
+    let clients = (0..FAKE_AUTH_CLIENTS)
+        .map(|_| {
+            let mut client = AuthClient {
+                client_id: [0x0; 8],
+                iv: [0x0; 16],
+                encrypted_cookie: [0x0; DESCRIPTOR_COOKIE_LEN],
+            };
+            crypto::random_bytes(&mut client.client_id);
+            crypto::random_bytes(&mut client.iv);
+            crypto::random_bytes(&mut client.encrypted_cookie);
+            client
+        })
+        .collect();
+    return (ephemeral_key, clients);
+}
+
+/// Parses the decrypted middle layer into the ephemeral key, the auth-client lines and the
+/// encrypted inner layer.
+fn parse_middle<'a>(items: &'a [Item]) -> Result<([u8; 32], Vec<AuthClient>, &'a [u8]), Error> {
+    match items.first() {
+        Some(i) if i.keyword == "desc-auth-type" && i.arg(0)? == "x25519" => (),
+        _ => {
+            return Err(invalid(
+                "Middle layer must start with desc-auth-type x25519",
+            ))
+        }
+    }
+    let mut ephemeral_key: Option<[u8; 32]> = None;
+    let mut clients: Vec<AuthClient> = vec![];
+    let mut encrypted: Option<&'a [u8]> = None;
+    for item in &items[1..] {
+        match item.keyword {
+            "desc-auth-ephemeral-key" => ephemeral_key = Some(decode_key(item.arg(0)?)?),
+            "auth-client" => clients.push(AuthClient {
+                client_id: decode_base64(item.arg(0)?, 8)?
+                    .as_slice()
+                    .try_into()
+                    .unwrap(),
+                iv: decode_base64(item.arg(1)?, 16)?
+                    .as_slice()
+                    .try_into()
+                    .unwrap(),
+                encrypted_cookie: decode_base64(item.arg(2)?, DESCRIPTOR_COOKIE_LEN)?
+                    .as_slice()
+                    .try_into()
+                    .unwrap(),
+            }),
+            "encrypted" => encrypted = Some(item.object("MESSAGE")?),
+            _ => (),
+        }
+    }
+    return match (ephemeral_key, encrypted) {
+        (Some(k), Some(e)) if !clients.is_empty() => Ok((k, clients, e)),
+        _ => Err(invalid("Incomplete middle layer")),
+    };
+}
+
+/// SECRET_DATA of the inner layer: the blinded key, followed by the descriptor cookie
+/// with client authorization.
+fn inner_secret_data(blinded: &[u8; 32], cookie: Option<&[u8; DESCRIPTOR_COOKIE_LEN]>) -> Vec<u8> {
+    let mut secret_data = blinded.to_vec();
+    if let Some(c) = cookie {
+        secret_data.extend_from_slice(c);
+    }
+    return secret_data;
+}
+
+/// Parses and checks a certificate object signed by the given key.
+fn parse_cert(
+    item: &Item,
+    cert_type: u8,
+    signed_by: &[u8; 32],
+    now: SystemTime,
+) -> Result<TorCustomFormatCert, Error> {
+    let cert = TorCustomFormatCert::from_bytes(item.object("ED25519 CERT")?)?;
+    if cert.cert_type() != cert_type {
+        return Err(invalid(&format!(
+            "Wrong certificate type in \"{}\"",
+            item.keyword
+        )));
+    }
+    if cert.signing_key() != Some(signed_by) || !cert.verify(signed_by) {
+        return Err(invalid(&format!(
+            "Invalid certificate in \"{}\"",
+            item.keyword
+        )));
+    }
+    if cert.is_expired(now) {
+        return Err(invalid(&format!(
+            "Expired certificate in \"{}\"",
+            item.keyword
+        )));
+    }
+    return Ok(cert);
+}
+
+fn decode_base64(s: &str, len: usize) -> Result<Vec<u8>, Error> {
+    return match encoding::base64_decode(s) {
+        Some(d) if d.len() == len => Ok(d),
+        _ => Err(invalid(&format!("Invalid base64 value: {}", s))),
+    };
+}
+
+fn decode_key(s: &str) -> Result<[u8; 32], Error> {
+    return Ok(decode_base64(s, 32)?.as_slice().try_into().unwrap());
+}
+
+/// Derives the key, IV and MAC key of a layer (rend-spec-v3 2.5.3).
+fn layer_keys(
+    secret_data: &[u8],
+    subcredential: &[u8; 32],
+    revision_counter: u64,
+    salt: &[u8],
+    constant: &[u8],
+) -> Vec<u8> {
+    let mut input: Vec<u8> = secret_data.to_vec();
+    input.extend_from_slice(subcredential);
+    input.extend_from_slice(&revision_counter.to_be_bytes());
+    input.extend_from_slice(salt);
+    input.extend_from_slice(constant);
+    return crypto::shake256(&input, S_KEY_LEN + S_IV_LEN + MAC_LEN);
+}
+
+fn layer_mac(mac_key: &[u8], salt: &[u8], encrypted: &[u8]) -> [u8; 32] {
+    let mut input: Vec<u8> = (mac_key.len() as u64).to_be_bytes().to_vec();
+    input.extend_from_slice(mac_key);
+    input.extend_from_slice(&(salt.len() as u64).to_be_bytes());
+    input.extend_from_slice(salt);
+    input.extend_from_slice(encrypted);
+    return crypto::sha3_256(&input);
+}
+
+/// Encrypts a layer into SALT | ENCRYPTED | MAC with a random salt.
+pub(crate) fn encrypt_layer(
+    plaintext: &[u8],
+    secret_data: &[u8],
+    subcredential: &[u8; 32],
+    revision_counter: u64,
+    constant: &[u8],
+) -> Vec<u8> {
+    let mut salt: [u8; SALT_LEN] = [0x0; SALT_LEN];
+    crypto::random_bytes(&mut salt);
+    let keys = layer_keys(
+        secret_data,
+        subcredential,
+        revision_counter,
+        &salt,
+        constant,
+    );
+    let mut encrypted = plaintext.to_vec();
+    crypto::aes256_ctr(&keys[..S_KEY_LEN], &keys[S_KEY_LEN..S_KEY_LEN + S_IV_LEN])
+        .apply_keystream(&mut encrypted);
+    let mac = layer_mac(&keys[S_KEY_LEN + S_IV_LEN..], &salt, &encrypted);
+    let mut out = salt.to_vec();
+    out.extend_from_slice(&encrypted);
+    out.extend_from_slice(&mac);
+    return out;
+}
+
+/// Decrypts SALT | ENCRYPTED | MAC. The padding after the plaintext is removed.
+pub(crate) fn decrypt_layer(
+    blob: &[u8],
+    secret_data: &[u8],
+    subcredential: &[u8; 32],
+    revision_counter: u64,
+    constant: &[u8],
+) -> Result<String, Error> {
+    if blob.len() < SALT_LEN + MAC_LEN {
+        return Err(invalid("Encrypted descriptor layer too short"));
+    }
+    let salt = &blob[..SALT_LEN];
+    let encrypted = &blob[SALT_LEN..blob.len() - MAC_LEN];
+    let keys = layer_keys(secret_data, subcredential, revision_counter, salt, constant);
+    let (key, iv, mac_key) = (
+        &keys[..S_KEY_LEN],
+        &keys[S_KEY_LEN..S_KEY_LEN + S_IV_LEN],
+        &keys[S_KEY_LEN + S_IV_LEN..],
+    );
+    if !crypto::ct_eq(
+        &layer_mac(mac_key, salt, encrypted),
+        &blob[blob.len() - MAC_LEN..],
+    ) {
+        return Err(invalid("Descriptor layer MAC mismatch"));
+    }
+    let mut plaintext = encrypted.to_vec();
+    crypto::aes256_ctr(key, iv).apply_keystream(&mut plaintext);
+    while plaintext.last() == Some(&0) {
+        plaintext.pop();
+    }
+    return String::from_utf8(plaintext).map_err(|_| invalid("Descriptor layer is not UTF-8"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::hex_decode;
+    use crate::hs::keys::{self, TimePeriod};
+    use std::time::UNIX_EPOCH;
+
+    // Descriptors generated by C tor, from arti's tor-netdoc. The second one requires
+    // client authorization.
+    const DESC: &str = include_str!("../../testdata/hsdesc1.txt");
+    const DESC_AUTH: &str = include_str!("../../testdata/hsdesc2.txt");
+
+    fn key(hex: &str) -> [u8; 32] {
+        let mut out: [u8; 32] = [0x0; 32];
+        out.copy_from_slice(&hex_decode(hex).unwrap());
+        return out;
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        return UNIX_EPOCH + Duration::from_secs(secs);
+    }
+
+    // 2023-01-24 03:00:00 UTC
+    fn decode_plain() -> HsDescriptor {
+        return HsDescriptor::decode(
+            DESC,
+            &key("43cc0d62fc6252f578705ca645a46109e265290343b1137e90189744b20b3f2d"),
+            &key("78210A0D2C72BB7A0CAF606BCD938B9A3696894FDDDBC3B87D424753A7E3DF37"),
+            None,
+            at(1674529200),
+        )
+        .unwrap();
+    }
+
+    // The client's x25519 key and the blinded key and subcredential for 2023-02-09 12:00:00 UTC
+    fn auth_keys() -> ([u8; 32], [u8; 32], [u8; 32], [u8; 32]) {
+        let identity = key("781D978CE6CE9CAA8BCA306F53E82D2C993E5C91346625F1C151DCFC56D753D3");
+        let period = TimePeriod::at(at(1675944000), 24 * 60);
+        let blinded = keys::blind_public_key(&identity, period).unwrap();
+        assert_eq!(
+            blinded,
+            key("706628758208395D461AA0F460A5E76E7B828C66B5E794768592B451302E961D")
+        );
+        let subcredential = keys::subcredential(&identity, &blinded);
+        assert_eq!(
+            subcredential,
+            key("24A133E905102BDA9A6AFE57F901366A1B8281865A91F1FE0853E4B50CC8B070")
+        );
+        return (
+            key("900467008E194C2C635A6E335E7724915E558CCB606C50094476F9731C129019"),
+            key("90F2D60F917F2423F0989CE9979639435A5B01CF7AB3E0A1B900BEB892BAE443"),
+            blinded,
+            subcredential,
+        );
+    }
+
+    // Keys of a made up service: the blinded key, its secret and the subcredential
+    fn service_keys(now: SystemTime) -> ([u8; 32], [u8; 64], [u8; 32]) {
+        let (expanded, identity) = crypto::ed25519_expand(&[0x42; 32]);
+        let period = TimePeriod::at(now, 24 * 60);
+        let blinded = keys::blind_public_key(&identity, period).unwrap();
+        let blinded_secret = keys::blind_secret_key(&expanded, &identity, period);
+        return (
+            blinded,
+            blinded_secret,
+            keys::subcredential(&identity, &blinded),
+        );
+    }
+
+    // Encodes the descriptor as the made up service would.
+    fn reencode(
+        desc: &HsDescriptor,
+        cookie: Option<&[u8; DESCRIPTOR_COOKIE_LEN]>,
+        now: SystemTime,
+    ) -> String {
+        let (blinded, blinded_secret, subcredential) = service_keys(now);
+        let (signing_secret, signing_key) = crypto::ed25519_generate();
+        let mut desc = desc.clone();
+        desc.signing_key = signing_key;
+        return desc
+            .encode(
+                &blinded,
+                &blinded_secret,
+                &signing_secret,
+                &subcredential,
+                cookie,
+                now + Duration::from_secs(24 * 60 * 60),
+            )
+            .unwrap();
+    }
+
+    // The decrypted middle and inner layers of a descriptor without client authorization.
+    fn layers(doc: &str, blinded: &[u8; 32], subcredential: &[u8; 32]) -> (String, String) {
+        let items = netdoc::parse_items(doc).unwrap();
+        let find = |keyword| items.iter().find(|i| i.keyword == keyword).unwrap();
+        let revision_counter = find("revision-counter").arg(0).unwrap().parse().unwrap();
+        let middle = decrypt_layer(
+            find("superencrypted").object("MESSAGE").unwrap(),
+            blinded,
+            subcredential,
+            revision_counter,
+            SUPERENCRYPTED_CONSTANT,
+        )
+        .unwrap();
+        let middle_items = netdoc::parse_items(&middle).unwrap();
+        let (_, _, encrypted) = parse_middle(&middle_items).unwrap();
+        let inner = decrypt_layer(
+            encrypted,
+            &inner_secret_data(blinded, None),
+            subcredential,
+            revision_counter,
+            ENCRYPTED_CONSTANT,
+        )
+        .unwrap();
+        return (middle, inner);
+    }
+
+    // Drops the objects, which are encrypted or signed anew, and lines we don't write.
+    fn without_objects(doc: &str) -> Vec<&str> {
+        let mut out = vec![];
+        let mut in_object = false;
+        for line in doc.lines() {
+            if line.starts_with("-----BEGIN") {
+                in_object = true;
+            } else if line.starts_with("-----END") {
+                in_object = false;
+            } else if !in_object && !line.starts_with("flow-control") {
+                out.push(line);
+            }
+        }
+        return out;
+    }
+
+    #[test]
+    fn decode_c_tor() {
+        let desc = decode_plain();
+        assert_eq!(desc.lifetime, Duration::from_secs(180 * 60));
+        assert_eq!(desc.revision_counter, 19655750);
+        assert_eq!(desc.auth_clients.len(), FAKE_AUTH_CLIENTS);
+        assert!(!desc.single_onion);
+        assert!(desc.pow_params.is_none());
+        assert_eq!(desc.intro_points.len(), 3);
+        assert_eq!(
+            desc.intro_points[0].onion_key,
+            key("553BF9F9E1979D6F5D5D7D20BB3FE7272E32E22B6E86E35C76A7CA8A377E402F")
+        );
+
+        // Expired certificates and the wrong blinded key are rejected
+        let blinded = key("43cc0d62fc6252f578705ca645a46109e265290343b1137e90189744b20b3f2d");
+        let subcredential = key("78210A0D2C72BB7A0CAF606BCD938B9A3696894FDDDBC3B87D424753A7E3DF37");
+        assert!(
+            HsDescriptor::decode(DESC, &blinded, &subcredential, None, at(1_800_000_000)).is_err()
+        );
+        assert!(
+            HsDescriptor::decode(DESC, &[0x0c; 32], &subcredential, None, at(1674529200)).is_err()
+        );
+        // A lifetime overflowing in seconds is rejected rather than panicking. It's parsed
+        // before the signature is checked, so the altered descriptor needn't be signed anew.
+        let huge = DESC.replacen(
+            "descriptor-lifetime 180",
+            "descriptor-lifetime 307445734561825861",
+            1,
+        );
+        assert!(
+            HsDescriptor::decode(&huge, &blinded, &subcredential, None, at(1674529200)).is_err()
+        );
+    }
+
+    #[test]
+    fn decode_c_tor_client_auth() {
+        let (_, client_secret, blinded, subcredential) = auth_keys();
+        let now = at(1675944000);
+        let err = HsDescriptor::decode(DESC_AUTH, &blinded, &subcredential, None, now).unwrap_err();
+        assert_eq!(
+            OnionError::from_error(&err),
+            Some(OnionError::ClientAuthMissing)
+        );
+        let err = HsDescriptor::decode(DESC_AUTH, &blinded, &subcredential, Some(&[0x1; 32]), now)
+            .unwrap_err();
+        assert_eq!(
+            OnionError::from_error(&err),
+            Some(OnionError::ClientAuthIncorrect)
+        );
+        let desc = HsDescriptor::decode(
+            DESC_AUTH,
+            &blinded,
+            &subcredential,
+            Some(&client_secret),
+            now,
+        )
+        .unwrap();
+        assert_eq!(desc.intro_points.len(), 3);
+    }
+
+    #[test]
+    fn reencode_c_tor() {
+        let now = at(1674529200);
+        let desc = decode_plain();
+        let doc = reencode(&desc, None, now);
+        let (blinded, _, subcredential) = service_keys(now);
+        let decoded = HsDescriptor::decode(&doc, &blinded, &subcredential, None, now).unwrap();
+        assert_eq!(decoded.lifetime, desc.lifetime);
+        assert_eq!(decoded.revision_counter, desc.revision_counter);
+        assert_eq!(decoded.auth_ephemeral_key, desc.auth_ephemeral_key);
+        assert_eq!(decoded.auth_clients, desc.auth_clients);
+        assert_eq!(decoded.intro_points, desc.intro_points);
+
+        // Apart from signatures and encryption, we write what C tor wrote
+        let (c_tor_middle, c_tor_inner) = layers(
+            DESC,
+            &key("43cc0d62fc6252f578705ca645a46109e265290343b1137e90189744b20b3f2d"),
+            &key("78210A0D2C72BB7A0CAF606BCD938B9A3696894FDDDBC3B87D424753A7E3DF37"),
+        );
+        let (middle, inner) = layers(&doc, &blinded, &subcredential);
+        assert_eq!(without_objects(&middle), without_objects(&c_tor_middle));
+        assert_eq!(without_objects(&inner), without_objects(&c_tor_inner));
+        let outer = without_objects(&doc);
+        assert_eq!(
+            outer[..outer.len() - 1],
+            without_objects(DESC)[..outer.len() - 1]
+        );
+    }
+
+    #[test]
+    fn reencode_c_tor_client_auth() {
+        let (client_key, client_secret, c_tor_blinded, c_tor_subcredential) = auth_keys();
+        let now = at(1675944000);
+        let mut desc = HsDescriptor::decode(
+            DESC_AUTH,
+            &c_tor_blinded,
+            &c_tor_subcredential,
+            Some(&client_secret),
+            now,
+        )
+        .unwrap();
+
+        // Authorize the same client for the made up service
+        let (blinded, _, subcredential) = service_keys(now);
+        let mut cookie: [u8; DESCRIPTOR_COOKIE_LEN] = [0x0; DESCRIPTOR_COOKIE_LEN];
+        crypto::random_bytes(&mut cookie);
+        let (ephemeral, clients) = clientauth::auth_clients(&cookie, &[client_key], &subcredential);
+        desc.auth_ephemeral_key = ephemeral;
+        desc.auth_clients = clients;
+        let doc = reencode(&desc, Some(&cookie), now);
+
+        let err = HsDescriptor::decode(&doc, &blinded, &subcredential, None, now).unwrap_err();
+        assert_eq!(
+            OnionError::from_error(&err),
+            Some(OnionError::ClientAuthMissing)
+        );
+        let decoded =
+            HsDescriptor::decode(&doc, &blinded, &subcredential, Some(&client_secret), now)
+                .unwrap();
+        assert_eq!(decoded.auth_clients, desc.auth_clients);
+        assert_eq!(decoded.intro_points, desc.intro_points);
+    }
+}
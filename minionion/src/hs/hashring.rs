@@ -0,0 +1,145 @@
+// The HSDir hash ring (rend-spec-v3 2.2.3): which relays store the descriptors of a
+// blinded key in a time period.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::keys::TimePeriod;
+use crate::crypto;
+use crate::dir::{Consensus, NetDir, Relay, RelayFlags};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many HSDirs to ask for a descriptor, per replica (hsdir_spread_fetch).
+pub fn spread_fetch(consensus: &Consensus) -> usize {
+    return consensus.param("hsdir_spread_fetch", 3, 1, 128) as usize;
+}
+
+/// How many HSDirs to upload a descriptor to, per replica (hsdir_spread_store).
+pub fn spread_store(consensus: &Consensus) -> usize {
+    return consensus.param("hsdir_spread_store", 4, 1, 128) as usize;
+}
+
+/// The shared random value the hash ring is built with in the time period. It's the one
+/// computed at the midnight before the period started, which is the current value in the first
+/// half of the period and the previous one in the second half. If the consensus lacks it,
+/// the disaster value is used, as in tor.
+pub fn shared_random_value(consensus: &Consensus, period: TimePeriod) -> [u8; 32] {
+    let day_of = |t: std::time::SystemTime| {
+        t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / DAY.as_secs()
+    };
+    let today = day_of(consensus.valid_after);
+    let srv = match day_of(period.start()) {
+        d if d == today => consensus.shared_rand_current,
+        d if d + 1 == today => consensus.shared_rand_previous,
+        _ => None,
+    };
+    if let Some((_, value)) = srv {
+        return value;
+    }
+    return disaster_srv(period);
+}
+
+/// The shared random value used when the consensus has none (rend-spec-v3 2.2.3.1).
+fn disaster_srv(period: TimePeriod) -> [u8; 32] {
+    let mut input: Vec<u8> = b"shared-random-disaster".to_vec();
+    input.extend_from_slice(&period.length.to_be_bytes());
+    input.extend_from_slice(&period.number.to_be_bytes());
+    return crypto::sha3_256(&input);
+}
+
+/// A relay's position on the ring.
+pub fn hsdir_index(
+    ed25519_identity: &[u8; 32],
+    shared_random: &[u8; 32],
+    period: TimePeriod,
+) -> [u8; 32] {
+    let mut input: Vec<u8> = b"node-idx".to_vec();
+    input.extend_from_slice(ed25519_identity);
+    input.extend_from_slice(shared_random);
+    input.extend_from_slice(&period.number.to_be_bytes());
+    input.extend_from_slice(&period.length.to_be_bytes());
+    return crypto::sha3_256(&input);
+}
+
+/// Where the replica of a descriptor is stored on the ring. Replicas count from 1.
+pub fn hs_index(blinded: &[u8; 32], replica: u64, period: TimePeriod) -> [u8; 32] {
+    let mut input: Vec<u8> = b"store-at-idx".to_vec();
+    input.extend_from_slice(blinded);
+    input.extend_from_slice(&replica.to_be_bytes());
+    input.extend_from_slice(&period.length.to_be_bytes());
+    input.extend_from_slice(&period.number.to_be_bytes());
+    return crypto::sha3_256(&input);
+}
+
+/// The HSDirs responsible for the blinded key: for each replica, the first `spread` relays
+/// following its position on the ring, skipping relays already picked for another replica.
+pub fn responsible_hsdirs<'a>(
+    netdir: &'a NetDir,
+    blinded: &[u8; 32],
+    period: TimePeriod,
+    spread: usize,
+) -> Vec<Relay<'a>> {
+    let srv = shared_random_value(&netdir.consensus, period);
+    let mut ring: Vec<([u8; 32], Relay<'a>)> = netdir
+        .relays()
+        .into_iter()
+        .filter(|r| r.has_flags(RelayFlags::HSDIR) && r.rs.supports_protocol("HSDir", 2))
+        .filter_map(|r| Some((hsdir_index(&r.md.ed25519_identity?, &srv, period), r)))
+        .collect();
+    ring.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let replicas = netdir.consensus.param("hsdir_n_replicas", 2, 1, 16) as u64;
+    let mut picked: Vec<Relay<'a>> = vec![];
+    for replica in 1..=replicas {
+        let index = hs_index(blinded, replica, period);
+        let start = ring.iter().position(|(i, _)| *i >= index).unwrap_or(0);
+        let mut added = 0;
+        for n in 0..ring.len() {
+            if added == spread {
+                break;
+            }
+            let relay = ring[(start + n) % ring.len()].1;
+            if !picked.iter().any(|p| p.identity() == relay.identity()) {
+                picked.push(relay);
+                added += 1;
+            }
+        }
+    }
+    return picked;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::hex_decode;
+
+    const PERIOD: TimePeriod = TimePeriod {
+        number: 42,
+        length: 24 * 60,
+    };
+
+    // From C tor's test_hs_common.c
+    #[test]
+    fn indexes() {
+        assert_eq!(
+            hs_index(&[0x42; 32], 1, PERIOD).to_vec(),
+            hex_decode("37e5cbbd56a22823714f18f1623ece5983a0d64c78495a8cfab854245e5f9a8a").unwrap()
+        );
+        assert_eq!(
+            hsdir_index(&[0x42; 32], &[0x43; 32], PERIOD).to_vec(),
+            hex_decode("db475361014a09965e7e5e4d4a25b8f8d4b8f16cb1d8a7e95eed50249cc1a2d5").unwrap()
+        );
+    }
+
+    #[test]
+    fn disaster() {
+        let period = TimePeriod {
+            number: 1,
+            length: 24 * 60,
+        };
+        assert_eq!(
+            disaster_srv(period).to_vec(),
+            hex_decode("F8A4948707653837FA44ABB5BBC75A12F6F101E7F8FAF699B9715F4965D3507D").unwrap()
+        );
+    }
+}
@@ -0,0 +1,206 @@
+// Time periods and key blinding (rend-spec-v3 2.2.1 and appendix A.2). Descriptors are
+// stored under a blinded key that changes every time period, so HSDirs can't tell which
+// service they belong to.
+
+use std::io::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+
+use super::OnionError;
+use crate::crypto;
+use crate::dir::Consensus;
+
+// Time periods start this long after midnight UTC, halfway between shared random values
+const ROTATION_TIME_OFFSET: Duration = Duration::from_secs(12 * 60 * 60);
+// Default time period length in minutes (hsdir_interval)
+const DEFAULT_PERIOD_LENGTH: u64 = 24 * 60;
+const BLIND_STRING: &[u8] = b"Derive temporary signing key\0";
+// The Ed25519 base point, written out as the spec hashes it
+const ED25519_BASEPOINT: &[u8] = b"(15112221349535400772501151409588531511454012693041857206046113283949847762202, 46316835694926478169428394003475163141307993866256225615783033603165251855960)";
+
+/// A time period, during which a service uses the same blinded key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimePeriod {
+    pub number: u64,
+    /// Length in minutes
+    pub length: u64,
+}
+
+impl TimePeriod {
+    /// The time period containing the time.
+    pub fn at(t: SystemTime, length: u64) -> TimePeriod {
+        let minutes = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+        let offset = ROTATION_TIME_OFFSET.as_secs() / 60;
+        return TimePeriod {
+            number: minutes.saturating_sub(offset) / length,
+            length,
+        };
+    }
+
+    /// The time period the consensus is in, which is used instead of the local clock.
+    pub fn current(consensus: &Consensus) -> TimePeriod {
+        let length = consensus.param("hsdir_interval", DEFAULT_PERIOD_LENGTH as i64, 30, 14400);
+        return TimePeriod::at(consensus.valid_after, length as u64);
+    }
+
+    pub fn next(&self) -> TimePeriod {
+        return TimePeriod {
+            number: self.number + 1,
+            length: self.length,
+        };
+    }
+
+    pub fn start(&self) -> SystemTime {
+        let minutes = self.number * self.length;
+        return UNIX_EPOCH + ROTATION_TIME_OFFSET + Duration::from_secs(minutes * 60);
+    }
+}
+
+/// The clamped blinding factor h for the identity key and time period.
+fn blinding_factor(identity: &[u8; 32], period: TimePeriod) -> [u8; 32] {
+    let mut input: Vec<u8> = BLIND_STRING.to_vec();
+    input.extend_from_slice(identity);
+    input.extend_from_slice(ED25519_BASEPOINT);
+    input.extend_from_slice(b"key-blind");
+    input.extend_from_slice(&period.number.to_be_bytes());
+    input.extend_from_slice(&period.length.to_be_bytes());
+    let mut h = crypto::sha3_256(&input);
+    h[0] &= 248;
+    h[31] &= 63;
+    h[31] |= 64;
+    return h;
+}
+
+/// The blinded public key A' = h * A for the time period.
+pub fn blind_public_key(identity: &[u8; 32], period: TimePeriod) -> Result<[u8; 32], Error> {
+    let point = match CompressedEdwardsY(*identity).decompress() {
+        Some(p) => p,
+        None => return Err(OnionError::BadAddress.into()),
+    };
+    let h = Scalar::from_bytes_mod_order(blinding_factor(identity, period));
+    return Ok((h * point).compress().to_bytes());
+}
+
+/// Blinds an expanded secret key (the scalar a followed by the hash prefix) for the time
+/// period: a' = h * a mod l and prefix' the first half of
+/// SHA-512("Derive temporary signing key hash input" | prefix), as in C tor.
+pub(crate) fn blind_secret_key(
+    expanded: &[u8; 64],
+    identity: &[u8; 32],
+    period: TimePeriod,
+) -> [u8; 64] {
+    let h = Scalar::from_bytes_mod_order(blinding_factor(identity, period));
+    let mut a: [u8; 32] = [0x0; 32];
+    a.copy_from_slice(&expanded[..32]);
+    let blinded = h * Scalar::from_bytes_mod_order(a);
+
+    let mut prefix_input: Vec<u8> = b"Derive temporary signing key hash input".to_vec();
+    prefix_input.extend_from_slice(&expanded[32..]);
+    let prefix = crypto::sha512(&prefix_input);
+
+    let mut out: [u8; 64] = [0x0; 64];
+    out[..32].copy_from_slice(blinded.as_bytes());
+    out[32..].copy_from_slice(&prefix[..32]);
+    return out;
+}
+
+/// The subcredential, which proves knowledge of the identity key to introduction
+/// points and keys the descriptor encryption (rend-spec-v3 2.1).
+pub fn subcredential(identity: &[u8; 32], blinded: &[u8; 32]) -> [u8; 32] {
+    let mut input: Vec<u8> = b"credential".to_vec();
+    input.extend_from_slice(identity);
+    let credential = crypto::sha3_256(&input);
+
+    let mut input: Vec<u8> = b"subcredential".to_vec();
+    input.extend_from_slice(&credential);
+    input.extend_from_slice(blinded);
+    return crypto::sha3_256(&input);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::hex_decode;
+
+    fn at(secs: u64) -> TimePeriod {
+        return TimePeriod::at(
+            UNIX_EPOCH + Duration::from_secs(secs),
+            DEFAULT_PERIOD_LENGTH,
+        );
+    }
+
+    // From rend-spec-v3 2.2.1 and C tor's test_hs_common.c
+    #[test]
+    fn time_periods() {
+        // 2016-04-13 11:00:00 and 11:59:59 UTC
+        assert_eq!(at(1460545200).number, 16903);
+        assert_eq!(at(1460548799).number, 16903);
+        // 2016-04-13 12:00:00 UTC
+        let period = at(1460548800);
+        assert_eq!(period.number, 16904);
+        assert_eq!(period.start(), UNIX_EPOCH + Duration::from_secs(1460548800));
+        assert_eq!(at(1460545200).next(), period);
+    }
+
+    // Generated with C tor, as used by arti's tor-hscrypto
+    #[test]
+    fn blinding_vectors() {
+        let mut identity: [u8; 32] = [0x0; 32];
+        identity.copy_from_slice(
+            &hex_decode("833990B085C1A688C1D4C8B1F6B56AFAF5A2ECA674449E1D704F83765CCB7BC6")
+                .unwrap(),
+        );
+        let mut expanded: [u8; 64] = [0x0; 64];
+        expanded.copy_from_slice(
+            &hex_decode(concat!(
+                "D8C7FF0E31295B66540D789AF3E3DF992038A9592EEA01D8B7CBA06D6E66D159",
+                "4D6167696320576F7264733A20737065697373636F62616C742062697669756D"
+            ))
+            .unwrap(),
+        );
+        // 1973-05-20 01:50:33 UTC
+        let period = at(106710633);
+        assert_eq!(period.number, 1234);
+
+        // C tor's 379E...2287, clamped
+        assert_eq!(
+            blinding_factor(&identity, period).to_vec(),
+            hex_decode("309E50DB31FEE6775ABD0AF6FB7C371E060308F4F847DB09FE4CFE13AF602247").unwrap()
+        );
+        let blinded = blind_public_key(&identity, period).unwrap();
+        assert_eq!(
+            blinded.to_vec(),
+            hex_decode("3A50BF210E8F9EE955AE0014F7A6917FB65EBF098A86305ABB508D1A7291B6D5").unwrap()
+        );
+        assert_eq!(
+            subcredential(&identity, &blinded).to_vec(),
+            hex_decode("635D55907816E8D76398A675A50B1C2F3E36B42A5CA77BA3A0441285161AE07D").unwrap()
+        );
+        assert_eq!(
+            blind_secret_key(&expanded, &identity, period).to_vec(),
+            hex_decode(concat!(
+                "A958DC83AC885F6814C67035DE817A2C604D5D2F715282079448F789B656350B",
+                "4540FE1F80AA3F7E91306B7BF7A8E367293352B14A29FDCC8C19F3558075524B"
+            ))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn blinded_keys_match() {
+        let (expanded, identity) = crypto::ed25519_expand(&[0x42; 32]);
+        let period = TimePeriod {
+            number: 20000,
+            length: DEFAULT_PERIOD_LENGTH,
+        };
+        let blinded_secret = blind_secret_key(&expanded, &identity, period);
+        let blinded = blind_public_key(&identity, period).unwrap();
+        assert_eq!(crypto::ed25519_public(&blinded_secret), blinded);
+
+        let sig = crypto::ed25519_sign(&blinded_secret, &blinded, b"descriptor");
+        assert!(crypto::ed25519_verify(&blinded, b"descriptor", &sig));
+        assert!(!crypto::ed25519_verify(&identity, b"descriptor", &sig));
+    }
+}
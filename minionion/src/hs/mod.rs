@@ -0,0 +1,93 @@
+// Onion services (rend-spec-v3).
+
+mod address;
+pub(crate) mod cache;
+mod cells;
+pub(crate) mod client;
+pub(crate) mod clientauth;
+mod desc;
+mod hashring;
+mod keys;
+mod ntor;
+mod pow;
+pub(crate) mod service;
+
+pub use address::OnionAddress;
+pub use hashring::{
+    hs_index, hsdir_index, responsible_hsdirs, shared_random_value, spread_fetch, spread_store,
+};
+pub use keys::{blind_public_key, subcredential, TimePeriod};
+pub use pow::{BuiltinEquiX, EquiX, SOLUTION_LEN};
+pub use service::{OnionService, OnionServiceConfig};
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Why an onion service could not be reached, as in tor's extended SOCKS5 errors (prop304).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnionError {
+    /// None of the responsible HSDirs had a descriptor
+    DescriptorNotFound,
+    /// A descriptor was found, but couldn't be verified or decrypted
+    DescriptorInvalid,
+    /// No introduction point accepted the introduction
+    IntroFailed,
+    /// The rendezvous point failed, or the service didn't complete the handshake there
+    RendezvousFailed,
+    /// The descriptor is only readable with client authorization, and we have no key
+    ClientAuthMissing,
+    /// Our client authorization key doesn't decrypt the descriptor
+    ClientAuthIncorrect,
+    /// The name ends in .onion, but is not a valid v3 onion address
+    BadAddress,
+    /// Introduction points were reachable, but timed out
+    IntroTimedOut,
+}
+
+impl OnionError {
+    /// Extracts the reason from an error created from an OnionError.
+    pub fn from_error(err: &Error) -> Option<OnionError> {
+        return err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<OnionError>())
+            .copied();
+    }
+
+    fn error_kind(self) -> ErrorKind {
+        return match self {
+            OnionError::DescriptorNotFound => ErrorKind::NotFound,
+            OnionError::DescriptorInvalid => ErrorKind::InvalidData,
+            OnionError::IntroFailed | OnionError::RendezvousFailed => ErrorKind::ConnectionRefused,
+            OnionError::ClientAuthMissing | OnionError::ClientAuthIncorrect => {
+                ErrorKind::PermissionDenied
+            }
+            OnionError::BadAddress => ErrorKind::InvalidInput,
+            OnionError::IntroTimedOut => ErrorKind::TimedOut,
+        };
+    }
+}
+
+impl fmt::Display for OnionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            OnionError::DescriptorNotFound => "onion service descriptor not found",
+            OnionError::DescriptorInvalid => "onion service descriptor is invalid",
+            OnionError::IntroFailed => "onion service introduction failed",
+            OnionError::RendezvousFailed => "onion service rendezvous failed",
+            OnionError::ClientAuthMissing => "onion service requires client authorization",
+            OnionError::ClientAuthIncorrect => "onion service client authorization failed",
+            OnionError::BadAddress => "invalid onion address",
+            OnionError::IntroTimedOut => "onion service introduction timed out",
+        };
+        return write!(f, "{}", msg);
+    }
+}
+
+impl std::error::Error for OnionError {}
+
+impl From<OnionError> for Error {
+    /// The reason can be recovered with `OnionError::from_error`.
+    fn from(reason: OnionError) -> Error {
+        return Error::new(reason.error_kind(), reason);
+    }
+}
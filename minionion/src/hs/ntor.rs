@@ -0,0 +1,310 @@
+// The hs-ntor handshake (rend-spec-v3 appendix B) run between client and service
+// through INTRODUCE1/INTRODUCE2 and RENDEZVOUS1/RENDEZVOUS2.
+
+use std::io::{Error, ErrorKind};
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::circuit::ONION_HOP_KEY_LEN;
+use crate::crypto;
+
+const PROTOID: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1";
+const T_HSENC: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1:hs_key_extract";
+const T_HSVERIFY: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1:hs_verify";
+const T_HSMAC: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1:hs_mac";
+const M_HSEXPAND: &[u8] = b"tor-hs-ntor-curve25519-sha3-256-1:hs_key_expand";
+
+// Length of the key encrypting the INTRODUCE1 payload
+const INTRO_ENC_KEY_LEN: usize = 32;
+/// Length of the MAC over INTRODUCE1
+pub(crate) const INTRO_MAC_LEN: usize = 32;
+
+fn handshake_failed(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg.to_string());
+}
+
+/// MAC(key, message) = SHA3-256(key_len | key | message)
+pub(crate) fn hs_mac(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    let mut input: Vec<u8> = (key.len() as u64).to_be_bytes().to_vec();
+    input.extend_from_slice(key);
+    input.extend_from_slice(msg);
+    return crypto::sha3_256(&input);
+}
+
+/// The keys protecting the encrypted part of INTRODUCE1, derived from the DH result
+/// between the client's key and the service's encryption key B (`dh` = EXP(B,x) = EXP(X,b)).
+pub(crate) fn intro_keys(
+    dh: &[u8; 32],
+    auth_key: &[u8; 32],
+    client_pk: &[u8; 32],
+    enc_key: &[u8; 32],
+    subcredential: &[u8; 32],
+) -> ([u8; INTRO_ENC_KEY_LEN], [u8; INTRO_MAC_LEN]) {
+    let mut input: Vec<u8> = dh.to_vec();
+    input.extend_from_slice(auth_key);
+    input.extend_from_slice(client_pk);
+    input.extend_from_slice(enc_key);
+    input.extend_from_slice(PROTOID);
+    input.extend_from_slice(T_HSENC);
+    input.extend_from_slice(M_HSEXPAND);
+    input.extend_from_slice(subcredential);
+    let keys = crypto::shake256(&input, INTRO_ENC_KEY_LEN + INTRO_MAC_LEN);
+    let mut enc: [u8; INTRO_ENC_KEY_LEN] = [0x0; INTRO_ENC_KEY_LEN];
+    let mut mac: [u8; INTRO_MAC_LEN] = [0x0; INTRO_MAC_LEN];
+    enc.copy_from_slice(&keys[..INTRO_ENC_KEY_LEN]);
+    mac.copy_from_slice(&keys[INTRO_ENC_KEY_LEN..]);
+    return (enc, mac);
+}
+
+/// Derives the AUTH value the service sends in RENDEZVOUS1 and the keys of the onion hop.
+/// `xy` = EXP(Y,x) = EXP(X,y) and `xb` = EXP(B,x) = EXP(X,b).
+pub(crate) fn rend_keys(
+    xy: &[u8; 32],
+    xb: &[u8; 32],
+    auth_key: &[u8; 32],
+    enc_key: &[u8; 32],
+    client_pk: &[u8; 32],
+    service_pk: &[u8; 32],
+) -> ([u8; 32], Vec<u8>) {
+    let mut rend_input: Vec<u8> = xy.to_vec();
+    rend_input.extend_from_slice(xb);
+    rend_input.extend_from_slice(auth_key);
+    rend_input.extend_from_slice(enc_key);
+    rend_input.extend_from_slice(client_pk);
+    rend_input.extend_from_slice(service_pk);
+    rend_input.extend_from_slice(PROTOID);
+    let seed = hs_mac(&rend_input, T_HSENC);
+    let verify = hs_mac(&rend_input, T_HSVERIFY);
+
+    let mut auth_input: Vec<u8> = verify.to_vec();
+    auth_input.extend_from_slice(auth_key);
+    auth_input.extend_from_slice(enc_key);
+    auth_input.extend_from_slice(service_pk);
+    auth_input.extend_from_slice(client_pk);
+    auth_input.extend_from_slice(PROTOID);
+    auth_input.extend_from_slice(b"Server");
+    let auth = hs_mac(&auth_input, T_HSMAC);
+
+    let mut expand_input: Vec<u8> = seed.to_vec();
+    expand_input.extend_from_slice(M_HSEXPAND);
+    return (auth, crypto::shake256(&expand_input, ONION_HOP_KEY_LEN));
+}
+
+/// Service side of the handshake, answering the client's key X from INTRODUCE2 with the
+/// secret key of the introduction point's encryption key B. Returns Y, AUTH and the onion
+/// hop keys.
+pub(crate) fn service_handshake(
+    enc_secret: &StaticSecret,
+    auth_key: &[u8; 32],
+    client_pk: &[u8; 32],
+) -> Result<([u8; 32], [u8; 32], Vec<u8>), Error> {
+    let mut seed: [u8; 32] = [0x0; 32];
+    crypto::random_bytes(&mut seed);
+    let secret = StaticSecret::from(seed);
+    let public = PublicKey::from(&secret);
+    let x = PublicKey::from(*client_pk);
+    let xy = secret.diffie_hellman(&x);
+    let xb = enc_secret.diffie_hellman(&x);
+    if xy.as_bytes().iter().all(|b| *b == 0) || xb.as_bytes().iter().all(|b| *b == 0) {
+        return Err(handshake_failed(
+            "hs-ntor handshake produced an invalid shared secret",
+        ));
+    }
+    let enc_key = PublicKey::from(enc_secret);
+    let (auth, keys) = rend_keys(
+        xy.as_bytes(),
+        xb.as_bytes(),
+        auth_key,
+        enc_key.as_bytes(),
+        client_pk,
+        public.as_bytes(),
+    );
+    return Ok((*public.as_bytes(), auth, keys));
+}
+
+/// Client side of an hs-ntor handshake with one introduction point's keys.
+pub(crate) struct HsNtorClient {
+    auth_key: [u8; 32],
+    enc_key: [u8; 32],
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl HsNtorClient {
+    pub(crate) fn new(auth_key: &[u8; 32], enc_key: &[u8; 32]) -> HsNtorClient {
+        let mut seed: [u8; 32] = [0x0; 32];
+        crypto::random_bytes(&mut seed);
+        return HsNtorClient::with_secret(auth_key, enc_key, StaticSecret::from(seed));
+    }
+
+    pub(crate) fn with_secret(
+        auth_key: &[u8; 32],
+        enc_key: &[u8; 32],
+        secret: StaticSecret,
+    ) -> HsNtorClient {
+        let public = PublicKey::from(&secret);
+        return HsNtorClient {
+            auth_key: *auth_key,
+            enc_key: *enc_key,
+            secret,
+            public,
+        };
+    }
+
+    /// The client's public key X, sent in INTRODUCE1.
+    pub(crate) fn public_key(&self) -> &[u8; 32] {
+        return self.public.as_bytes();
+    }
+
+    /// The keys for the encrypted part of INTRODUCE1.
+    pub(crate) fn intro_keys(
+        &self,
+        subcredential: &[u8; 32],
+    ) -> ([u8; INTRO_ENC_KEY_LEN], [u8; INTRO_MAC_LEN]) {
+        let xb = self.secret.diffie_hellman(&PublicKey::from(self.enc_key));
+        return intro_keys(
+            xb.as_bytes(),
+            &self.auth_key,
+            self.public.as_bytes(),
+            &self.enc_key,
+            subcredential,
+        );
+    }
+
+    /// Checks the service's reply in RENDEZVOUS2 (Y and AUTH) and derives the onion hop keys.
+    pub(crate) fn complete(&self, reply: &[u8]) -> Result<Vec<u8>, Error> {
+        if reply.len() < 64 {
+            return Err(handshake_failed("RENDEZVOUS2 too short"));
+        }
+        let mut y: [u8; 32] = [0x0; 32];
+        y.copy_from_slice(&reply[..32]);
+        let xy = self.secret.diffie_hellman(&PublicKey::from(y));
+        let xb = self.secret.diffie_hellman(&PublicKey::from(self.enc_key));
+        if xy.as_bytes().iter().all(|b| *b == 0) || xb.as_bytes().iter().all(|b| *b == 0) {
+            return Err(handshake_failed(
+                "hs-ntor handshake produced an invalid shared secret",
+            ));
+        }
+        let (auth, keys) = rend_keys(
+            xy.as_bytes(),
+            xb.as_bytes(),
+            &self.auth_key,
+            &self.enc_key,
+            self.public.as_bytes(),
+            &y,
+        );
+        if !crypto::ct_eq(&auth, &reply[32..64]) {
+            return Err(handshake_failed("hs-ntor handshake AUTH mismatch"));
+        }
+        return Ok(keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::hex_decode;
+
+    fn key(hex: &str) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&hex_decode(hex).unwrap());
+        return key;
+    }
+
+    #[test]
+    fn mac() {
+        // From hs_ntor_ref.py in C tor, as used by arti's tests
+        assert_eq!(
+            hs_mac(b"who", b"knows?"),
+            key("5e7da329630fdaa3eab7498bb1dc625bbb9ca968f10392b6af92d51d5db17473")
+        );
+        assert_eq!(
+            hs_mac(b"gone", b"by"),
+            key("90071aabb06d3f7c777db41542f4790c7dd9e2e7b2b842f54c9c42bbdb37e9a0")
+        );
+    }
+
+    #[test]
+    fn handshake() {
+        let auth_key = [7; 32];
+        let subcredential = [5; 32];
+        let enc_secret = StaticSecret::from([9; 32]);
+        let enc_key = *PublicKey::from(&enc_secret).as_bytes();
+
+        let client = HsNtorClient::new(&auth_key, &enc_key);
+        let dh = enc_secret.diffie_hellman(&PublicKey::from(*client.public_key()));
+        let service_intro_keys = intro_keys(
+            dh.as_bytes(),
+            &auth_key,
+            client.public_key(),
+            &enc_key,
+            &subcredential,
+        );
+        assert_eq!(client.intro_keys(&subcredential), service_intro_keys);
+        assert_ne!(client.intro_keys(&[6; 32]), service_intro_keys);
+
+        let (y, auth, service_keys) =
+            service_handshake(&enc_secret, &auth_key, client.public_key()).unwrap();
+        let mut reply = y.to_vec();
+        reply.extend_from_slice(&auth);
+        assert_eq!(client.complete(&reply).unwrap(), service_keys);
+        assert_eq!(service_keys.len(), ONION_HOP_KEY_LEN);
+
+        let mut tampered = reply.clone();
+        tampered[40] ^= 0x01;
+        assert!(client.complete(&tampered).is_err());
+        assert!(client.complete(&reply[..63]).is_err());
+        // A reply meant for another client
+        let other = HsNtorClient::new(&auth_key, &enc_key);
+        assert!(other.complete(&reply).is_err());
+    }
+
+    /// Vectors generated with C tor and chutney, from arti's hs_ntor tests. The INTRODUCE1
+    /// cell of this handshake is checked in the cells module.
+    #[test]
+    fn c_tor_vectors() {
+        let auth_key = key("34E171E4358E501BFF21ED907E96AC6BFEF697C779D040BBAF49ACC30FC5D21F");
+        let enc_key = key("8E5127A40E83AABF6493E41F142B6EE3604B85A3961CD7E38D247239AFF71979");
+        let enc_secret = StaticSecret::from(key(
+            "A0ED5DBF94EEB2EDB3B514E4CF6ABFF6022051CC5F103391F1970A3FCD15296A",
+        ));
+        let x = StaticSecret::from(key(
+            "60B4D6BF5234DCF87A4E9D7487BDF3F4A69B6729835E825CA29089CFDDA1E341",
+        ));
+        let y = StaticSecret::from(key(
+            "68CB5188CA0CD7924250404FAB54EE1392D3D2B9C049A2E446513875952F8F55",
+        ));
+        assert_eq!(PublicKey::from(&enc_secret).as_bytes(), &enc_key);
+
+        let client = HsNtorClient::with_secret(&auth_key, &enc_key, x);
+
+        // The service's RENDEZVOUS1 handshake with its ephemeral key y
+        let client_pk = PublicKey::from(*client.public_key());
+        let service_pk = PublicKey::from(&y);
+        let (auth, service_keys) = rend_keys(
+            y.diffie_hellman(&client_pk).as_bytes(),
+            enc_secret.diffie_hellman(&client_pk).as_bytes(),
+            &auth_key,
+            &enc_key,
+            client.public_key(),
+            service_pk.as_bytes(),
+        );
+        let mut reply = service_pk.as_bytes().to_vec();
+        reply.extend_from_slice(&auth);
+        assert_eq!(
+            reply,
+            hex_decode(
+                "8fbe0db4d4a9c7ff46701e3e0ee7fd05cd28be4f302460addeec9e93354ee700\
+                 4A92E8437B8424D5E5EC279245D5C72B25A0327ACF6DAF902079FCB643D8B208"
+            )
+            .unwrap()
+        );
+
+        let mut seed =
+            hex_decode("4D0C72FE8AFF35559D95ECC18EB5A36883402B28CDFD48C8A530A5A3D7D578DB").unwrap();
+        seed.extend_from_slice(M_HSEXPAND);
+        let expected_keys = crypto::shake256(&seed, ONION_HOP_KEY_LEN);
+        assert_eq!(service_keys, expected_keys);
+        assert_eq!(client.complete(&reply).unwrap(), expected_keys);
+    }
+}
@@ -0,0 +1,452 @@
+// The proof-of-work defense against introduction floods (prop327, v1): services advertise a
+// seed and a suggested effort in their descriptor, clients solve an Equi-X puzzle for it,
+// and services answer introductions with higher effort first.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::Error;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::crypto;
+use crate::dir::netdoc::{self, invalid, Item};
+use crate::encoding;
+
+/// The Equi-X puzzle the defense is built on. `BuiltinEquiX` is used by default;
+/// applications may supply another implementation, e.g. bindings to tor's.
+pub trait EquiX: Send + Sync + fmt::Debug {
+    /// Solutions for the challenge, each encoded as equix_solution is in tor (16 bytes).
+    fn solve(&self, challenge: &[u8]) -> Vec<[u8; SOLUTION_LEN]>;
+    fn verify(&self, challenge: &[u8], solution: &[u8; SOLUTION_LEN]) -> bool;
+}
+
+/// Equi-X from the tor project's `equix` crate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuiltinEquiX;
+
+impl EquiX for BuiltinEquiX {
+    fn solve(&self, challenge: &[u8]) -> Vec<[u8; SOLUTION_LEN]> {
+        // A few challenges yield no valid hash function; solvers skip to the next nonce
+        return match equix::solve(challenge) {
+            Ok(solutions) => solutions.iter().map(|s| s.to_bytes()).collect(),
+            Err(_) => vec![],
+        };
+    }
+
+    fn verify(&self, challenge: &[u8], solution: &[u8; SOLUTION_LEN]) -> bool {
+        return equix::verify_bytes(challenge, solution).is_ok();
+    }
+}
+
+pub const SOLUTION_LEN: usize = 16;
+/// EXT_FIELD_TYPE of the extension carrying the solution in INTRODUCE1
+pub(crate) const EXT_TYPE_POW: u8 = 0x02;
+pub(crate) const SEED_LEN: usize = 32;
+pub(crate) const SEED_HEAD_LEN: usize = 4;
+const POW_VERSION_V1: u8 = 1;
+pub(crate) const NONCE_LEN: usize = 16;
+const CHALLENGE_PREFIX: &[u8] = b"Tor hs intro v1\0";
+// Services pick a new seed this often, accepting solutions for the previous one as well
+const SEED_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+// How often services adjust the suggested effort, as in tor
+const EFFORT_UPDATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The pow-params line of a descriptor.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PowParams {
+    pub(crate) seed: [u8; SEED_LEN],
+    pub(crate) suggested_effort: u32,
+    pub(crate) expiration: SystemTime,
+}
+
+impl PowParams {
+    /// Parses `pow-params v1 <seed> <suggested effort> <expiration>`. Other schemes are
+    /// ignored, returning None.
+    pub(crate) fn parse(item: &Item) -> Result<Option<PowParams>, Error> {
+        if item.arg(0)? != "v1" {
+            return Ok(None);
+        }
+        let seed = encoding::base64_decode(item.arg(1)?)
+            .and_then(|s| s.as_slice().try_into().ok())
+            .ok_or_else(|| invalid("Invalid pow-params seed"))?;
+        let suggested_effort = item
+            .arg(2)?
+            .parse::<u32>()
+            .map_err(|_| invalid("Invalid pow-params effort"))?;
+        let expiration = item.arg(3)?;
+        let mut parts = expiration.splitn(2, 'T');
+        let expiration =
+            netdoc::parse_time(parts.next().unwrap_or(""), parts.next().unwrap_or(""))?;
+        return Ok(Some(PowParams {
+            seed,
+            suggested_effort,
+            expiration,
+        }));
+    }
+
+    pub(crate) fn format(&self) -> String {
+        return format!(
+            "pow-params v1 {} {} {}\n",
+            encoding::base64_encode(&self.seed).trim_end_matches('='),
+            self.suggested_effort,
+            netdoc::format_time(self.expiration).replacen(' ', "T", 1)
+        );
+    }
+}
+
+/// A solved puzzle, as carried in the INTRODUCE1 extension.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PowSolution {
+    pub(crate) nonce: [u8; NONCE_LEN],
+    pub(crate) effort: u32,
+    pub(crate) seed_head: [u8; SEED_HEAD_LEN],
+    pub(crate) solution: [u8; SOLUTION_LEN],
+}
+
+impl PowSolution {
+    /// The body of the extension.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut body: Vec<u8> = vec![POW_VERSION_V1];
+        body.extend_from_slice(&self.nonce);
+        body.extend_from_slice(&self.effort.to_be_bytes());
+        body.extend_from_slice(&self.seed_head);
+        body.extend_from_slice(&self.solution);
+        return body;
+    }
+
+    /// Parses the body of the extension. Other versions are ignored, returning None.
+    pub(crate) fn parse(body: &[u8]) -> Option<PowSolution> {
+        if body.len() != 1 + NONCE_LEN + 4 + SEED_HEAD_LEN + SOLUTION_LEN
+            || body[0] != POW_VERSION_V1
+        {
+            return None;
+        }
+        let (nonce, rest) = body[1..].split_at(NONCE_LEN);
+        let (effort, rest) = rest.split_at(4);
+        let (seed_head, solution) = rest.split_at(SEED_HEAD_LEN);
+        return Some(PowSolution {
+            nonce: nonce.try_into().unwrap(),
+            effort: u32::from_be_bytes(effort.try_into().unwrap()),
+            seed_head: seed_head.try_into().unwrap(),
+            solution: solution.try_into().unwrap(),
+        });
+    }
+}
+
+/// The Equi-X challenge P | ID | C | N | E.
+fn challenge(
+    blinded: &[u8; 32],
+    seed: &[u8; SEED_LEN],
+    nonce: &[u8; NONCE_LEN],
+    effort: u32,
+) -> Vec<u8> {
+    let mut challenge: Vec<u8> = CHALLENGE_PREFIX.to_vec();
+    challenge.extend_from_slice(blinded);
+    challenge.extend_from_slice(seed);
+    challenge.extend_from_slice(nonce);
+    challenge.extend_from_slice(&effort.to_be_bytes());
+    return challenge;
+}
+
+/// Whether the solution carries the claimed effort: R * E must not overflow 32 bits,
+/// where R is the big-endian 32-bit BLAKE2b hash of the challenge and the solution.
+fn meets_effort(challenge: &[u8], solution: &[u8; SOLUTION_LEN], effort: u32) -> bool {
+    let mut input = challenge.to_vec();
+    input.extend_from_slice(solution);
+    let r = u32::from_be_bytes(crypto::blake2b(&input, 4).as_slice().try_into().unwrap());
+    return (r as u64) * (effort as u64) <= u32::MAX as u64;
+}
+
+/// Solves the puzzle of the service with the blinded key for the effort, trying nonces
+/// from a random one on.
+pub(crate) fn solve(
+    equix: &dyn EquiX,
+    blinded: &[u8; 32],
+    seed: &[u8; SEED_LEN],
+    effort: u32,
+) -> PowSolution {
+    let mut nonce: [u8; NONCE_LEN] = [0x0; NONCE_LEN];
+    crypto::random_bytes(&mut nonce);
+    return solve_from(equix, blinded, seed, effort, nonce);
+}
+
+/// Solves the puzzle trying nonces from the given one on, counting up little-endian as tor does.
+fn solve_from(
+    equix: &dyn EquiX,
+    blinded: &[u8; 32],
+    seed: &[u8; SEED_LEN],
+    effort: u32,
+    mut nonce: [u8; NONCE_LEN],
+) -> PowSolution {
+    loop {
+        let challenge = challenge(blinded, seed, &nonce, effort);
+        for solution in equix.solve(&challenge) {
+            if meets_effort(&challenge, &solution, effort) {
+                return PowSolution {
+                    nonce,
+                    effort,
+                    seed_head: head(seed),
+                    solution,
+                };
+            }
+        }
+        let next = u128::from_le_bytes(nonce).wrapping_add(1);
+        nonce = next.to_le_bytes();
+    }
+}
+
+/// Checks a solution for the seed it names. Replays must be caught by the caller.
+pub(crate) fn verify(
+    equix: &dyn EquiX,
+    blinded: &[u8; 32],
+    seed: &[u8; SEED_LEN],
+    solution: &PowSolution,
+) -> bool {
+    let challenge = challenge(blinded, seed, &solution.nonce, solution.effort);
+    return head(seed) == solution.seed_head
+        && meets_effort(&challenge, &solution.solution, solution.effort)
+        && equix.verify(&challenge, &solution.solution);
+}
+
+/// The service side of the defense: the seeds solutions are accepted for, the effort
+/// suggested to clients, and how busy the introduction queue was since the last update.
+pub(crate) struct PowState {
+    seed: [u8; SEED_LEN],
+    previous_seed: Option<[u8; SEED_LEN]>,
+    seed_expiration: SystemTime,
+    suggested_effort: u32,
+    // Seed heads and nonces of accepted solutions, so they can't be replayed
+    used: HashSet<([u8; SEED_HEAD_LEN], [u8; NONCE_LEN])>,
+    update_started: Instant,
+    total_effort: u64,
+    handled: u64,
+    max_trimmed_effort: u32,
+    had_queue: bool,
+}
+
+impl PowState {
+    pub(crate) fn new() -> PowState {
+        let mut seed: [u8; SEED_LEN] = [0x0; SEED_LEN];
+        crypto::random_bytes(&mut seed);
+        return PowState {
+            seed,
+            previous_seed: None,
+            seed_expiration: SystemTime::now() + SEED_LIFETIME,
+            suggested_effort: 0,
+            used: HashSet::new(),
+            update_started: Instant::now(),
+            total_effort: 0,
+            handled: 0,
+            max_trimmed_effort: 0,
+            had_queue: false,
+        };
+    }
+
+    pub(crate) fn params(&self) -> PowParams {
+        return PowParams {
+            seed: self.seed,
+            suggested_effort: self.suggested_effort,
+            expiration: self.seed_expiration,
+        };
+    }
+
+    /// Rotates the seed once it expired and adjusts the suggested effort like tor does:
+    /// up to the average effort handled if requests above the suggestion had to be dropped,
+    /// unchanged while there was a queue, and decaying otherwise.
+    /// Returns whether the descriptor needs to be published again.
+    pub(crate) fn update(&mut self, now: SystemTime) -> bool {
+        let mut changed = false;
+        if now >= self.seed_expiration {
+            self.previous_seed = Some(self.seed);
+            crypto::random_bytes(&mut self.seed);
+            self.seed_expiration = now + SEED_LIFETIME;
+            let previous_head = self.previous_seed.map(|s| head(&s));
+            self.used.retain(|(h, _)| Some(*h) == previous_head);
+            changed = true;
+        }
+        if self.update_started.elapsed() >= EFFORT_UPDATE_INTERVAL {
+            let previous = self.suggested_effort;
+            if self.max_trimmed_effort > self.suggested_effort && self.handled > 0 {
+                let average = (self.total_effort / self.handled) as u32;
+                self.suggested_effort = std::cmp::max(self.suggested_effort + 1, average);
+            } else if !self.had_queue {
+                self.suggested_effort = self.suggested_effort * 2 / 3;
+            }
+            self.update_started = Instant::now();
+            self.total_effort = 0;
+            self.handled = 0;
+            self.max_trimmed_effort = 0;
+            self.had_queue = false;
+            changed |= self.suggested_effort != previous;
+        }
+        return changed;
+    }
+
+    /// Checks a solution against the current or previous seed and records it.
+    /// Returns the effort it proves, or None if it's invalid or replayed.
+    pub(crate) fn check(
+        &mut self,
+        equix: &dyn EquiX,
+        blinded: &[u8; 32],
+        solution: &PowSolution,
+    ) -> Option<u32> {
+        let seed = std::iter::once(self.seed)
+            .chain(self.previous_seed)
+            .find(|s| head(s) == solution.seed_head)?;
+        if self.used.contains(&(solution.seed_head, solution.nonce))
+            || !verify(equix, blinded, &seed, solution)
+        {
+            return None;
+        }
+        self.used.insert((solution.seed_head, solution.nonce));
+        return Some(solution.effort);
+    }
+
+    /// Records that an introduction with the effort was answered.
+    pub(crate) fn handled(&mut self, effort: u32) {
+        self.handled += 1;
+        self.total_effort += effort as u64;
+    }
+
+    /// Records that introductions were waiting, and the highest effort of any dropped.
+    pub(crate) fn queued(&mut self, max_trimmed_effort: Option<u32>) {
+        self.had_queue = true;
+        if let Some(e) = max_trimmed_effort {
+            self.max_trimmed_effort = std::cmp::max(self.max_trimmed_effort, e);
+        }
+    }
+}
+
+fn head(seed: &[u8; SEED_LEN]) -> [u8; SEED_HEAD_LEN] {
+    return seed[..SEED_HEAD_LEN].try_into().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::hex_decode;
+    use std::convert::TryFrom;
+
+    fn bytes<T: for<'a> TryFrom<&'a [u8]>>(hex: &str) -> T {
+        return hex_decode(hex).unwrap().as_slice().try_into().ok().unwrap();
+    }
+
+    const BLINDED: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+    const SEED: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+
+    fn solution(seed: &str, nonce: &str, effort: u32, solution: &str) -> PowSolution {
+        return PowSolution {
+            nonce: bytes(nonce),
+            effort,
+            seed_head: bytes(&seed[..2 * SEED_HEAD_LEN]),
+            solution: bytes(solution),
+        };
+    }
+
+    // Verify vectors from C tor (prop327), as in arti's tor-hscrypto
+    #[test]
+    fn verify_vectors() {
+        let (blinded, seed) = (bytes(BLINDED), bytes(SEED));
+        let zero = "0000000000000000000000000000000000000000000000000000000000000000";
+        let nonce = "00000000000000000000000000000000";
+        let all_zero = solution(zero, nonce, 1, "00000000000000000000000000000000");
+        let input = challenge(&blinded, &bytes(zero), &all_zero.nonce, 1);
+        // Enough effort, but not an Equi-X solution
+        assert!(meets_effort(&input, &all_zero.solution, 1));
+        assert!(!BuiltinEquiX.verify(&input, &all_zero.solution));
+        assert!(!verify(&BuiltinEquiX, &blinded, &bytes(zero), &all_zero));
+        let input = challenge(&blinded, &bytes(zero), &all_zero.nonce, 10);
+        assert!(!meets_effort(&input, &all_zero.solution, 10));
+
+        let nonce = "55555555555555555555555555555555";
+        let ok = solution(SEED, nonce, 0, "4312f87ceab844c78e1c793a913812d7");
+        assert!(verify(&BuiltinEquiX, &blinded, &seed, &ok));
+        // The seed head must match
+        let mut wrong_head = ok.clone();
+        wrong_head.seed_head = [0x0, 0x0, 0x0, 0x1];
+        assert!(!verify(&BuiltinEquiX, &blinded, &seed, &wrong_head));
+
+        let nonce = "59217255555555555555555555555555";
+        let ok = solution(SEED, nonce, 1_000_000, "0f3db97b9cac20c1771680a1a34848d3");
+        assert!(verify(&BuiltinEquiX, &blinded, &seed, &ok));
+
+        let blinded = bytes("bfd298428562e530c52bdb36d81a0e293ef4a0e94d787f0f8c0c611f4f9e78ed");
+        let seed_hex = "86fb0acf4932cda44dbb451282f415479462dd10cb97ff5e7e8e2a53c3767a7f";
+        let seed = bytes(seed_hex);
+        let nonce = "2eff9fdbc34326d9d2f18ed277469c63";
+        let ok = solution(seed_hex, nonce, 100_000, "400cb091139f86b352119f6e131802d6");
+        assert!(verify(&BuiltinEquiX, &blinded, &seed, &ok));
+        // The effort and nonce are part of the challenge
+        let mut wrong_effort = ok.clone();
+        wrong_effort.effort = 99_999;
+        let input = challenge(&blinded, &seed, &ok.nonce, 99_999);
+        assert!(!meets_effort(&input, &ok.solution, 99_999));
+        assert!(!verify(&BuiltinEquiX, &blinded, &seed, &wrong_effort));
+        let mut wrong_nonce = ok.clone();
+        wrong_nonce.nonce = bytes("2eff9fdbc34326d9a2f18ed277469c63");
+        assert!(!verify(&BuiltinEquiX, &blinded, &seed, &wrong_nonce));
+    }
+
+    // Solve vectors from C tor, starting close to the nonce it ends at
+    #[test]
+    fn solve_vectors() {
+        let vectors = [
+            (
+                0,
+                "55555555555555555555555555555555",
+                BLINDED,
+                "55555555555555555555555555555555",
+                "4312f87ceab844c78e1c793a913812d7",
+            ),
+            (
+                1,
+                "55555555555555555555555555555555",
+                BLINDED,
+                "55555555555555555555555555555555",
+                "84355542ab2b3f79532ef055144ac5ab",
+            ),
+            (
+                1,
+                "55555555555555555555555555555555",
+                "1111111111111111111111111111111111111111111111111111111111111110",
+                "55555555555555555555555555555555",
+                "115e4b70da858792fc205030b8c83af9",
+            ),
+            (
+                2,
+                "55555555555555555555555555555555",
+                BLINDED,
+                "55555555555555555555555555555555",
+                "4600a93a535ed76dc746c99942ab7de2",
+            ),
+            (
+                10,
+                "55555555555555555555555555555555",
+                BLINDED,
+                "56555555555555555555555555555555",
+                "128bbda5df2929c3be086de2aad34aed",
+            ),
+            (
+                10,
+                "ffffffffffffffffffffffffffffffff",
+                BLINDED,
+                "01000000000000000000000000000000",
+                "203af985537fadb23f3ed5873b4c81ce",
+            ),
+            (
+                1337,
+                "feffffffffffffffffffffffffffffff",
+                "4111111111111111111111111111111111111111111111111111111111111111",
+                "01000000000000000000000000000000",
+                "31c377cb72796ed80ae77df6ac1d6bfd",
+            ),
+        ];
+        let seed = bytes(SEED);
+        for (effort, first_nonce, blinded, nonce, proof) in vectors.iter() {
+            let blinded = bytes(blinded);
+            let solved = solve_from(&BuiltinEquiX, &blinded, &seed, *effort, bytes(first_nonce));
+            assert_eq!(solved, solution(SEED, nonce, *effort, proof));
+            assert!(verify(&BuiltinEquiX, &blinded, &seed, &solved));
+        }
+    }
+}
@@ -0,0 +1,943 @@
+// Hosting onion services (rend-spec-v3): keeping introduction points open, publishing
+// descriptors to the responsible HSDirs and meeting clients at their rendezvous points.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::cells::{self, Introduce2};
+use super::clientauth::{self, DESCRIPTOR_COOKIE_LEN};
+use super::desc::{self, HsDescriptor, IntroPoint};
+use super::hashring;
+use super::keys::{self, TimePeriod};
+use super::ntor;
+use super::pow::PowState;
+use super::OnionAddress;
+use crate::cell::{EndReason, RelayCell, RelayCommand};
+use crate::circuit::{CircTarget, Circuit};
+use crate::client::ClientInner;
+use crate::crypto;
+use crate::dirclient;
+use crate::stream::TorStream;
+
+// Key files as C tor writes them: a 32-byte tag padded with NULs, then the key
+const SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+const PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+const SECRET_KEY_TAG: &str = "== ed25519v1-secret: type0 ==";
+const PUBLIC_KEY_TAG: &str = "== ed25519v1-public: type0 ==";
+const KEY_TAG_LEN: usize = 32;
+const HOSTNAME_FILE: &str = "hostname";
+const AUTHORIZED_CLIENTS_DIR: &str = "authorized_clients";
+const PUBLISH_PATH: &str = "/tor/hs/3/publish";
+const NUM_INTRO_POINTS: usize = 3;
+const DESCRIPTOR_LIFETIME: Duration = Duration::from_secs(3 * 60 * 60);
+// Certificates in descriptors are valid this long, as in tor
+const CERT_LIFETIME: Duration = Duration::from_secs(54 * 60 * 60);
+// Descriptors are uploaded again this often even if nothing changed
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// How often intro points are checked and replaced if their circuit closed
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10);
+// How long to wait for INTRO_ESTABLISHED
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+// How long threads waiting on circuits sleep before checking whether the service is still up
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// With the proof-of-work defense, introductions wait in a queue for this many threads
+const NUM_REND_WORKERS: usize = 4;
+// The lower half of the queue is dropped when it grows beyond this
+const MAX_QUEUED_INTROS: usize = 1000;
+// Introductions are remembered this long to detect replays by the intro point
+const REPLAY_CACHE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+// and at most this many of them, as many as tor accepts before replacing an intro point
+const MAX_REPLAY_CACHE_ENTRIES: usize = 16384;
+
+/// Configuration of an onion service, see `TorClient::launch_onion_service`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnionServiceConfig {
+    /// Where the identity key and the hostname are kept, created if needed
+    pub dir: PathBuf,
+    /// Virtual ports and the local addresses connections to them are forwarded to.
+    /// Like in tor, a target is picked at random if a virtual port is given several times.
+    pub ports: Vec<(u16, SocketAddr)>,
+    /// Whether to defend against introduction floods with proof-of-work puzzles, as with
+    /// tor's HiddenServicePoWDefensesEnabled. Needs `ClientConfig::equix`, which is set by default.
+    pub pow_defenses: bool,
+}
+
+impl OnionServiceConfig {
+    pub fn new(dir: &Path) -> OnionServiceConfig {
+        return OnionServiceConfig {
+            dir: dir.to_path_buf(),
+            ports: vec![],
+            pow_defenses: false,
+        };
+    }
+}
+
+/// A running onion service. It is reachable until the handle is dropped.
+pub struct OnionService {
+    service: Arc<Service>,
+}
+
+impl OnionService {
+    pub fn address(&self) -> OnionAddress {
+        return OnionAddress::from_identity(self.service.identity);
+    }
+}
+
+/// An introduction point we established, with the keys clients use to reach us through it.
+struct EstablishedIntro {
+    target: CircTarget,
+    circ: Circuit,
+    auth_key: [u8; 32],
+    enc_secret: StaticSecret,
+}
+
+// Shared with the threads keeping the service up
+struct Service {
+    config: OnionServiceConfig,
+    identity: [u8; 32],
+    // Expanded, as C tor stores it
+    identity_secret: [u8; 64],
+    // x25519 keys of the clients allowed to read the descriptor, or empty if anyone may
+    authorized_clients: Vec<[u8; 32]>,
+    intro_points: Mutex<Vec<Arc<EstablishedIntro>>>,
+    // Set with the proof-of-work defense
+    pow: Option<Mutex<PowState>>,
+    queue: Arc<IntroQueue>,
+}
+
+/// An introduction waiting for a rendezvous worker, with the effort its client proved.
+struct PendingIntro {
+    effort: u32,
+    // Among equal efforts, earlier introductions are answered first
+    seq: u64,
+    intro: Arc<EstablishedIntro>,
+    intro2: Introduce2,
+}
+
+impl PartialEq for PendingIntro {
+    fn eq(&self, other: &PendingIntro) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for PendingIntro {}
+
+impl PartialOrd for PendingIntro {
+    fn partial_cmp(&self, other: &PendingIntro) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for PendingIntro {
+    fn cmp(&self, other: &PendingIntro) -> Ordering {
+        return self
+            .effort
+            .cmp(&other.effort)
+            .then_with(|| other.seq.cmp(&self.seq));
+    }
+}
+
+// Kept outside the service, so workers waiting on it don't keep the service up
+struct IntroQueue {
+    pending: Mutex<(BinaryHeap<PendingIntro>, u64)>,
+    ready: Condvar,
+}
+
+impl IntroQueue {
+    fn new() -> IntroQueue {
+        return IntroQueue {
+            pending: Mutex::new((BinaryHeap::new(), 0)),
+            ready: Condvar::new(),
+        };
+    }
+
+    /// Queues the introduction. Returns whether others were already waiting and, if the
+    /// queue had to be trimmed, the highest effort dropped.
+    fn push(
+        &self,
+        effort: u32,
+        intro: Arc<EstablishedIntro>,
+        intro2: Introduce2,
+    ) -> (bool, Option<u32>) {
+        let mut pending = self.pending.lock().unwrap();
+        let (heap, seq) = &mut *pending;
+        let waiting = !heap.is_empty();
+        heap.push(PendingIntro {
+            effort,
+            seq: *seq,
+            intro,
+            intro2,
+        });
+        *seq += 1;
+        let mut trimmed: Option<u32> = None;
+        if heap.len() > MAX_QUEUED_INTROS {
+            let mut sorted = std::mem::take(heap).into_sorted_vec();
+            let dropped = sorted.drain(..sorted.len() / 2);
+            trimmed = dropped.map(|p| p.effort).max();
+            *heap = BinaryHeap::from(sorted);
+        }
+        self.ready.notify_one();
+        return (waiting, trimmed);
+    }
+
+    /// The introduction with the highest effort, or None if there was none for a while.
+    fn pop(&self) -> Option<PendingIntro> {
+        let pending = self.pending.lock().unwrap();
+        let (mut pending, _) = self
+            .ready
+            .wait_timeout_while(pending, IDLE_TIMEOUT, |(heap, _)| heap.is_empty())
+            .unwrap();
+        return pending.0.pop();
+    }
+}
+
+/// CLIENT_PK of introductions already answered, as the intro point could replay them.
+/// The oldest entries are forgotten so that the cache stays bounded.
+struct ReplayCache {
+    seen: HashSet<[u8; 32]>,
+    order: VecDeque<(Instant, [u8; 32])>,
+}
+
+impl ReplayCache {
+    fn new() -> ReplayCache {
+        return ReplayCache {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        };
+    }
+
+    /// Remembers the key. Returns false if it was seen before.
+    fn insert(&mut self, key: [u8; 32], now: Instant) -> bool {
+        while let Some((at, old)) = self.order.front() {
+            let expired = now.saturating_duration_since(*at) >= REPLAY_CACHE_LIFETIME;
+            if !expired && self.order.len() < MAX_REPLAY_CACHE_ENTRIES {
+                break;
+            }
+            self.seen.remove(old);
+            self.order.pop_front();
+        }
+        if !self.seen.insert(key) {
+            return false;
+        }
+        self.order.push_back((now, key));
+        return true;
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        for intro in self.intro_points.lock().unwrap().iter() {
+            intro.circ.close();
+        }
+    }
+}
+
+/// Loads the service's identity key from its directory, generating it on first use,
+/// and writes the hostname file. The key files are those of C tor, so services can be
+/// moved between the two. If `authorized_clients/*.auth` files exist, only those
+/// clients can read the descriptor.
+pub(crate) fn launch(
+    client: &Arc<ClientInner>,
+    config: OnionServiceConfig,
+) -> Result<OnionService, Error> {
+    if config.ports.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Onion service has no ports",
+        ));
+    }
+    if config.pow_defenses && client.pow_solver().is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The proof-of-work defense needs an Equi-X implementation",
+        ));
+    }
+    fs::create_dir_all(&config.dir)?;
+    restrict_permissions(&config.dir, 0o700)?;
+    let (identity_secret, identity) = load_identity(&config.dir)?;
+    write_hostname(&config.dir, &identity)?;
+
+    let authorized_clients =
+        clientauth::load_authorized_clients(&config.dir.join(AUTHORIZED_CLIENTS_DIR))?;
+
+    let pow = if config.pow_defenses {
+        Some(Mutex::new(PowState::new()))
+    } else {
+        None
+    };
+    let service = Arc::new(Service {
+        config,
+        identity,
+        identity_secret,
+        authorized_clients,
+        intro_points: Mutex::new(vec![]),
+        pow,
+        queue: Arc::new(IntroQueue::new()),
+    });
+    if service.pow.is_some() {
+        for _ in 0..NUM_REND_WORKERS {
+            let queue = service.queue.clone();
+            let (weak_service, weak_client) = (Arc::downgrade(&service), Arc::downgrade(client));
+            thread::spawn(move || rendezvous_worker(queue, weak_service, weak_client));
+        }
+    }
+    let weak_service = Arc::downgrade(&service);
+    let weak_client = Arc::downgrade(client);
+    thread::spawn(move || maintain_service(weak_service, weak_client));
+    return Ok(OnionService { service });
+}
+
+/// Reads the expanded identity key, or generates one if there is none, and makes sure
+/// the public key file matches it. Returns the secret and the public key.
+fn load_identity(dir: &Path) -> Result<([u8; 64], [u8; 32]), Error> {
+    let secret_path = dir.join(SECRET_KEY_FILE);
+    let secret: [u8; 64] = match read_key_file(&secret_path, SECRET_KEY_TAG, 64)? {
+        Some(k) => k.as_slice().try_into().unwrap(),
+        None => {
+            let key = crypto::ed25519_generate().0;
+            write_key_file(&secret_path, SECRET_KEY_TAG, &key)?;
+            key
+        }
+    };
+    let public = crypto::ed25519_public(&secret);
+
+    let public_path = dir.join(PUBLIC_KEY_FILE);
+    match read_key_file(&public_path, PUBLIC_KEY_TAG, 32)? {
+        Some(k) if k != public => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} doesn't match {}",
+                    public_path.display(),
+                    secret_path.display()
+                ),
+            ))
+        }
+        Some(_) => {}
+        None => write_key_file(&public_path, PUBLIC_KEY_TAG, &public)?,
+    }
+    return Ok((secret, public));
+}
+
+/// Writes the service's address to the hostname file, as C tor does.
+fn write_hostname(dir: &Path, identity: &[u8; 32]) -> Result<(), Error> {
+    let hostname = format!("{}\n", OnionAddress::from_identity(*identity));
+    return fs::write(dir.join(HOSTNAME_FILE), hostname);
+}
+
+/// Reads a key file in C tor's format, returning None if it doesn't exist.
+fn read_key_file(path: &Path, tag: &str, len: usize) -> Result<Option<Vec<u8>>, Error> {
+    let contents = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if contents.len() != KEY_TAG_LEN + len || contents[..KEY_TAG_LEN] != key_tag(tag) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} isn't a valid key file", path.display()),
+        ));
+    }
+    return Ok(Some(contents[KEY_TAG_LEN..].to_vec()));
+}
+
+/// Writes a new key file in C tor's format, readable only by us from the start.
+fn write_key_file(path: &Path, tag: &str, key: &[u8]) -> Result<(), Error> {
+    let mut contents = key_tag(tag).to_vec();
+    contents.extend_from_slice(key);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    return options.open(path)?.write_all(&contents);
+}
+
+fn key_tag(tag: &str) -> [u8; KEY_TAG_LEN] {
+    let mut padded: [u8; KEY_TAG_LEN] = [0x0; KEY_TAG_LEN];
+    padded[..tag.len()].copy_from_slice(tag.as_bytes());
+    return padded;
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path, mode: u32) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    return fs::set_permissions(path, fs::Permissions::from_mode(mode));
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path, _mode: u32) -> Result<(), Error> {
+    return Ok(());
+}
+
+/// Replaces intro points whose circuit closed and publishes descriptors whenever they
+/// changed, the time period or the proof-of-work parameters changed or the last upload
+/// is getting old.
+fn maintain_service(service: Weak<Service>, client: Weak<ClientInner>) {
+    let mut published: Option<(Instant, TimePeriod)> = None;
+    loop {
+        let (service_ref, client_ref) = match (service.upgrade(), client.upgrade()) {
+            (Some(s), Some(c)) => (s, c),
+            _ => return,
+        };
+        let mut changed = service_ref.replace_intro_points(&service, &client_ref);
+        if let Some(pow) = &service_ref.pow {
+            changed |= pow.lock().unwrap().update(SystemTime::now());
+        }
+        let period = client_ref.time_period();
+        let intro_points = service_ref.intro_points.lock().unwrap().clone();
+        if publish_due(published, changed, period) && !intro_points.is_empty() {
+            service_ref.publish(&client_ref, &intro_points, period);
+            published = Some((Instant::now(), period));
+        }
+        drop(service_ref);
+        drop(client_ref);
+        thread::sleep(MAINTENANCE_INTERVAL);
+    }
+}
+
+/// Whether descriptors must be uploaded, given when and for which time period they last were.
+fn publish_due(
+    published: Option<(Instant, TimePeriod)>,
+    changed: bool,
+    period: TimePeriod,
+) -> bool {
+    return match published {
+        Some((at, p)) => changed || p != period || at.elapsed() >= REPUBLISH_INTERVAL,
+        None => true,
+    };
+}
+
+impl Service {
+    /// Drops intro points whose circuit closed and establishes new ones.
+    /// Returns whether the set changed.
+    fn replace_intro_points(&self, weak_self: &Weak<Service>, client: &Arc<ClientInner>) -> bool {
+        let mut intro_points = self.intro_points.lock().unwrap().clone();
+        let before = intro_points.len();
+        intro_points.retain(|i| !i.circ.is_closed());
+        let mut changed = intro_points.len() != before;
+        while intro_points.len() < NUM_INTRO_POINTS {
+            let skip: Vec<[u8; 20]> = intro_points.iter().map(|i| i.target.rsa_identity).collect();
+            let intro = match client
+                .pick_intro_point(&skip)
+                .and_then(|target| establish_intro(client, target))
+            {
+                Ok(i) => Arc::new(i),
+                // Try again on the next round
+                Err(_) => break,
+            };
+            let (weak_service, weak_client) = (weak_self.clone(), Arc::downgrade(client));
+            let serving = intro.clone();
+            thread::spawn(move || serve_intro_point(weak_service, weak_client, serving));
+            intro_points.push(intro);
+            changed = true;
+        }
+        *self.intro_points.lock().unwrap() = intro_points;
+        return changed;
+    }
+
+    /// Uploads descriptors for the current and the next time period, so clients whose
+    /// consensus is a bit ahead find us as well.
+    fn publish(
+        &self,
+        client: &ClientInner,
+        intro_points: &[Arc<EstablishedIntro>],
+        period: TimePeriod,
+    ) {
+        let now = SystemTime::now();
+        for period in &[period, period.next()] {
+            let blinded = match keys::blind_public_key(&self.identity, *period) {
+                Ok(b) => b,
+                Err(_) => return,
+            };
+            let blinded_secret =
+                keys::blind_secret_key(&self.identity_secret, &self.identity, *period);
+            let subcredential = keys::subcredential(&self.identity, &blinded);
+            let (signing_secret, signing_key) = crypto::ed25519_generate();
+            let mut cookie: [u8; DESCRIPTOR_COOKIE_LEN] = [0x0; DESCRIPTOR_COOKIE_LEN];
+            crypto::random_bytes(&mut cookie);
+            let (auth_ephemeral_key, auth_clients, cookie) = if self.authorized_clients.is_empty() {
+                let (key, clients) = desc::fake_auth_clients();
+                (key, clients, None)
+            } else {
+                let (key, clients) =
+                    clientauth::auth_clients(&cookie, &self.authorized_clients, &subcredential);
+                (key, clients, Some(cookie))
+            };
+            let desc = HsDescriptor {
+                lifetime: DESCRIPTOR_LIFETIME,
+                // HSDirs only accept descriptors with a higher counter than the one they have
+                revision_counter: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                signing_key,
+                auth_ephemeral_key,
+                auth_clients,
+                single_onion: client.single_onion_mode(),
+                pow_params: self.pow.as_ref().map(|p| p.lock().unwrap().params()),
+                intro_points: intro_points
+                    .iter()
+                    .map(|i| IntroPoint {
+                        link_specifiers: i.target.link_specifiers(),
+                        onion_key: i.target.ntor_onion_key,
+                        auth_key: i.auth_key,
+                        enc_key: *PublicKey::from(&i.enc_secret).as_bytes(),
+                    })
+                    .collect(),
+            };
+            let doc = match desc.encode(
+                &blinded,
+                &blinded_secret,
+                &signing_secret,
+                &subcredential,
+                cookie.as_ref(),
+                now + CERT_LIFETIME,
+            ) {
+                Ok(d) => d,
+                Err(_) => return,
+            };
+
+            let hsdirs: Vec<CircTarget> = client.with_netdir(|netdir| {
+                let spread = hashring::spread_store(&netdir.consensus);
+                return hashring::responsible_hsdirs(netdir, &blinded, *period, spread)
+                    .iter()
+                    .map(CircTarget::from_relay)
+                    .collect();
+            });
+            for hsdir in &hsdirs {
+                if let Ok(circ) = client.build_circuit_to(hsdir) {
+                    let _ = dirclient::post(&circ, PUBLISH_PATH, doc.as_bytes());
+                    circ.close();
+                }
+            }
+        }
+    }
+
+    /// Checks and decrypts INTRODUCE2 with the subcredential of the current or the next
+    /// time period, as we publish descriptors for both. Returns it with the blinded key
+    /// of that period.
+    fn parse_introduce2(
+        &self,
+        client: &ClientInner,
+        intro: &EstablishedIntro,
+        body: &[u8],
+    ) -> Result<(Introduce2, [u8; 32]), Error> {
+        let period = client.time_period();
+        for period in &[period, period.next()] {
+            let blinded = keys::blind_public_key(&self.identity, *period)?;
+            let subcredential = keys::subcredential(&self.identity, &blinded);
+            let result =
+                cells::parse_introduce2(body, &intro.auth_key, &intro.enc_secret, &subcredential);
+            if let Ok(intro2) = result {
+                return Ok((intro2, blinded));
+            }
+        }
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid INTRODUCE2"));
+    }
+
+    /// The effort the introduction proves, 0 without a solution. None if the solution
+    /// is invalid or was used before.
+    fn pow_effort(
+        &self,
+        client: &ClientInner,
+        intro2: &Introduce2,
+        blinded: &[u8; 32],
+    ) -> Option<u32> {
+        let (pow, (equix, _)) = match (&self.pow, client.pow_solver()) {
+            (Some(p), Some(s)) => (p, s),
+            _ => return Some(0),
+        };
+        return match &intro2.pow {
+            Some(solution) => pow.lock().unwrap().check(equix, blinded, solution),
+            None => Some(0),
+        };
+    }
+
+    /// The local address for a virtual port.
+    fn target_for(&self, port: u16) -> Option<SocketAddr> {
+        let targets: Vec<SocketAddr> = self
+            .config
+            .ports
+            .iter()
+            .filter(|(p, _)| *p == port)
+            .map(|(_, t)| *t)
+            .collect();
+        if targets.is_empty() {
+            return None;
+        }
+        return Some(targets[crypto::random_below(targets.len() as u64) as usize]);
+    }
+}
+
+/// Builds a circuit to the relay and makes it an introduction point with a fresh auth key.
+fn establish_intro(client: &ClientInner, target: CircTarget) -> Result<EstablishedIntro, Error> {
+    let circ = client.build_service_circuit_to(&target)?;
+    let (auth_secret, auth_key) = crypto::ed25519_generate();
+    let mut seed: [u8; 32] = [0x0; 32];
+    crypto::random_bytes(&mut seed);
+    let enc_secret = StaticSecret::from(seed);
+
+    let nonce = match circ.last_hop_nonce() {
+        Some(n) => n,
+        None => {
+            circ.close();
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Circuit lacks the nonce to authenticate ESTABLISH_INTRO",
+            ));
+        }
+    };
+    let body = cells::establish_intro_body(&auth_key, &auth_secret, &nonce);
+    let result = circ
+        .send_control(circ.n_hops() - 1, RelayCommand::EstablishIntro, body)
+        .and_then(|_| wait_for(&circ, RelayCommand::IntroEstablished, REPLY_TIMEOUT));
+    if let Err(e) = result {
+        circ.close();
+        return Err(e);
+    }
+    return Ok(EstablishedIntro {
+        target,
+        circ,
+        auth_key,
+        enc_secret,
+    });
+}
+
+/// Answers introductions arriving through the intro point until its circuit closes.
+/// With the proof-of-work defense, they are queued for the rendezvous workers instead.
+fn serve_intro_point(
+    service: Weak<Service>,
+    client: Weak<ClientInner>,
+    intro: Arc<EstablishedIntro>,
+) {
+    let mut seen = ReplayCache::new();
+    loop {
+        let cell = match intro.circ.wait_control(IDLE_TIMEOUT) {
+            Ok((_, c)) => c,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                if service.strong_count() == 0 {
+                    return;
+                }
+                continue;
+            }
+            Err(_) => return,
+        };
+        if cell.command != RelayCommand::Introduce2 {
+            continue;
+        }
+        let (service_ref, client_ref) = match (service.upgrade(), client.upgrade()) {
+            (Some(s), Some(c)) => (s, c),
+            _ => return,
+        };
+        let (intro2, blinded) = match service_ref.parse_introduce2(&client_ref, &intro, &cell.data)
+        {
+            Ok(i) => i,
+            Err(_) => continue,
+        };
+        if !seen.insert(intro2.client_pk, Instant::now()) {
+            continue;
+        }
+        let effort = match service_ref.pow_effort(&client_ref, &intro2, &blinded) {
+            Some(e) => e,
+            None => continue,
+        };
+        let intro = intro.clone();
+        if let Some(pow) = &service_ref.pow {
+            let (waiting, trimmed) = service_ref.queue.push(effort, intro, intro2);
+            if waiting {
+                pow.lock().unwrap().queued(trimmed);
+            }
+            continue;
+        }
+        let weak_service = Arc::downgrade(&service_ref);
+        thread::spawn(move || {
+            if let Some(circ) = rendezvous(&client_ref, &intro, &intro2) {
+                serve_streams(weak_service, circ);
+            }
+        });
+    }
+}
+
+/// Answers queued introductions, highest effort first, until the service is dropped.
+fn rendezvous_worker(queue: Arc<IntroQueue>, service: Weak<Service>, client: Weak<ClientInner>) {
+    loop {
+        let pending = queue.pop();
+        let (service_ref, client_ref) = match (service.upgrade(), client.upgrade()) {
+            (Some(s), Some(c)) => (s, c),
+            _ => return,
+        };
+        let pending = match pending {
+            Some(p) => p,
+            None => continue,
+        };
+        if let Some(pow) = &service_ref.pow {
+            pow.lock().unwrap().handled(pending.effort);
+        }
+        drop(service_ref);
+        if let Some(circ) = rendezvous(&client_ref, &pending.intro, &pending.intro2) {
+            let weak_service = service.clone();
+            thread::spawn(move || serve_streams(weak_service, circ));
+        }
+    }
+}
+
+/// Completes the handshake at the client's rendezvous point, returning the circuit to it.
+fn rendezvous(
+    client: &ClientInner,
+    intro: &EstablishedIntro,
+    intro2: &Introduce2,
+) -> Option<Circuit> {
+    let rp = CircTarget::from_link_specifiers(&intro2.rp_specs, intro2.rp_ntor_key).ok()?;
+    let (y, auth, keys) =
+        ntor::service_handshake(&intro.enc_secret, &intro.auth_key, &intro2.client_pk).ok()?;
+    let circ = client.build_service_circuit_to(&rp).ok()?;
+    let body = cells::rendezvous1_body(&intro2.cookie, &y, &auth);
+    if circ
+        .send_control(circ.n_hops() - 1, RelayCommand::Rendezvous1, body)
+        .is_err()
+    {
+        circ.close();
+        return None;
+    }
+    circ.add_onion_hop(&keys, true);
+    return Some(circ);
+}
+
+/// Serves the streams the client opens on the rendezvous circuit.
+fn serve_streams(service: Weak<Service>, circ: Circuit) {
+    loop {
+        let begin = match circ.wait_begin(IDLE_TIMEOUT) {
+            Ok(b) => b,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                if service.strong_count() == 0 {
+                    circ.close();
+                    return;
+                }
+                continue;
+            }
+            Err(_) => return,
+        };
+        // BEGIN carries ":port", the address being the service itself
+        let target = service
+            .upgrade()
+            .and_then(|s| s.target_for(parse_begin_port(&begin)?));
+        let target = match target {
+            Some(t) => t,
+            // As tor with HiddenServiceAllowUnknownPorts 0, the client is cut off
+            None => {
+                circ.end_stream(begin.stream_id, EndReason::Done);
+                circ.close();
+                return;
+            }
+        };
+        let circ = circ.clone();
+        thread::spawn(move || match TcpStream::connect(target) {
+            Ok(conn) => {
+                if circ.accept_connected(begin.stream_id).is_ok() {
+                    splice(TorStream::new(circ, begin.stream_id), conn);
+                }
+            }
+            Err(_) => circ.end_stream(begin.stream_id, EndReason::ConnectRefused),
+        });
+    }
+}
+
+fn parse_begin_port(begin: &RelayCell) -> Option<u16> {
+    let end = begin.data.iter().position(|b| *b == 0)?;
+    let addr = std::str::from_utf8(&begin.data[..end]).ok()?;
+    return addr.rsplit(':').next()?.parse::<u16>().ok();
+}
+
+/// Copies data between the stream and the local connection until either side closes.
+fn splice(stream: TorStream, conn: TcpStream) {
+    let (mut stream_reader, mut conn_writer) = match (stream.try_clone(), conn.try_clone()) {
+        (Ok(s), Ok(c)) => (s, c),
+        _ => return,
+    };
+    thread::spawn(move || {
+        let _ = io::copy(&mut stream_reader, &mut conn_writer);
+        let _ = conn_writer.shutdown(Shutdown::Write);
+    });
+    let (mut stream_writer, mut conn_reader) = (stream, conn);
+    let _ = io::copy(&mut conn_reader, &mut stream_writer);
+    stream_writer.close();
+}
+
+fn wait_for(circ: &Circuit, command: RelayCommand, timeout: Duration) -> Result<RelayCell, Error> {
+    let (_, cell) = circ.wait_control(timeout)?;
+    if cell.command != command {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Expected {:?}, got {:?}", command, cell.command),
+        ));
+    }
+    return Ok(cell);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dir::Consensus;
+    use std::collections::HashMap;
+
+    fn consensus(valid_after: SystemTime) -> Consensus {
+        let hour = Duration::from_secs(60 * 60);
+        return Consensus {
+            valid_after,
+            fresh_until: valid_after + hour,
+            valid_until: valid_after + 3 * hour,
+            params: HashMap::new(),
+            bandwidth_weights: HashMap::new(),
+            shared_rand_current: None,
+            shared_rand_previous: None,
+            relays: vec![],
+        };
+    }
+
+    /// An empty directory for the test, removed first if an earlier run left it behind.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minionion-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    fn key_files() {
+        let dir = test_dir("key-files");
+        let (secret, public) = load_identity(&dir).unwrap();
+        assert_eq!(public, crypto::ed25519_public(&secret));
+
+        let secret_file = fs::read(dir.join(SECRET_KEY_FILE)).unwrap();
+        assert_eq!(secret_file.len(), 96);
+        assert_eq!(
+            &secret_file[..KEY_TAG_LEN],
+            b"== ed25519v1-secret: type0 ==\0\0\0"
+        );
+        assert_eq!(&secret_file[KEY_TAG_LEN..], &secret[..]);
+        let public_file = fs::read(dir.join(PUBLIC_KEY_FILE)).unwrap();
+        assert_eq!(public_file.len(), 64);
+        assert_eq!(
+            &public_file[..KEY_TAG_LEN],
+            b"== ed25519v1-public: type0 ==\0\0\0"
+        );
+        assert_eq!(&public_file[KEY_TAG_LEN..], &public[..]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let meta = fs::metadata(dir.join(SECRET_KEY_FILE)).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        }
+
+        // The same keys are loaded again, and a lost public key file is rewritten
+        assert_eq!(load_identity(&dir).unwrap(), (secret, public));
+        fs::remove_file(dir.join(PUBLIC_KEY_FILE)).unwrap();
+        assert_eq!(load_identity(&dir).unwrap(), (secret, public));
+        assert_eq!(fs::read(dir.join(PUBLIC_KEY_FILE)).unwrap(), public_file);
+
+        write_hostname(&dir, &public).unwrap();
+        let hostname = fs::read_to_string(dir.join(HOSTNAME_FILE)).unwrap();
+        assert_eq!(
+            hostname,
+            format!("{}\n", OnionAddress::from_identity(public))
+        );
+        assert!(hostname.ends_with(".onion\n"));
+        assert_eq!(hostname.len(), 56 + ".onion\n".len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_key_files() {
+        let dir = test_dir("bad-key-files");
+        let path = dir.join(SECRET_KEY_FILE);
+        assert!(read_key_file(&path, SECRET_KEY_TAG, 64).unwrap().is_none());
+
+        let mut contents = key_tag(SECRET_KEY_TAG).to_vec();
+        contents.extend_from_slice(&[7; 64]);
+        fs::write(&path, &contents).unwrap();
+        assert_eq!(
+            read_key_file(&path, SECRET_KEY_TAG, 64).unwrap(),
+            Some(vec![7; 64])
+        );
+        // Wrong tag or length
+        assert!(read_key_file(&path, PUBLIC_KEY_TAG, 64).is_err());
+        assert!(read_key_file(&path, SECRET_KEY_TAG, 32).is_err());
+        fs::write(&path, &contents[..95]).unwrap();
+        assert!(read_key_file(&path, SECRET_KEY_TAG, 64).is_err());
+        assert!(load_identity(&dir).is_err());
+        // The tag is padded with NULs, not spaces
+        contents[SECRET_KEY_TAG.len()..KEY_TAG_LEN].copy_from_slice(b"   ");
+        fs::write(&path, &contents).unwrap();
+        assert!(read_key_file(&path, SECRET_KEY_TAG, 64).is_err());
+
+        // A public key that doesn't belong to the secret one
+        let dir2 = test_dir("mismatched-key-files");
+        load_identity(&dir2).unwrap();
+        let (_, other_public) = crypto::ed25519_generate();
+        fs::remove_file(dir2.join(PUBLIC_KEY_FILE)).unwrap();
+        write_key_file(&dir2.join(PUBLIC_KEY_FILE), PUBLIC_KEY_TAG, &other_public).unwrap();
+        let err = load_identity(&dir2).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("doesn't match"));
+        // Existing key files are never overwritten
+        assert!(write_key_file(&dir2.join(PUBLIC_KEY_FILE), PUBLIC_KEY_TAG, &[0; 32]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&dir2).unwrap();
+    }
+
+    #[test]
+    fn replay_cache() {
+        let start = Instant::now();
+        let mut cache = ReplayCache::new();
+        assert!(cache.insert([1; 32], start));
+        assert!(!cache.insert([1; 32], start));
+        assert!(cache.insert([2; 32], start + Duration::from_secs(60)));
+        // The first entry expired, the second not yet
+        let later = start + REPLAY_CACHE_LIFETIME;
+        assert!(cache.insert([1; 32], later));
+        assert!(!cache.insert([2; 32], later));
+        assert_eq!(cache.seen.len(), 2);
+
+        let mut cache = ReplayCache::new();
+        for i in 0..MAX_REPLAY_CACHE_ENTRIES as u32 + 1 {
+            let mut key = [0; 32];
+            key[..4].copy_from_slice(&i.to_be_bytes());
+            assert!(cache.insert(key, start));
+        }
+        assert_eq!(cache.seen.len(), MAX_REPLAY_CACHE_ENTRIES);
+        assert_eq!(cache.order.len(), MAX_REPLAY_CACHE_ENTRIES);
+        // The oldest was forgotten to make room
+        assert!(cache.insert([0; 32], start));
+    }
+
+    #[test]
+    fn republish_on_new_period() {
+        // Time periods start at 12:00 UTC by default
+        let before = UNIX_EPOCH + Duration::from_secs(19000 * 86400 + 11 * 3600);
+        let period = TimePeriod::current(&consensus(before));
+        assert!(publish_due(None, false, period));
+        let published = Some((Instant::now(), period));
+        assert!(!publish_due(published, false, period));
+        assert!(publish_due(published, true, period));
+
+        // A refreshed consensus an hour later is in the next time period
+        let refreshed = TimePeriod::current(&consensus(before + Duration::from_secs(3600)));
+        assert_eq!(refreshed, period.next());
+        assert!(publish_due(published, false, refreshed));
+
+        if let Some(old) = Instant::now().checked_sub(REPUBLISH_INTERVAL) {
+            assert!(publish_due(Some((old, period)), false, period));
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Stream isolation: which streams may share a circuit, following tor's
+// connection_edge_compatible_with_circuit.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Properties in which streams must agree to share a circuit, as in tor's Isolate* port flags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IsolationFlags(u8);
+
+impl IsolationFlags {
+    pub const CLIENT_ADDR: IsolationFlags = IsolationFlags(1 << 0);
+    pub const SOCKS_AUTH: IsolationFlags = IsolationFlags(1 << 1);
+    pub const DEST_PORT: IsolationFlags = IsolationFlags(1 << 2);
+    pub const DEST_ADDR: IsolationFlags = IsolationFlags(1 << 3);
+    // Always isolated, whatever the flags say
+    const SESSION_GROUP: IsolationFlags = IsolationFlags(1 << 4);
+    const TOKEN: IsolationFlags = IsolationFlags(1 << 5);
+
+    pub fn empty() -> IsolationFlags {
+        return IsolationFlags(0);
+    }
+
+    pub fn contains(self, other: IsolationFlags) -> bool {
+        return self.0 & other.0 == other.0;
+    }
+
+    pub fn insert(&mut self, other: IsolationFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: IsolationFlags) {
+        self.0 &= !other.0;
+    }
+
+    fn intersects(self, other: IsolationFlags) -> bool {
+        return self.0 & other.0 != 0;
+    }
+}
+
+impl Default for IsolationFlags {
+    /// Like tor, only streams with different SOCKS credentials are isolated by default.
+    fn default() -> IsolationFlags {
+        return IsolationFlags::SOCKS_AUTH;
+    }
+}
+
+impl std::ops::BitOr for IsolationFlags {
+    type Output = IsolationFlags;
+    fn bitor(self, rhs: IsolationFlags) -> IsolationFlags {
+        return IsolationFlags(self.0 | rhs.0);
+    }
+}
+
+/// An explicit token for callers of the library. Streams with different tokens, or with
+/// and without one, never share a circuit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IsolationToken(u64);
+
+impl IsolationToken {
+    /// Returns a token different from all others handed out before.
+    pub fn new() -> IsolationToken {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        return IsolationToken(NEXT.fetch_add(1, Ordering::Relaxed));
+    }
+}
+
+impl Default for IsolationToken {
+    fn default() -> IsolationToken {
+        return IsolationToken::new();
+    }
+}
+
+/// Where a stream comes from and which of its properties it must not share a circuit over.
+/// The destination is taken from the request itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamIsolation {
+    pub flags: IsolationFlags,
+    /// Streams from different session groups, e.g. different listeners, never share circuits
+    pub session_group: u32,
+    pub client_addr: Option<IpAddr>,
+    /// SOCKS username and password, or other credentials used the same way
+    pub socks_auth: Option<Vec<u8>>,
+    pub token: Option<IsolationToken>,
+}
+
+/// What a stream looks like to the circuit it's attached to.
+#[derive(Debug, Clone, PartialEq)]
+struct StreamValues {
+    session_group: u32,
+    client_addr: Option<IpAddr>,
+    socks_auth: Option<Vec<u8>>,
+    token: Option<IsolationToken>,
+    dest_addr: String,
+    dest_port: Option<u16>,
+}
+
+impl StreamValues {
+    fn new(iso: &StreamIsolation, dest_addr: &str, dest_port: Option<u16>) -> StreamValues {
+        return StreamValues {
+            session_group: iso.session_group,
+            client_addr: iso.client_addr,
+            socks_auth: iso.socks_auth.clone(),
+            token: iso.token,
+            dest_addr: dest_addr.to_ascii_lowercase(),
+            dest_port,
+        };
+    }
+
+    /// The fields in which the two differ.
+    fn differences(&self, other: &StreamValues) -> IsolationFlags {
+        let mut diff = IsolationFlags::empty();
+        if self.session_group != other.session_group {
+            diff.insert(IsolationFlags::SESSION_GROUP);
+        }
+        if self.token != other.token {
+            diff.insert(IsolationFlags::TOKEN);
+        }
+        if self.client_addr != other.client_addr {
+            diff.insert(IsolationFlags::CLIENT_ADDR);
+        }
+        if self.socks_auth != other.socks_auth {
+            diff.insert(IsolationFlags::SOCKS_AUTH);
+        }
+        if self.dest_addr != other.dest_addr {
+            diff.insert(IsolationFlags::DEST_ADDR);
+        }
+        if self.dest_port != other.dest_port {
+            diff.insert(IsolationFlags::DEST_PORT);
+        }
+        return diff;
+    }
+}
+
+/// The streams a circuit has carried so far, as far as isolation is concerned.
+#[derive(Debug, Clone)]
+pub(crate) struct CircuitIsolation {
+    // Values of the first stream
+    first: Option<StreamValues>,
+    // Fields in which later streams differed from the first
+    mixed: IsolationFlags,
+}
+
+impl CircuitIsolation {
+    pub(crate) fn new() -> CircuitIsolation {
+        return CircuitIsolation {
+            first: None,
+            mixed: IsolationFlags::empty(),
+        };
+    }
+
+    /// Whether the stream may be attached. Only the new stream's flags count,
+    /// so streams that don't ask for isolation can join isolated ones.
+    pub(crate) fn allows(
+        &self,
+        iso: &StreamIsolation,
+        dest_addr: &str,
+        dest_port: Option<u16>,
+    ) -> bool {
+        let first = match &self.first {
+            Some(f) => f,
+            None => return true,
+        };
+        let flags = iso.flags | IsolationFlags::SESSION_GROUP | IsolationFlags::TOKEN;
+        // The circuit already carries streams which differ in a field this stream is isolated on
+        if flags.intersects(self.mixed) {
+            return false;
+        }
+        let diff = first.differences(&StreamValues::new(iso, dest_addr, dest_port));
+        return !flags.intersects(diff);
+    }
+
+    /// Records that the stream was attached.
+    pub(crate) fn add(&mut self, iso: &StreamIsolation, dest_addr: &str, dest_port: Option<u16>) {
+        let values = StreamValues::new(iso, dest_addr, dest_port);
+        match &self.first {
+            Some(first) => {
+                let diff = first.differences(&values);
+                self.mixed.insert(diff);
+            }
+            None => self.first = Some(values),
+        }
+    }
+}
@@ -1,3 +1,28 @@
+mod cbt;
 mod cell;
+mod chanmgr;
+mod circid;
+mod circmgr;
+mod circuit;
+mod client;
 mod connection;
+mod crypto;
+pub mod dir;
+mod dirclient;
+mod encoding;
+mod guard;
+pub mod hs;
+mod isolation;
+mod ntor;
+mod path;
+mod state;
+mod stream;
+pub use cell::{EndReason, Resolved, ResolvedValue};
+pub use client::{Bridge, ClientConfig, TorClient};
 pub use connection::TorConnection;
+pub use guard::{GuardChoice, GuardInfo, GuardMgr, GuardStatus, GuardUsability, Reachability};
+pub use hs::{BuiltinEquiX, EquiX, OnionAddress, OnionError, OnionService, OnionServiceConfig};
+pub use isolation::{IsolationFlags, IsolationToken, StreamIsolation};
+pub use path::{NodeRestrictions, NodeSet, Path, PathSelector, Role};
+pub use state::StateFile;
+pub use stream::TorStream;
@@ -0,0 +1,131 @@
+// Circuit handshakes: ntor (tor-spec 5.1.4) and CREATE_FAST (tor-spec 5.1.3).
+
+use std::io::{Error, ErrorKind};
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::cell::FAST_KEY_LEN;
+use crate::crypto;
+
+const PROTOID: &[u8] = b"ntor-curve25519-sha256-1";
+const T_MAC: &[u8] = b"ntor-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"ntor-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"ntor-curve25519-sha256-1:verify";
+const M_EXPAND: &[u8] = b"ntor-curve25519-sha256-1:key_expand";
+
+/// Length of the key material for one hop: Df, Db, Kf and Kb
+pub(crate) const HOP_KEY_LEN: usize = 20 + 20 + 16 + 16;
+/// Length of KH, the nonce following the hop keys in ntor key material (tor-spec 5.2.2)
+pub(crate) const NONCE_LEN: usize = 20;
+
+fn handshake_failed(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg.to_string());
+}
+
+/// Client side of an ntor handshake in progress.
+pub(crate) struct NtorClient {
+    relay_id: [u8; 20],
+    onion_key: [u8; 32],
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl NtorClient {
+    /// Starts a handshake with the relay, returning the state and the onion skin to send.
+    pub(crate) fn new(relay_id: &[u8; 20], onion_key: &[u8; 32]) -> (NtorClient, Vec<u8>) {
+        let mut seed: [u8; 32] = [0x0; 32];
+        crypto::random_bytes(&mut seed);
+        let secret = StaticSecret::from(seed);
+        let public = PublicKey::from(&secret);
+
+        let mut onion_skin: Vec<u8> = vec![];
+        onion_skin.extend_from_slice(relay_id);
+        onion_skin.extend_from_slice(onion_key);
+        onion_skin.extend_from_slice(public.as_bytes());
+        let client = NtorClient {
+            relay_id: *relay_id,
+            onion_key: *onion_key,
+            secret,
+            public,
+        };
+        return (client, onion_skin);
+    }
+
+    /// Checks the relay's reply (Y and AUTH) and derives `key_len` bytes of key material.
+    pub(crate) fn complete(self, reply: &[u8], key_len: usize) -> Result<Vec<u8>, Error> {
+        if reply.len() < 64 {
+            return Err(handshake_failed("ntor reply too short"));
+        }
+        let mut y: [u8; 32] = [0x0; 32];
+        y.copy_from_slice(&reply[..32]);
+        let auth = &reply[32..64];
+
+        let xy = self.secret.diffie_hellman(&PublicKey::from(y));
+        let xb = self.secret.diffie_hellman(&PublicKey::from(self.onion_key));
+        // An all-zero shared secret means the relay sent a point of small order
+        if xy.as_bytes().iter().all(|b| *b == 0) || xb.as_bytes().iter().all(|b| *b == 0) {
+            return Err(handshake_failed(
+                "ntor handshake produced an invalid shared secret",
+            ));
+        }
+
+        let mut secret_input: Vec<u8> = vec![];
+        secret_input.extend_from_slice(xy.as_bytes());
+        secret_input.extend_from_slice(xb.as_bytes());
+        secret_input.extend_from_slice(&self.relay_id);
+        secret_input.extend_from_slice(&self.onion_key);
+        secret_input.extend_from_slice(self.public.as_bytes());
+        secret_input.extend_from_slice(&y);
+        secret_input.extend_from_slice(PROTOID);
+
+        let key_seed = crypto::hmac_sha256(T_KEY, &secret_input);
+        let verify = crypto::hmac_sha256(T_VERIFY, &secret_input);
+
+        let mut auth_input: Vec<u8> = vec![];
+        auth_input.extend_from_slice(&verify);
+        auth_input.extend_from_slice(&self.relay_id);
+        auth_input.extend_from_slice(&self.onion_key);
+        auth_input.extend_from_slice(&y);
+        auth_input.extend_from_slice(self.public.as_bytes());
+        auth_input.extend_from_slice(PROTOID);
+        auth_input.extend_from_slice(b"Server");
+        if !crypto::ct_eq(&crypto::hmac_sha256(T_MAC, &auth_input), auth) {
+            return Err(handshake_failed("ntor handshake AUTH mismatch"));
+        }
+
+        return Ok(crypto::hkdf_sha256_expand(&key_seed, M_EXPAND, key_len));
+    }
+}
+
+/// Client side of a CREATE_FAST handshake in progress.
+pub(crate) struct FastClient {
+    x: [u8; FAST_KEY_LEN],
+}
+
+impl FastClient {
+    pub(crate) fn new() -> FastClient {
+        let mut x: [u8; FAST_KEY_LEN] = [0x0; FAST_KEY_LEN];
+        crypto::random_bytes(&mut x);
+        return FastClient { x };
+    }
+
+    pub(crate) fn x(&self) -> &[u8; FAST_KEY_LEN] {
+        return &self.x;
+    }
+
+    /// Checks the relay's derivative key data KH and derives `key_len` bytes of key material.
+    pub(crate) fn complete(
+        self,
+        y: &[u8; FAST_KEY_LEN],
+        kh: &[u8; FAST_KEY_LEN],
+        key_len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut k0 = self.x.to_vec();
+        k0.extend_from_slice(y);
+        let keys = crypto::kdf_tor(&k0, FAST_KEY_LEN + key_len);
+        if !crypto::ct_eq(&keys[..FAST_KEY_LEN], kh) {
+            return Err(handshake_failed("CREATE_FAST derivative key data mismatch"));
+        }
+        return Ok(keys[FAST_KEY_LEN..].to_vec());
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+
+use crate::crypto;
+use crate::dir::{AddrPattern, NetDir, Relay, RelayFlags};
+use crate::encoding;
+
+// Ports used by long-lived connections, for which exits must be Stable (path-spec 2.2)
+const LONG_LIVED_PORTS: [u16; 12] = [
+    21, 22, 706, 1863, 5050, 5190, 5222, 5223, 6523, 6667, 6697, 8300,
+];
+
+/// The position a relay is chosen for, which determines the bandwidth weights used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Guard,
+    Middle,
+    Exit,
+}
+
+/// A guard, middle and exit relay satisfying tor's path restrictions.
+#[derive(Debug, Clone)]
+pub struct Path<'a> {
+    pub guard: Relay<'a>,
+    pub middle: Relay<'a>,
+    pub exit: Relay<'a>,
+}
+
+/// One entry of a node list such as ExitNodes.
+#[derive(Debug, Clone, PartialEq)]
+enum NodeSpec {
+    Identity([u8; 20]),
+    Nickname(String),
+    Addr(AddrPattern),
+}
+
+/// A set of relays given by identity, nickname or address, as in tor's EntryNodes,
+/// ExitNodes and ExcludeNodes options.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NodeSet {
+    specs: Vec<NodeSpec>,
+}
+
+impl NodeSet {
+    /// Parses a comma-separated list of "$fingerprint", nicknames and address patterns.
+    /// Fingerprints may be followed by "~nickname" or "=nickname", which is ignored.
+    pub fn parse(s: &str) -> Result<NodeSet, Error> {
+        let mut specs: Vec<NodeSpec> = vec![];
+        for entry in s.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let invalid = |why: &str| {
+                return Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid node '{}': {}", entry, why),
+                );
+            };
+            if entry.starts_with('{') {
+                return Err(invalid(
+                    "country codes need GeoIP data, which isn't supported",
+                ));
+            }
+            let hex = entry.trim_start_matches('$');
+            let hex = hex.split(|c| c == '~' || c == '=').next().unwrap();
+            if hex.len() == 40 {
+                if let Some(id) = encoding::hex_decode(hex) {
+                    let mut identity: [u8; 20] = [0x0; 20];
+                    identity.copy_from_slice(&id);
+                    specs.push(NodeSpec::Identity(identity));
+                    continue;
+                }
+            }
+            if entry.starts_with('$') {
+                return Err(invalid("fingerprints are 40 hex digits"));
+            }
+            if entry.contains('.') || entry.contains(':') || entry.contains('*') {
+                specs.push(NodeSpec::Addr(
+                    AddrPattern::parse(entry).map_err(|e| invalid(&e.to_string()))?,
+                ));
+                continue;
+            }
+            if entry.len() > 19 || !entry.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(invalid("not a fingerprint, nickname or address"));
+            }
+            specs.push(NodeSpec::Nickname(entry.to_string()));
+        }
+        return Ok(NodeSet { specs });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.specs.is_empty();
+    }
+
+    pub fn contains(&self, relay: &Relay) -> bool {
+        return self.specs.iter().any(|spec| match spec {
+            NodeSpec::Identity(id) => relay.identity() == id,
+            NodeSpec::Nickname(name) => relay.rs.nickname.eq_ignore_ascii_case(name),
+            NodeSpec::Addr(pattern) => relay.rs.or_addrs.iter().any(|a| pattern.matches(&a.ip())),
+        });
+    }
+}
+
+/// Relays the configuration requires or forbids in some positions.
+#[derive(Debug, Clone, Default)]
+pub struct NodeRestrictions {
+    /// If set, guards must be in it
+    pub entry_nodes: Option<NodeSet>,
+    /// If set, exits must be in it
+    pub exit_nodes: Option<NodeSet>,
+    /// Relays never used in any position
+    pub exclude_nodes: NodeSet,
+}
+
+impl NodeRestrictions {
+    fn allows(&self, relay: &Relay, role: Role) -> bool {
+        if self.exclude_nodes.contains(relay) {
+            return false;
+        }
+        let required = match role {
+            Role::Guard => &self.entry_nodes,
+            Role::Middle => &None,
+            Role::Exit => &self.exit_nodes,
+        };
+        return match required {
+            Some(set) => set.contains(relay),
+            None => true,
+        };
+    }
+
+    /// Guards in the directory which must not be used, to hand to the guard manager.
+    pub fn excluded_guards(&self, netdir: &NetDir) -> HashSet<[u8; 20]> {
+        return netdir
+            .relays()
+            .iter()
+            .filter(|r| r.has_flags(RelayFlags::GUARD) && !self.allows(r, Role::Guard))
+            .map(|r| *r.identity())
+            .collect();
+    }
+}
+
+/// Chooses relays for circuits, weighted by consensus bandwidth.
+pub struct PathSelector<'a> {
+    netdir: &'a NetDir,
+    restrictions: Option<&'a NodeRestrictions>,
+}
+
+impl<'a> PathSelector<'a> {
+    pub fn new(netdir: &'a NetDir) -> PathSelector<'a> {
+        return PathSelector {
+            netdir,
+            restrictions: None,
+        };
+    }
+
+    /// Only picks relays the restrictions allow in each position.
+    pub fn with_restrictions(mut self, restrictions: &'a NodeRestrictions) -> PathSelector<'a> {
+        self.restrictions = Some(restrictions);
+        return self;
+    }
+
+    /// Picks a full path for a circuit exiting to the given port.
+    /// Like tor, the exit is picked first as it is the most constrained position.
+    pub fn pick_path(&self, port: u16) -> Result<Path<'a>, Error> {
+        let exit = self.pick_exit(Some(port), &[])?;
+        let guard = self.pick_guard(&[exit])?;
+        let middle = self.pick_middle(&[guard, exit])?;
+        return Ok(Path {
+            guard,
+            middle,
+            exit,
+        });
+    }
+
+    /// Picks the middle and exit for a circuit through an already chosen guard.
+    /// Without a port, the exit only needs to allow some port, e.g. for RELAY_RESOLVE.
+    pub fn pick_path_with_guard(
+        &self,
+        guard: Relay<'a>,
+        port: Option<u16>,
+    ) -> Result<Path<'a>, Error> {
+        let exit = self.pick_exit(port, &[guard])?;
+        let middle = self.pick_middle(&[guard, exit])?;
+        return Ok(Path {
+            guard,
+            middle,
+            exit,
+        });
+    }
+
+    /// Picks a guard not conflicting with any of the given relays.
+    pub fn pick_guard(&self, exclude: &[Relay]) -> Result<Relay<'a>, Error> {
+        return self.pick(Role::Guard, exclude, |r| {
+            r.has_flags(RelayFlags::GUARD | RelayFlags::FAST | RelayFlags::STABLE)
+        });
+    }
+
+    /// Picks a relay to add to the guard sample, which must not be one of the given ones.
+    pub(crate) fn sample_guard(&self, skip: &[[u8; 20]]) -> Result<Relay<'a>, Error> {
+        return self.pick(Role::Guard, &[], |r| {
+            r.has_flags(RelayFlags::GUARD) && !skip.contains(r.identity())
+        });
+    }
+
+    /// Picks a middle relay not conflicting with any of the given relays.
+    pub fn pick_middle(&self, exclude: &[Relay]) -> Result<Relay<'a>, Error> {
+        return self.pick(Role::Middle, exclude, |r| r.has_flags(RelayFlags::FAST));
+    }
+
+    /// Picks an introduction point for an onion service, skipping the given relays.
+    /// It must understand ESTABLISH_INTRO with Ed25519 auth keys (HSIntro=4).
+    pub(crate) fn pick_intro_point(&self, skip: &[[u8; 20]]) -> Result<Relay<'a>, Error> {
+        return self.pick(Role::Middle, &[], |r| {
+            r.has_flags(RelayFlags::FAST | RelayFlags::STABLE)
+                && r.rs.supports_protocol("HSIntro", 4)
+                && !skip.contains(r.identity())
+        });
+    }
+
+    /// Picks an exit whose policy summary allows the given port.
+    /// Without a port, any relay allowing at least one port qualifies.
+    pub fn pick_exit(&self, port: Option<u16>, exclude: &[Relay]) -> Result<Relay<'a>, Error> {
+        let need_stable = match port {
+            Some(p) => LONG_LIVED_PORTS.contains(&p),
+            None => false,
+        };
+        return self.pick(Role::Exit, exclude, |r| {
+            if !r.has_flags(RelayFlags::FAST) || r.has_flags(RelayFlags::BAD_EXIT) {
+                return false;
+            }
+            if need_stable && !r.has_flags(RelayFlags::STABLE) {
+                return false;
+            }
+            return match port {
+                Some(p) => r.md.ipv4_policy.allows_port(p),
+                None => r.md.ipv4_policy.allows_some_port(),
+            };
+        });
+    }
+
+    fn pick<F>(&self, role: Role, exclude: &[Relay], usable: F) -> Result<Relay<'a>, Error>
+    where
+        F: Fn(&Relay) -> bool,
+    {
+        let candidates: Vec<(Relay<'a>, u64)> = self
+            .netdir
+            .relays()
+            .into_iter()
+            .filter(|r| r.has_flags(RelayFlags::RUNNING | RelayFlags::VALID))
+            .filter(|r| role == Role::Middle || !r.has_flags(RelayFlags::MIDDLE_ONLY))
+            .filter(|r| usable(r))
+            .filter(|r| match self.restrictions {
+                Some(n) => n.allows(r, role),
+                None => true,
+            })
+            .filter(|r| !exclude.iter().any(|e| r.conflicts_with(e)))
+            .map(|r| (r, self.weighted_bandwidth(&r, role)))
+            .collect();
+
+        let total: u64 = candidates.iter().map(|(_, w)| w).sum();
+        if candidates.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No usable relay for {:?} position", role),
+            ));
+        }
+        // If all weights are zero, fall back to picking uniformly
+        if total == 0 {
+            let n = crypto::random_below(candidates.len() as u64) as usize;
+            return Ok(candidates[n].0);
+        }
+
+        let mut point = crypto::random_below(total);
+        for (relay, weight) in &candidates {
+            if point < *weight {
+                return Ok(*relay);
+            }
+            point -= weight;
+        }
+        unreachable!();
+    }
+
+    /// Applies the consensus bandwidth weights (dir-spec 3.8.3) for a position.
+    /// The result is scaled by 10000, which doesn't matter for relative weighting.
+    fn weighted_bandwidth(&self, relay: &Relay, role: Role) -> u64 {
+        let is_guard = relay.has_flags(RelayFlags::GUARD);
+        let is_exit = relay.has_flags(RelayFlags::EXIT) && !relay.has_flags(RelayFlags::BAD_EXIT);
+        let suffix = match (is_guard, is_exit) {
+            (true, true) => "d",
+            (true, false) => "g",
+            (false, true) => "e",
+            (false, false) => "m",
+        };
+        let prefix = match role {
+            Role::Guard => "Wg",
+            Role::Middle => "Wm",
+            Role::Exit => "We",
+        };
+        // Exit-only relays are never weighted for the guard position
+        let weight = if role == Role::Guard && suffix == "e" {
+            0
+        } else {
+            self.netdir
+                .consensus
+                .weight(&format!("{}{}", prefix, suffix))
+        };
+        return relay.rs.bandwidth.saturating_mul(weight.max(0) as u64);
+    }
+}
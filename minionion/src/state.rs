@@ -0,0 +1,104 @@
+// Persistent state, stored in the same line-based "Key value" format as tor's state file.
+
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dir::netdoc;
+
+/// Contents of a state file. Keys may occur multiple times and their order is preserved.
+#[derive(Debug, Clone, Default)]
+pub struct StateFile {
+    entries: Vec<(String, String)>,
+}
+
+impl StateFile {
+    pub fn new() -> StateFile {
+        return StateFile { entries: vec![] };
+    }
+
+    /// Loads the state file. A missing file results in empty state.
+    pub fn load(path: &Path) -> Result<StateFile, Error> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(StateFile::new()),
+            Err(e) => return Err(e),
+        };
+        return Ok(StateFile::parse(&text));
+    }
+
+    pub fn parse(text: &str) -> StateFile {
+        let mut entries: Vec<(String, String)> = vec![];
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut kv = line.splitn(2, |c: char| c.is_ascii_whitespace());
+            let key = kv.next().unwrap().to_string();
+            let value = kv.next().unwrap_or("").trim().to_string();
+            entries.push((key, value));
+        }
+        return StateFile { entries };
+    }
+
+    /// Writes the state file atomically by writing to a temporary file and renaming it.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut f = fs::File::create(&tmp_path)?;
+        writeln!(
+            f,
+            "# minitor state file last generated on {}",
+            netdoc::format_time(SystemTime::now())
+        )?;
+        writeln!(f, "# You *do not* need to edit this file.\n")?;
+        for (key, value) in &self.entries {
+            writeln!(f, "{} {}", key, value)?;
+        }
+        f.sync_all()?;
+        return fs::rename(&tmp_path, path);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        return self
+            .entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str());
+    }
+
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        return self
+            .entries
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect();
+    }
+
+    /// Replaces all values of a key.
+    pub fn set_all(&mut self, key: &str, values: Vec<String>) {
+        self.entries.retain(|(k, _)| k != key);
+        for v in values {
+            self.entries.push((key.to_string(), v));
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: String) {
+        self.set_all(key, vec![value]);
+    }
+}
+
+/// Formats a timestamp the way tor's state file does, e.g. "2020-01-31T12:00:00".
+pub(crate) fn format_state_time(t: SystemTime) -> String {
+    return netdoc::format_time(t).replacen(' ', "T", 1);
+}
+
+pub(crate) fn parse_state_time(s: &str) -> Option<SystemTime> {
+    let mut parts = s.splitn(2, 'T');
+    let t = netdoc::parse_time(parts.next()?, parts.next()?).ok()?;
+    // Guard against bogus timestamps before the epoch
+    return if t > UNIX_EPOCH { Some(t) } else { None };
+}
@@ -0,0 +1,88 @@
+use std::io::{Error, Read, Write};
+use std::sync::Arc;
+
+use crate::cell::EndReason;
+use crate::circuit::Circuit;
+
+struct Inner {
+    circ: Circuit,
+    stream_id: u16,
+    // Whether the circuit was built for this stream alone and is closed with it
+    owns_circuit: bool,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.circ.end_stream(self.stream_id, EndReason::Done);
+        if self.owns_circuit {
+            self.circ.close();
+        }
+    }
+}
+
+/// An open stream through a circuit, e.g. a TCP connection made by an exit.
+/// The stream is closed with a RELAY_END once the last handle to it is dropped.
+pub struct TorStream {
+    inner: Arc<Inner>,
+}
+
+impl TorStream {
+    pub(crate) fn new(circ: Circuit, stream_id: u16) -> TorStream {
+        return TorStream {
+            inner: Arc::new(Inner {
+                circ,
+                stream_id,
+                owns_circuit: false,
+            }),
+        };
+    }
+
+    /// A stream on a circuit nothing else uses, such as a rendezvous circuit.
+    /// The circuit is torn down once the stream is dropped.
+    pub(crate) fn with_own_circuit(circ: Circuit, stream_id: u16) -> TorStream {
+        return TorStream {
+            inner: Arc::new(Inner {
+                circ,
+                stream_id,
+                owns_circuit: true,
+            }),
+        };
+    }
+
+    /// Returns another handle to the stream, so reading and writing can happen on different threads.
+    pub fn try_clone(&self) -> Result<TorStream, Error> {
+        return Ok(TorStream {
+            inner: self.inner.clone(),
+        });
+    }
+
+    /// Closes the stream in both directions right away, waking up blocked readers.
+    pub fn close(&self) {
+        self.inner
+            .circ
+            .end_stream(self.inner.stream_id, EndReason::Done);
+    }
+}
+
+impl Read for TorStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        return self.inner.circ.read_stream(self.inner.stream_id, buf);
+    }
+}
+
+impl Write for TorStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        return self.inner.circ.write_stream(self.inner.stream_id, buf);
+    }
+
+    /// Data is sent as soon as it is written, so there is nothing to flush.
+    fn flush(&mut self) -> Result<(), Error> {
+        return Ok(());
+    }
+}
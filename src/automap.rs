@@ -0,0 +1,72 @@
+// Virtual addresses handed out for .onion names, like tor's AutomapHostsOnResolve.
+// Applications that can only connect to IPs resolve the name through the DNS listener,
+// and connections to the virtual address are then recognised by the transparent proxy.
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+// tor's default VirtualAddrNetworkIPv4, 127.192.0.0/10
+const NETWORK: u32 = 0x7fc0_0000;
+const PREFIX_LEN: u32 = 10;
+// Names mapped to virtual addresses, as tor's default AutomapHostsSuffixes
+const SUFFIXES: &[&str] = &[".onion"];
+
+pub struct VirtualAddrMap {
+    inner: Mutex<Maps>,
+}
+
+struct Maps {
+    by_name: HashMap<String, Ipv4Addr>,
+    by_addr: HashMap<Ipv4Addr, String>,
+    next: u32,
+}
+
+impl VirtualAddrMap {
+    pub fn new() -> VirtualAddrMap {
+        return VirtualAddrMap {
+            inner: Mutex::new(Maps {
+                by_name: HashMap::new(),
+                by_addr: HashMap::new(),
+                next: 0,
+            }),
+        };
+    }
+
+    /// Whether lookups of the name should be answered with a virtual address.
+    pub fn applies_to(name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        let name = name.trim_end_matches('.');
+        return SUFFIXES.iter().any(|s| name.ends_with(s));
+    }
+
+    /// Returns the virtual address for the name, allocating one if needed.
+    /// Returns None once the pool is exhausted.
+    pub fn map(&self, name: &str) -> Option<Ipv4Addr> {
+        let name = name.to_ascii_lowercase().trim_end_matches('.').to_string();
+        let mut maps = self.inner.lock().unwrap();
+        if let Some(addr) = maps.by_name.get(&name) {
+            return Some(*addr);
+        }
+        loop {
+            if maps.next >= 1 << (32 - PREFIX_LEN) {
+                return None;
+            }
+            let addr = Ipv4Addr::from(NETWORK | maps.next);
+            maps.next += 1;
+            // Addresses ending in .0 or .255 confuse some applications, so tor skips them too
+            let last = addr.octets()[3];
+            if last == 0 || last == 255 {
+                continue;
+            }
+            maps.by_name.insert(name.clone(), addr);
+            maps.by_addr.insert(addr, name);
+            return Some(addr);
+        }
+    }
+
+    /// Returns the name the address was handed out for, if it is a virtual address.
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<String> {
+        return self.inner.lock().unwrap().by_addr.get(&addr).cloned();
+    }
+}
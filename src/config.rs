@@ -0,0 +1,681 @@
+// Configuration in a subset of torrc syntax (tor's manual, "THE CONFIGURATION FILE FORMAT"),
+// with options given on the command line replacing those of the file.
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+use minionion::{Bridge, IsolationFlags, NodeSet};
+
+use crate::log::{Destination, LogSpec, Severity};
+
+const DEFAULT_SOCKS_PORT: u16 = 9050;
+const DEFAULT_DATA_DIR: &str = "minitor-data";
+
+// Every option we understand, spelled as in tor's manual. ClientOnionPoWMaxEffort is ours;
+// tor hardcodes the limit.
+const OPTIONS: &[&str] = &[
+    "AutomapHostsOnResolve",
+    "Bridge",
+    "ClientOnionAuthDir",
+    "ClientOnionPoWMaxEffort",
+    "ControlPort",
+    "DataDirectory",
+    "DNSPort",
+    "EntryNodes",
+    "ExcludeNodes",
+    "ExitNodes",
+    "FallbackDir",
+    "HiddenServiceDir",
+    "HiddenServiceNonAnonymousMode",
+    "HiddenServicePort",
+    "HiddenServicePoWDefensesEnabled",
+    "HiddenServiceSingleHopMode",
+    "HTTPTunnelPort",
+    "Log",
+    "SocksPort",
+    "TransPort",
+    "UseBridges",
+];
+
+pub const USAGE: &str = "Usage: minitor [-f FILE] [--Option value ...]
+Options are read from FILE in torrc syntax. Options given on the command line
+replace all occurrences of the same option in the file.";
+
+/// A listener from SocksPort and the like.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortConfig {
+    pub addr: SocketAddr,
+    /// Properties in which streams from the listener must agree to share a circuit
+    pub isolation: IsolationFlags,
+}
+
+/// An onion service from HiddenServiceDir and the HiddenServicePort and
+/// HiddenServicePoWDefensesEnabled lines following it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HiddenService {
+    pub dir: PathBuf,
+    /// Virtual port and the local address connections to it are forwarded to
+    pub ports: Vec<(u16, SocketAddr)>,
+    pub pow_defenses: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub socks_ports: Vec<PortConfig>,
+    pub http_tunnel_ports: Vec<PortConfig>,
+    pub dns_ports: Vec<PortConfig>,
+    pub trans_ports: Vec<PortConfig>,
+    pub control_ports: Vec<SocketAddr>,
+    pub automap_hosts_on_resolve: bool,
+    pub data_dir: PathBuf,
+    pub bridges: Vec<Bridge>,
+    pub use_bridges: bool,
+    /// ORPorts of directory caches to bootstrap from, tried in order
+    pub fallback_dirs: Vec<SocketAddr>,
+    pub entry_nodes: Option<NodeSet>,
+    pub exit_nodes: Option<NodeSet>,
+    pub exclude_nodes: NodeSet,
+    pub onion_auth_dir: Option<PathBuf>,
+    /// Highest proof-of-work effort for introductions, or the library's default
+    pub onion_pow_max_effort: Option<u32>,
+    pub hidden_services: Vec<HiddenService>,
+    pub hs_single_hop_mode: bool,
+    pub hs_non_anonymous_mode: bool,
+    pub logs: Vec<LogSpec>,
+}
+
+/// An option as given in the file or on the command line.
+struct Entry {
+    key: &'static str,
+    value: String,
+    // Where the option was given, for error messages
+    origin: String,
+}
+
+impl Config {
+    /// Builds the configuration from the command line arguments (without the program name).
+    pub fn from_args(args: &[String]) -> Result<Config, Error> {
+        let mut file: Option<PathBuf> = None;
+        let mut overrides: Vec<Entry> = vec![];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-f" {
+                match args.next() {
+                    Some(path) => file = Some(PathBuf::from(path)),
+                    None => return Err(invalid("-f needs a file name")),
+                }
+                continue;
+            }
+            let key = option_key(arg.trim_start_matches('-'))
+                .ok_or_else(|| invalid(&format!("Unknown option '{}'", arg)))?;
+            let value = args
+                .next()
+                .ok_or_else(|| invalid(&format!("{} needs a value", arg)))?;
+            overrides.push(Entry {
+                key,
+                value: value.clone(),
+                origin: "command line".to_string(),
+            });
+        }
+
+        let mut entries = match &file {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| {
+                    Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e))
+                })?;
+                parse_torrc(&text, &path.display().to_string())?
+            }
+            None => vec![],
+        };
+        entries.retain(|e| !overrides.iter().any(|o| o.key == e.key));
+        entries.extend(overrides);
+        return Config::from_entries(&entries);
+    }
+
+    fn from_entries(entries: &[Entry]) -> Result<Config, Error> {
+        let mut config = Config {
+            socks_ports: vec![],
+            http_tunnel_ports: vec![],
+            dns_ports: vec![],
+            trans_ports: vec![],
+            control_ports: vec![],
+            automap_hosts_on_resolve: false,
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            bridges: vec![],
+            use_bridges: false,
+            fallback_dirs: vec![],
+            entry_nodes: None,
+            exit_nodes: None,
+            exclude_nodes: NodeSet::default(),
+            onion_auth_dir: None,
+            onion_pow_max_effort: None,
+            hidden_services: vec![],
+            hs_single_hop_mode: false,
+            hs_non_anonymous_mode: false,
+            logs: vec![],
+        };
+        let mut socks_port_given = false;
+        for entry in entries {
+            config.apply(entry).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{}: invalid {} '{}': {}",
+                        entry.origin, entry.key, entry.value, e
+                    ),
+                )
+            })?;
+            socks_port_given |= entry.key == "SocksPort";
+        }
+
+        if !socks_port_given {
+            config.socks_ports.push(PortConfig {
+                addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_SOCKS_PORT),
+                isolation: IsolationFlags::default(),
+            });
+        }
+        if config.use_bridges && config.bridges.is_empty() {
+            return Err(invalid("UseBridges is set, but no Bridge is configured"));
+        }
+        if !config.use_bridges && config.fallback_dirs.is_empty() {
+            return Err(invalid(
+                "No FallbackDir is configured to bootstrap from, and UseBridges is not set",
+            ));
+        }
+        if let Some(hs) = config.hidden_services.iter().find(|hs| hs.ports.is_empty()) {
+            return Err(invalid(&format!(
+                "HiddenServiceDir {} has no HiddenServicePort",
+                hs.dir.display()
+            )));
+        }
+        // Like tor, non-anonymous services must be asked for twice and exclude client use
+        if config.hs_single_hop_mode != config.hs_non_anonymous_mode {
+            return Err(invalid(
+                "HiddenServiceSingleHopMode and HiddenServiceNonAnonymousMode must be set together",
+            ));
+        }
+        let client_ports = config.socks_ports.len()
+            + config.http_tunnel_ports.len()
+            + config.dns_ports.len()
+            + config.trans_ports.len();
+        if config.hs_single_hop_mode && client_ports > 0 {
+            return Err(invalid(
+                "HiddenServiceSingleHopMode needs SocksPort 0 and no other client ports",
+            ));
+        }
+        if config.logs.is_empty() {
+            config.logs.push(LogSpec {
+                min: Severity::Notice,
+                max: Severity::Err,
+                dest: Destination::Stdout,
+            });
+        }
+        return Ok(config);
+    }
+
+    fn apply(&mut self, entry: &Entry) -> Result<(), Error> {
+        let value = entry.value.as_str();
+        match entry.key {
+            "AutomapHostsOnResolve" => self.automap_hosts_on_resolve = parse_bool(value)?,
+            "Bridge" => self.bridges.push(parse_bridge(value)?),
+            "ClientOnionAuthDir" => self.onion_auth_dir = Some(PathBuf::from(value)),
+            "ClientOnionPoWMaxEffort" => {
+                let effort = value
+                    .parse::<u32>()
+                    .map_err(|_| invalid("expected an effort of 0 to 4294967295"))?;
+                self.onion_pow_max_effort = Some(effort);
+            }
+            "ControlPort" => {
+                let port = parse_port(value)?;
+                if port
+                    .iter()
+                    .any(|p| p.isolation != IsolationFlags::default())
+                {
+                    return Err(invalid("isolation flags don't apply to the control port"));
+                }
+                self.control_ports.extend(port.map(|p| p.addr));
+            }
+            "DataDirectory" => self.data_dir = PathBuf::from(value),
+            "DNSPort" => self.dns_ports.extend(parse_port(value)?),
+            "EntryNodes" => self.entry_nodes = Some(NodeSet::parse(value)?),
+            "ExcludeNodes" => self.exclude_nodes = NodeSet::parse(value)?,
+            "ExitNodes" => self.exit_nodes = Some(NodeSet::parse(value)?),
+            "FallbackDir" => self.fallback_dirs.push(parse_fallback_dir(value)?),
+            "HiddenServiceDir" => self.hidden_services.push(HiddenService {
+                dir: PathBuf::from(value),
+                ports: vec![],
+                pow_defenses: false,
+            }),
+            "HiddenServiceNonAnonymousMode" => self.hs_non_anonymous_mode = parse_bool(value)?,
+            "HiddenServicePort" => {
+                let port = parse_hs_port(value)?;
+                match self.hidden_services.last_mut() {
+                    Some(hs) => hs.ports.push(port),
+                    None => return Err(invalid("must follow a HiddenServiceDir")),
+                }
+            }
+            "HiddenServicePoWDefensesEnabled" => {
+                let enabled = parse_bool(value)?;
+                match self.hidden_services.last_mut() {
+                    Some(hs) => hs.pow_defenses = enabled,
+                    None => return Err(invalid("must follow a HiddenServiceDir")),
+                }
+            }
+            "HiddenServiceSingleHopMode" => self.hs_single_hop_mode = parse_bool(value)?,
+            "HTTPTunnelPort" => self.http_tunnel_ports.extend(parse_port(value)?),
+            "Log" => self.logs.push(LogSpec::parse(value)?),
+            "SocksPort" => self.socks_ports.extend(parse_port(value)?),
+            "TransPort" => self.trans_ports.extend(parse_port(value)?),
+            "UseBridges" => self.use_bridges = parse_bool(value)?,
+            _ => unreachable!(),
+        }
+        return Ok(());
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidInput, msg.to_string());
+}
+
+fn option_key(name: &str) -> Option<&'static str> {
+    return OPTIONS
+        .iter()
+        .find(|o| o.eq_ignore_ascii_case(name))
+        .copied();
+}
+
+/// Splits a torrc into options. Lines may be continued with a trailing backslash,
+/// and everything after a '#' is a comment.
+fn parse_torrc(text: &str, file_name: &str) -> Result<Vec<Entry>, Error> {
+    let mut entries: Vec<Entry> = vec![];
+    let mut logical_line = String::new();
+    let mut start_line = 0;
+    for (i, line) in text.lines().enumerate() {
+        if logical_line.is_empty() {
+            start_line = i + 1;
+        }
+        let line = match line.find('#') {
+            Some(p) => &line[..p],
+            None => line,
+        };
+        if let Some(continued) = line.trim_end().strip_suffix('\\') {
+            logical_line.push_str(continued);
+            logical_line.push(' ');
+            continue;
+        }
+        logical_line.push_str(line);
+        let full_line = std::mem::take(&mut logical_line);
+        let full_line = full_line.trim();
+        if full_line.is_empty() {
+            continue;
+        }
+
+        let origin = format!("{}:{}", file_name, start_line);
+        let mut parts = full_line.splitn(2, char::is_whitespace);
+        let name = parts.next().unwrap();
+        let key = option_key(name)
+            .ok_or_else(|| invalid(&format!("{}: unknown option '{}'", origin, name)))?;
+        let value = parts.next().unwrap_or("").trim();
+        // Values may be quoted, e.g. paths with spaces
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(v) => v,
+            None => value,
+        };
+        entries.push(Entry {
+            key,
+            value: value.to_string(),
+            origin,
+        });
+    }
+    return Ok(entries);
+}
+
+fn parse_bool(value: &str) -> Result<bool, Error> {
+    return match value {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(invalid("expected 0 or 1")),
+    };
+}
+
+/// Parses "[address:]port [flags]", where port 0 disables the listener.
+/// Without an address, the listener is bound to localhost. The flags are tor's
+/// isolation flags, each of which may be negated with a "No" prefix.
+fn parse_port(value: &str) -> Result<Option<PortConfig>, Error> {
+    let mut parts = value.split_whitespace();
+    let addr = parts.next().unwrap_or("");
+    let mut isolation = IsolationFlags::default();
+    for flag in parts {
+        let (negated, name) = match flag.get(..2) {
+            Some(p) if p.eq_ignore_ascii_case("no") => (true, &flag[2..]),
+            _ => (false, flag),
+        };
+        let bit = match name.to_ascii_lowercase().as_str() {
+            "isolateclientaddr" => IsolationFlags::CLIENT_ADDR,
+            "isolatesocksauth" => IsolationFlags::SOCKS_AUTH,
+            "isolatedestport" => IsolationFlags::DEST_PORT,
+            "isolatedestaddr" => IsolationFlags::DEST_ADDR,
+            _ => return Err(invalid(&format!("unknown flag {}", flag))),
+        };
+        if negated {
+            isolation.remove(bit);
+        } else {
+            isolation.insert(bit);
+        }
+    }
+    if addr == "auto" {
+        return Err(invalid("automatically chosen ports aren't supported"));
+    }
+    let addr = match addr.parse::<u16>() {
+        Ok(0) => return Ok(None),
+        Ok(port) => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+        Err(_) => addr
+            .parse::<SocketAddr>()
+            .map_err(|_| invalid("expected [address:]port"))?,
+    };
+    return Ok(Some(PortConfig { addr, isolation }));
+}
+
+/// Parses "[transport] address:port [fingerprint]". Fingerprints may contain spaces.
+fn parse_bridge(value: &str) -> Result<Bridge, Error> {
+    let mut parts = value.split_whitespace();
+    let first = parts.next().unwrap_or("");
+    let addr = match first.parse::<SocketAddr>() {
+        Ok(a) => a,
+        Err(_) if parts.next().is_some() => {
+            return Err(invalid(&format!(
+                "pluggable transport {} isn't supported",
+                first
+            )))
+        }
+        Err(_) => return Err(invalid("expected address:port")),
+    };
+    let fingerprint: String = parts.collect();
+    if fingerprint.is_empty() {
+        return Ok(Bridge {
+            addr,
+            identity: None,
+        });
+    }
+    if fingerprint.len() != 40 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid("fingerprints are 40 hex digits"));
+    }
+    let mut identity: [u8; 20] = [0x0; 20];
+    for (i, b) in identity.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&fingerprint[2 * i..2 * i + 2], 16).unwrap();
+    }
+    return Ok(Bridge {
+        addr,
+        identity: Some(identity),
+    });
+}
+
+/// Parses "address:port [orport=PORT] [id=FINGERPRINT] ...", as in tor, where the port is
+/// the DirPort if an ORPort is given. Only the ORPort is used, as directory documents are
+/// fetched over BEGIN_DIR; the other arguments are ignored.
+fn parse_fallback_dir(value: &str) -> Result<SocketAddr, Error> {
+    let mut parts = value.split_whitespace();
+    let mut addr = parts
+        .next()
+        .unwrap_or("")
+        .parse::<SocketAddr>()
+        .map_err(|_| invalid("expected address:port"))?;
+    for arg in parts {
+        if let Some(port) = arg.strip_prefix("orport=") {
+            match port.parse::<u16>() {
+                Ok(p) if p != 0 => addr.set_port(p),
+                _ => return Err(invalid(&format!("invalid ORPort {}", port))),
+            }
+        }
+    }
+    return Ok(addr);
+}
+
+/// Parses "VIRTPORT [TARGET]", where TARGET is a port or address:port on this machine.
+fn parse_hs_port(value: &str) -> Result<(u16, SocketAddr), Error> {
+    let mut parts = value.split_whitespace();
+    let virt_port = match parts.next().map(|p| p.parse::<u16>()) {
+        Some(Ok(p)) if p != 0 => p,
+        _ => return Err(invalid("expected a virtual port")),
+    };
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let target = match parts.next() {
+        None => SocketAddr::new(localhost, virt_port),
+        Some(t) if t.starts_with("unix:") => {
+            return Err(invalid("unix socket targets aren't supported"))
+        }
+        Some(t) => match t.parse::<u16>() {
+            Ok(port) => SocketAddr::new(localhost, port),
+            Err(_) => t
+                .parse::<SocketAddr>()
+                .map_err(|_| invalid("expected a target port or address:port"))?,
+        },
+    };
+    if parts.next().is_some() {
+        return Err(invalid("trailing garbage after target"));
+    }
+    return Ok((virt_port, target));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(torrc: &str) -> Result<Config, Error> {
+        return Config::from_entries(&parse_torrc(torrc, "torrc")?);
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        return s.parse().unwrap();
+    }
+
+    #[test]
+    fn torrc_syntax() {
+        let text = "# A comment\n\
+                    SocksPort 9150 # trailing comment\n\
+                    \n\
+                    socksport 127.0.0.1:9151 \\\n\
+                    \x20 IsolateDestPort\n\
+                    DataDirectory \"/var/lib/mini tor\"\n\
+                    FallbackDir 127.0.0.1:9001\n";
+        let entries = parse_torrc(text, "torrc").unwrap();
+        let keys: Vec<&str> = entries.iter().map(|e| e.key).collect();
+        assert_eq!(
+            keys,
+            vec!["SocksPort", "SocksPort", "DataDirectory", "FallbackDir"]
+        );
+        assert_eq!(entries[0].value, "9150");
+        assert_eq!(entries[0].origin, "torrc:2");
+        // Continued lines are joined and reported at their first line
+        assert_eq!(entries[1].value, "127.0.0.1:9151    IsolateDestPort");
+        assert_eq!(entries[1].origin, "torrc:4");
+        assert_eq!(entries[2].value, "/var/lib/mini tor");
+
+        let config = Config::from_entries(&entries).unwrap();
+        assert_eq!(config.socks_ports.len(), 2);
+        assert!(config.socks_ports[1]
+            .isolation
+            .contains(IsolationFlags::DEST_PORT));
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/mini tor"));
+        assert_eq!(config.fallback_dirs, vec![addr("127.0.0.1:9001")]);
+    }
+
+    #[test]
+    fn unknown_option() {
+        let err = parse_torrc("SocksPort 9050\n\nORPort 9001\n", "/etc/torrc")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "/etc/torrc:3: unknown option 'ORPort'");
+        let err = load("FallbackDir 127.0.0.1:9001\nSocksPort 9050 IsolateEverything\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("torrc:2: invalid SocksPort"));
+    }
+
+    #[test]
+    fn command_line_overrides() {
+        let path = std::env::temp_dir().join(format!("minitor-torrc-{}", std::process::id()));
+        fs::write(
+            &path,
+            "SocksPort 9150\nSocksPort 9151\nDNSPort 5353\nFallbackDir 127.0.0.1:9001\n",
+        )
+        .unwrap();
+        let args: Vec<String> = vec![
+            "-f",
+            path.to_str().unwrap(),
+            "--socksport",
+            "9250",
+            "--SocksPort",
+            "9251",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let config = Config::from_args(&args);
+        let _ = fs::remove_file(&path);
+        let config = config.unwrap();
+        let ports: Vec<SocketAddr> = config.socks_ports.iter().map(|p| p.addr).collect();
+        assert_eq!(ports, vec![addr("127.0.0.1:9250"), addr("127.0.0.1:9251")]);
+        assert_eq!(config.dns_ports[0].addr, addr("127.0.0.1:5353"));
+
+        let args: Vec<String> = vec!["--NoSuchOption".to_string(), "1".to_string()];
+        assert!(Config::from_args(&args).is_err());
+    }
+
+    #[test]
+    fn ports() {
+        let port = parse_port("9050").unwrap().unwrap();
+        assert_eq!(port.addr, addr("127.0.0.1:9050"));
+        assert_eq!(port.isolation, IsolationFlags::default());
+        let port = parse_port("[::1]:9050 NoIsolateSOCKSAuth isolateclientaddr")
+            .unwrap()
+            .unwrap();
+        assert_eq!(port.addr, addr("[::1]:9050"));
+        assert_eq!(port.isolation, IsolationFlags::CLIENT_ADDR);
+        assert!(parse_port("0").unwrap().is_none());
+        assert!(parse_port("auto").is_err());
+        assert!(parse_port("9050 IsolateNothing").is_err());
+        assert!(parse_port("localhost:9050").is_err());
+
+        // SocksPort 0 disables the default listener
+        let config = load("SocksPort 0\nFallbackDir 127.0.0.1:9001\n").unwrap();
+        assert!(config.socks_ports.is_empty());
+        let config = load("FallbackDir 127.0.0.1:9001\n").unwrap();
+        assert_eq!(config.socks_ports[0].addr, addr("127.0.0.1:9050"));
+    }
+
+    #[test]
+    fn bridges() {
+        let bridge =
+            parse_bridge("192.0.2.1:443 4C17 FAB0 5E0B 7CB2 6453 8B8A 9A54 2D4B 4EEE 4C8F")
+                .unwrap();
+        assert_eq!(bridge.addr, addr("192.0.2.1:443"));
+        let mut identity = [0u8; 20];
+        identity.copy_from_slice(&[
+            0x4c, 0x17, 0xfa, 0xb0, 0x5e, 0x0b, 0x7c, 0xb2, 0x64, 0x53, 0x8b, 0x8a, 0x9a, 0x54,
+            0x2d, 0x4b, 0x4e, 0xee, 0x4c, 0x8f,
+        ]);
+        assert_eq!(bridge.identity, Some(identity));
+        assert_eq!(parse_bridge("192.0.2.1:443").unwrap().identity, None);
+
+        let err = parse_bridge("obfs4 192.0.2.1:443 4C17FAB05E0B7CB264538B8A9A542D4B4EEE4C8F")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "pluggable transport obfs4 isn't supported");
+        assert!(parse_bridge("192.0.2.1:443 4C17FAB0").is_err());
+        assert!(parse_bridge("bridge.example").is_err());
+
+        let err = load("UseBridges 1\n").err().unwrap();
+        assert!(err.to_string().contains("no Bridge is configured"));
+        let config = load("UseBridges 1\nBridge 192.0.2.1:443\n").unwrap();
+        assert!(config.fallback_dirs.is_empty());
+    }
+
+    #[test]
+    fn fallback_dirs() {
+        assert_eq!(
+            parse_fallback_dir("192.0.2.1:9001").unwrap(),
+            addr("192.0.2.1:9001")
+        );
+        // Tor's format lists the DirPort first
+        let line = "192.0.2.1:80 orport=443 id=4C17FAB05E0B7CB264538B8A9A542D4B4EEE4C8F weight=10";
+        assert_eq!(parse_fallback_dir(line).unwrap(), addr("192.0.2.1:443"));
+        assert!(parse_fallback_dir("192.0.2.1:80 orport=0").is_err());
+        assert!(parse_fallback_dir("192.0.2.1").is_err());
+        assert!(load("SocksPort 9050\n").is_err());
+    }
+
+    #[test]
+    fn hidden_services() {
+        let config = load(
+            "FallbackDir 127.0.0.1:9001\n\
+             HiddenServiceDir /var/lib/hs\n\
+             HiddenServicePort 80 8080\n\
+             HiddenServicePort 22\n\
+             HiddenServicePoWDefensesEnabled 1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.hidden_services,
+            vec![HiddenService {
+                dir: PathBuf::from("/var/lib/hs"),
+                ports: vec![(80, addr("127.0.0.1:8080")), (22, addr("127.0.0.1:22"))],
+                pow_defenses: true,
+            }]
+        );
+
+        let err = load("FallbackDir 127.0.0.1:9001\nHiddenServicePort 80\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "torrc:2: invalid HiddenServicePort '80': must follow a HiddenServiceDir"
+        );
+        let err = load("FallbackDir 127.0.0.1:9001\nHiddenServiceDir /var/lib/hs\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "HiddenServiceDir /var/lib/hs has no HiddenServicePort"
+        );
+        assert!(parse_hs_port("0 8080").is_err());
+        assert!(parse_hs_port("80 unix:/run/hs.sock").is_err());
+        assert!(parse_hs_port("80 8080 extra").is_err());
+    }
+
+    #[test]
+    fn single_hop_mode() {
+        let service = "FallbackDir 127.0.0.1:9001\n\
+                       HiddenServiceDir /var/lib/hs\n\
+                       HiddenServicePort 80\n";
+        let err = load(&format!(
+            "{}HiddenServiceSingleHopMode 1\nSocksPort 0\n",
+            service
+        ))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("must be set together"));
+        let err = load(&format!(
+            "{}HiddenServiceSingleHopMode 1\nHiddenServiceNonAnonymousMode 1\n",
+            service
+        ))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("needs SocksPort 0"));
+        let err = load(&format!(
+            "{}HiddenServiceSingleHopMode 1\nHiddenServiceNonAnonymousMode 1\nSocksPort 0\nDNSPort 5353\n",
+            service
+        ))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("needs SocksPort 0"));
+        let config = load(&format!(
+            "{}HiddenServiceSingleHopMode 1\nHiddenServiceNonAnonymousMode 1\nSocksPort 0\n",
+            service
+        ))
+        .unwrap();
+        assert!(config.hs_single_hop_mode && config.hs_non_anonymous_mode);
+    }
+}
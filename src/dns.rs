@@ -0,0 +1,301 @@
+// DNS resolver answering A, AAAA and PTR queries with RELAY_RESOLVE, like tor's DNSPort.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+use minionion::{Resolved, ResolvedValue, StreamIsolation, TorClient};
+
+use crate::automap::VirtualAddrMap;
+
+const HEADER_LEN: usize = 12;
+// Largest message we accept; UDP queries are much smaller in practice
+const MAX_MESSAGE_LEN: usize = 4096;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+// TTL of answers for virtual addresses, which stay valid while we run
+const VIRTUAL_ADDR_TTL: u32 = 60;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+
+/// The single question of a query.
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    // The encoded question, echoed in the response
+    raw: Vec<u8>,
+}
+
+/// Answers queries arriving on the UDP socket, each on its own thread.
+/// With a virtual address map, .onion names are answered with addresses from it.
+pub fn serve_udp(
+    client: Arc<TorClient>,
+    isolation: StreamIsolation,
+    automap: Option<Arc<VirtualAddrMap>>,
+    socket: UdpSocket,
+) -> Result<(), Error> {
+    let mut buf: Vec<u8> = vec![0x0; MAX_MESSAGE_LEN];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf)?;
+        let query = buf[..n].to_vec();
+        let client = client.clone();
+        let automap = automap.clone();
+        let isolation = StreamIsolation {
+            client_addr: Some(peer.ip()),
+            ..isolation.clone()
+        };
+        let socket = socket.try_clone()?;
+        thread::spawn(move || {
+            if let Some(response) = answer(&client, &isolation, automap.as_deref(), &query) {
+                let _ = socket.send_to(&response, peer);
+            }
+        });
+    }
+}
+
+/// Answers queries on a TCP connection, which are prefixed with their length (RFC 1035 4.2.2).
+pub fn serve_tcp(
+    client: &TorClient,
+    isolation: StreamIsolation,
+    automap: Option<&VirtualAddrMap>,
+    mut conn: TcpStream,
+) -> Result<(), Error> {
+    loop {
+        let mut len_buf: [u8; 2] = [0x0; 2];
+        match conn.read_exact(&mut len_buf) {
+            Ok(()) => (),
+            // The client is done
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let mut query: Vec<u8> = vec![0x0; u16::from_be_bytes(len_buf) as usize];
+        conn.read_exact(&mut query)?;
+        let response = match answer(client, &isolation, automap, &query) {
+            Some(r) => r,
+            None => return Err(Error::new(ErrorKind::InvalidData, "Malformed DNS query")),
+        };
+        conn.write_all(&(response.len() as u16).to_be_bytes())?;
+        conn.write_all(&response)?;
+    }
+}
+
+/// Builds the response to a query. Returns None if the query is too broken to answer at all.
+fn answer(
+    client: &TorClient,
+    isolation: &StreamIsolation,
+    automap: Option<&VirtualAddrMap>,
+    query: &[u8],
+) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    // Responses to responses would let two resolvers ping-pong forever
+    if query[2] & 0x80 != 0 {
+        return None;
+    }
+    let opcode = (query[2] >> 3) & 0x0f;
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    if opcode != 0 {
+        return Some(response(query, None, RCODE_NOTIMP, &[]));
+    }
+    if qdcount != 1 {
+        return Some(response(query, None, RCODE_FORMERR, &[]));
+    }
+    let question = match parse_question(query) {
+        Some(q) => q,
+        None => return Some(response(query, None, RCODE_FORMERR, &[])),
+    };
+    if question.qclass != CLASS_IN {
+        return Some(response(query, Some(&question), RCODE_NOTIMP, &[]));
+    }
+    if let Some(map) = automap {
+        if let Some((rcode, records)) = answer_virtual(map, &question) {
+            return Some(response(query, Some(&question), rcode, &records));
+        }
+    }
+
+    let result = match question.qtype {
+        TYPE_A | TYPE_AAAA => client.resolve(&question.name, isolation),
+        TYPE_PTR => match parse_reverse_name(&question.name) {
+            Some(addr) => client.resolve_ptr(addr, isolation),
+            None => return Some(response(query, Some(&question), RCODE_NXDOMAIN, &[])),
+        },
+        _ => return Some(response(query, Some(&question), RCODE_NOTIMP, &[])),
+    };
+    let answers = match result {
+        Ok(a) => a,
+        Err(_) => return Some(response(query, Some(&question), RCODE_SERVFAIL, &[])),
+    };
+
+    let mut records: Vec<Vec<u8>> = vec![];
+    let mut rcode = RCODE_NOERROR;
+    for Resolved { value, ttl } in &answers {
+        let rdata: Vec<u8> = match (question.qtype, value) {
+            (TYPE_A, ResolvedValue::Ip(IpAddr::V4(ip))) => ip.octets().to_vec(),
+            (TYPE_AAAA, ResolvedValue::Ip(IpAddr::V6(ip))) => ip.octets().to_vec(),
+            (TYPE_PTR, ResolvedValue::Hostname(name)) => match encode_name(name) {
+                Some(n) => n,
+                None => continue,
+            },
+            (_, ResolvedValue::Error) => {
+                rcode = RCODE_NXDOMAIN;
+                continue;
+            }
+            (_, ResolvedValue::TransientError) => {
+                rcode = RCODE_SERVFAIL;
+                continue;
+            }
+            // Answers of another type, e.g. an IPv4 address for an AAAA query
+            _ => continue,
+        };
+        records.push(resource_record(question.qtype, *ttl, &rdata));
+    }
+    // Errors only matter if there is nothing to answer with
+    if !records.is_empty() {
+        rcode = RCODE_NOERROR;
+    }
+    return Some(response(query, Some(&question), rcode, &records));
+}
+
+/// Answers queries for .onion names and for virtual addresses without asking an exit.
+fn answer_virtual(map: &VirtualAddrMap, question: &Question) -> Option<(u8, Vec<Vec<u8>>)> {
+    match question.qtype {
+        TYPE_A if VirtualAddrMap::applies_to(&question.name) => {
+            return match map.map(&question.name) {
+                Some(addr) => Some((
+                    RCODE_NOERROR,
+                    vec![resource_record(TYPE_A, VIRTUAL_ADDR_TTL, &addr.octets())],
+                )),
+                None => Some((RCODE_SERVFAIL, vec![])),
+            };
+        }
+        // The pool only has IPv4 addresses
+        TYPE_AAAA if VirtualAddrMap::applies_to(&question.name) => {
+            return Some((RCODE_NOERROR, vec![]));
+        }
+        TYPE_PTR => {
+            let name = match parse_reverse_name(&question.name) {
+                Some(IpAddr::V4(addr)) => map.lookup(addr)?,
+                _ => return None,
+            };
+            let rdata = encode_name(&name)?;
+            return Some((
+                RCODE_NOERROR,
+                vec![resource_record(TYPE_PTR, VIRTUAL_ADDR_TTL, &rdata)],
+            ));
+        }
+        _ => return None,
+    }
+}
+
+fn parse_question(query: &[u8]) -> Option<Question> {
+    let mut pos = HEADER_LEN;
+    let mut labels: Vec<String> = vec![];
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers make no sense in the only question
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = query.get(pos..pos + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += len;
+    }
+    let fixed = query.get(pos..pos + 4)?;
+    return Some(Question {
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+        raw: query[HEADER_LEN..pos + 4].to_vec(),
+    });
+}
+
+/// Turns an in-addr.arpa or ip6.arpa name back into the address it is for.
+fn parse_reverse_name(name: &str) -> Option<IpAddr> {
+    let name = name.to_ascii_lowercase();
+    let name = name.trim_end_matches('.');
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets: Vec<u8> = vec![];
+        for label in rest.split('.').rev() {
+            octets.push(label.parse::<u8>().ok()?);
+        }
+        if octets.len() != 4 {
+            return None;
+        }
+        return Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3],
+        )));
+    }
+    if let Some(rest) = name.strip_suffix(".ip6.arpa") {
+        let nibbles: Vec<&str> = rest.split('.').rev().collect();
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut octets: [u8; 16] = [0x0; 16];
+        for (i, pair) in nibbles.chunks(2).enumerate() {
+            if pair[0].len() != 1 || pair[1].len() != 1 {
+                return None;
+            }
+            let hi = u8::from_str_radix(pair[0], 16).ok()?;
+            let lo = u8::from_str_radix(pair[1], 16).ok()?;
+            octets[i] = (hi << 4) | lo;
+        }
+        return Some(IpAddr::V6(Ipv6Addr::from(octets)));
+    }
+    return None;
+}
+
+fn encode_name(name: &str) -> Option<Vec<u8>> {
+    let mut out: Vec<u8> = vec![];
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    return Some(out);
+}
+
+fn resource_record(rtype: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+    // The name is a pointer to the question, which always starts right after the header
+    let mut rr: Vec<u8> = vec![0xc0, HEADER_LEN as u8];
+    rr.extend_from_slice(&rtype.to_be_bytes());
+    rr.extend_from_slice(&CLASS_IN.to_be_bytes());
+    rr.extend_from_slice(&ttl.to_be_bytes());
+    rr.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    rr.extend_from_slice(rdata);
+    return rr;
+}
+
+fn response(query: &[u8], question: Option<&Question>, rcode: u8, records: &[Vec<u8>]) -> Vec<u8> {
+    let mut out: Vec<u8> = query[..2].to_vec();
+    // QR, the query's opcode and RD, then RA and the response code
+    out.push(0x80 | (query[2] & 0x79));
+    out.push(0x80 | rcode);
+    out.extend_from_slice(&(question.is_some() as u16).to_be_bytes());
+    out.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    // No authority or additional records
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(q) = question {
+        out.extend_from_slice(&q.raw);
+    }
+    for rr in records {
+        out.extend_from_slice(rr);
+    }
+    return out;
+}
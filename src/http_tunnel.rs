@@ -0,0 +1,139 @@
+// HTTP CONNECT tunnel, like tor's HTTPTunnelPort.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use minionion::{EndReason, StreamIsolation, TorClient};
+
+// Requests with longer headers are refused
+const MAX_HEADER_LEN: usize = 8192;
+
+fn protocol_error(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg.to_string());
+}
+
+/// Handles an HTTP proxy client: reads the CONNECT request, opens the stream
+/// and relays data until either side closes.
+pub fn serve(
+    client: &TorClient,
+    mut isolation: StreamIsolation,
+    mut conn: TcpStream,
+) -> Result<(), Error> {
+    let (head, leftover) = read_head(&mut conn)?;
+    let mut lines = head.split("\r\n");
+    let request_line: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+    if request_line.len() != 3 || !request_line[2].starts_with("HTTP/1.") {
+        send_status(&mut conn, 400, "Bad Request", "Malformed request line")?;
+        return Err(protocol_error("Malformed HTTP request line"));
+    }
+    if request_line[0] != "CONNECT" {
+        send_status(
+            &mut conn,
+            405,
+            "Method Not Allowed",
+            "This is an HTTP CONNECT tunnel, not a full HTTP proxy",
+        )?;
+        return Err(protocol_error("HTTP method other than CONNECT"));
+    }
+    let (host, port) = match parse_authority(request_line[1]) {
+        Some(a) => a,
+        None => {
+            send_status(&mut conn, 400, "Bad Request", "Expected host:port")?;
+            return Err(protocol_error("Invalid CONNECT target"));
+        }
+    };
+
+    // Like tor, credentials and isolation headers count as SOCKS authentication
+    let mut auth: Vec<u8> = vec![];
+    for line in lines {
+        let mut kv = line.splitn(2, ':');
+        let name = kv.next().unwrap_or("").trim().to_ascii_lowercase();
+        if name == "proxy-authorization" || name == "x-tor-stream-isolation" {
+            auth.extend_from_slice(kv.next().unwrap_or("").trim().as_bytes());
+            auth.push(0);
+        }
+    }
+    if !auth.is_empty() {
+        isolation.socks_auth = Some(auth);
+    }
+
+    let mut stream = match client.connect(&host, port, &isolation) {
+        Ok(s) => s,
+        Err(e) => {
+            let (code, reason) = status_for_error(&e);
+            send_status(&mut conn, code, reason, &e.to_string())?;
+            return Err(e);
+        }
+    };
+    conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n")?;
+    // Data the client sent right after the request belongs to the tunnel
+    if !leftover.is_empty() {
+        stream.write_all(&leftover)?;
+    }
+    crate::splice(conn, stream);
+    return Ok(());
+}
+
+/// Reads the request line and headers, returning them and whatever was read past them.
+fn read_head(conn: &mut TcpStream) -> Result<(String, Vec<u8>), Error> {
+    let mut buf: Vec<u8> = vec![];
+    let mut chunk: [u8; 1024] = [0x0; 1024];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buf[..end]).to_string();
+            return Ok((head, buf[end + 4..].to_vec()));
+        }
+        if buf.len() > MAX_HEADER_LEN {
+            send_status(conn, 431, "Request Header Fields Too Large", "")?;
+            return Err(protocol_error("HTTP request header too long"));
+        }
+        let n = conn.read(&mut chunk)?;
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before end of HTTP request",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Splits "host:port" or "[ipv6]:port".
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    let colon = authority.rfind(':')?;
+    let port = authority[colon + 1..].parse::<u16>().ok()?;
+    let host = &authority[..colon];
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    };
+    if host.is_empty() {
+        return None;
+    }
+    return Some((host.to_string(), port));
+}
+
+fn send_status(conn: &mut TcpStream, code: u16, reason: &str, body: &str) -> Result<(), Error> {
+    let response = format!(
+        "HTTP/1.0 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        code,
+        reason,
+        body.len(),
+        body
+    );
+    return conn.write_all(response.as_bytes());
+}
+
+/// Maps errors from opening the stream to HTTP statuses.
+fn status_for_error(err: &Error) -> (u16, &'static str) {
+    return match EndReason::from_error(err) {
+        Some(EndReason::ExitPolicy) => (403, "Forbidden"),
+        Some(EndReason::ResolveFailed) => (404, "Not Found"),
+        Some(EndReason::Timeout) => (504, "Gateway Timeout"),
+        Some(_) => (502, "Bad Gateway"),
+        None if err.kind() == ErrorKind::TimedOut => (504, "Gateway Timeout"),
+        // We couldn't even get a circuit
+        None => (503, "Service Unavailable"),
+    };
+}
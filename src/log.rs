@@ -0,0 +1,133 @@
+// Log destinations configured with tor's Log option.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Severity {
+    Debug,
+    Info,
+    Notice,
+    Warn,
+    Err,
+}
+
+impl Severity {
+    fn parse(s: &str) -> Option<Severity> {
+        return match s.to_ascii_lowercase().as_str() {
+            "debug" => Some(Severity::Debug),
+            "info" => Some(Severity::Info),
+            "notice" => Some(Severity::Notice),
+            "warn" => Some(Severity::Warn),
+            "err" => Some(Severity::Err),
+            _ => None,
+        };
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Severity::Debug => "debug",
+            Severity::Info => "info",
+            Severity::Notice => "notice",
+            Severity::Warn => "warn",
+            Severity::Err => "err",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    Stdout,
+    Stderr,
+    File(PathBuf),
+}
+
+/// A single Log line: messages from `min` up to `max` severity go to the destination.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSpec {
+    pub min: Severity,
+    pub max: Severity,
+    pub dest: Destination,
+}
+
+impl LogSpec {
+    /// Parses "minSeverity[-maxSeverity] stderr|stdout|file FILENAME".
+    pub fn parse(s: &str) -> Result<LogSpec, Error> {
+        let invalid = |why: &str| return Error::new(ErrorKind::InvalidInput, why.to_string());
+        let mut parts = s.split_whitespace();
+        let severities = parts.next().unwrap_or("");
+        let mut range = severities.splitn(2, '-');
+        let min = Severity::parse(range.next().unwrap())
+            .ok_or_else(|| invalid("Expected a severity of debug, info, notice, warn or err"))?;
+        let max = match range.next() {
+            Some(m) => Severity::parse(m).ok_or_else(|| invalid("Invalid maximum severity"))?,
+            None => Severity::Err,
+        };
+        if min > max {
+            return Err(invalid("Minimum severity is above the maximum"));
+        }
+        let kind = parts.next();
+        let path = parts.collect::<Vec<&str>>().join(" ");
+        let dest = match (kind, path.is_empty()) {
+            (Some("stdout"), true) => Destination::Stdout,
+            (Some("stderr"), true) => Destination::Stderr,
+            (Some("file"), false) => Destination::File(PathBuf::from(path)),
+            (Some("syslog"), _) => return Err(invalid("Logging to syslog isn't supported")),
+            _ => return Err(invalid("Expected stdout, stderr or file FILENAME")),
+        };
+        return Ok(LogSpec { min, max, dest });
+    }
+}
+
+enum Output {
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Writes messages to all destinations whose severity range includes them.
+pub struct Logger {
+    outputs: Mutex<Vec<(Severity, Severity, Output)>>,
+}
+
+impl Logger {
+    /// Opens the destinations. Log files are appended to.
+    pub fn open(specs: &[LogSpec]) -> Result<Logger, Error> {
+        let mut outputs = vec![];
+        for spec in specs {
+            let output = match &spec.dest {
+                Destination::Stdout => Output::Stdout,
+                Destination::Stderr => Output::Stderr,
+                Destination::File(path) => {
+                    Output::File(OpenOptions::new().create(true).append(true).open(path)?)
+                }
+            };
+            outputs.push((spec.min, spec.max, output));
+        }
+        return Ok(Logger {
+            outputs: Mutex::new(outputs),
+        });
+    }
+
+    pub fn log(&self, severity: Severity, msg: &str) {
+        let line = format!("[{}] {}\n", severity, msg);
+        let mut outputs = self.outputs.lock().unwrap();
+        for (min, max, output) in outputs.iter_mut() {
+            if severity < *min || severity > *max {
+                continue;
+            }
+            // There is nowhere left to report failing to log
+            let _ = match output {
+                Output::Stdout => io::stdout().write_all(line.as_bytes()),
+                Output::Stderr => io::stderr().write_all(line.as_bytes()),
+                Output::File(f) => f.write_all(line.as_bytes()),
+            };
+        }
+    }
+}
@@ -1,9 +1,346 @@
-use minionion::TorConnection;
-use std::net::Ipv4Addr;
-use std::net::SocketAddr;
-use std::net::SocketAddrV4;
+mod automap;
+mod config;
+mod dns;
+mod http_tunnel;
+mod log;
+mod socks;
+mod transparent;
+
+use automap::VirtualAddrMap;
+use config::{Config, PortConfig};
+use log::{Logger, Severity};
+use minionion::{
+    ClientConfig, GuardStatus, NodeRestrictions, OnionServiceConfig, StreamIsolation, TorClient,
+    TorStream,
+};
+use std::io::{self, Error};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How often the guard status is checked for changes worth logging
+const GUARD_STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
 fn main() {
-    let hardcoded_relay: SocketAddr =
-        std::net::SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 9001));
-    TorConnection::handshake(hardcoded_relay).unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", config::USAGE);
+        return;
+    }
+    let config = match Config::from_args(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("minitor: {}", e);
+            process::exit(1);
+        }
+    };
+    let log = match Logger::open(&config.logs) {
+        Ok(l) => Arc::new(l),
+        Err(e) => {
+            eprintln!("minitor: can't open log file: {}", e);
+            process::exit(1);
+        }
+    };
+    for addr in &config.control_ports {
+        log.log(
+            Severity::Warn,
+            &format!(
+                "The control port isn't supported, not listening on {}",
+                addr
+            ),
+        );
+    }
+
+    // With bridges, the directory is fetched from them and the directory cache is unused
+    let dir_caches = if config.use_bridges {
+        vec![config.bridges[0].addr]
+    } else {
+        config.fallback_dirs.clone()
+    };
+    let mut client_config = ClientConfig::new(dir_caches[0], &config.data_dir);
+    if config.use_bridges {
+        client_config.bridges = config.bridges.clone();
+    }
+    client_config.restrictions = NodeRestrictions {
+        entry_nodes: config.entry_nodes.clone(),
+        exit_nodes: config.exit_nodes.clone(),
+        exclude_nodes: config.exclude_nodes.clone(),
+    };
+    client_config.onion_auth_dir = config.onion_auth_dir.clone();
+    if let Some(effort) = config.onion_pow_max_effort {
+        client_config.onion_pow_max_effort = effort;
+    }
+    client_config.single_onion_mode = config.hs_single_hop_mode;
+    let mut client: Option<Arc<TorClient>> = None;
+    for dir_cache in dir_caches {
+        client_config.dir_cache = dir_cache;
+        match TorClient::bootstrap(client_config.clone()) {
+            Ok(c) => {
+                client = Some(Arc::new(c));
+                break;
+            }
+            Err(e) => log.log(Severity::Warn, &format!("Bootstrapping failed: {}", e)),
+        }
+    }
+    let client = match client {
+        Some(c) => c,
+        None => {
+            log.log(
+                Severity::Err,
+                "No directory cache could be bootstrapped from",
+            );
+            process::exit(1);
+        }
+    };
+    log.log(Severity::Notice, "Bootstrapped");
+    if !config.use_bridges {
+        watch_guards(&client, &log);
+    }
+
+    // Services stay up as long as their handles are kept
+    let mut services = vec![];
+    for hs in &config.hidden_services {
+        let mut hs_config = OnionServiceConfig::new(&hs.dir);
+        hs_config.ports = hs.ports.clone();
+        hs_config.pow_defenses = hs.pow_defenses;
+        match client.launch_onion_service(hs_config) {
+            Ok(service) => {
+                log.log(
+                    Severity::Notice,
+                    &format!("Publishing onion service {}", service.address()),
+                );
+                services.push(service);
+            }
+            Err(e) => log.log(
+                Severity::Err,
+                &format!(
+                    "Launching the onion service in {} failed: {}",
+                    hs.dir.display(),
+                    e
+                ),
+            ),
+        }
+    }
+
+    // Shared by the DNS listener, which hands out virtual addresses for .onion names,
+    // and the transparent proxy, which connects to the names behind them
+    let automap = if config.automap_hosts_on_resolve {
+        Some(Arc::new(VirtualAddrMap::new()))
+    } else {
+        None
+    };
+    // Like tor, streams from different listeners never share circuits
+    let mut session_group: u32 = 0;
+    let mut isolation = |port: &PortConfig| {
+        session_group += 1;
+        return StreamIsolation {
+            flags: port.isolation,
+            session_group,
+            ..StreamIsolation::default()
+        };
+    };
+    let mut listeners = vec![];
+    for port in &config.socks_ports {
+        listeners.push(listen(
+            "SOCKS proxy",
+            port.addr,
+            isolation(port),
+            &client,
+            &log,
+            socks::serve,
+        ));
+    }
+    for port in &config.http_tunnel_ports {
+        listeners.push(listen(
+            "HTTP CONNECT tunnel",
+            port.addr,
+            isolation(port),
+            &client,
+            &log,
+            http_tunnel::serve,
+        ));
+    }
+    for port in &config.dns_ports {
+        let dns_isolation = isolation(port);
+        let dns_automap = automap.clone();
+        listeners.push(listen(
+            "DNS (TCP)",
+            port.addr,
+            dns_isolation.clone(),
+            &client,
+            &log,
+            move |c, iso, conn| dns::serve_tcp(c, iso, dns_automap.as_deref(), conn),
+        ));
+        listeners.push(listen_udp(
+            port.addr,
+            dns_isolation,
+            &client,
+            &log,
+            automap.clone(),
+        ));
+    }
+    for port in &config.trans_ports {
+        let trans_automap = automap.clone();
+        listeners.push(listen(
+            "Transparent proxy",
+            port.addr,
+            isolation(port),
+            &client,
+            &log,
+            move |c, iso, conn| transparent::serve(c, iso, trans_automap.as_deref(), conn),
+        ));
+    }
+    if listeners.is_empty() && services.is_empty() {
+        log.log(Severity::Warn, "No listeners are configured, exiting");
+    }
+    for l in listeners {
+        let _ = l.join();
+    }
+    while !services.is_empty() {
+        thread::park();
+    }
+}
+
+/// Logs the guard status on a new thread whenever it changes.
+fn watch_guards(client: &Arc<TorClient>, log: &Arc<Logger>) {
+    let (client, log) = (client.clone(), log.clone());
+    thread::spawn(move || {
+        let mut last = String::new();
+        loop {
+            let status = client.guard_status();
+            let summary = describe_guards(&status);
+            if summary != last {
+                let severity = if status.stuck {
+                    Severity::Warn
+                } else {
+                    Severity::Info
+                };
+                log.log(severity, &summary);
+                last = summary;
+            }
+            thread::sleep(GUARD_STATUS_INTERVAL);
+        }
+    });
+}
+
+fn describe_guards(status: &GuardStatus) -> String {
+    let primary: Vec<String> = status
+        .primary
+        .iter()
+        .map(|g| format!("{} ({:?})", g.nickname, g.reachable))
+        .collect();
+    let mut summary = format!(
+        "Guards: {} sampled, {} usable, {} confirmed, primary: {}",
+        status.sampled,
+        status.usable_filtered,
+        status.confirmed,
+        primary.join(", ")
+    );
+    if status.stuck {
+        summary.push_str("; no guard is usable, so no circuits can be built");
+    }
+    return summary;
+}
+
+/// Accepts connections on a new thread, handling each one on its own thread.
+/// Streams for a connection are isolated as given, and by the client's address.
+fn listen<F>(
+    name: &'static str,
+    addr: SocketAddr,
+    isolation: StreamIsolation,
+    client: &Arc<TorClient>,
+    log: &Arc<Logger>,
+    handler: F,
+) -> thread::JoinHandle<()>
+where
+    F: Fn(&TorClient, StreamIsolation, TcpStream) -> Result<(), Error> + Send + Sync + 'static,
+{
+    let (client, log) = (client.clone(), log.clone());
+    let handler = Arc::new(handler);
+    return thread::spawn(move || {
+        let listener = match TcpListener::bind(addr) {
+            Ok(l) => l,
+            Err(e) => {
+                log.log(
+                    Severity::Err,
+                    &format!("{}: can't listen on {}: {}", name, addr, e),
+                );
+                return;
+            }
+        };
+        log.log(Severity::Notice, &format!("{} listening on {}", name, addr));
+        for conn in listener.incoming() {
+            let conn = match conn {
+                Ok(c) => c,
+                Err(e) => {
+                    log.log(
+                        Severity::Warn,
+                        &format!("{}: failed to accept connection: {}", name, e),
+                    );
+                    continue;
+                }
+            };
+            let (client, log) = (client.clone(), log.clone());
+            let handler = handler.clone();
+            let isolation = StreamIsolation {
+                client_addr: conn.peer_addr().ok().map(|a| a.ip()),
+                ..isolation.clone()
+            };
+            thread::spawn(move || {
+                if let Err(e) = handler(&client, isolation, conn) {
+                    log.log(Severity::Info, &format!("{}: request failed: {}", name, e));
+                }
+            });
+        }
+    });
+}
+
+/// Answers DNS queries arriving over UDP on a new thread.
+fn listen_udp(
+    addr: SocketAddr,
+    isolation: StreamIsolation,
+    client: &Arc<TorClient>,
+    log: &Arc<Logger>,
+    automap: Option<Arc<VirtualAddrMap>>,
+) -> thread::JoinHandle<()> {
+    let (client, log) = (client.clone(), log.clone());
+    return thread::spawn(move || {
+        let socket = match UdpSocket::bind(addr) {
+            Ok(s) => s,
+            Err(e) => {
+                log.log(
+                    Severity::Err,
+                    &format!("DNS (UDP): can't listen on {}: {}", addr, e),
+                );
+                return;
+            }
+        };
+        log.log(
+            Severity::Notice,
+            &format!("DNS (UDP) listening on {}", addr),
+        );
+        if let Err(e) = dns::serve_udp(client, isolation, automap, socket) {
+            log.log(Severity::Err, &format!("DNS (UDP): {}", e));
+        }
+    });
+}
+
+/// Copies data between the connection and the stream in both directions.
+/// Once either side is done, both are closed.
+fn splice(conn: TcpStream, stream: TorStream) {
+    let (mut conn_rd, mut stream_wr) = match (conn.try_clone(), stream.try_clone()) {
+        (Ok(c), Ok(s)) => (c, s),
+        _ => return,
+    };
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut conn_rd, &mut stream_wr);
+        stream_wr.close();
+    });
+    let (mut conn_wr, mut stream_rd) = (&conn, stream);
+    let _ = io::copy(&mut stream_rd, &mut conn_wr);
+    let _ = conn.shutdown(Shutdown::Both);
+    stream_rd.close();
+    let _ = upstream.join();
 }
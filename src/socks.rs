@@ -0,0 +1,370 @@
+// SOCKS server side: SOCKS4, SOCKS4a, SOCKS5 (RFC 1928 and RFC 1929) and tor's
+// RESOLVE and RESOLVE_PTR extensions (socks-extensions.txt).
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+
+use minionion::{EndReason, OnionError, Resolved, ResolvedValue, StreamIsolation, TorClient};
+
+const SOCKS_VERSION_4: u8 = 0x04;
+const SOCKS_VERSION_5: u8 = 0x05;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const USERPASS_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_RESOLVE: u8 = 0xf0;
+const CMD_RESOLVE_PTR: u8 = 0xf1;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// SOCKS5 reply codes
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+// tor's extended replies for onion services (prop304)
+const REPLY_ONION_DESC_NOT_FOUND: u8 = 0xf0;
+const REPLY_ONION_DESC_INVALID: u8 = 0xf1;
+const REPLY_ONION_INTRO_FAILED: u8 = 0xf2;
+const REPLY_ONION_REND_FAILED: u8 = 0xf3;
+const REPLY_ONION_MISSING_CLIENT_AUTH: u8 = 0xf4;
+const REPLY_ONION_BAD_CLIENT_AUTH: u8 = 0xf5;
+const REPLY_ONION_BAD_ADDRESS: u8 = 0xf6;
+const REPLY_ONION_INTRO_TIMED_OUT: u8 = 0xf7;
+
+// SOCKS4 reply codes
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Connect,
+    Resolve,
+    ResolvePtr,
+}
+
+/// A request, after negotiation.
+#[derive(Debug, Clone)]
+struct Request {
+    version: u8,
+    command: Command,
+    host: String,
+    port: u16,
+    // Credentials, which select a circuit like tor's IsolateSOCKSAuth
+    auth: Option<Vec<u8>>,
+}
+
+/// The answer to a request, in a form both SOCKS versions can express.
+enum Answer {
+    Failed(u8),
+    Connected,
+    Address(IpAddr),
+    Hostname(String),
+}
+
+fn protocol_error(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg.to_string());
+}
+
+fn read_u8(rdr: &mut dyn Read) -> Result<u8, Error> {
+    let mut buf: [u8; 1] = [0x0];
+    rdr.read_exact(&mut buf)?;
+    return Ok(buf[0]);
+}
+
+fn read_vec(rdr: &mut dyn Read, len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf: Vec<u8> = vec![0x0; len];
+    rdr.read_exact(&mut buf)?;
+    return Ok(buf);
+}
+
+/// Reads a NUL-terminated string, as used by SOCKS4.
+fn read_nul_terminated(rdr: &mut dyn Read) -> Result<Vec<u8>, Error> {
+    // Limit the length so a client can't make us buffer forever
+    const MAX_LEN: usize = 1024;
+    let mut out: Vec<u8> = vec![];
+    loop {
+        let b = read_u8(rdr)?;
+        if b == 0 {
+            return Ok(out);
+        }
+        if out.len() == MAX_LEN {
+            return Err(protocol_error("SOCKS4 string too long"));
+        }
+        out.push(b);
+    }
+}
+
+/// Handles a SOCKS client: negotiates, carries out the request and, for CONNECT,
+/// relays data until either side closes.
+pub fn serve(
+    client: &TorClient,
+    mut isolation: StreamIsolation,
+    mut conn: TcpStream,
+) -> Result<(), Error> {
+    let request = match read_u8(&mut conn)? {
+        SOCKS_VERSION_4 => read_socks4_request(&mut conn)?,
+        SOCKS_VERSION_5 => match read_socks5_request(&mut conn)? {
+            Ok(r) => r,
+            Err(reply) => {
+                send_reply(&mut conn, SOCKS_VERSION_5, &Answer::Failed(reply))?;
+                return Err(protocol_error("Unsupported SOCKS5 request"));
+            }
+        },
+        _ => return Err(protocol_error("Unsupported SOCKS version")),
+    };
+    isolation.socks_auth = request.auth.clone();
+
+    match request.command {
+        Command::Connect => match client.connect(&request.host, request.port, &isolation) {
+            Ok(stream) => {
+                send_reply(&mut conn, request.version, &Answer::Connected)?;
+                crate::splice(conn, stream);
+            }
+            Err(e) => {
+                send_reply(
+                    &mut conn,
+                    request.version,
+                    &Answer::Failed(reply_for_error(&e)),
+                )?;
+                return Err(e);
+            }
+        },
+        Command::Resolve | Command::ResolvePtr => {
+            let result = if request.command == Command::Resolve {
+                client.resolve(&request.host, &isolation)
+            } else {
+                match request.host.parse::<IpAddr>() {
+                    Ok(addr) => client.resolve_ptr(addr, &isolation),
+                    Err(_) => Err(protocol_error("RESOLVE_PTR needs an IP address")),
+                }
+            };
+            let answer = match result {
+                Ok(answers) => answer_for_resolved(&answers),
+                Err(e) => Answer::Failed(reply_for_error(&e)),
+            };
+            send_reply(&mut conn, request.version, &answer)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Reads a SOCKS4 or SOCKS4a request, after the version byte.
+fn read_socks4_request(conn: &mut TcpStream) -> Result<Request, Error> {
+    let header = read_vec(conn, 7)?;
+    let command = match header[0] {
+        CMD_CONNECT => Some(Command::Connect),
+        CMD_RESOLVE => Some(Command::Resolve),
+        _ => None,
+    };
+    let port = u16::from_be_bytes([header[1], header[2]]);
+    let ip = Ipv4Addr::new(header[3], header[4], header[5], header[6]);
+    let user_id = read_nul_terminated(conn)?;
+    // SOCKS4a: an address of 0.0.0.x with x != 0 means a hostname follows
+    let host = if ip.octets()[..3] == [0, 0, 0] && ip.octets()[3] != 0 {
+        match String::from_utf8(read_nul_terminated(conn)?) {
+            Ok(h) => h,
+            Err(_) => {
+                send_reply(
+                    conn,
+                    SOCKS_VERSION_4,
+                    &Answer::Failed(REPLY_GENERAL_FAILURE),
+                )?;
+                return Err(protocol_error("Hostname is not valid UTF-8"));
+            }
+        }
+    } else {
+        ip.to_string()
+    };
+    let command = match command {
+        Some(c) => c,
+        None => {
+            send_reply(
+                conn,
+                SOCKS_VERSION_4,
+                &Answer::Failed(REPLY_COMMAND_NOT_SUPPORTED),
+            )?;
+            return Err(protocol_error("Unsupported SOCKS4 command"));
+        }
+    };
+    return Ok(Request {
+        version: SOCKS_VERSION_4,
+        command,
+        host,
+        port,
+        // An empty user ID is no credential at all
+        auth: if user_id.is_empty() {
+            None
+        } else {
+            Some(user_id)
+        },
+    });
+}
+
+/// Reads a SOCKS5 request, after the version byte. Requests we can't serve
+/// yield the reply code to send instead.
+fn read_socks5_request(conn: &mut TcpStream) -> Result<Result<Request, u8>, Error> {
+    let auth = negotiate_auth(conn)?;
+
+    let header = read_vec(conn, 4)?;
+    if header[0] != SOCKS_VERSION_5 {
+        return Err(protocol_error("Unexpected SOCKS version in request"));
+    }
+    let host = match header[3] {
+        ATYP_IPV4 => {
+            let a = read_vec(conn, 4)?;
+            Ipv4Addr::new(a[0], a[1], a[2], a[3]).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = read_u8(conn)? as usize;
+            match String::from_utf8(read_vec(conn, len)?) {
+                Ok(h) => h,
+                Err(_) => return Ok(Err(REPLY_GENERAL_FAILURE)),
+            }
+        }
+        ATYP_IPV6 => {
+            let mut a: [u8; 16] = [0x0; 16];
+            conn.read_exact(&mut a)?;
+            Ipv6Addr::from(a).to_string()
+        }
+        _ => return Ok(Err(REPLY_ADDRESS_TYPE_NOT_SUPPORTED)),
+    };
+    let port_bytes = read_vec(conn, 2)?;
+    let port = u16::from_be_bytes([port_bytes[0], port_bytes[1]]);
+
+    let command = match header[1] {
+        CMD_CONNECT => Command::Connect,
+        CMD_RESOLVE => Command::Resolve,
+        CMD_RESOLVE_PTR if header[3] != ATYP_DOMAIN => Command::ResolvePtr,
+        CMD_RESOLVE_PTR => return Ok(Err(REPLY_ADDRESS_TYPE_NOT_SUPPORTED)),
+        _ => return Ok(Err(REPLY_COMMAND_NOT_SUPPORTED)),
+    };
+    return Ok(Ok(Request {
+        version: SOCKS_VERSION_5,
+        command,
+        host,
+        port,
+        auth,
+    }));
+}
+
+/// Selects an authentication method and returns the credentials given, if any.
+/// Username/password is preferred when offered, since it's what clients use to request isolation.
+fn negotiate_auth(conn: &mut TcpStream) -> Result<Option<Vec<u8>>, Error> {
+    let n_methods = read_u8(conn)? as usize;
+    let methods = read_vec(conn, n_methods)?;
+    if methods.contains(&AUTH_USERNAME_PASSWORD) {
+        conn.write_all(&[SOCKS_VERSION_5, AUTH_USERNAME_PASSWORD])?;
+        if read_u8(conn)? != USERPASS_VERSION {
+            return Err(protocol_error("Unsupported username/password auth version"));
+        }
+        let ulen = read_u8(conn)? as usize;
+        let mut key = read_vec(conn, ulen)?;
+        let plen = read_u8(conn)? as usize;
+        key.push(0);
+        key.extend(read_vec(conn, plen)?);
+        // Any credentials are accepted; they only select a circuit
+        conn.write_all(&[USERPASS_VERSION, 0x00])?;
+        return Ok(Some(key));
+    }
+    if methods.contains(&AUTH_NONE) {
+        conn.write_all(&[SOCKS_VERSION_5, AUTH_NONE])?;
+        return Ok(None);
+    }
+    conn.write_all(&[SOCKS_VERSION_5, AUTH_NO_ACCEPTABLE])?;
+    return Err(protocol_error("No acceptable SOCKS authentication method"));
+}
+
+fn send_reply(conn: &mut TcpStream, version: u8, answer: &Answer) -> Result<(), Error> {
+    if version == SOCKS_VERSION_4 {
+        // SOCKS4 can only carry IPv4 addresses and a single failure code
+        let (code, ip) = match answer {
+            Answer::Failed(_) | Answer::Hostname(_) | Answer::Address(IpAddr::V6(_)) => {
+                (SOCKS4_REJECTED, [0x0; 4])
+            }
+            Answer::Connected => (SOCKS4_GRANTED, [0x0; 4]),
+            Answer::Address(IpAddr::V4(ip)) => (SOCKS4_GRANTED, ip.octets()),
+        };
+        let mut reply: Vec<u8> = vec![0x00, code, 0, 0];
+        reply.extend_from_slice(&ip);
+        return conn.write_all(&reply);
+    }
+
+    // The bound address is not meaningful for streams through Tor
+    let mut reply: Vec<u8> = vec![SOCKS_VERSION_5, REPLY_SUCCEEDED, 0x00];
+    match answer {
+        Answer::Failed(code) => {
+            reply[1] = *code;
+            reply.extend_from_slice(&[ATYP_IPV4, 0, 0, 0, 0]);
+        }
+        Answer::Connected => reply.extend_from_slice(&[ATYP_IPV4, 0, 0, 0, 0]),
+        Answer::Address(IpAddr::V4(ip)) => {
+            reply.push(ATYP_IPV4);
+            reply.extend_from_slice(&ip.octets());
+        }
+        Answer::Address(IpAddr::V6(ip)) => {
+            reply.push(ATYP_IPV6);
+            reply.extend_from_slice(&ip.octets());
+        }
+        Answer::Hostname(name) => {
+            let name = &name.as_bytes()[..std::cmp::min(name.len(), 255)];
+            reply.extend_from_slice(&[ATYP_DOMAIN, name.len() as u8]);
+            reply.extend_from_slice(name);
+        }
+    }
+    // Port field
+    reply.extend_from_slice(&[0, 0]);
+    return conn.write_all(&reply);
+}
+
+/// Picks the answer to report from a RELAY_RESOLVED cell. Like tor, the first answer is used.
+fn answer_for_resolved(answers: &[Resolved]) -> Answer {
+    return match answers.first() {
+        Some(Resolved {
+            value: ResolvedValue::Ip(ip),
+            ..
+        }) => Answer::Address(*ip),
+        Some(Resolved {
+            value: ResolvedValue::Hostname(name),
+            ..
+        }) => Answer::Hostname(name.clone()),
+        Some(Resolved {
+            value: ResolvedValue::TransientError,
+            ..
+        }) => Answer::Failed(REPLY_GENERAL_FAILURE),
+        _ => Answer::Failed(REPLY_HOST_UNREACHABLE),
+    };
+}
+
+/// Maps errors to SOCKS5 replies, following tor's stream_end_reason_to_socks5_response().
+fn reply_for_error(err: &Error) -> u8 {
+    if let Some(e) = OnionError::from_error(err) {
+        return match e {
+            OnionError::DescriptorNotFound => REPLY_ONION_DESC_NOT_FOUND,
+            OnionError::DescriptorInvalid => REPLY_ONION_DESC_INVALID,
+            OnionError::IntroFailed => REPLY_ONION_INTRO_FAILED,
+            OnionError::RendezvousFailed => REPLY_ONION_REND_FAILED,
+            OnionError::ClientAuthMissing => REPLY_ONION_MISSING_CLIENT_AUTH,
+            OnionError::ClientAuthIncorrect => REPLY_ONION_BAD_CLIENT_AUTH,
+            OnionError::BadAddress => REPLY_ONION_BAD_ADDRESS,
+            OnionError::IntroTimedOut => REPLY_ONION_INTRO_TIMED_OUT,
+        };
+    }
+    return match EndReason::from_error(err) {
+        Some(EndReason::ResolveFailed) => REPLY_HOST_UNREACHABLE,
+        Some(EndReason::ConnectRefused) | Some(EndReason::ConnReset) => REPLY_CONNECTION_REFUSED,
+        Some(EndReason::ExitPolicy) => REPLY_NOT_ALLOWED,
+        Some(EndReason::Timeout) => REPLY_TTL_EXPIRED,
+        Some(EndReason::NoRoute) => REPLY_NETWORK_UNREACHABLE,
+        Some(_) => REPLY_GENERAL_FAILURE,
+        None if err.kind() == ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        None => REPLY_GENERAL_FAILURE,
+    };
+}
@@ -0,0 +1,97 @@
+// Transparent proxy for connections redirected with iptables, like tor's TransPort.
+// The destination the client intended is read back with SO_ORIGINAL_DST, so this only works on Linux.
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+
+use minionion::{StreamIsolation, TorClient};
+
+use crate::automap::VirtualAddrMap;
+
+/// Connects a redirected connection to its original destination through an exit.
+/// Connections to virtual addresses are made to the name the address was handed out for.
+pub fn serve(
+    client: &TorClient,
+    isolation: StreamIsolation,
+    automap: Option<&VirtualAddrMap>,
+    conn: TcpStream,
+) -> Result<(), Error> {
+    let dest = original_dst(&conn)?;
+    // A connection that was not redirected would otherwise loop back into us
+    if dest == conn.local_addr()? {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Connection was not redirected to the transparent proxy",
+        ));
+    }
+    let virtual_name = match (automap, dest.ip()) {
+        (Some(map), IpAddr::V4(ip)) => map.lookup(ip),
+        _ => None,
+    };
+    let host = match virtual_name {
+        Some(name) => name,
+        None => dest.ip().to_string(),
+    };
+    let stream = client.connect(&host, dest.port(), &isolation)?;
+    crate::splice(conn, stream);
+    return Ok(());
+}
+
+#[cfg(target_os = "linux")]
+fn original_dst(conn: &TcpStream) -> Result<SocketAddr, Error> {
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+
+    let fd = conn.as_raw_fd();
+    // sockaddr_in6 is large enough for either family
+    let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    let (level, name) = match conn.local_addr()? {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+    };
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut addr as *mut libc::sockaddr_in6 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    match addr.sin6_family as libc::c_int {
+        libc::AF_INET => {
+            let v4 = unsafe { *(&addr as *const libc::sockaddr_in6 as *const libc::sockaddr_in) };
+            return Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(v4.sin_addr.s_addr)),
+                u16::from_be(v4.sin_port),
+            )));
+        }
+        libc::AF_INET6 => {
+            return Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                0,
+                0,
+            )));
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unknown address family for original destination",
+            ))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_dst(_conn: &TcpStream) -> Result<SocketAddr, Error> {
+    return Err(Error::new(
+        ErrorKind::Other,
+        "Transparent proxying is only supported on Linux",
+    ));
+}